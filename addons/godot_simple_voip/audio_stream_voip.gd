@@ -8,6 +8,20 @@ class_name AudioStreamVOIP
 ## [AudioStreamPlayer3D], set [member peer_id], and start playback.
 ##[br][br]
 ## The stream handles short buffering internally to keep voice playback smooth.
+##[br][br]
+## When using your own networking instead of the VOIP singleton, push the
+## received Opus packets of one peer with [method push_packet]. Each stream
+## owns its decoder, so decoder state is never mixed between peers.
+
+## Emitted when voice starts arriving after a period of silence.
+signal speech_started
+## Emitted when no voice arrived for [member stall_timeout_ms] while the peer
+## was speaking.
+signal stream_stalled
+## Emitted when no voice arrived for [member speech_end_timeout_ms]; the peer
+## is considered to have stopped talking. Also emitted by [method flush] while
+## the peer was speaking.
+signal speech_ended
 
 ## Which peer's voice should be played through this stream.
 @export var peer_id: int = 0:
@@ -18,7 +32,43 @@ class_name AudioStreamVOIP
 		peer_id = value
 		_set_voice_signal_enabled(true)
 
+## Collapse stereo PCM passed to [method push_pcm] to a single centered
+## channel before playback.
+##
+## Only matters for your own stereo input, e.g. a stereo source played by an
+## [AudioStreamPlayer3D], so panning comes entirely from the spatializer.
+## Opus voice is mono already and decoded to identical left and right
+## channels, so [method push_packet] and [VoipManager] don't need it.
+@export var mono := false
+
+## How much voice to buffer before playback starts or restarts, in milliseconds.
+##
+## Higher values survive more network jitter at the cost of added delay.
+@export_range(0.0, 1000.0, 1.0, "suffix:ms") var prebuffer_ms := 60.0:
+	set(value):
+		prebuffer_ms = maxf(0.0, value)
+		_update_start_buffer_frames()
+
+## Time without incoming voice after which [signal stream_stalled] is emitted.
+@export_range(0.0, 2000.0, 1.0, "suffix:ms") var stall_timeout_ms := 120.0
+
+## Time without incoming voice after which [signal speech_ended] is emitted.
+@export_range(0.0, 5000.0, 1.0, "suffix:ms") var speech_end_timeout_ms := 400.0
+
+## Fade out the last buffered voice when the stream stalls, instead of cutting
+## it off with a click.
+@export var auto_fade_out := true
+
+## Length of the gain ramp applied when a peer starts talking. Removes the
+## click heard at push-to-talk press. Set to 0 to disable.
+@export_range(0.0, 100.0, 0.5, "suffix:ms") var fade_in_ms := 5.0
+
+## Length of the gain ramp applied by [member auto_fade_out] when a peer
+## stops talking.
+@export_range(0.0, 100.0, 0.5, "suffix:ms") var fade_out_ms := 10.0
+
 var _playback: AudioStreamGeneratorPlayback = null
+var _decoder: OpusCodec = null
 var _pending_frames: PackedVector2Array = PackedVector2Array()
 var _pending_read_pos := 0
 var _started := false
@@ -26,6 +76,12 @@ var _sample_rate := 48_000
 var _frame_size := 960
 var _start_buffer_frames := 2_880
 var _max_pending_frames := 48_000
+var _paused := false
+var _speaking := false
+var _stalled := false
+var _last_receive_usec := 0
+var _fade_in_total := 0
+var _fade_in_remaining := 0
 
 var _dbg_chunks_received := 0
 var _dbg_frames_received := 0
@@ -41,6 +97,7 @@ func _init() -> void:
 	if _sample_rate <= 0:
 		_sample_rate = 48_000
 	mix_rate = _sample_rate
+	_update_start_buffer_frames()
 	_set_voice_signal_enabled(true)
 
 
@@ -72,7 +129,7 @@ func configure_stream(sample_rate: int, frame_size: int) -> void:
 		mix_rate = sample_rate
 	_sample_rate = sample_rate
 	_frame_size = frame_size
-	_start_buffer_frames = _frame_size * 3
+	_update_start_buffer_frames()
 	_max_pending_frames = _sample_rate
 
 
@@ -80,16 +137,98 @@ func configure_stream(sample_rate: int, frame_size: int) -> void:
 ##
 ## Usually called automatically by the VOIP singleton each frame.
 func pump_playback() -> void:
+	_check_liveness()
 	_flush_pending_to_playback()
 
 
-func _on_voice_data(speaker_peer_id: int, pcm_data: PackedVector2Array) -> void:
-	if speaker_peer_id != peer_id or not pcm_data:
+## Returns true while voice is arriving for this stream.
+func is_speaking() -> bool:
+	return _speaking
+
+
+## Returns how much voice is queued and not played yet, in milliseconds.
+func get_buffered_ms() -> float:
+	var frames := _pending_available()
+	if _playback != null:
+		var capacity := int(buffer_length * _sample_rate)
+		frames += maxi(0, capacity - _playback.get_frames_available())
+	return frames * 1000.0 / _sample_rate
+
+
+## Drops all buffered voice, including audio already queued in the playback.
+##
+## Playback restarts once [member prebuffer_ms] of new voice has arrived. Call
+## this when switching voice channels or teleporting, so stale audio isn't replayed.
+## Emits [signal speech_ended] if the peer was speaking.
+func flush() -> void:
+	var was_speaking := _speaking
+	_pending_frames = PackedVector2Array()
+	_pending_read_pos = 0
+	_started = false
+	_speaking = false
+	_stalled = false
+	# Decoder prediction state belongs to the old audio as well.
+	_decoder = null
+	if _playback != null:
+		_playback.clear_buffer()
+	if was_speaking:
+		speech_ended.emit()
+
+
+## Stops queueing incoming voice. Voice received while paused is discarded.
+func pause() -> void:
+	_paused = true
+	flush()
+
+
+## Resumes queueing incoming voice after [method pause].
+func resume() -> void:
+	_paused = false
+
+
+## Returns true while the stream is paused.
+func is_paused() -> bool:
+	return _paused
+
+
+## Decodes an Opus packet with this stream's own decoder and queues it for playback.
+##
+## Push the packets of a single peer in the order they were sent.
+func push_packet(opus_packet: PackedByteArray) -> void:
+	if _paused or opus_packet.is_empty():
+		return
+	if _decoder == null:
+		_decoder = OpusCodec.new()
+	push_pcm(_decoder.decode_with_sample_rate(opus_packet, _sample_rate))
+
+
+## Queues already decoded PCM data for playback.
+func push_pcm(pcm_data: PackedVector2Array) -> void:
+	if _paused or not pcm_data:
 		return
 
 	_dbg_chunks_received += 1
 	_dbg_frames_received += pcm_data.size()
 
+	if mono:
+		pcm_data = _downmix_to_mono(pcm_data)
+
+	_last_receive_usec = Time.get_ticks_usec()
+	var was_stalled := _stalled
+	_stalled = false
+	if not _speaking or was_stalled:
+		# The stall faded the voice out and let the buffer run dry, so start
+		# over like a new talk spurt.
+		_fade_in_total = int(round(fade_in_ms * 0.001 * _sample_rate))
+		_fade_in_remaining = _fade_in_total
+		_started = false
+	if not _speaking:
+		_speaking = true
+		speech_started.emit()
+
+	if _fade_in_remaining > 0:
+		pcm_data = _apply_fade_in(pcm_data)
+
 	_pending_frames.append_array(pcm_data)
 	if _pending_available() > _max_pending_frames:
 		# Keep latency bounded if we ever fall behind.
@@ -101,11 +240,77 @@ func _on_voice_data(speaker_peer_id: int, pcm_data: PackedVector2Array) -> void:
 	_flush_pending_to_playback()
 
 
+func _on_voice_data(speaker_peer_id: int, pcm_data: PackedVector2Array) -> void:
+	if speaker_peer_id != peer_id:
+		return
+	push_pcm(pcm_data)
+
+
+func _check_liveness() -> void:
+	if not _speaking:
+		return
+
+	var silent_ms := (Time.get_ticks_usec() - _last_receive_usec) / 1000.0
+	if not _stalled and silent_ms >= stall_timeout_ms:
+		_stalled = true
+		if auto_fade_out:
+			_fade_out_pending()
+		stream_stalled.emit()
+
+	if silent_ms >= speech_end_timeout_ms:
+		_speaking = false
+		speech_ended.emit()
+
+
+func _fade_out_pending() -> void:
+	var fade_frames := mini(_fade_out_frames(), _pending_available())
+	if fade_frames <= 0:
+		return
+
+	var start := _pending_frames.size() - fade_frames
+	for i in range(fade_frames):
+		var gain := 1.0 - float(i + 1) / float(fade_frames)
+		_pending_frames[start + i] *= gain
+
+
+## Returns true once the next packet should have arrived already.
+func _is_voice_overdue() -> bool:
+	var packet_usec := _frame_size * 1_000_000 / _sample_rate
+	return Time.get_ticks_usec() - _last_receive_usec > packet_usec
+
+
+func _fade_out_frames() -> int:
+	return int(round(fade_out_ms * 0.001 * _sample_rate))
+
+
+func _apply_fade_in(pcm_data: PackedVector2Array) -> PackedVector2Array:
+	var out := pcm_data.duplicate()
+	var count := mini(_fade_in_remaining, out.size())
+	for i in range(count):
+		var position := _fade_in_total - _fade_in_remaining + i + 1
+		out[i] *= float(position) / float(_fade_in_total)
+	_fade_in_remaining -= count
+	return out
+
+
+func _downmix_to_mono(pcm_data: PackedVector2Array) -> PackedVector2Array:
+	var out := PackedVector2Array()
+	out.resize(pcm_data.size())
+	for i in range(pcm_data.size()):
+		var sample := (pcm_data[i].x + pcm_data[i].y) * 0.5
+		out[i] = Vector2(sample, sample)
+	return out
+
+
 func _flush_pending_to_playback() -> void:
 	if _playback == null:
 		return
 
 	if _pending_available() <= 0:
+		if _started and not _speaking:
+			# Re-prime on the next talk spurt.
+			_started = false
+			return
 		if _started:
 			var now_usec := Time.get_ticks_usec()
 			if now_usec - _dbg_last_underrun_usec >= 100_000:
@@ -124,6 +329,10 @@ func _flush_pending_to_playback() -> void:
 		return
 
 	var available_pending := _pending_available()
+	if auto_fade_out and not _stalled and _is_voice_overdue():
+		# The next packet is late; hold back the tail so it can still be
+		# faded if the stream stalls.
+		available_pending -= _fade_out_frames()
 	var to_push := mini(frames_available, available_pending)
 	if to_push <= 0:
 		return
@@ -192,6 +401,10 @@ func _get_voip_singleton() -> Node:
 	return tree.root.get_node("VOIP")
 
 
+func _update_start_buffer_frames() -> void:
+	_start_buffer_frames = int(round(prebuffer_ms * 0.001 * _sample_rate))
+
+
 func _pending_available() -> int:
 	return _pending_frames.size() - _pending_read_pos
 
@@ -0,0 +1,77 @@
+extends VoipTransport
+class_name VoipENetTransport
+
+## Carries voice on a dedicated unreliable ENet channel.
+##
+## Voice is sent as raw bytes through [SceneMultiplayer] on its own transfer
+## channel, which [ENetMultiplayerPeer] maps to a separate ENet channel. Lost
+## or delayed voice then never holds back reliable gameplay traffic, and no
+## RPC overhead is added per packet. Create the peer with enough channels,
+## e.g. [code]peer.create_server(port, 32, 2)[/code] for [member channel] 1.
+##[br][br]
+## Other raw bytes sent with [method SceneMultiplayer.send_bytes] are left
+## alone; voice packets carry a marker byte to tell them apart.
+
+## Marks raw multiplayer bytes as voice.
+const PACKET_TAG := 0x56
+
+## Transfer channel reserved for voice. Must be below the channel count the
+## [ENetMultiplayerPeer] was created with.
+@export_range(1, 255) var channel := 1
+
+var _warned_peer_type := false
+
+
+func _ready() -> void:
+	multiplayer.peer_connected.connect(_on_peer_connected)
+	multiplayer.peer_disconnected.connect(_on_peer_disconnected)
+	var scene_multiplayer := multiplayer as SceneMultiplayer
+	if scene_multiplayer == null:
+		push_error("VoipENetTransport needs the default SceneMultiplayer.")
+		return
+	scene_multiplayer.peer_packet.connect(_on_peer_packet)
+
+
+func send_packet(peer_id: int, packet: PackedByteArray) -> void:
+	var scene_multiplayer := multiplayer as SceneMultiplayer
+	if scene_multiplayer == null or not _is_online():
+		return
+	if not _warned_peer_type and not (multiplayer.multiplayer_peer is ENetMultiplayerPeer):
+		_warned_peer_type = true
+		push_warning("VoipENetTransport: the multiplayer peer isn't an ENetMultiplayerPeer, voice may share a channel with other traffic.")
+
+	var tagged := PackedByteArray([PACKET_TAG])
+	tagged.append_array(packet)
+	scene_multiplayer.send_bytes(tagged, peer_id, MultiplayerPeer.TRANSFER_MODE_UNRELIABLE, channel)
+
+
+## Polls the multiplayer peer, which hands queued packets to the network.
+func flush() -> void:
+	if _is_online():
+		multiplayer.multiplayer_peer.poll()
+
+
+func get_peers() -> Array[int]:
+	var peers: Array[int] = []
+	if is_inside_tree() and _is_online():
+		peers.assign(multiplayer.get_peers())
+	return peers
+
+
+func _is_online() -> bool:
+	var peer := multiplayer.multiplayer_peer
+	return peer != null and peer.get_connection_status() == MultiplayerPeer.CONNECTION_CONNECTED
+
+
+func _on_peer_connected(peer_id: int) -> void:
+	peer_connected.emit(peer_id)
+
+
+func _on_peer_disconnected(peer_id: int) -> void:
+	peer_disconnected.emit(peer_id)
+
+
+func _on_peer_packet(peer_id: int, packet: PackedByteArray) -> void:
+	if packet.size() < 2 or packet[0] != PACKET_TAG:
+		return
+	packet_received.emit(peer_id, packet.slice(1))
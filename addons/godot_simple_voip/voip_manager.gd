@@ -0,0 +1,1250 @@
+extends Node
+class_name VoipManager
+
+## Voice chat over your own networking in a few lines of code.
+##
+## The manager encodes the local microphone from the [code]VOIP[/code]
+## singleton and emits it as packets through [signal packet_ready]. For every
+## peer passed to [method register_peer] it owns a decoder, a jitter buffer
+## and an [AudioStreamPlayer] with an [AudioStreamVOIP] that plays the peer's
+## voice. Assign a [VoipTransport] to [member transport] and the manager
+## sends and receives by itself. Without one, send the packets any way you
+## like and hand received ones to [method receive_packet]:
+## [codeblock]
+## @onready var voip := VoipManager.new()
+##
+## func _ready() -> void:
+##     add_child(voip)
+##     voip.packet_ready.connect(func(packet): _send_voice.rpc(packet))
+##     multiplayer.peer_connected.connect(voip.register_peer)
+##     multiplayer.peer_disconnected.connect(voip.unregister_peer)
+##
+## @rpc("any_peer", "unreliable")
+## func _send_voice(packet: PackedByteArray) -> void:
+##     voip.receive_packet(multiplayer.get_remote_sender_id(), packet)
+## [/codeblock]
+## The manager takes over sending, so it turns off
+## [code]VOIP.sending_voice[/code] when it enters the tree.
+##[br][br]
+## With [member routing_mode] set to SERVER_RELAY, clients only send to
+## [member relay_peer_id], and the manager with [member is_relay_server]
+## forwards every packet to the other peers, filtered by
+## [member relay_filter]. The relay server can also take away a peer's right
+## to talk with [method set_peer_transmit_allowed]; clients can't get around
+## that, since their voice never reaches anyone else.
+##[br][br]
+## Voice can be split into channels for team chat, proximity groups or
+## spectators. Every peer talks into one channel, set with
+## [method set_peer_channel], and only peers in channels this manager joined
+## with [method join_channel] are heard. All peers start in
+## [constant DEFAULT_CHANNEL], which is joined from the start.
+##[br][br]
+## While a peer marked with [method set_peer_priority] talks, every other peer
+## is turned down by [member duck_amount_db], and so is
+## [member duck_music_bus] if set.
+##[br][br]
+## For proximity voice, set [member spatial_parent_path] and every peer is
+## played by an [AudioStreamPlayer3D] under its character instead.
+##[br][br]
+## For a "test my mic" screen, turn on [member loopback_enabled] to hear
+## yourself through the whole encode, network and decode path.
+##[br][br]
+## [method start_session_recording] records the conversation, mixed into one
+## file or as one file per peer.
+##[br][br]
+## Voice can be encrypted end to end with [method set_encryption_key], so
+## relays and anyone sniffing the network can neither listen in nor inject
+## voice.
+
+## Emitted with an encoded packet of local voice, to be delivered to every
+## registered peer, or only to [member relay_peer_id] when relaying.
+signal packet_ready(packet: PackedByteArray)
+## Emitted on the relay server with a packet to forward to [param peer_id].
+## Only needed without a [member transport].
+signal relay_packet_ready(peer_id: int, packet: PackedByteArray)
+## Emitted after [method register_peer] created the peer's playback.
+signal peer_registered(peer_id: int)
+## Emitted after [method unregister_peer] removed the peer's playback.
+signal peer_unregistered(peer_id: int)
+## Emitted when voice of [param peer_id] louder than
+## [member speaking_threshold_db] starts being played, e.g. to highlight the
+## peer's avatar.
+signal peer_started_speaking(peer_id: int)
+## Emitted when [param peer_id] was quiet or sent nothing for
+## [member speaking_hold_ms], or was unregistered while speaking.
+signal peer_stopped_speaking(peer_id: int)
+## Emitted when [param peer_id] called [method shutdown], after the voice it
+## sent last was played.
+signal peer_voice_left(peer_id: int)
+
+## Carries the voice packets. Peers connecting and disconnecting on the
+## transport are registered and unregistered automatically. Leave it empty to
+## move packets yourself with [signal packet_ready] and [method receive_packet].
+@export var transport: VoipTransport:
+	set(value):
+		if transport == value:
+			return
+		_disconnect_transport()
+		transport = value
+		_connect_transport()
+
+## How voice travels between peers. See [member routing_mode].
+enum RoutingMode { PEER_TO_PEER, SERVER_RELAY }
+
+## PEER_TO_PEER sends local voice to every peer directly. SERVER_RELAY sends
+## it to [member relay_peer_id] only, which forwards it; this saves upload
+## bandwidth on clients and lets the server decide who hears whom.
+@export var routing_mode := RoutingMode.PEER_TO_PEER:
+	set(value):
+		routing_mode = value
+		_apply_bitrate()
+
+## Peer that relays voice in SERVER_RELAY mode.
+@export var relay_peer_id := 1
+
+## Whether this manager is the relay. Set it on the server.
+@export var is_relay_server := false
+
+## Encoder bitrate in PEER_TO_PEER mode, in bits per second. Lower than
+## [member relay_bitrate] because the upload is repeated for every peer.
+@export_range(6000, 64000, 1000, "suffix:bps") var peer_to_peer_bitrate := 20000:
+	set(value):
+		peer_to_peer_bitrate = value
+		_apply_bitrate()
+
+## Encoder bitrate in SERVER_RELAY mode, in bits per second.
+@export_range(6000, 64000, 1000, "suffix:bps") var relay_bitrate := 32000:
+	set(value):
+		relay_bitrate = value
+		_apply_bitrate()
+
+## Most upload bandwidth voice may use, in kilobits per second, counting every
+## copy sent and network overhead. Within it, the encoder bitrate is lowered
+## as far as needed, and packets that still don't fit are held back briefly
+## and sent together in one datagram, keeping bandwidth free for gameplay on
+## poor connections. 0 means no limit.
+@export_range(0.0, 1000.0, 1.0, "suffix:kbps") var uplink_budget_kbps := 0.0:
+	set(value):
+		uplink_budget_kbps = value
+		_apply_bitrate()
+
+## Decides on the relay server whether a speaker is forwarded to a listener:
+## [code]func(speaker_id: int, listener_id: int) -> bool[/code]. Everyone hears
+## everyone when it's not set.
+var relay_filter: Callable
+
+## Channel every peer talks into until [method set_peer_channel] moves it.
+const DEFAULT_CHANNEL := &""
+
+## Peer id the local voice is played back under in loopback mode.
+const LOOPBACK_PEER_ID := -1
+
+## How a conversation is recorded, see [method start_session_recording].
+enum RecordingLayout { MIXED, MULTITRACK }
+
+## Bytes the network adds to every datagram (IPv4 and UDP headers).
+const _DATAGRAM_OVERHEAD_BYTES := 28
+## Lowest bitrate [member uplink_budget_kbps] lowers the encoder to.
+const _MIN_BITRATE := 6000
+## Packets held back by [member uplink_budget_kbps] at most; older ones are
+## dropped.
+const _MAX_QUEUED_PACKETS := 10
+## Packets sent together in one datagram at most when held back.
+const _MAX_COALESCED_PACKETS := 4
+## Largest datagram the pacing has to let through, a typical Ethernet MTU.
+const _MAX_DATAGRAM_BYTES := 1500
+## Times the leave packet is sent, since the transport may drop any of them.
+const _LEAVE_PACKET_COPIES := 3
+
+## Track id of the local voice in session recordings.
+const _LOCAL_TRACK := 0
+## How long the mix waits for late voice before writing it.
+const _RECORDING_LATENCY_MSEC := 500
+## Voice arriving this much later than where its track ended starts a new
+## talk spurt at the current time instead of continuing the track.
+const _RECORDING_GAP_MSEC := 100
+
+## Whether local voice is encoded and emitted through [signal packet_ready].
+@export var send_local_voice := true
+
+## Plays voice with an [AudioStreamPlayer3D] under the node at this path,
+## relative to the manager, instead of a plain [AudioStreamPlayer].
+## [code]{peer_id}[/code] is replaced by the peer id, e.g.
+## [code]../Players/{peer_id}/Head[/code]. A peer's player is added once its
+## node exists, and created again if the node is freed and spawned anew.
+@export var spatial_parent_path := "":
+	set(value):
+		if spatial_parent_path == value:
+			return
+		spatial_parent_path = value
+		for peer_id in _peers:
+			_free_player(_peers[peer_id])
+			_create_player(peer_id)
+
+## [member AudioStreamPlayer3D.unit_size] of spatial players.
+@export_range(0.1, 100.0, 0.1) var spatial_unit_size := 10.0
+
+## [member AudioStreamPlayer3D.max_distance] of spatial players; 0 means no
+## limit.
+@export_range(0.0, 4096.0, 1.0, "suffix:m") var spatial_max_distance := 0.0
+
+## Plays the local microphone back after encoding and decoding it, as peer
+## [constant LOOPBACK_PEER_ID]. The loopback peer isn't returned by
+## [method get_peers] and never gets voice sent to it.
+@export var loopback_enabled := false:
+	set(value):
+		if loopback_enabled == value:
+			return
+		loopback_enabled = value
+		if is_inside_tree():
+			_update_loopback_peer()
+
+## Time the local voice takes to come back in loopback mode.
+@export_range(0.0, 2000.0, 10.0, "suffix:ms") var loopback_delay_ms := 200.0
+
+## Share of loopback packets dropped, to hear how packet loss sounds.
+@export_range(0.0, 50.0, 0.5, "suffix:%") var loopback_packet_loss_percent := 0.0
+
+## Random extra delay of each loopback packet, to hear how jitter sounds.
+@export_range(0.0, 200.0, 1.0, "suffix:ms") var loopback_jitter_ms := 0.0
+
+## Audio bus the voice of registered peers is played on, unless
+## [method set_peer_bus] picked another one.
+@export var playback_bus: StringName = &"Master":
+	set(value):
+		playback_bus = value
+		for peer_id in _peers:
+			_apply_peer_bus(peer_id)
+
+## Level decoded voice has to reach for [signal peer_started_speaking].
+## Keeps breathing and background noise that passed the sender's gate from
+## lighting up the peer.
+@export_range(-80.0, 0.0, 0.5, "suffix:dB") var speaking_threshold_db := -50.0
+
+## Time a peer stays speaking after its voice dropped below
+## [member speaking_threshold_db] or stopped arriving. Bridges the short
+## pauses between words.
+@export_range(0.0, 2000.0, 10.0, "suffix:ms") var speaking_hold_ms := 300.0
+
+## Most peers decoded and played at once, to bound CPU use in big lobbies.
+## Priority speakers get a slot first, then peers already being played, then
+## those sending the most voice data, which grows with louder and busier
+## speech. Voice of the other peers is dropped until a slot frees up. 0 plays
+## everyone.
+@export_range(0, 64) var max_concurrent_speakers := 0
+
+## How much other voices and [member duck_music_bus] are turned down while a
+## priority speaker talks, in dB.
+@export_range(-60.0, 0.0, 0.5, "suffix:dB") var duck_amount_db := -12.0:
+	set(value):
+		duck_amount_db = value
+		_configure_music_ducker()
+
+## Time to turn down when a priority speaker starts talking.
+@export_range(0.0, 1000.0, 1.0, "suffix:ms") var duck_attack_ms := 50.0:
+	set(value):
+		duck_attack_ms = value
+		_configure_music_ducker()
+
+## Time to come back up after a priority speaker stopped talking.
+@export_range(0.0, 5000.0, 10.0, "suffix:ms") var duck_release_ms := 400.0:
+	set(value):
+		duck_release_ms = value
+		_configure_music_ducker()
+
+## Bus ducked along with other voices, e.g. music. An
+## [AudioEffectVoipDucker] is added to it for this. Leave empty to only duck
+## voices.
+@export var duck_music_bus: StringName = &"":
+	set(value):
+		if duck_music_bus == value:
+			return
+		_remove_music_ducker()
+		duck_music_bus = value
+		if is_inside_tree():
+			_add_music_ducker()
+
+var _encoder := OpusCodec.new()
+## Local voice held back by [member uplink_budget_kbps].
+var _outgoing_packets: Array[VoipPacket] = []
+## Bytes that may be sent right now under [member uplink_budget_kbps].
+var _uplink_allowance := 0.0
+var _cipher := VoipCipher.new()
+var _pending_frames: PackedVector2Array = []
+var _next_sequence := 0
+var _shut_down := false
+var _output_sample_rate := 48_000
+## Peer id -> { "decoder", "jitter", "stream", "player", "meter", "speaking",
+## "last_voice_msec", "left", "net" }. "net" holds what
+## [method get_peer_stats] needs.
+var _peers: Dictionary = {}
+var _joined_channels: Array[StringName] = [DEFAULT_CHANNEL]
+## Peer id -> channel the peer talks into.
+var _peer_channels: Dictionary = {}
+## Peer id -> Array[StringName] the peer listens to, known on the relay server.
+var _peer_subscriptions: Dictionary = {}
+## Peer id -> true. Kept across registrations, so a mute sticks when the peer
+## reconnects.
+var _muted_peers: Dictionary = {}
+## Peer id -> bus, kept across registrations like mutes.
+var _peer_buses: Dictionary = {}
+## Peer id -> volume in dB, kept across registrations like mutes.
+var _peer_volumes_db: Dictionary = {}
+var _solo_peer_id := 0
+## Peer id -> true for peers the relay server doesn't forward. Kept across
+## registrations, so a ban sticks when the peer reconnects.
+var _transmit_blocked_peers: Dictionary = {}
+## Peer id -> true for peers holding a slot of [member max_concurrent_speakers].
+var _speaker_slots: Dictionary = {}
+## Peer id -> true, kept across registrations like mutes.
+var _priority_peers: Dictionary = {}
+## Encoded local voice on its simulated way back:
+## Array of { "due_msec", "packet" }, sorted by "due_msec".
+var _loopback_queue: Array[Dictionary] = []
+var _session_layout := RecordingLayout.MIXED
+var _session_path := ""
+var _session_start_usec := -1
+## Track id -> VoipVoiceRecorder. MIXED uses a single recorder for all tracks.
+var _session_recorders: Dictionary = {}
+## Track id -> frame position where the track's last voice ended.
+var _session_positions: Dictionary = {}
+var _session_local_decoder: OpusCodec
+## Current duck of non-priority voices in dB, 0 when not ducking.
+var _duck_db := 0.0
+var _ducking := false
+var _music_ducker: AudioEffectVoipDucker
+
+
+func _ready() -> void:
+	_apply_bitrate()
+	_output_sample_rate = int(round(AudioServer.get_mix_rate()))
+	if _output_sample_rate <= 0:
+		_output_sample_rate = _encoder.get_sample_rate()
+	VOIP.sending_voice = false
+	VOIP.local_voice_captured.connect(_on_local_voice_captured)
+	_add_music_ducker()
+	_update_loopback_peer()
+
+
+func _exit_tree() -> void:
+	if VOIP.local_voice_captured.is_connected(_on_local_voice_captured):
+		VOIP.local_voice_captured.disconnect(_on_local_voice_captured)
+	stop_session_recording()
+	_remove_music_ducker()
+
+
+func _notification(what: int) -> void:
+	if what == NOTIFICATION_WM_CLOSE_REQUEST:
+		shutdown()
+
+
+func _process(delta: float) -> void:
+	_release_loopback_packets()
+	_attach_spatial_players()
+	_assign_speaker_slots()
+	_pace_outgoing_packets(delta)
+	for peer_id in _peers:
+		var peer: Dictionary = _peers[peer_id]
+		var jitter: VoipJitterBuffer = peer["jitter"]
+		if jitter.get_held_count() > 0:
+			_play_released(peer_id, peer, jitter.poll())
+	_update_speaking()
+	_update_ducking(delta)
+	_commit_session_recording()
+
+
+## Starts playing the voice of [param peer_id]. Registering a peer twice does
+## nothing.
+func register_peer(peer_id: int) -> void:
+	if _peers.has(peer_id):
+		return
+
+	var stream := AudioStreamVOIP.new()
+	stream.peer_id = peer_id
+	_peers[peer_id] = {
+		"decoder": OpusCodec.new(),
+		"jitter": VoipJitterBuffer.new(),
+		"stream": stream,
+		"player": null,
+		"meter": VoipLevelMeter.new(),
+		"speaking": false,
+		"last_voice_msec": 0,
+		"left": false,
+		"net": _new_net_stats(),
+	}
+	_create_player(peer_id)
+	_apply_bitrate()
+	peer_registered.emit(peer_id)
+
+
+## Stops playing the voice of [param peer_id] and frees its playback.
+func unregister_peer(peer_id: int) -> void:
+	if not _peers.has(peer_id):
+		return
+
+	var peer: Dictionary = _peers[peer_id]
+	_peers.erase(peer_id)
+	_peer_channels.erase(peer_id)
+	_peer_subscriptions.erase(peer_id)
+	var decoder: OpusCodec = peer["decoder"]
+	decoder.stop_worker()
+	_free_player(peer)
+	if peer["speaking"]:
+		peer_stopped_speaking.emit(peer_id)
+	_apply_bitrate()
+	peer_unregistered.emit(peer_id)
+
+
+## Returns true if [param peer_id] was registered.
+func has_peer(peer_id: int) -> bool:
+	return _peers.has(peer_id)
+
+
+## Returns the ids of all registered peers.
+func get_peers() -> Array[int]:
+	var ids: Array[int] = []
+	ids.assign(_peers.keys())
+	ids.erase(LOOPBACK_PEER_ID)
+	return ids
+
+
+## Stops voice cleanly, e.g. right before quitting or leaving a match.
+##
+## Sends the voice still waiting, including a partly filled frame and packets
+## held back by [member uplink_budget_kbps], then tells every peer through the
+## [member transport] that this peer is leaving, so they play what arrived
+## instead of concealing missing audio. No local voice is sent afterwards.
+## Called automatically when the window is closed. Calling it again does
+## nothing.
+func shutdown() -> void:
+	if _shut_down:
+		return
+	if send_local_voice and not get_peers().is_empty():
+		_flush_local_voice()
+		_send_leave_packet()
+	_shut_down = true
+	_pending_frames.clear()
+	_outgoing_packets.clear()
+	if transport != null:
+		transport.flush()
+
+
+## Returns true after [method shutdown] was called.
+func is_shut_down() -> bool:
+	return _shut_down
+
+
+## Returns the player of [param peer_id], an [AudioStreamPlayer] or, with
+## [member spatial_parent_path], an [AudioStreamPlayer3D]. Returns null if the
+## peer isn't registered or its spatial player has no parent yet.
+func get_peer_player(peer_id: int) -> Node:
+	if not _peers.has(peer_id):
+		return null
+	var player: Node = _peers[peer_id]["player"]
+	if not is_instance_valid(player) or player.get_parent() == null:
+		return null
+	return player
+
+
+## Returns the connection quality of [param peer_id] as a dictionary:
+## [code]packet_loss_percent[/code], [code]jitter_ms[/code] (variation of the
+## packet arrival times), [code]bitrate_bps[/code] (average size of the voice
+## received), [code]buffer_ms[/code] (voice waiting to be played),
+## [code]mos[/code] (rough mean opinion score from 1 to 4.5, where above 4 is
+## good and below 3 is bad), [code]packets_received[/code] and
+## [code]packets_lost[/code]. Returns an empty dictionary for unregistered
+## peers.
+func get_peer_stats(peer_id: int) -> Dictionary:
+	if not _peers.has(peer_id):
+		return {}
+	var peer: Dictionary = _peers[peer_id]
+	var net: Dictionary = peer["net"]
+	var jitter: VoipJitterBuffer = peer["jitter"]
+	var counters := jitter.get_stats()
+	var lost: int = counters["lost"]
+	var expected: int = counters["released"]
+	var loss_percent := 100.0 * lost / expected if expected > 0 else 0.0
+
+	var packets: int = net["packets"]
+	var frame_sec := float(_encoder.get_frame_size()) / _encoder.get_sample_rate()
+	var bitrate := 8.0 * int(net["bytes"]) / (packets * frame_sec) if packets > 0 else 0.0
+
+	var stream: AudioStreamVOIP = peer["stream"]
+	var buffer_ms := stream.get_buffered_ms()
+	var delay_ms := buffer_ms + frame_sec * 1000.0 + float(net["jitter_ms"])
+	return {
+		"packet_loss_percent": loss_percent,
+		"jitter_ms": net["jitter_ms"],
+		"bitrate_bps": bitrate,
+		"buffer_ms": buffer_ms,
+		"mos": _estimate_mos(loss_percent, delay_ms),
+		"packets_received": counters["received"],
+		"packets_lost": lost,
+	}
+
+
+## Returns true while voice of [param peer_id] is heard, see
+## [signal peer_started_speaking].
+func is_peer_speaking(peer_id: int) -> bool:
+	return _peers.has(peer_id) and _peers[peer_id]["speaking"]
+
+
+## Mutes or unmutes [param peer_id]. Muted voice is still received but not
+## decoded or played. Also works for peers that aren't registered yet.
+func set_peer_muted(peer_id: int, muted: bool) -> void:
+	if muted:
+		_muted_peers[peer_id] = true
+	else:
+		_muted_peers.erase(peer_id)
+	_silence_if_inaudible(peer_id)
+
+
+func is_peer_muted(peer_id: int) -> bool:
+	return _muted_peers.has(peer_id)
+
+
+## Sets the playback volume of [param peer_id] in dB.
+func set_peer_volume_db(peer_id: int, volume_db: float) -> void:
+	_peer_volumes_db[peer_id] = volume_db
+	_apply_peer_volume(peer_id)
+
+
+func get_peer_volume_db(peer_id: int) -> float:
+	return _peer_volumes_db.get(peer_id, 0.0)
+
+
+## Plays [param peer_id] on [param bus] instead of [member playback_bus], so
+## its voice goes through that bus's effects, e.g. a radio filter for team
+## chat. Pass an empty name to go back to [member playback_bus].
+func set_peer_bus(peer_id: int, bus: StringName) -> void:
+	if bus.is_empty():
+		_peer_buses.erase(peer_id)
+	else:
+		_peer_buses[peer_id] = bus
+	_apply_peer_bus(peer_id)
+
+
+## Returns the bus [param peer_id] is played on.
+func get_peer_bus(peer_id: int) -> StringName:
+	return _peer_buses.get(peer_id, playback_bus)
+
+
+## Plays only [param peer_id] until called again with 0. Mutes still apply.
+func solo_peer(peer_id: int) -> void:
+	_solo_peer_id = peer_id
+	for other_id in _peers:
+		_silence_if_inaudible(other_id)
+
+
+## Returns the peer set with [method solo_peer], or 0 if none is.
+func get_solo_peer() -> int:
+	return _solo_peer_id
+
+
+## Returns true if voice of [param peer_id] is played, considering mutes and
+## [method solo_peer].
+func is_peer_audible(peer_id: int) -> bool:
+	if _muted_peers.has(peer_id):
+		return false
+	return _solo_peer_id == 0 or _solo_peer_id == peer_id
+
+
+## Marks [param peer_id] as a priority speaker, e.g. a game master or squad
+## leader. Priority speakers are never ducked.
+func set_peer_priority(peer_id: int, priority: bool) -> void:
+	if priority:
+		_priority_peers[peer_id] = true
+	else:
+		_priority_peers.erase(peer_id)
+	_apply_peer_volume(peer_id)
+
+
+func is_peer_priority(peer_id: int) -> bool:
+	return _priority_peers.has(peer_id)
+
+
+## Returns true while a priority speaker is heard and others are ducked.
+func is_ducking() -> bool:
+	return _ducking
+
+
+## Starts recording the conversation, your own voice as others hear it and
+## every peer that is heard, to [param path]. The format follows the
+## extension like [code]VOIP.start_recording()[/code]. With MULTITRACK, each voice
+## gets its own file named after [param path] with [code]_local[/code] or
+## [code]_peer<id>[/code] appended, all starting at the same moment so they
+## line up in an editor.
+func start_session_recording(path: String, layout := RecordingLayout.MIXED) -> Error:
+	if is_session_recording():
+		return ERR_ALREADY_IN_USE
+	_session_layout = layout
+	_session_path = path
+	_session_recorders.clear()
+	_session_positions.clear()
+	_session_local_decoder = OpusCodec.new()
+	if layout == RecordingLayout.MIXED:
+		var recorder := VoipVoiceRecorder.new()
+		var err := recorder.start(path, _output_sample_rate)
+		if err != OK:
+			return err
+		_session_recorders[_LOCAL_TRACK] = recorder
+	_session_start_usec = Time.get_ticks_usec()
+	return OK
+
+
+## Finishes all files of the session recording.
+func stop_session_recording() -> void:
+	if not is_session_recording():
+		return
+	var end_frame := _session_frame_now()
+	for recorder in _session_recorders.values():
+		recorder.commit_until(end_frame)
+		recorder.stop()
+	_session_recorders.clear()
+	_session_positions.clear()
+	_session_local_decoder = null
+	_session_start_usec = -1
+
+
+func is_session_recording() -> bool:
+	return _session_start_usec >= 0
+
+
+## On the relay server, allows or denies [param peer_id] to talk, e.g. for
+## server mutes and bans. Voice of denied peers is dropped before forwarding.
+## Also works for peers that aren't registered yet. Only enforced in
+## SERVER_RELAY mode; peer-to-peer clients send to each other directly.
+func set_peer_transmit_allowed(peer_id: int, allowed: bool) -> void:
+	if allowed:
+		_transmit_blocked_peers.erase(peer_id)
+	else:
+		_transmit_blocked_peers[peer_id] = true
+
+
+func is_peer_transmit_allowed(peer_id: int) -> bool:
+	return not _transmit_blocked_peers.has(peer_id)
+
+
+## Encrypts all voice sent from now on with [param key], a 32-byte key from
+## [method VoipCipher.generate_key]. Every peer of the session needs the same
+## key, shared over a secure channel. While a key is set, unencrypted voice is
+## dropped. A relay server doesn't need the key to forward voice.
+func set_encryption_key(key: PackedByteArray) -> Error:
+	var err := _cipher.set_key(key)
+	_apply_bitrate()
+	return err
+
+
+## Goes back to sending and accepting unencrypted voice.
+func clear_encryption_key() -> void:
+	_cipher.clear_key()
+	_apply_bitrate()
+
+
+func is_encrypted() -> bool:
+	return _cipher.has_key()
+
+
+## Starts hearing the peers talking into [param channel].
+func join_channel(channel: StringName) -> void:
+	if _joined_channels.has(channel):
+		return
+	_joined_channels.append(channel)
+	_restart_peers_in_channel(channel)
+
+
+## Stops hearing the peers talking into [param channel].
+func leave_channel(channel: StringName) -> void:
+	_joined_channels.erase(channel)
+
+
+## Returns the channels this manager hears.
+func get_joined_channels() -> Array[StringName]:
+	return _joined_channels.duplicate()
+
+
+## Returns true if peers talking into [param channel] are heard.
+func is_in_channel(channel: StringName) -> bool:
+	return _joined_channels.has(channel)
+
+
+## Sets the channel [param peer_id] talks into. Call it on every machine that
+## should follow the change, e.g. from a team switch RPC.
+func set_peer_channel(peer_id: int, channel: StringName) -> void:
+	if get_peer_channel(peer_id) == channel:
+		return
+	_peer_channels[peer_id] = channel
+	_restart_peer(peer_id)
+
+
+## Returns the channel [param peer_id] talks into.
+func get_peer_channel(peer_id: int) -> StringName:
+	return _peer_channels.get(peer_id, DEFAULT_CHANNEL)
+
+
+## Tells the relay server which channels [param peer_id] listens to, so it
+## only forwards voice the peer would hear. Peers without subscriptions get
+## all voice. Ignored when [member relay_filter] is set.
+func set_peer_subscriptions(peer_id: int, channels: Array[StringName]) -> void:
+	_peer_subscriptions[peer_id] = channels.duplicate()
+
+
+## Queues a packet emitted by [signal packet_ready] or
+## [signal relay_packet_ready] on [param peer_id]'s side. Packets of
+## unregistered peers and malformed packets are ignored.
+func receive_packet(peer_id: int, packet: PackedByteArray) -> void:
+	if not _peers.has(peer_id):
+		return
+	if VoipPacket.is_bundle(packet):
+		for voip_packet in VoipPacket.unpack_bundle(packet):
+			_receive_voip_packet(peer_id, voip_packet)
+		return
+	var voip_packet := VoipPacket.unpack(packet)
+	if voip_packet != null:
+		_receive_voip_packet(peer_id, voip_packet)
+
+
+func _receive_voip_packet(peer_id: int, voip_packet: VoipPacket) -> void:
+	if voip_packet.has_flag(VoipPacket.FLAG_PCM):
+		return
+	var encrypted := voip_packet.has_flag(VoipPacket.FLAG_ENCRYPTED)
+	if _cipher.has_key() and not encrypted:
+		return
+
+	var speaker_id := peer_id
+	if routing_mode == RoutingMode.SERVER_RELAY:
+		if is_relay_server:
+			if not is_peer_transmit_allowed(peer_id):
+				return
+			_relay_packet(peer_id, voip_packet)
+		elif peer_id == relay_peer_id and voip_packet.peer_id != 0:
+			# Only the relay is trusted to name the speaker.
+			speaker_id = voip_packet.peer_id
+			register_peer(speaker_id)
+
+	# Relays forward encrypted voice as is; only listeners need to decrypt it.
+	if encrypted and not voip_packet.decrypt(_cipher):
+		return
+	if voip_packet.has_flag(VoipPacket.FLAG_LEAVE):
+		_on_peer_voice_left(speaker_id)
+		return
+	if not _joined_channels.has(get_peer_channel(speaker_id)):
+		return
+	_peers[speaker_id]["left"] = false
+	_track_arrival(_peers[speaker_id]["net"], voip_packet)
+	var jitter: VoipJitterBuffer = _peers[speaker_id]["jitter"]
+	jitter.push(voip_packet.sequence, voip_packet.payload)
+
+
+func _on_peer_voice_left(peer_id: int) -> void:
+	var peer: Dictionary = _peers[peer_id]
+	# The leave packet is sent several times.
+	if peer["left"]:
+		return
+	peer["left"] = true
+	# Play what already arrived instead of concealing what never will.
+	var jitter: VoipJitterBuffer = peer["jitter"]
+	_play_released(peer_id, peer, jitter.flush())
+	jitter.reset()
+	peer["last_voice_msec"] = 0
+	peer_voice_left.emit(peer_id)
+
+
+func _new_net_stats() -> Dictionary:
+	return {
+		"packets": 0,
+		"bytes": 0,
+		"jitter_ms": 0.0,
+		"last_arrival_msec": -1,
+		"last_timestamp": 0,
+		"activity": 0.0,
+	}
+
+
+## Interarrival jitter as in RFC 3550: how much the time between two packets
+## arriving differs from the time between them being sent, smoothed.
+func _track_arrival(net: Dictionary, voip_packet: VoipPacket) -> void:
+	var now := Time.get_ticks_msec()
+	net["packets"] += 1
+	net["bytes"] += voip_packet.payload.size()
+	net["activity"] += (voip_packet.payload.size() - float(net["activity"])) * 0.1
+	if int(net["last_arrival_msec"]) >= 0:
+		var transit_change := (now - int(net["last_arrival_msec"])) \
+			- (voip_packet.timestamp - int(net["last_timestamp"]))
+		net["jitter_ms"] += (absf(transit_change) - float(net["jitter_ms"])) / 16.0
+	net["last_arrival_msec"] = now
+	net["last_timestamp"] = voip_packet.timestamp
+
+
+## Simplified ITU-T G.107 E-model, assuming Opus with packet loss concealment.
+func _estimate_mos(loss_percent: float, delay_ms: float) -> float:
+	var delay_impairment := 0.024 * delay_ms
+	if delay_ms > 177.3:
+		delay_impairment += 0.11 * (delay_ms - 177.3)
+	var loss_impairment := 95.0 * loss_percent / (loss_percent + 20.0)
+	var r := clampf(93.2 - delay_impairment - loss_impairment, 0.0, 100.0)
+	return clampf(1.0 + 0.035 * r + r * (r - 60.0) * (100.0 - r) * 0.000007, 1.0, 4.5)
+
+
+func _create_player(peer_id: int) -> void:
+	var peer: Dictionary = _peers[peer_id]
+	var stream: AudioStreamVOIP = peer["stream"]
+	if spatial_parent_path.is_empty() or peer_id == LOOPBACK_PEER_ID:
+		var player := AudioStreamPlayer.new()
+		player.name = "VoipPeer%d" % peer_id
+		player.stream = stream
+		player.bus = get_peer_bus(peer_id)
+		add_child(player)
+		player.play()
+		peer["player"] = player
+	else:
+		var player_3d := AudioStreamPlayer3D.new()
+		player_3d.name = "VoipPeer%d" % peer_id
+		player_3d.stream = stream
+		player_3d.bus = get_peer_bus(peer_id)
+		player_3d.unit_size = spatial_unit_size
+		player_3d.max_distance = spatial_max_distance
+		# Starts once it enters the tree under the peer's node.
+		player_3d.autoplay = true
+		peer["player"] = player_3d
+		_attach_spatial_player(peer_id)
+	_apply_peer_volume(peer_id)
+
+
+func _free_player(peer: Dictionary) -> void:
+	var player: Node = peer["player"]
+	if is_instance_valid(player):
+		player.queue_free()
+	peer["player"] = null
+
+
+func _attach_spatial_players() -> void:
+	if spatial_parent_path.is_empty():
+		return
+	for peer_id in _peers:
+		var player: Node = _peers[peer_id]["player"]
+		if not is_instance_valid(player):
+			# Freed along with the node it was under, e.g. a despawned character.
+			_create_player(peer_id)
+		elif player.get_parent() == null:
+			_attach_spatial_player(peer_id)
+
+
+func _attach_spatial_player(peer_id: int) -> void:
+	var path := NodePath(spatial_parent_path.format({ "peer_id": peer_id }))
+	var parent := get_node_or_null(path)
+	if parent != null:
+		parent.add_child(_peers[peer_id]["player"])
+
+
+func _session_frame_now() -> int:
+	return (Time.get_ticks_usec() - _session_start_usec) * _output_sample_rate / 1_000_000
+
+
+func _record_session(track_id: int, pcm: PackedVector2Array, latency_msec := 0.0) -> void:
+	var recorder := _get_session_recorder(track_id)
+	if recorder == null:
+		return
+	# Place the audio when it was spoken rather than when it got here.
+	var now_frame := _session_frame_now() - int(latency_msec * _output_sample_rate / 1000.0)
+	var gap_frames := _RECORDING_GAP_MSEC * _output_sample_rate / 1000
+	var position: int = _session_positions.get(track_id, -1)
+	if position < 0 or now_frame - pcm.size() - position > gap_frames:
+		# A new talk spurt; keep it where it happened.
+		position = maxi(0, now_frame - pcm.size())
+	recorder.mix_at(pcm, position)
+	_session_positions[track_id] = position + pcm.size()
+
+
+func _get_session_recorder(track_id: int) -> VoipVoiceRecorder:
+	if _session_layout == RecordingLayout.MIXED:
+		return _session_recorders.get(_LOCAL_TRACK)
+	if _session_recorders.has(track_id):
+		return _session_recorders[track_id]
+
+	var suffix := "_local" if track_id == _LOCAL_TRACK else "_peer%d" % track_id
+	var path := "%s%s.%s" % [_session_path.get_basename(), suffix, _session_path.get_extension()]
+	var recorder := VoipVoiceRecorder.new()
+	if recorder.start(path, _output_sample_rate) != OK:
+		return null
+	_session_recorders[track_id] = recorder
+	return recorder
+
+
+func _commit_session_recording() -> void:
+	if not is_session_recording():
+		return
+	var latency_frames := _RECORDING_LATENCY_MSEC * _output_sample_rate / 1000
+	var commit_frame := _session_frame_now() - latency_frames
+	for recorder in _session_recorders.values():
+		recorder.commit_until(commit_frame)
+
+
+func _update_loopback_peer() -> void:
+	if loopback_enabled:
+		register_peer(LOOPBACK_PEER_ID)
+	else:
+		_loopback_queue.clear()
+		unregister_peer(LOOPBACK_PEER_ID)
+
+
+func _queue_loopback(voip_packet: VoipPacket) -> void:
+	if randf() * 100.0 < loopback_packet_loss_percent:
+		return
+	# A copy, because the sent packet gets encrypted in place.
+	var copy := VoipPacket.create(0, voip_packet.sequence, voip_packet.timestamp, 0, voip_packet.payload)
+	var delay_ms := loopback_delay_ms + randf() * loopback_jitter_ms
+	var entry := { "due_msec": Time.get_ticks_msec() + int(delay_ms), "packet": copy }
+	var index := _loopback_queue.bsearch_custom(entry, func(a, b): return a["due_msec"] < b["due_msec"], false)
+	_loopback_queue.insert(index, entry)
+
+
+## Loopback voice goes straight to the jitter buffer: it is never encrypted
+## for ourselves and belongs to no channel.
+func _release_loopback_packets() -> void:
+	var now := Time.get_ticks_msec()
+	while not _loopback_queue.is_empty() and int(_loopback_queue[0]["due_msec"]) <= now:
+		var voip_packet: VoipPacket = _loopback_queue.pop_front()["packet"]
+		if not _peers.has(LOOPBACK_PEER_ID):
+			continue
+		var peer: Dictionary = _peers[LOOPBACK_PEER_ID]
+		_track_arrival(peer["net"], voip_packet)
+		var jitter: VoipJitterBuffer = peer["jitter"]
+		jitter.push(voip_packet.sequence, voip_packet.payload)
+
+
+func _relay_packet(speaker_id: int, voip_packet: VoipPacket) -> void:
+	voip_packet.peer_id = speaker_id
+	var packet := voip_packet.pack()
+	for listener_id in get_peers():
+		if listener_id == speaker_id:
+			continue
+		if not _relay_allows(speaker_id, listener_id):
+			continue
+		relay_packet_ready.emit(listener_id, packet)
+		if transport != null:
+			transport.send_packet(listener_id, packet)
+
+
+func _relay_allows(speaker_id: int, listener_id: int) -> bool:
+	if relay_filter.is_valid():
+		return relay_filter.call(speaker_id, listener_id)
+	if not _peer_subscriptions.has(listener_id):
+		return true
+	var channels: Array[StringName] = _peer_subscriptions[listener_id]
+	return channels.has(get_peer_channel(speaker_id))
+
+
+func _restart_peers_in_channel(channel: StringName) -> void:
+	for peer_id in _peers:
+		if get_peer_channel(peer_id) == channel:
+			_restart_peer(peer_id)
+
+
+## Voice of a peer that wasn't heard for a while mustn't be treated as lost
+## packets, so the jitter buffer starts over.
+func _restart_peer(peer_id: int) -> void:
+	if not _peers.has(peer_id):
+		return
+	var jitter: VoipJitterBuffer = _peers[peer_id]["jitter"]
+	jitter.reset()
+
+
+func _send_local_packet(packet: PackedByteArray) -> void:
+	packet_ready.emit(packet)
+	if transport == null:
+		return
+	if routing_mode == RoutingMode.SERVER_RELAY and not is_relay_server:
+		transport.send_packet(relay_peer_id, packet)
+	else:
+		transport.send_packet_to_peers(get_peers(), packet)
+
+
+func _apply_bitrate() -> void:
+	var bitrate := relay_bitrate if routing_mode == RoutingMode.SERVER_RELAY else peer_to_peer_bitrate
+	var recipients := _uplink_recipient_count()
+	if uplink_budget_kbps > 0.0 and recipients > 0:
+		var packets_per_sec := float(_encoder.get_sample_rate()) / _encoder.get_frame_size()
+		var overhead_bytes := _DATAGRAM_OVERHEAD_BYTES + VoipPacket.get_header_size()
+		if _cipher.has_key():
+			overhead_bytes += VoipCipher.OVERHEAD
+		var budget_bps := uplink_budget_kbps * 1000.0 / recipients
+		var payload_bps := budget_bps - packets_per_sec * overhead_bytes * 8.0
+		bitrate = clampi(int(payload_bps), _MIN_BITRATE, bitrate)
+	_encoder.set_bitrate(bitrate)
+
+
+## How many copies of every local packet go out.
+func _uplink_recipient_count() -> int:
+	if routing_mode == RoutingMode.SERVER_RELAY and not is_relay_server:
+		return 1
+	return get_peers().size()
+
+
+## Token bucket over [member uplink_budget_kbps]. Packets that have to wait
+## are coalesced into bundles, which saves the per-datagram overhead.
+func _pace_outgoing_packets(delta: float) -> void:
+	if uplink_budget_kbps <= 0.0:
+		for voip_packet in _outgoing_packets:
+			_send_local_packet(voip_packet.pack())
+		_outgoing_packets.clear()
+		return
+
+	var bytes_per_sec := uplink_budget_kbps * 125.0
+	var recipients := maxi(1, _uplink_recipient_count())
+	# Allow bursts of a tenth of a second, but always enough for one full
+	# datagram to every recipient, or a tiny budget would never send anything.
+	var burst_bytes := maxf(bytes_per_sec * 0.1, float(_MAX_DATAGRAM_BYTES * recipients))
+	_uplink_allowance = minf(_uplink_allowance + bytes_per_sec * delta, burst_bytes)
+	while _outgoing_packets.size() > _MAX_QUEUED_PACKETS:
+		_outgoing_packets.pop_front()
+
+	while not _outgoing_packets.is_empty():
+		var count := mini(_outgoing_packets.size(), _MAX_COALESCED_PACKETS)
+		var datagram := _pack_outgoing(count)
+		var cost := float(datagram.size() + _DATAGRAM_OVERHEAD_BYTES) * recipients
+		# A bundle may cost more than the burst ever allows; send fewer packets.
+		while cost > _uplink_allowance and count > 1:
+			count -= 1
+			datagram = _pack_outgoing(count)
+			cost = float(datagram.size() + _DATAGRAM_OVERHEAD_BYTES) * recipients
+		if cost > _uplink_allowance:
+			return
+		_uplink_allowance -= cost
+		_outgoing_packets = _outgoing_packets.slice(count)
+		_send_local_packet(datagram)
+
+
+func _pack_outgoing(count: int) -> PackedByteArray:
+	if count == 1:
+		return _outgoing_packets[0].pack()
+	return VoipPacket.pack_bundle(_outgoing_packets.slice(0, count))
+
+
+func _connect_transport() -> void:
+	if transport == null:
+		return
+	transport.packet_received.connect(receive_packet)
+	transport.peer_connected.connect(register_peer)
+	transport.peer_disconnected.connect(unregister_peer)
+	for peer_id in transport.get_peers():
+		register_peer(peer_id)
+
+
+func _disconnect_transport() -> void:
+	if transport == null:
+		return
+	transport.packet_received.disconnect(receive_packet)
+	transport.peer_connected.disconnect(register_peer)
+	transport.peer_disconnected.disconnect(unregister_peer)
+
+
+func _silence_if_inaudible(peer_id: int) -> void:
+	if _peers.has(peer_id) and not is_peer_audible(peer_id):
+		var stream: AudioStreamVOIP = _peers[peer_id]["stream"]
+		stream.flush()
+
+
+func _apply_peer_volume(peer_id: int) -> void:
+	if not _peers.has(peer_id):
+		return
+	var player: Node = _peers[peer_id]["player"]
+	if not is_instance_valid(player):
+		return
+	var duck_db := 0.0 if is_peer_priority(peer_id) else _duck_db
+	player.set(&"volume_db", get_peer_volume_db(peer_id) + duck_db)
+
+
+func _apply_peer_bus(peer_id: int) -> void:
+	if not _peers.has(peer_id):
+		return
+	var player: Node = _peers[peer_id]["player"]
+	if is_instance_valid(player):
+		player.set(&"bus", get_peer_bus(peer_id))
+
+
+func _is_priority_speaker_heard() -> bool:
+	for peer_id in _priority_peers:
+		if not _peers.has(peer_id) or not is_peer_audible(peer_id):
+			continue
+		var stream: AudioStreamVOIP = _peers[peer_id]["stream"]
+		if stream.is_speaking():
+			return true
+	return false
+
+
+func _update_ducking(delta: float) -> void:
+	var ducking := _is_priority_speaker_heard()
+	if ducking != _ducking:
+		_ducking = ducking
+		if _music_ducker != null:
+			_music_ducker.set_ducked(ducking)
+
+	var target_db := duck_amount_db if ducking else 0.0
+	if is_equal_approx(_duck_db, target_db):
+		return
+	var time_ms := duck_attack_ms if target_db < _duck_db else duck_release_ms
+	var coeff := exp(-delta * 1000.0 / time_ms) if time_ms > 0.0 else 0.0
+	_duck_db = target_db + (_duck_db - target_db) * coeff
+	if absf(_duck_db - target_db) < 0.01:
+		_duck_db = target_db
+	for peer_id in _peers:
+		_apply_peer_volume(peer_id)
+
+
+func _add_music_ducker() -> void:
+	if duck_music_bus.is_empty():
+		return
+	var bus_idx := AudioServer.get_bus_index(duck_music_bus)
+	if bus_idx < 0:
+		push_error("VoipManager: duck_music_bus '%s' doesn't exist." % duck_music_bus)
+		return
+	_music_ducker = AudioEffectVoipDucker.new()
+	_configure_music_ducker()
+	_music_ducker.set_ducked(_ducking)
+	AudioServer.add_bus_effect(bus_idx, _music_ducker)
+
+
+func _remove_music_ducker() -> void:
+	if _music_ducker == null:
+		return
+	var bus_idx := AudioServer.get_bus_index(duck_music_bus)
+	if bus_idx >= 0:
+		for i in range(AudioServer.get_bus_effect_count(bus_idx)):
+			if AudioServer.get_bus_effect(bus_idx, i) == _music_ducker:
+				AudioServer.remove_bus_effect(bus_idx, i)
+				break
+	_music_ducker = null
+
+
+func _configure_music_ducker() -> void:
+	if _music_ducker == null:
+		return
+	_music_ducker.amount_db = duck_amount_db
+	_music_ducker.attack_ms = duck_attack_ms
+	_music_ducker.release_ms = duck_release_ms
+
+
+func _assign_speaker_slots() -> void:
+	if max_concurrent_speakers <= 0:
+		_speaker_slots.clear()
+		return
+	var now := Time.get_ticks_msec()
+	var candidates: Array[int] = []
+	for peer_id in _peers:
+		var last_arrival: int = _peers[peer_id]["net"]["last_arrival_msec"]
+		if peer_id == LOOPBACK_PEER_ID or last_arrival < 0 or not is_peer_audible(peer_id):
+			continue
+		if now - last_arrival <= int(speaking_hold_ms):
+			candidates.append(peer_id)
+	candidates.sort_custom(func(a: int, b: int) -> bool: return _speaker_rank(a) > _speaker_rank(b))
+
+	_speaker_slots.clear()
+	for peer_id in candidates.slice(0, max_concurrent_speakers):
+		_speaker_slots[peer_id] = true
+
+
+func _speaker_rank(peer_id: int) -> float:
+	var rank: float = _peers[peer_id]["net"]["activity"]
+	if _speaker_slots.has(peer_id):
+		# Keeps who is heard from flapping between similar speakers.
+		rank += 1000.0
+	if is_peer_priority(peer_id):
+		rank += 2000.0
+	return rank
+
+
+func _has_speaker_slot(peer_id: int) -> bool:
+	return max_concurrent_speakers <= 0 or peer_id == LOOPBACK_PEER_ID \
+		or _speaker_slots.has(peer_id)
+
+
+func _play_released(peer_id: int, peer: Dictionary, released: Array[Dictionary]) -> void:
+	if not is_peer_audible(peer_id) or not _has_speaker_slot(peer_id):
+		return
+	var decoder: OpusCodec = peer["decoder"]
+	var stream: AudioStreamVOIP = peer["stream"]
+	var meter: VoipLevelMeter = peer["meter"]
+	for packet in released:
+		# Lost packets have an empty payload, which makes Opus conceal them.
+		var opus_data: PackedByteArray = packet["payload"]
+		var pcm := decoder.decode_with_sample_rate(opus_data, _output_sample_rate)
+		stream.push_pcm(pcm)
+		if is_session_recording() and peer_id != LOOPBACK_PEER_ID:
+			_record_session(peer_id, pcm)
+		if opus_data.is_empty():
+			continue
+		meter.process(pcm, _output_sample_rate)
+		if meter.get_rms_db() >= speaking_threshold_db:
+			peer["last_voice_msec"] = Time.get_ticks_msec()
+
+
+func _update_speaking() -> void:
+	var now := Time.get_ticks_msec()
+	for peer_id in _peers:
+		var peer: Dictionary = _peers[peer_id]
+		var speaking: bool = peer["last_voice_msec"] > 0 \
+			and now - int(peer["last_voice_msec"]) <= int(speaking_hold_ms)
+		if speaking == peer["speaking"]:
+			continue
+		peer["speaking"] = speaking
+		if speaking:
+			peer_started_speaking.emit(peer_id)
+		else:
+			peer_stopped_speaking.emit(peer_id)
+
+
+## Encodes the partly filled frame and sends every packet held back by
+## [member uplink_budget_kbps] right away.
+func _flush_local_voice() -> void:
+	if not _pending_frames.is_empty():
+		_pending_frames.resize(_encoder.get_frame_size())
+		_on_local_voice_captured(PackedVector2Array())
+	for voip_packet in _outgoing_packets:
+		_send_local_packet(voip_packet.pack())
+	_outgoing_packets.clear()
+
+
+func _send_leave_packet() -> void:
+	var voip_packet := VoipPacket.create(0, _next_sequence, Time.get_ticks_msec(), VoipPacket.FLAG_LEAVE, PackedByteArray())
+	if _cipher.has_key():
+		voip_packet.encrypt(_cipher)
+	var packet := voip_packet.pack()
+	for i in _LEAVE_PACKET_COPIES:
+		_send_local_packet(packet)
+
+
+func _on_local_voice_captured(pcm_data: PackedVector2Array) -> void:
+	if _shut_down:
+		return
+	var sending := send_local_voice and not get_peers().is_empty()
+	if not sending and not loopback_enabled:
+		_pending_frames.clear()
+		return
+
+	_pending_frames.append_array(pcm_data)
+	var frame_size := _encoder.get_frame_size()
+	while _pending_frames.size() >= frame_size:
+		var opus_data := _encoder.encode(_pending_frames.slice(0, frame_size))
+		_pending_frames = _pending_frames.slice(frame_size)
+		var voip_packet := VoipPacket.create(0, _next_sequence, Time.get_ticks_msec(), 0, opus_data)
+		_next_sequence = (_next_sequence + 1) & 0xFFFF
+		if loopback_enabled:
+			_queue_loopback(voip_packet)
+		if not sending:
+			continue
+		if is_session_recording():
+			var local_pcm := _session_local_decoder.decode_with_sample_rate(opus_data, _output_sample_rate)
+			_record_session(_LOCAL_TRACK, local_pcm, VOIP.get_processing_latency_ms())
+		if _cipher.has_key():
+			voip_packet.encrypt(_cipher)
+		if uplink_budget_kbps > 0.0:
+			_outgoing_packets.append(voip_packet)
+			_pace_outgoing_packets(0.0)
+		else:
+			_send_local_packet(voip_packet.pack())
+
+	if not _pending_frames.is_empty() and not VOIP.is_transmitting():
+		# Send the end of a transmission now instead of with the next one.
+		_pending_frames.resize(frame_size)
+		_on_local_voice_captured(PackedVector2Array())
@@ -0,0 +1,67 @@
+extends VoipTransport
+class_name VoipMultiplayerTransport
+
+## Carries voice over Godot's high-level multiplayer.
+##
+## Sends packets with unreliable RPCs through the node's [MultiplayerAPI] and
+## follows its peers, so projects that already use [member Node.multiplayer]
+## need no networking code of their own. Like any node using RPCs, it must
+## exist at the same node path on every peer.
+
+## Transfer channel used for voice, so voice and gameplay RPCs don't wait
+## for each other. Channel 0 is shared with most other traffic.
+@export_range(0, 255) var transfer_channel := 1:
+	set(value):
+		transfer_channel = value
+		_configure_rpc()
+
+
+func _ready() -> void:
+	_configure_rpc()
+	multiplayer.peer_connected.connect(_on_peer_connected)
+	multiplayer.peer_disconnected.connect(_on_peer_disconnected)
+
+
+func send_packet(peer_id: int, packet: PackedByteArray) -> void:
+	if not _is_online():
+		return
+	_rpc_receive_voice.rpc_id(peer_id, packet)
+
+
+## Polls the multiplayer peer, which hands queued packets to the network.
+func flush() -> void:
+	if _is_online():
+		multiplayer.multiplayer_peer.poll()
+
+
+func get_peers() -> Array[int]:
+	var peers: Array[int] = []
+	if is_inside_tree() and _is_online():
+		peers.assign(multiplayer.get_peers())
+	return peers
+
+
+func _is_online() -> bool:
+	var peer := multiplayer.multiplayer_peer
+	return peer != null and peer.get_connection_status() == MultiplayerPeer.CONNECTION_CONNECTED
+
+
+func _configure_rpc() -> void:
+	rpc_config(&"_rpc_receive_voice", {
+		"rpc_mode": MultiplayerAPI.RPC_MODE_ANY_PEER,
+		"transfer_mode": MultiplayerPeer.TRANSFER_MODE_UNRELIABLE,
+		"call_local": false,
+		"channel": transfer_channel,
+	})
+
+
+func _on_peer_connected(peer_id: int) -> void:
+	peer_connected.emit(peer_id)
+
+
+func _on_peer_disconnected(peer_id: int) -> void:
+	peer_disconnected.emit(peer_id)
+
+
+func _rpc_receive_voice(packet: PackedByteArray) -> void:
+	packet_received.emit(multiplayer.get_remote_sender_id(), packet)
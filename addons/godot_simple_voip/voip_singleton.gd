@@ -12,56 +12,204 @@ extends Node
 
 ## Emitted when new voice data is received from a peer.
 signal peer_voice_data_received(peer_id: int, pcm_data: PackedVector2Array)
+## Emitted with the processed microphone frames that pass the transmit gate,
+## whether or not they are sent. Nothing is emitted while [member muted].
+## Used by [VoipManager] to send voice over other networking.
+signal local_voice_captured(pcm_data: PackedVector2Array)
 ## Emitted once per debug window with the latest telemetry snapshot.
 signal debug_stats_updated(stats: Dictionary)
+## Emitted when the selected input device stayed silent while another
+## available device picked up sound. See [member input_silence_timeout_sec].
+signal input_device_suggested(device_name: String)
+## Emitted [member level_update_hz] times per second with the smoothed
+## microphone levels in dBFS.
+signal level_changed(peak_db: float, rms_db: float)
+## Emitted when the microphone input keeps clipping, at most once per
+## second. Prompt the player to lower their microphone gain.
+signal clipping_detected
+## Emitted when voice activity is detected on the local microphone.
+signal speaking_started
+## Emitted when the local microphone fell silent for [member vad_hangover_ms].
+signal speaking_stopped
+## Emitted when a peer called [method shutdown] and won't send more voice.
+signal peer_voice_left(peer_id: int)
+## Emitted when [method auto_tune] finished measuring, with the chosen values.
+signal auto_tune_finished(report: Dictionary)
+## Emitted when [method measure_noise_floor] finished, with the measured levels
+## and suggested thresholds.
+signal noise_floor_measured(result: Dictionary)
 
 ## VOIP will automatically create an audio bus with this name if it doesn't exist.
 const BUS_NAME = "VOIP"
 
+## When local voice is transmitted. See [member transmit_mode].
+enum TransmitMode { OPEN, PUSH_TO_TALK, VOICE_ACTIVATED }
+
+## Noise suppression effect inserted by [method setup_capture_bus].
+enum NoiseSuppression { NONE, RNNOISE, DEEP_FILTER_NET, NOISE_GATE }
+
+## Stages of the microphone processing chain, see [member processing_chain].
+enum ProcessingStage { HIGH_PASS = 1, NOISE_GATE = 2, DENOISER = 4, AGC = 8, LIMITER = 16 }
+
 ## Whether voice should be sent to peers. If false, this peer
 ## will not send voice data to anyone.
 @export var sending_voice := true
 
+## Player-facing microphone mute. No voice is sent while muted, but capture,
+## noise suppression and voice activity detection keep running, so
+## unmuting is instantaneous and [signal speaking_started] still works, e.g.
+## for a "you are muted" hint.
+@export var muted := false
+
 ## Automatically route the local microphone into the VOIP bus.
 @export var auto_capture_microphone := true
 
+## Play the processed microphone back locally, so players can hear how they
+## sound, e.g. in an audio settings menu.
+@export var monitor_enabled := false:
+	set(value):
+		monitor_enabled = value
+		if is_inside_tree():
+			_update_monitor_player()
+
+## Volume of the local microphone monitor.
+@export_range(-60.0, 12.0, 0.5, "suffix:dB") var monitor_volume_db := 0.0:
+	set(value):
+		monitor_volume_db = value
+		if _monitor_player != null:
+			_monitor_player.volume_db = value
+
+## Stages of the microphone processing chain that are active. The VOIP bus
+## always runs them in the order high-pass, noise gate, denoiser, AGC and
+## limiter, whichever are enabled. If [enum NoiseSuppression] NOISE_GATE was
+## chosen as the bus's noise suppression, the gate is the denoiser stage.
+@export_flags("High-pass", "Noise gate", "Denoiser", "AGC", "Limiter")
+var processing_chain: int = ProcessingStage.HIGH_PASS | ProcessingStage.DENOISER | ProcessingStage.LIMITER:
+	set(value):
+		processing_chain = value
+		_apply_runtime_effect_config()
+
+## Automatically level the microphone so quiet and loud players end up at
+## comparable loudness. Same as the AGC flag of [member processing_chain].
+var auto_gain_control: bool:
+	get:
+		return processing_chain & ProcessingStage.AGC != 0
+	set(value):
+		if value:
+			processing_chain |= ProcessingStage.AGC
+		else:
+			processing_chain &= ~ProcessingStage.AGC
+
+## When the microphone is transmitted: always, while [method set_ptt_pressed]
+## is held, or while the input is louder than [member vad_threshold_db].
+@export var transmit_mode := TransmitMode.OPEN:
+	set(value):
+		transmit_mode = value
+		_apply_transmit_config()
+
+## Input level that starts transmission in
+## [constant TransmitMode.VOICE_ACTIVATED] mode.
+@export_range(-80.0, 0.0, 0.5, "suffix:dB") var vad_threshold_db := -40.0:
+	set(value):
+		vad_threshold_db = value
+		_apply_transmit_config()
+
+## Speech probability from [AudioEffectRNNoise] required for voice activity.
+## Keeps loud non-speech noise, like keyboard clicks, from counting as
+## speaking. Ignored when RNNoise is not on the VOIP bus.
+@export_range(0.0, 1.0, 0.01) var vad_min_voice_probability := 0.5:
+	set(value):
+		vad_min_voice_probability = value
+		_apply_transmit_config()
+
+## How long voice activity continues after the input got quiet.
+## Keeps word endings and short pauses from being cut off.
+@export_range(0.0, 2000.0, 10.0, "suffix:ms") var vad_hangover_ms := 300.0:
+	set(value):
+		vad_hangover_ms = value
+		_apply_transmit_config()
+
 # Internal runtime settings (kept off the exported singleton API).
 ## Whether Opus compression is used for network transport.
 var opus_compression_enabled := true
 var _capture_buffer_length_sec := 2.0
 var _debug_packet_stats := false
 var _debug_stage_isolation := false
-var _high_pass_enabled := true
 var _high_pass_cutoff_hz := 100.0
 var _low_pass_enabled := true
 var _low_pass_cutoff_hz := 16000.0
-var _rnnoise_enabled := true
 var _compressor_enabled := true
 var _compressor_threshold_db := -7.0
 var _amplify_enabled := true
 var _amplify_db := 7.0
-var _limiter_enabled := true
+var _anonymizer_enabled := false
+var _anonymizer_seed := 0
 var _max_packets_per_frame := 64
 
+## Seconds of silence on the selected input device before other input devices
+## are probed for activity. Set to 0 to disable probing.
+@export var input_silence_timeout_sec := 0.0
+
+## Switch to the suggested input device automatically instead of only
+## emitting [signal input_device_suggested].
+@export var auto_switch_input_device := false
+
+## How often [signal level_changed] is emitted. Set to 0 to disable it.
+@export_range(0.0, 60.0, 1.0, "suffix:Hz") var level_update_hz := 20.0
+
+## Run [method auto_tune] once the singleton is ready.
+@export var auto_tune_on_ready := false
+
+## Number of 20 ms Opus frames sent together in one network packet. Higher
+## values save per-packet overhead on transports like WebSocket or WebRTC
+## data channels, at the cost of 20 ms extra delay per additional frame.
+@export_range(1, 3) var frames_per_packet := 1
+
+## When acting as the relay server, combine all voice packets headed to the
+## same client within a frame into one datagram. Saves per-packet overhead
+## when many peers talk at once. Clients unbundle automatically.
+@export var aggregate_relay_packets := false
+
+## Re-request lost voice packets over a reliable channel. Gaps are held a bit
+## longer while the answer is on its way, which adds delay only when packets
+## actually go missing. Useful on lossy links with low round-trip times.
+@export var request_retransmissions := false
+
 ## The peers whose peer_id is in peer_filter will not be sent voice data.
 ## Can be used to save bandwidth.
 @export var peer_filter: Array[int] = []
 
 var _bus_idx := -1
 var _mic_capture_player: AudioStreamPlayer = null
+var _monitor_player: AudioStreamPlayer = null
+var _monitor_playback: AudioStreamGeneratorPlayback = null
 var _capture: AudioEffectCapture = null
 var _high_pass: AudioEffectHighPassFilter = null
 var _low_pass: AudioEffectLowPassFilter = null
 var _rnnoise: AudioEffectRNNoise = null
+var _denoiser: AudioEffect = null
+var _noise_gate: AudioEffectNoiseGate = null
 var _compressor: AudioEffectCompressor = null
 var _amplify: AudioEffectAmplify = null
-var _limiter: AudioEffectHardLimiter = null
+var _limiter: AudioEffect = null
+var _anonymizer: AudioEffectVoiceAnonymizer = null
+var _agc: AudioEffectVoipAGC = null
+var _clip_detector: AudioEffectClipDetector = null
+var _transmit_gate := VoipTransmitGate.new()
+var _level_meter := VoipLevelMeter.new()
+var _recorder := VoipVoiceRecorder.new()
+var _level_update_accum := 0.0
 var _encode_opus: OpusCodec
 var _decode_opus_by_peer: Dictionary = {}
+var _jitter_by_peer: Dictionary = {}
 var _resampler: Resampler
+var _capture_resampler := StreamingResampler.new()
 var _opus_sample_rate := 48_000
 var _opus_frame_size := 960
 var _packet_duration_sec := 0.02
+## Rate of the captured microphone audio. Captured audio is resampled to
+## the Opus rate before anything else sees it.
+var _capture_sample_rate := 48_000
 var _input_sample_rate := 48_000
 var _input_packet_frames := 960
 var _output_sample_rate := 48_000
@@ -126,6 +274,78 @@ var _process_dt_max := 0.0
 var _last_process_ts := -1.0
 var _process_gap_over_100ms := 0
 
+## Capture RMS below this level counts as silence for device probing.
+const INPUT_SILENCE_RMS := 0.0005
+## How long each candidate input device is listened to while probing.
+const INPUT_PROBE_SEC := 0.6
+## Initial part of each probe window that is ignored while the device opens.
+const INPUT_PROBE_SETTLE_SEC := 0.15
+
+var _input_silence_sec := 0.0
+## RMS level of the raw input during the current frame, from the clip detector
+## in front of the voice chain. 0 when no input audio arrived.
+var _input_rms := 0.0
+var _input_probe_active := false
+var _input_probe_candidates: PackedStringArray = []
+var _input_probe_index := 0
+var _input_probe_elapsed := 0.0
+var _input_probe_original_device := ""
+var _input_probe_best_device := ""
+var _input_probe_best_rms := 0.0
+
+## Relay bundles are flushed before they grow past this size, to stay below
+## typical path MTUs.
+const MAX_RELAY_BUNDLE_BYTES := 1100
+
+var _relay_bundle_by_peer: Dictionary = {}
+var _relay_bundle_bytes_by_peer: Dictionary = {}
+
+## Clip events within [constant CLIPPING_WINDOW_SEC] that count as sustained
+## clipping.
+const CLIPPING_MIN_EVENTS := 4
+const CLIPPING_WINDOW_SEC := 1.0
+
+var _clipping_count := 0
+var _clipping_window_events := 0
+var _clipping_window_sec := 0.0
+
+## Lower bound for the auto-tuned playback prebuffer.
+const AUTO_TUNE_MIN_PREBUFFER_MS := 40.0
+## Upper bound for the auto-tuned playback prebuffer.
+const AUTO_TUNE_MAX_PREBUFFER_MS := 300.0
+## Lower bound for the auto-tuned time the jitter buffers wait for a missing packet.
+const AUTO_TUNE_MIN_JITTER_HOLD_MS := 40
+## Upper bound for the auto-tuned time the jitter buffers wait for a missing packet.
+const AUTO_TUNE_MAX_JITTER_HOLD_MS := 200
+
+var _auto_tune_remaining_sec := 0.0
+var _auto_tune_process_dts: PackedFloat64Array = []
+var _auto_tune_capture_dts: PackedFloat64Array = []
+var _auto_tune_last_capture_usec := 0
+var _auto_tune_report: Dictionary = {}
+var _tuned_prebuffer_ms := -1.0
+var _tuned_jitter_hold_ms := -1
+
+## Speech must be this much louder than the noise floor to count as voice.
+const NOISE_FLOOR_VAD_MARGIN_DB := 10.0
+## A noise gate closes this much above the noise floor.
+const NOISE_FLOOR_GATE_MARGIN_DB := 6.0
+
+var _noise_floor_remaining_sec := 0.0
+var _noise_floor_levels_db: PackedFloat64Array = []
+var _noise_floor_peak := 0.0
+
+## How long [method shutdown] blocks so the final packets leave the machine.
+const SHUTDOWN_FLUSH_MSEC := 50
+
+var _shut_down := false
+
+## How many recently sent or relayed packets per speaker can be retransmitted.
+const RETRANSMIT_HISTORY_PACKETS := 32
+
+var _voice_history_by_peer: Dictionary = {}
+var _pending_send_frames: Array[VoipPacket] = []
+
 ## Network sample rate used by the packet contract.
 const NETWORK_SAMPLE_RATE := 48_000
 ## Network packet size in frames.
@@ -139,20 +359,28 @@ func _ready() -> void:
 	_opus_sample_rate = _encode_opus.get_sample_rate()
 	_opus_frame_size = _encode_opus.get_frame_size()
 	_packet_duration_sec = float(_opus_frame_size) / float(_opus_sample_rate)
-	_input_sample_rate = int(round(AudioServer.get_input_mix_rate()))
-	if _input_sample_rate <= 0:
-		_input_sample_rate = int(round(AudioServer.get_mix_rate()))
-	if _input_sample_rate <= 0:
-		_input_sample_rate = _opus_sample_rate
+	_capture_sample_rate = int(round(AudioServer.get_input_mix_rate()))
+	if _capture_sample_rate <= 0:
+		_capture_sample_rate = int(round(AudioServer.get_mix_rate()))
+	if _capture_sample_rate <= 0:
+		_capture_sample_rate = _opus_sample_rate
+	# Work at the Opus rate from the start, so 44.1 kHz and mobile rates get
+	# the same packet sizes, gate timing and encoder input as 48 kHz.
+	_capture_resampler.set_rates(_capture_sample_rate, _opus_sample_rate)
+	_input_sample_rate = _opus_sample_rate
 	_input_packet_frames = maxi(1, int(round(_input_sample_rate * _packet_duration_sec)))
 	_output_sample_rate = int(round(AudioServer.get_mix_rate()))
 	if _output_sample_rate <= 0:
 		_output_sample_rate = _opus_sample_rate
 	_output_packet_frames = maxi(1, int(round(_output_sample_rate * _packet_duration_sec)))
+	_apply_transmit_config()
 	_setup_bus()
 	_ensure_microphone_capture_player()
+	_update_monitor_player()
 	_track_existing_players()
 	get_tree().node_added.connect(_on_node_added)
+	if auto_tune_on_ready:
+		auto_tune()
 
 
 ## Returns the Opus codec sample rate used for network packets.
@@ -165,102 +393,396 @@ func get_opus_frame_size() -> int:
 	return _opus_frame_size
 
 
-func _setup_bus() -> void:
-	_bus_idx = AudioServer.get_bus_index(BUS_NAME)
-	
-	if _bus_idx != -1:
-		# Bus exists, verify it has capture
-		_verify_and_add_capture()
-		_cache_existing_effects()
-		_apply_runtime_effect_config()
+## Sets whether the push-to-talk key is held. Only has an effect in
+## [constant TransmitMode.PUSH_TO_TALK] mode.
+func set_ptt_pressed(pressed: bool) -> void:
+	_transmit_gate.set_ptt_pressed(pressed)
+
+
+## Returns the smoothed peak level of the microphone in dBFS.
+func get_input_peak_db() -> float:
+	return _level_meter.get_peak_db()
+
+
+## Returns the smoothed RMS level of the microphone in dBFS.
+func get_input_rms_db() -> float:
+	return _level_meter.get_rms_db()
+
+
+## Returns how many times the microphone input clipped since startup or the
+## last [method reset_clipping_count].
+func get_clipping_count() -> int:
+	return _clipping_count
+
+
+## Sets the clipping counter back to zero, e.g. after the player changed
+## their microphone gain.
+func reset_clipping_count() -> void:
+	_clipping_count = 0
+
+
+## Returns true while voice activity is detected on the local microphone,
+## regardless of [member transmit_mode].
+func is_speaking() -> bool:
+	return _transmit_gate.is_speaking()
+
+
+## Returns true while local voice passes the [member transmit_mode] gate.
+func is_transmitting() -> bool:
+	return not muted and _transmit_gate.is_open()
+
+
+## Returns how much the enabled effects on the VOIP bus delay the
+## microphone, in milliseconds, e.g. the model latency of
+## [AudioEffectDeepFilterNet]. Audio from [signal local_voice_captured] was
+## spoken about this long earlier, so subtract it when lining voice up with
+## gameplay timestamps, e.g. for lip sync.
+func get_processing_latency_ms() -> float:
+	if _bus_idx == -1:
+		return 0.0
+	var latency_ms := 0.0
+	for i in range(AudioServer.get_bus_effect_count(_bus_idx)):
+		if not AudioServer.is_bus_effect_enabled(_bus_idx, i):
+			continue
+		var effect := AudioServer.get_bus_effect(_bus_idx, i)
+		if effect.has_method("get_latency_ms"):
+			latency_ms += effect.get_latency_ms()
+	return latency_ms
+
+
+## Starts writing the processed microphone audio to [param path]. A path
+## ending in [code].ogg[/code] or [code].opus[/code] is written as Ogg Opus,
+## anything else as WAV. Audio is recorded whether or not it's transmitted,
+## except while [member muted].
+##
+## With [param trim_silence], the file starts shortly before the first and ends
+## shortly after the last speech, as detected for [method is_speaking], e.g. for
+## voice messages.
+func start_recording(path: String, trim_silence: bool = false) -> Error:
+	_recorder.trim_silence = trim_silence
+	return _recorder.start(path, _input_sample_rate)
+
+
+## Finishes the file started with [method start_recording].
+func stop_recording() -> Error:
+	return _recorder.stop()
+
+
+## Returns true while [method start_recording] is writing a file.
+func is_recording() -> bool:
+	return _recorder.is_recording()
+
+
+## Enables or disables the voice anonymizer on the outgoing voice.
+##
+## Use the same [param voice_seed] for the whole match so the local player sounds
+## consistent, e.g. [code]AudioEffectVoiceAnonymizer.make_seed(match_id, multiplayer.get_unique_id())[/code].
+func set_voice_anonymizer(enabled: bool, voice_seed: int = 0) -> void:
+	_anonymizer_enabled = enabled
+	_anonymizer_seed = voice_seed
+	_apply_runtime_effect_config()
+
+
+## Measures how regularly this machine processes frames and delivers
+## microphone audio for [param duration_sec] seconds, then sets the playback
+## prebuffer of all [AudioStreamVOIP] streams, which is the minimum playback
+## delay, and how long the jitter buffers wait for missing packets
+## ([member VoipJitterBuffer.max_hold_ms]) accordingly.
+##
+## Slow or heavily loaded machines get a larger prebuffer and hold time to
+## avoid stutter.
+## [signal auto_tune_finished] reports the chosen values.
+func auto_tune(duration_sec: float = 2.0) -> void:
+	_auto_tune_remaining_sec = maxf(0.1, duration_sec)
+	_auto_tune_process_dts = PackedFloat64Array()
+	_auto_tune_capture_dts = PackedFloat64Array()
+	_auto_tune_last_capture_usec = 0
+
+
+## Listens to the microphone for [param seconds] while the player stays
+## silent and returns the ambient noise level with suggested thresholds:
+## [code]noise_floor_db[/code], [code]peak_db[/code],
+## [code]suggested_vad_threshold_db[/code] and
+## [code]suggested_gate_threshold_db[/code]. The result is empty if no
+## microphone audio was captured. With [param apply], [member vad_threshold_db]
+## is set to the suggestion. Use it with [code]await[/code].
+##
+## The level is measured where the noise gate sees it, with
+## [method AudioEffectNoiseGate.measure_noise_floor], or at the raw input if the
+## bus has no enabled gate, so the gate and later effects don't hide the noise.
+func measure_noise_floor(seconds: float = 3.0, apply: bool = false) -> Dictionary:
+	seconds = maxf(0.5, seconds)
+	var gate := _get_active_noise_gate()
+	var result: Dictionary
+	if gate != null:
+		gate.measure_noise_floor(seconds)
+		var levels: Array = await gate.noise_floor_measured
+		result = _make_noise_floor_result(levels[0], levels[1])
+		noise_floor_measured.emit(result.duplicate())
+	else:
+		_noise_floor_remaining_sec = seconds
+		_noise_floor_levels_db = PackedFloat64Array()
+		_noise_floor_peak = 0.0
+		result = await noise_floor_measured
+	if apply and not result.is_empty():
+		vad_threshold_db = result["suggested_vad_threshold_db"]
+	return result
+
+
+## Returns the values chosen by the last [method auto_tune] run, or an empty
+## dictionary if it hasn't run yet.
+func get_auto_tune_report() -> Dictionary:
+	return _auto_tune_report.duplicate()
+
+
+## Stops voice cleanly, e.g. right before quitting.
+##
+## Sends all pending voice, tells the other peers this peer is leaving so
+## they stop concealing its missing audio, stops codec workers and then
+## blocks for a moment so the final packets are sent. Called automatically
+## when the window is closed. Calling it again does nothing.
+func shutdown() -> void:
+	if _shut_down:
 		return
-	
-	# Create new bus if it doesn't exist
-	_bus_idx = AudioServer.bus_count
-	AudioServer.add_bus(_bus_idx)
-	AudioServer.set_bus_name(_bus_idx, BUS_NAME)
-	
-	# Add audio effects to process voice
-	
+	_shut_down = true
+	set_process(false)
+
+	_recorder.stop()
+	var peer := multiplayer.multiplayer_peer
+	if peer != null and peer.get_connection_status() == MultiplayerPeer.CONNECTION_CONNECTED:
+		_pad_partial_packet()
+		_send_buffered_voice()
+		_flush_pending_send_frames()
+		# Voice the server just sent may still wait in relay bundles.
+		_flush_relay_bundles()
+		if multiplayer.is_server():
+			for peer_id in multiplayer.get_peers():
+				_rpc_client_peer_voice_left.rpc_id(peer_id, multiplayer.get_unique_id())
+		else:
+			_rpc_server_peer_voice_left.rpc_id(1)
+		peer.poll()
+		OS.delay_msec(SHUTDOWN_FLUSH_MSEC)
+
+	_encode_opus.stop_worker()
+	for decoder in _decode_opus_by_peer.values():
+		decoder.stop_worker()
+	_decode_opus_by_peer.clear()
+	_jitter_by_peer.clear()
+	_voice_history_by_peer.clear()
+	_voice_buffer.clear()
+	_voice_read_pos = 0
+
+
+## Sends the voice captured so far right away, completing a partly filled
+## packet with silence and including frames waiting to be bundled by
+## [member frames_per_packet]. Call it before closing the connection so the
+## end of the last sentence isn't cut off.
+func flush_voice() -> void:
+	if _shut_down or multiplayer.get_peers().is_empty():
+		return
+	_pad_partial_packet()
+	_send_buffered_voice()
+	_flush_pending_send_frames()
+
+
+## Returns true after [method shutdown] was called.
+func is_shut_down() -> bool:
+	return _shut_down
+
+
+func _notification(what: int) -> void:
+	if what == NOTIFICATION_WM_CLOSE_REQUEST:
+		shutdown()
+
+
+func _apply_transmit_config() -> void:
+	if _transmit_gate == null:
+		return
+	_transmit_gate.transmit_mode = transmit_mode
+	_transmit_gate.vad_threshold_db = vad_threshold_db
+	_transmit_gate.hangover_ms = vad_hangover_ms
+	_transmit_gate.min_voice_probability = vad_min_voice_probability
+
+
+func _setup_bus() -> void:
+	# Existing buses were set up by the user; only add what VOIP can't work without.
+	var existed := AudioServer.get_bus_index(BUS_NAME) != -1
+	var suppression := NoiseSuppression.NONE if existed else NoiseSuppression.RNNOISE
+	_bus_idx = setup_capture_bus(BUS_NAME, suppression)
+	_cache_existing_effects()
+	_apply_runtime_effect_config()
+
+
+## Creates or completes an audio bus for microphone capture and returns its index.
+##
+## A new bus gets the full voice chain: filters, the selected
+## [param noise_suppression], dynamics, the capture effect and a final
+## silencer so the local microphone isn't heard. On an existing bus, a missing
+## [AudioEffectClipDetector] is added first, since it measures the raw input
+## for [member input_silence_timeout_sec], a missing capture effect is added at
+## the end and the selected noise suppression is placed right before the
+## capture effect, since suppression after capture has no effect on the sent
+## voice. Other effects are left untouched.
+func setup_capture_bus(bus_name: String = BUS_NAME, noise_suppression: NoiseSuppression = NoiseSuppression.RNNOISE) -> int:
+	var bus_idx := AudioServer.get_bus_index(bus_name)
+	if bus_idx == -1:
+		bus_idx = AudioServer.bus_count
+		AudioServer.add_bus(bus_idx)
+		AudioServer.set_bus_name(bus_idx, bus_name)
+		_add_voice_chain(bus_idx, noise_suppression)
+		return bus_idx
+
+	var has_clip_detector := false
+	for i in range(AudioServer.get_bus_effect_count(bus_idx)):
+		if AudioServer.get_bus_effect(bus_idx, i) is AudioEffectClipDetector:
+			has_clip_detector = true
+			break
+	if not has_clip_detector:
+		AudioServer.add_bus_effect(bus_idx, AudioEffectClipDetector.new(), 0)
+
+	var capture_idx := -1
+	for i in range(AudioServer.get_bus_effect_count(bus_idx)):
+		if AudioServer.get_bus_effect(bus_idx, i) is AudioEffectCapture:
+			capture_idx = i
+			break
+	if capture_idx == -1:
+		var capture := AudioEffectCapture.new()
+		capture.buffer_length = _capture_buffer_length_sec
+		AudioServer.add_bus_effect(bus_idx, capture)
+		capture_idx = AudioServer.get_bus_effect_count(bus_idx) - 1
+
+	var suppressor := _create_noise_suppressor(noise_suppression)
+	if suppressor == null:
+		return bus_idx
+
+	for i in range(AudioServer.get_bus_effect_count(bus_idx)):
+		var effect := AudioServer.get_bus_effect(bus_idx, i)
+		if effect.get_class() != suppressor.get_class():
+			continue
+		if i < capture_idx:
+			return bus_idx
+		# Move it in front of the capture effect.
+		suppressor = effect
+		AudioServer.remove_bus_effect(bus_idx, i)
+		break
+	AudioServer.add_bus_effect(bus_idx, suppressor, capture_idx)
+	return bus_idx
+
+
+func _add_voice_chain(bus_idx: int, noise_suppression: NoiseSuppression) -> void:
+	# Detect clipping before anything below can hide it
+	AudioServer.add_bus_effect(bus_idx, AudioEffectClipDetector.new())
+
 	# Remove constant noise from the background
-	_high_pass = AudioEffectHighPassFilter.new()
-	_high_pass.cutoff_hz = _high_pass_cutoff_hz
-	AudioServer.add_bus_effect(_bus_idx, _high_pass)
-	
-	_low_pass = AudioEffectLowPassFilter.new()
-	_low_pass.cutoff_hz = _low_pass_cutoff_hz
-	AudioServer.add_bus_effect(_bus_idx, _low_pass)
-	
-	# Remove noise using neural network
-	_rnnoise = AudioEffectRNNoise.new()
-	AudioServer.add_bus_effect(_bus_idx, _rnnoise)
-	
+	var high_pass := AudioEffectHighPassFilter.new()
+	high_pass.cutoff_hz = _high_pass_cutoff_hz
+	AudioServer.add_bus_effect(bus_idx, high_pass)
+
+	var low_pass := AudioEffectLowPassFilter.new()
+	low_pass.cutoff_hz = _low_pass_cutoff_hz
+	AudioServer.add_bus_effect(bus_idx, low_pass)
+
+	# Close on background noise between words, see processing_chain
+	if noise_suppression != NoiseSuppression.NOISE_GATE:
+		AudioServer.add_bus_effect(bus_idx, AudioEffectNoiseGate.new())
+
+	# Remove noise, e.g. using a neural network
+	var suppressor := _create_noise_suppressor(noise_suppression)
+	if suppressor != null:
+		AudioServer.add_bus_effect(bus_idx, suppressor)
+
+	# Level quiet and loud speakers, see auto_gain_control
+	AudioServer.add_bus_effect(bus_idx, AudioEffectVoipAGC.new())
+
 	# Compress the louder sounds to be quieter
-	_compressor = AudioEffectCompressor.new()
-	_compressor.threshold = _compressor_threshold_db
-	_compressor.attack_us = 2000
-	AudioServer.add_bus_effect(_bus_idx, _compressor)
-	
+	var compressor := AudioEffectCompressor.new()
+	compressor.threshold = _compressor_threshold_db
+	compressor.attack_us = 2000
+	AudioServer.add_bus_effect(bus_idx, compressor)
+
 	# Amplify everything to offset the compression
-	_amplify = AudioEffectAmplify.new()
-	_amplify.volume_db = _amplify_db
-	AudioServer.add_bus_effect(_bus_idx, _amplify)
-	
-	# Ensure no clipping
-	_limiter = AudioEffectHardLimiter.new()
-	AudioServer.add_bus_effect(_bus_idx, _limiter)
-	
+	var amplify := AudioEffectAmplify.new()
+	amplify.volume_db = _amplify_db
+	AudioServer.add_bus_effect(bus_idx, amplify)
+
+	# Optionally disguise the speaker's voice before it leaves this machine
+	AudioServer.add_bus_effect(bus_idx, AudioEffectVoiceAnonymizer.new())
+
+	# Ensure no clipping, last so nothing above can push it over again
+	AudioServer.add_bus_effect(bus_idx, AudioEffectVoipLimiter.new())
+
 	# For capturing the mic input
-	_capture = AudioEffectCapture.new()
-	_capture.buffer_length = _capture_buffer_length_sec
-	AudioServer.add_bus_effect(_bus_idx, _capture)
-	
+	var capture := AudioEffectCapture.new()
+	capture.buffer_length = _capture_buffer_length_sec
+	AudioServer.add_bus_effect(bus_idx, capture)
+
 	# Silence the player's own mic locally after it's been captured
 	var silence := AudioEffectAmplify.new()
 	silence.volume_db = -70.0
-	AudioServer.add_bus_effect(_bus_idx, silence)
-
-	_apply_runtime_effect_config()
+	AudioServer.add_bus_effect(bus_idx, silence)
 
 
-func _verify_and_add_capture() -> void:
-	# Check if capture effect already exists
-	for i in range(AudioServer.get_bus_effect_count(_bus_idx)):
-		if AudioServer.get_bus_effect(_bus_idx, i) is AudioEffectCapture:
-			_capture = AudioServer.get_bus_effect(_bus_idx, i)
-			_capture.buffer_length = _capture_buffer_length_sec
-			return
-	
-	# Add capture if it doesn't exist
-	_capture = AudioEffectCapture.new()
-	_capture.buffer_length = _capture_buffer_length_sec
-	AudioServer.add_bus_effect(_bus_idx, _capture)
+func _create_noise_suppressor(noise_suppression: NoiseSuppression) -> AudioEffect:
+	match noise_suppression:
+		NoiseSuppression.RNNOISE:
+			return AudioEffectRNNoise.new()
+		NoiseSuppression.DEEP_FILTER_NET:
+			return AudioEffectDeepFilterNet.new()
+		NoiseSuppression.NOISE_GATE:
+			return AudioEffectNoiseGate.new()
+	return null
 
 
 func _cache_existing_effects() -> void:
 	_high_pass = null
 	_low_pass = null
 	_rnnoise = null
+	_denoiser = null
+	_noise_gate = null
 	_compressor = null
 	_amplify = null
 	_limiter = null
+	_anonymizer = null
+	_agc = null
+	_clip_detector = null
+	_capture = null
 
 	for i in range(AudioServer.get_bus_effect_count(_bus_idx)):
 		var effect := AudioServer.get_bus_effect(_bus_idx, i)
-		if effect is AudioEffectHighPassFilter and _high_pass == null:
+		if effect is AudioEffectCapture and _capture == null:
+			_capture = effect as AudioEffectCapture
+			_capture.buffer_length = _capture_buffer_length_sec
+		elif effect is AudioEffectHighPassFilter and _high_pass == null:
 			_high_pass = effect as AudioEffectHighPassFilter
 		elif effect is AudioEffectLowPassFilter and _low_pass == null:
 			_low_pass = effect as AudioEffectLowPassFilter
 		elif effect is AudioEffectRNNoise and _rnnoise == null:
 			_rnnoise = effect as AudioEffectRNNoise
+			if _denoiser == null:
+				_denoiser = effect
+		elif effect is AudioEffectDeepFilterNet and _denoiser == null:
+			_denoiser = effect
+		elif effect is AudioEffectNoiseGate and _noise_gate == null:
+			_noise_gate = effect as AudioEffectNoiseGate
 		elif effect is AudioEffectCompressor and _compressor == null:
 			_compressor = effect as AudioEffectCompressor
 		elif effect is AudioEffectAmplify:
 			var amp := effect as AudioEffectAmplify
 			if _amplify == null or amp.volume_db > _amplify.volume_db:
 				_amplify = amp
-		elif effect is AudioEffectHardLimiter and _limiter == null:
-			_limiter = effect as AudioEffectHardLimiter
+		elif (effect is AudioEffectVoipLimiter or effect is AudioEffectHardLimiter) and _limiter == null:
+			_limiter = effect
+		elif effect is AudioEffectVoiceAnonymizer and _anonymizer == null:
+			_anonymizer = effect as AudioEffectVoiceAnonymizer
+		elif effect is AudioEffectVoipAGC and _agc == null:
+			_agc = effect as AudioEffectVoipAGC
+		elif effect is AudioEffectClipDetector and _clip_detector == null:
+			_clip_detector = effect as AudioEffectClipDetector
+
+	# A gate chosen as the noise suppression is the denoiser stage.
+	if _denoiser == null and _noise_gate != null:
+		_denoiser = _noise_gate
+		_noise_gate = null
 
 
 func _apply_runtime_effect_config() -> void:
@@ -274,12 +796,14 @@ func _apply_runtime_effect_config() -> void:
 
 	if _high_pass != null:
 		_high_pass.cutoff_hz = _high_pass_cutoff_hz
-		_set_effect_enabled(_high_pass, _high_pass_enabled)
+		_set_effect_enabled(_high_pass, _has_stage(ProcessingStage.HIGH_PASS))
 	if _low_pass != null:
 		_low_pass.cutoff_hz = _low_pass_cutoff_hz
 		_set_effect_enabled(_low_pass, _low_pass_enabled)
-	if _rnnoise != null:
-		_set_effect_enabled(_rnnoise, _rnnoise_enabled)
+	if _noise_gate != null:
+		_set_effect_enabled(_noise_gate, _has_stage(ProcessingStage.NOISE_GATE))
+	if _denoiser != null:
+		_set_effect_enabled(_denoiser, _has_stage(ProcessingStage.DENOISER))
 	if _compressor != null:
 		_compressor.threshold = _compressor_threshold_db
 		_set_effect_enabled(_compressor, _compressor_enabled)
@@ -287,7 +811,16 @@ func _apply_runtime_effect_config() -> void:
 		_amplify.volume_db = _amplify_db
 		_set_effect_enabled(_amplify, _amplify_enabled)
 	if _limiter != null:
-		_set_effect_enabled(_limiter, _limiter_enabled)
+		_set_effect_enabled(_limiter, _has_stage(ProcessingStage.LIMITER))
+	if _anonymizer != null:
+		_anonymizer.seed = _anonymizer_seed
+		_set_effect_enabled(_anonymizer, _anonymizer_enabled)
+	if _agc != null:
+		_set_effect_enabled(_agc, _has_stage(ProcessingStage.AGC))
+
+
+func _has_stage(stage: ProcessingStage) -> bool:
+	return processing_chain & stage != 0
 
 
 func _set_effect_enabled(effect: AudioEffect, enabled: bool) -> void:
@@ -307,11 +840,16 @@ func _find_effect_index(effect: AudioEffect) -> int:
 
 
 func _process(delta: float) -> void:
+	_input_rms = _clip_detector.take_input_rms() if _clip_detector != null else 0.0
 	_process_calls += 1
 	_process_dt_sum += delta
 	_process_dt_max = maxf(_process_dt_max, delta)
 	if multiplayer.multiplayer_peer == null and not _decode_opus_by_peer.is_empty():
 		_decode_opus_by_peer.clear()
+	if multiplayer.multiplayer_peer == null and not _jitter_by_peer.is_empty():
+		_jitter_by_peer.clear()
+	if multiplayer.multiplayer_peer == null and not _voice_history_by_peer.is_empty():
+		_voice_history_by_peer.clear()
 	if multiplayer.multiplayer_peer == null and not _recv_seq_last_by_peer.is_empty():
 		_recv_seq_last_by_peer.clear()
 	if multiplayer.multiplayer_peer == null:
@@ -323,8 +861,21 @@ func _process(delta: float) -> void:
 	_last_process_ts = now_sec
 
 	_refresh_stream_bindings()
+	_poll_jitter_buffers()
 	_collect_playback_stage_stats()
-	_process_voice()
+	if _auto_tune_remaining_sec > 0.0:
+		_process_auto_tune(delta)
+	if _noise_floor_remaining_sec > 0.0:
+		_process_noise_floor(delta)
+	if _input_probe_active:
+		_process_input_probe(delta)
+		_send_buffered_voice()
+	else:
+		_process_voice()
+		_track_input_silence(delta)
+		_emit_level_if_due(delta)
+		_track_clipping(delta)
+	_flush_relay_bundles()
 	_update_debug_stats(delta)
 
 
@@ -357,6 +908,41 @@ func _ensure_microphone_capture_player() -> void:
 	_mic_capture_player.play()
 
 
+func _update_monitor_player() -> void:
+	if not monitor_enabled:
+		if _monitor_player != null:
+			_monitor_player.queue_free()
+		_monitor_player = null
+		_monitor_playback = null
+		return
+
+	if _monitor_player != null:
+		return
+
+	var generator := AudioStreamGenerator.new()
+	generator.mix_rate = _input_sample_rate
+	generator.buffer_length = 0.1
+	_monitor_player = AudioStreamPlayer.new()
+	_monitor_player.name = "VOIPMicrophoneMonitor"
+	_monitor_player.stream = generator
+	_monitor_player.volume_db = monitor_volume_db
+	add_child(_monitor_player)
+	_monitor_player.play()
+	_monitor_playback = _monitor_player.get_stream_playback() as AudioStreamGeneratorPlayback
+
+
+func _push_monitor_frames(frames: PackedVector2Array) -> void:
+	if _monitor_playback == null:
+		return
+	var available := _monitor_playback.get_frames_available()
+	if available <= 0:
+		return
+	if frames.size() > available:
+		# Drop the oldest audio rather than letting the monitor lag behind.
+		frames = frames.slice(frames.size() - available)
+	_monitor_playback.push_buffer(frames)
+
+
 func _disable_internal_microphone_capture() -> void:
 	if _mic_capture_player == null or not is_instance_valid(_mic_capture_player):
 		_mic_capture_player = null
@@ -465,6 +1051,8 @@ func _refresh_stream_bindings() -> void:
 
 		var stream := stream_ref as AudioStreamVOIP
 		stream.configure_stream(_output_sample_rate, _output_packet_frames)
+		if _tuned_prebuffer_ms >= 0.0 and stream.prebuffer_ms != _tuned_prebuffer_ms:
+			stream.prebuffer_ms = _tuned_prebuffer_ms
 		stream.bind_playback(generator_playback)
 		stream.pump_playback()
 
@@ -497,19 +1085,44 @@ func _process_voice() -> void:
 	_stats_capture_polls += 1
 	if count > 0:
 		_stats_capture_nonzero_polls += 1
-		var frames := _capture.get_buffer(count)
-		_voice_buffer.append_array(frames)
+		var frames := _capture_resampler.process(_capture.get_buffer(count))
+		_level_meter.process(frames, _input_sample_rate)
+		_push_monitor_frames(frames)
+		var was_transmitting := _transmit_gate.is_open()
+		var was_speaking := _transmit_gate.is_speaking()
+		_transmit_gate.set_voice_probability(_current_voice_probability())
+		var transmitted := _transmit_gate.process(frames, _input_sample_rate)
+		_voice_buffer.append_array(transmitted)
+		if was_transmitting and not _transmit_gate.is_open():
+			_pad_partial_packet()
+		if _transmit_gate.is_speaking() != was_speaking:
+			if was_speaking:
+				speaking_stopped.emit()
+			else:
+				speaking_started.emit()
+		if not muted:
+			_recorder.write_voice(frames, _transmit_gate.is_speaking())
+			local_voice_captured.emit(transmitted)
 		_stats_capture_frames += count
+		_track_auto_tune_capture()
 	else:
 		_stats_capture_empty_polls += 1
-	
+
+	_send_buffered_voice()
+
+
+func _send_buffered_voice() -> void:
 	# Keep buffer size reasonable to avoid excessive memory usage
 	if _available_voice_frames() > 48_000 * 2:
 		_voice_read_pos = _voice_buffer.size() - (48_000 * 2)
 		_compact_voice_buffer_if_needed()
 
-	if not sending_voice or multiplayer.get_peers().is_empty():
-		# Don't keep stale voice when transmission is disabled.
+	if not sending_voice or muted or multiplayer.get_peers().is_empty():
+		# Frames already encoded end the last sentence, so they still go
+		# out. Don't keep stale voice when transmission is disabled.
+		if not multiplayer.get_peers().is_empty():
+			_flush_pending_send_frames()
+		_pending_send_frames.clear()
 		_voice_buffer.clear()
 		_voice_read_pos = 0
 		return
@@ -519,6 +1132,54 @@ func _process_voice() -> void:
 		_send_next_packet()
 		packets_sent_this_frame += 1
 
+	if not _transmit_gate.is_open():
+		# Don't hold the end of a transmission back waiting for more frames.
+		_flush_pending_send_frames()
+
+
+func _track_clipping(delta: float) -> void:
+	if _clip_detector == null:
+		return
+
+	var events := _clip_detector.take_clip_events()
+	_clipping_count += events
+	_clipping_window_events += events
+	_clipping_window_sec += delta
+	if _clipping_window_sec < CLIPPING_WINDOW_SEC:
+		return
+
+	if _clipping_window_events >= CLIPPING_MIN_EVENTS:
+		clipping_detected.emit()
+	_clipping_window_events = 0
+	_clipping_window_sec = 0.0
+
+
+func _emit_level_if_due(delta: float) -> void:
+	if level_update_hz <= 0.0:
+		return
+	_level_update_accum += delta
+	if _level_update_accum < 1.0 / level_update_hz:
+		return
+	_level_update_accum = 0.0
+	level_changed.emit(_level_meter.get_peak_db(), _level_meter.get_rms_db())
+
+
+func _current_voice_probability() -> float:
+	if _rnnoise == null or _rnnoise != _denoiser or not _has_stage(ProcessingStage.DENOISER):
+		return -1.0
+	return _rnnoise.get_voice_probability()
+
+
+func _pad_partial_packet() -> void:
+	# Complete the last packet of a transmission with silence, so it's sent
+	# now instead of being glued to the start of the next one.
+	var partial := _available_voice_frames() % _input_packet_frames
+	if partial == 0:
+		return
+	var padding := PackedVector2Array()
+	padding.resize(_input_packet_frames - partial)
+	_voice_buffer.append_array(padding)
+
 
 func _send_next_packet() -> void:
 	if _available_voice_frames() < _input_packet_frames:
@@ -539,7 +1200,7 @@ func _send_next_packet() -> void:
 		if opus_data.is_empty():
 			return
 		_stats_sent_bytes += opus_data.size()
-		_send_voice_bytes(seq, opus_data)
+		_queue_voice_bytes(seq, opus_data)
 	else:
 		var opus_packet_pcm := _resample_to_network_packet(input_chunk)
 		if opus_packet_pcm.size() != _opus_frame_size:
@@ -551,6 +1212,180 @@ func _send_next_packet() -> void:
 	_mark_send_timing()
 
 
+func _process_auto_tune(delta: float) -> void:
+	_auto_tune_process_dts.append(delta * 1000.0)
+	_auto_tune_remaining_sec -= delta
+	if _auto_tune_remaining_sec > 0.0:
+		return
+
+	_auto_tune_remaining_sec = 0.0
+	var process_p95 := _percentile(_auto_tune_process_dts, 0.95)
+	var capture_p95 := _percentile(_auto_tune_capture_dts, 0.95)
+	var packet_ms := _packet_duration_sec * 1000.0
+
+	# Cover one packet plus the worst regular gap on either side of the
+	# pipeline, twice, so a late frame and a late capture can coincide.
+	var prebuffer := packet_ms + 2.0 * maxf(process_p95, capture_p95)
+	_tuned_prebuffer_ms = clampf(prebuffer, AUTO_TUNE_MIN_PREBUFFER_MS, AUTO_TUNE_MAX_PREBUFFER_MS)
+
+	# The jitter buffers are only polled once per frame, so a gap has to be
+	# held for a packet plus two late frames before the packet counts as lost.
+	var jitter_hold := packet_ms + 2.0 * process_p95
+	_tuned_jitter_hold_ms = clampi(ceili(jitter_hold), AUTO_TUNE_MIN_JITTER_HOLD_MS, AUTO_TUNE_MAX_JITTER_HOLD_MS)
+	for jitter in _jitter_by_peer.values():
+		jitter.max_hold_ms = _tuned_jitter_hold_ms
+
+	_auto_tune_report = {
+		"prebuffer_ms": _tuned_prebuffer_ms,
+		"jitter_hold_ms": _tuned_jitter_hold_ms,
+		"process_dt_p95_ms": process_p95,
+		"capture_interval_p95_ms": capture_p95,
+		"packet_ms": packet_ms,
+	}
+	auto_tune_finished.emit(_auto_tune_report.duplicate())
+
+
+func _track_auto_tune_capture() -> void:
+	if _auto_tune_remaining_sec <= 0.0:
+		return
+
+	var now_usec := Time.get_ticks_usec()
+	if _auto_tune_last_capture_usec > 0:
+		_auto_tune_capture_dts.append((now_usec - _auto_tune_last_capture_usec) / 1000.0)
+	_auto_tune_last_capture_usec = now_usec
+
+
+func _process_noise_floor(delta: float) -> void:
+	if _input_rms > 0.0:
+		_noise_floor_levels_db.append(linear_to_db(maxf(_input_rms, 0.00001)))
+		_noise_floor_peak = maxf(_noise_floor_peak, _input_rms)
+	_noise_floor_remaining_sec -= delta
+	if _noise_floor_remaining_sec > 0.0:
+		return
+
+	_noise_floor_remaining_sec = 0.0
+	if _noise_floor_levels_db.is_empty():
+		noise_floor_measured.emit({})
+		return
+
+	# A high percentile ignores the quietest moments but also keeps a single
+	# cough or click from raising the floor much.
+	var noise_floor_db := _percentile(_noise_floor_levels_db, 0.9)
+	noise_floor_measured.emit(_make_noise_floor_result(noise_floor_db, linear_to_db(maxf(_noise_floor_peak, 0.00001))))
+
+
+func _make_noise_floor_result(noise_floor_db: float, peak_db: float) -> Dictionary:
+	return {
+		"noise_floor_db": noise_floor_db,
+		"peak_db": peak_db,
+		"suggested_vad_threshold_db": clampf(noise_floor_db + NOISE_FLOOR_VAD_MARGIN_DB, -80.0, -10.0),
+		"suggested_gate_threshold_db": clampf(noise_floor_db + NOISE_FLOOR_GATE_MARGIN_DB, -80.0, -10.0),
+	}
+
+
+## Returns the noise gate on the bus that processes audio, or null.
+func _get_active_noise_gate() -> AudioEffectNoiseGate:
+	for effect in [_noise_gate, _denoiser]:
+		if effect is AudioEffectNoiseGate:
+			var idx := _find_effect_index(effect)
+			if idx != -1 and AudioServer.is_bus_effect_enabled(_bus_idx, idx):
+				return effect
+	return null
+
+
+func _percentile(values: PackedFloat64Array, fraction: float) -> float:
+	if values.is_empty():
+		return 0.0
+	var sorted := values.duplicate()
+	sorted.sort()
+	var index := clampi(int(ceil(fraction * sorted.size())) - 1, 0, sorted.size() - 1)
+	return sorted[index]
+
+
+func _track_input_silence(delta: float) -> void:
+	if input_silence_timeout_sec <= 0.0 or _clip_detector == null:
+		_input_silence_sec = 0.0
+		return
+
+	# Measured before the chain, so a closed noise gate doesn't look like a
+	# silent device. Frames without any input audio count as silence.
+	if _input_rms >= INPUT_SILENCE_RMS:
+		_input_silence_sec = 0.0
+		return
+
+	_input_silence_sec += delta
+	# Probing switches the input device, so don't cut off a transmission.
+	if _input_silence_sec >= input_silence_timeout_sec and not _transmit_gate.is_ptt_pressed():
+		_input_silence_sec = 0.0
+		_start_input_probe()
+
+
+func _start_input_probe() -> void:
+	var current := AudioServer.input_device
+	_input_probe_candidates = PackedStringArray()
+	for device in AudioServer.get_input_device_list():
+		if device != current and device != "Default":
+			_input_probe_candidates.append(device)
+	if _input_probe_candidates.is_empty():
+		return
+
+	_input_probe_active = true
+	_input_probe_original_device = current
+	_input_probe_best_device = ""
+	_input_probe_best_rms = 0.0
+	_input_probe_index = 0
+	# Voice captured so far still goes out while probing, ending like a
+	# closed gate.
+	_pad_partial_packet()
+	_begin_probe_candidate()
+
+
+func _begin_probe_candidate() -> void:
+	_input_probe_elapsed = 0.0
+	AudioServer.input_device = _input_probe_candidates[_input_probe_index]
+
+
+func _process_input_probe(delta: float) -> void:
+	if _transmit_gate.is_ptt_pressed():
+		# The player wants to talk; give the original device back right away.
+		_input_probe_best_device = ""
+		_finish_input_probe()
+		return
+
+	_input_probe_elapsed += delta
+
+	# Probe audio is only measured at the raw input, never transmitted.
+	if _capture != null:
+		_capture.clear_buffer()
+	if _input_probe_elapsed >= INPUT_PROBE_SETTLE_SEC and _input_rms > _input_probe_best_rms:
+		_input_probe_best_rms = _input_rms
+		_input_probe_best_device = _input_probe_candidates[_input_probe_index]
+
+	if _input_probe_elapsed < INPUT_PROBE_SEC:
+		return
+
+	_input_probe_index += 1
+	if _input_probe_index < _input_probe_candidates.size():
+		_begin_probe_candidate()
+		return
+
+	_finish_input_probe()
+
+
+func _finish_input_probe() -> void:
+	_input_probe_active = false
+	var found_active := _input_probe_best_rms >= INPUT_SILENCE_RMS and not _input_probe_best_device.is_empty()
+	if found_active and auto_switch_input_device:
+		AudioServer.input_device = _input_probe_best_device
+	else:
+		AudioServer.input_device = _input_probe_original_device
+	if _capture != null:
+		_capture.clear_buffer()
+
+	if found_active:
+		input_device_suggested.emit(_input_probe_best_device)
+
+
 func _resample_to_network_packet(input_frames: PackedVector2Array) -> PackedVector2Array:
 	if _input_sample_rate == _opus_sample_rate:
 		if input_frames.size() == _opus_frame_size:
@@ -581,12 +1416,44 @@ func _compact_voice_buffer_if_needed() -> void:
 		_voice_buffer = _voice_buffer.slice(_voice_read_pos)
 		_voice_read_pos = 0
 
+
+func _queue_voice_bytes(seq: int, opus_data: PackedByteArray) -> void:
+	if frames_per_packet <= 1:
+		_send_voice_bytes(seq, opus_data)
+		return
+
+	_pending_send_frames.append(VoipPacket.create(multiplayer.get_unique_id(), seq, 0, 0, opus_data))
+	if _pending_send_frames.size() >= frames_per_packet:
+		_flush_pending_send_frames()
+
+
+func _flush_pending_send_frames() -> void:
+	if _pending_send_frames.is_empty():
+		return
+	var frames := _pending_send_frames
+	_pending_send_frames = []
+	if frames.size() == 1:
+		_send_voice_bytes(frames[0].sequence, frames[0].payload)
+		return
+
+	for frame in frames:
+		_remember_voice(multiplayer.get_unique_id(), frame.sequence, frame.payload)
+	var bundle_data := VoipPacket.pack_bundle(frames)
+	if multiplayer.is_server():
+		for peer_id in multiplayer.get_peers():
+			_rpc_client_receive_voice_bundle.rpc_id(peer_id, bundle_data)
+			_stats_server_relay_packets += 1
+		return
+
+	_rpc_server_receive_voice_bundle.rpc_id(1, bundle_data)
+
+
 func _send_voice_bytes(seq: int, opus_data: PackedByteArray) -> void:
+	_remember_voice(multiplayer.get_unique_id(), seq, opus_data)
 	if multiplayer.is_server():
 		# Server-originated voice: send to all clients.
 		for peer_id in multiplayer.get_peers():
-			_rpc_client_receive_voice_bytes.rpc_id(peer_id, multiplayer.get_unique_id(), seq, opus_data)
-			_stats_server_relay_packets += 1
+			_relay_voice_bytes(peer_id, multiplayer.get_unique_id(), seq, opus_data)
 		return
 
 	# Client-originated voice: upload to server for relay.
@@ -611,39 +1478,238 @@ func _rpc_server_receive_voice_bytes(seq: int, opus_data: PackedByteArray) -> vo
 	var sender_id := multiplayer.get_remote_sender_id()
 	if sender_id == 0:
 		return
+	_receive_server_voice_bytes(sender_id, seq, opus_data)
+
+	# Relay client voice to all other clients.
+	for peer_id in multiplayer.get_peers():
+		if peer_id == sender_id:
+			continue
+		_relay_voice_bytes(peer_id, sender_id, seq, opus_data)
+
+
+@rpc("any_peer", "unreliable_ordered", "call_remote")
+func _rpc_server_receive_voice_bundle(bundle_data: PackedByteArray) -> void:
+	if not multiplayer.is_server():
+		return
+
+	var sender_id := multiplayer.get_remote_sender_id()
+	if sender_id == 0:
+		return
+
+	# Rebuild the bundle so the speaker id can't be spoofed by the client.
+	var frames: Array[VoipPacket] = []
+	for packet in VoipPacket.unpack_bundle(bundle_data):
+		_receive_server_voice_bytes(sender_id, packet.sequence, packet.payload)
+		frames.append(VoipPacket.create(sender_id, packet.sequence, 0, 0, packet.payload))
+	if frames.is_empty():
+		return
+
+	var relay_data := VoipPacket.pack_bundle(frames)
+	for peer_id in multiplayer.get_peers():
+		if peer_id == sender_id:
+			continue
+		_rpc_client_receive_voice_bundle.rpc_id(peer_id, relay_data)
+		_stats_server_relay_packets += 1
+
+
+func _receive_server_voice_bytes(sender_id: int, seq: int, opus_data: PackedByteArray) -> void:
 	_stats_server_received_packets += 1
 	_mark_recv_timing()
 	_track_recv_sequence(sender_id, seq)
+	_remember_voice(sender_id, seq, opus_data)
 
 	# Play remote client voice on server, if server has matching AudioStreamVOIP players.
-	var decoder := _get_decoder_for_peer(sender_id)
-	var pcm_data: PackedVector2Array = decoder.decode_with_sample_rate(opus_data, _output_sample_rate)
-	_track_recv_level(pcm_data)
-	_stats_decoded_packets += 1
-	peer_voice_data_received.emit(sender_id, pcm_data)
-	_stats_emitted_packets += 1
+	_queue_received_voice(sender_id, seq, opus_data)
 
-	# Relay client voice to all other clients.
-	for peer_id in multiplayer.get_peers():
-		if peer_id == sender_id:
-			continue
+
+func _relay_voice_bytes(peer_id: int, sender_id: int, seq: int, opus_data: PackedByteArray) -> void:
+	if not aggregate_relay_packets:
 		_rpc_client_receive_voice_bytes.rpc_id(peer_id, sender_id, seq, opus_data)
 		_stats_server_relay_packets += 1
+		return
+
+	var packet := VoipPacket.create(sender_id, seq, 0, 0, opus_data)
+	var packet_bytes := VoipPacket.get_header_size() + opus_data.size()
+	var pending_bytes := int(_relay_bundle_bytes_by_peer.get(peer_id, 0))
+	if pending_bytes > 0 and pending_bytes + packet_bytes > MAX_RELAY_BUNDLE_BYTES:
+		_flush_relay_bundle(peer_id)
+		pending_bytes = 0
+
+	if not _relay_bundle_by_peer.has(peer_id):
+		var new_bundle: Array[VoipPacket] = []
+		_relay_bundle_by_peer[peer_id] = new_bundle
+	var bundle: Array[VoipPacket] = _relay_bundle_by_peer[peer_id]
+	bundle.append(packet)
+	_relay_bundle_bytes_by_peer[peer_id] = pending_bytes + packet_bytes
+
+
+func _flush_relay_bundles() -> void:
+	for peer_id in _relay_bundle_by_peer.keys():
+		_flush_relay_bundle(peer_id)
+
+
+func _flush_relay_bundle(peer_id: int) -> void:
+	if not _relay_bundle_by_peer.has(peer_id):
+		return
+	var bundle: Array[VoipPacket] = _relay_bundle_by_peer[peer_id]
+	_relay_bundle_by_peer.erase(peer_id)
+	_relay_bundle_bytes_by_peer.erase(peer_id)
+	if bundle.is_empty() or not multiplayer.get_peers().has(peer_id):
+		return
+
+	_rpc_client_receive_voice_bundle.rpc_id(peer_id, VoipPacket.pack_bundle(bundle))
+	_stats_server_relay_packets += 1
+
+
+@rpc("authority", "unreliable_ordered", "call_remote")
+func _rpc_client_receive_voice_bundle(bundle_data: PackedByteArray) -> void:
+	for packet in VoipPacket.unpack_bundle(bundle_data):
+		_receive_client_voice_bytes(packet.peer_id, packet.sequence, packet.payload)
 
 
 @rpc("authority", "unreliable_ordered", "call_remote")
 func _rpc_client_receive_voice_bytes(sender_id: int, seq: int, opus_data: PackedByteArray) -> void:
+	_receive_client_voice_bytes(sender_id, seq, opus_data)
+
+
+func _receive_client_voice_bytes(sender_id: int, seq: int, opus_data: PackedByteArray) -> void:
 	if sender_id == 0:
 		return
 	_stats_client_received_packets += 1
 	_mark_recv_timing()
 	_track_recv_sequence(sender_id, seq)
+	_queue_received_voice(sender_id, seq, opus_data)
+
+
+func _queue_received_voice(sender_id: int, seq: int, opus_data: PackedByteArray) -> void:
+	var jitter := _get_jitter_buffer_for_peer(sender_id)
+	jitter.push(seq, opus_data)
+	_play_released_voice(sender_id, jitter.poll())
+
+
+func _poll_jitter_buffers() -> void:
+	for peer_id in _jitter_by_peer.keys():
+		var jitter: VoipJitterBuffer = _jitter_by_peer[peer_id]
+		if jitter.get_held_count() > 0:
+			_play_released_voice(peer_id, jitter.poll())
+
+
+func _play_released_voice(sender_id: int, released: Array[Dictionary]) -> void:
+	if released.is_empty():
+		return
+
 	var decoder := _get_decoder_for_peer(sender_id)
-	var pcm_data: PackedVector2Array = decoder.decode_with_sample_rate(opus_data, _output_sample_rate)
-	_track_recv_level(pcm_data)
-	_stats_decoded_packets += 1
-	peer_voice_data_received.emit(sender_id, pcm_data)
-	_stats_emitted_packets += 1
+	for packet in released:
+		# Lost packets have an empty payload, which makes Opus conceal them.
+		var opus_data: PackedByteArray = packet["payload"]
+		var pcm_data: PackedVector2Array = decoder.decode_with_sample_rate(opus_data, _output_sample_rate)
+		_track_recv_level(pcm_data)
+		_stats_decoded_packets += 1
+		peer_voice_data_received.emit(sender_id, pcm_data)
+		_stats_emitted_packets += 1
+
+
+func _get_jitter_buffer_for_peer(peer_id: int) -> VoipJitterBuffer:
+	if _jitter_by_peer.has(peer_id):
+		return _jitter_by_peer[peer_id]
+
+	var jitter := VoipJitterBuffer.new()
+	jitter.nack_enabled = request_retransmissions
+	if _tuned_jitter_hold_ms >= 0:
+		jitter.max_hold_ms = _tuned_jitter_hold_ms
+	jitter.packet_missing.connect(_on_packet_missing.bind(peer_id))
+	_jitter_by_peer[peer_id] = jitter
+	return jitter
+
+
+func _remember_voice(speaker_id: int, seq: int, opus_data: PackedByteArray) -> void:
+	if not _voice_history_by_peer.has(speaker_id):
+		_voice_history_by_peer[speaker_id] = {}
+	# Keyed by the 16-bit sequence the jitter buffer reports.
+	var history: Dictionary = _voice_history_by_peer[speaker_id]
+	history[seq & 0xFFFF] = opus_data
+	history.erase((seq - RETRANSMIT_HISTORY_PACKETS) & 0xFFFF)
+
+
+func _on_packet_missing(seq: int, speaker_id: int) -> void:
+	if multiplayer.multiplayer_peer == null:
+		return
+
+	if multiplayer.is_server():
+		# Voice from a client reached the server directly, so ask the client.
+		_rpc_request_retransmit.rpc_id(speaker_id, speaker_id, seq)
+	else:
+		_rpc_request_retransmit.rpc_id(1, speaker_id, seq)
+
+
+@rpc("any_peer", "reliable", "call_remote")
+func _rpc_request_retransmit(speaker_id: int, seq: int) -> void:
+	var history: Dictionary = _voice_history_by_peer.get(speaker_id, {})
+	if not history.has(seq):
+		return
+	var requester_id := multiplayer.get_remote_sender_id()
+	_rpc_receive_retransmit.rpc_id(requester_id, speaker_id, seq, history[seq])
+
+
+@rpc("any_peer", "reliable", "call_remote")
+func _rpc_receive_retransmit(speaker_id: int, seq: int, opus_data: PackedByteArray) -> void:
+	var sender_id := multiplayer.get_remote_sender_id()
+	if sender_id != 1 and sender_id != speaker_id:
+		return
+	if not _jitter_by_peer.has(speaker_id):
+		return
+
+	if multiplayer.is_server():
+		# Keep it for clients that missed the same packet.
+		_remember_voice(speaker_id, seq, opus_data)
+	var jitter: VoipJitterBuffer = _jitter_by_peer[speaker_id]
+	jitter.push_retransmitted(seq, opus_data)
+	_play_released_voice(speaker_id, jitter.poll())
+
+
+## Returns the packet counters of the jitter buffer for [param peer_id]:
+## [code]received[/code], [code]released[/code], [code]lost[/code],
+## [code]late[/code], [code]duplicate[/code] and [code]reordered[/code].
+##
+## Many late or reordered packets point at the network, while clean counters
+## together with choppy audio point at the audio pipeline. Returns an empty
+## dictionary if no voice was received from the peer yet.
+func get_peer_jitter_stats(peer_id: int) -> Dictionary:
+	if not _jitter_by_peer.has(peer_id):
+		return {}
+	var jitter: VoipJitterBuffer = _jitter_by_peer[peer_id]
+	return jitter.get_stats()
+
+
+@rpc("any_peer", "reliable", "call_remote")
+func _rpc_server_peer_voice_left() -> void:
+	if not multiplayer.is_server():
+		return
+
+	var sender_id := multiplayer.get_remote_sender_id()
+	if sender_id == 0:
+		return
+	_on_peer_voice_left(sender_id)
+	for peer_id in multiplayer.get_peers():
+		if peer_id == sender_id:
+			continue
+		_rpc_client_peer_voice_left.rpc_id(peer_id, sender_id)
+
+
+@rpc("authority", "reliable", "call_remote")
+func _rpc_client_peer_voice_left(sender_id: int) -> void:
+	_on_peer_voice_left(sender_id)
+
+
+func _on_peer_voice_left(sender_id: int) -> void:
+	if _jitter_by_peer.has(sender_id):
+		# Play what already arrived, but don't wait for anything else.
+		var jitter: VoipJitterBuffer = _jitter_by_peer[sender_id]
+		_play_released_voice(sender_id, jitter.flush())
+		_jitter_by_peer.erase(sender_id)
+	_decode_opus_by_peer.erase(sender_id)
+	_recv_seq_last_by_peer.erase(sender_id)
+	peer_voice_left.emit(sender_id)
 
 
 func _get_decoder_for_peer(peer_id: int) -> OpusCodec:
@@ -873,6 +1939,12 @@ func _build_stats_snapshot() -> Dictionary:
 	if _stats_send_level_count > 0:
 		send_level_rms_avg = _stats_send_level_rms_sum / float(_stats_send_level_count)
 
+	var jitter_totals := {"lost": 0, "late": 0, "duplicate": 0, "reordered": 0}
+	for jitter in _jitter_by_peer.values():
+		var jitter_stats: Dictionary = jitter.get_stats()
+		for key in jitter_totals:
+			jitter_totals[key] += int(jitter_stats.get(key, 0))
+
 	var recv_level_rms_avg := 0.0
 	if _stats_recv_level_count > 0:
 		recv_level_rms_avg = _stats_recv_level_rms_sum / float(_stats_recv_level_count)
@@ -882,6 +1954,7 @@ func _build_stats_snapshot() -> Dictionary:
 		"mode": "opus" if opus_compression_enabled else "pcm",
 		"capture_frames": _stats_capture_frames,
 		"sent_packets": _stats_sent_packets,
+		"pending_send_frames": _pending_send_frames.size(),
 		"sent_bytes": _stats_sent_bytes,
 		"sent_kbps": sent_kbps,
 		"server_received_packets": _stats_server_received_packets,
@@ -895,6 +1968,10 @@ func _build_stats_snapshot() -> Dictionary:
 		"recv_seq_gaps": _stats_send_seq_gaps,
 		"recv_seq_reorders": _stats_send_seq_reorders,
 		"recv_seq_duplicates": _stats_send_seq_duplicates,
+		"jitter_lost_total": jitter_totals["lost"],
+		"jitter_late_total": jitter_totals["late"],
+		"jitter_duplicate_total": jitter_totals["duplicate"],
+		"jitter_reordered_total": jitter_totals["reordered"],
 		"send_level_rms_avg": send_level_rms_avg,
 		"send_level_peak_max": _stats_send_level_peak_max,
 		"recv_level_rms_avg": recv_level_rms_avg,
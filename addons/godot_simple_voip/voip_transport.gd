@@ -0,0 +1,42 @@
+extends Node
+class_name VoipTransport
+
+## Base class for the networking that carries voice packets of a [VoipManager].
+##
+## Extend it to send voice over ENet, Steam, WebRTC or your own sockets, and
+## assign it to [member VoipManager.transport]. The manager never looks into
+## the transport; it only calls [method send_packet] and listens to the
+## signals below. Implementations must override [method send_packet] and
+## emit [signal packet_received] for every packet that arrives. Voice is
+## real-time, so transports should send unreliably and may drop packets.
+
+## Emitted for every voice packet received from [param peer_id].
+signal packet_received(peer_id: int, packet: PackedByteArray)
+## Emitted when a peer that can receive voice appeared.
+signal peer_connected(peer_id: int)
+## Emitted when a peer went away.
+signal peer_disconnected(peer_id: int)
+
+
+## Sends [param packet] to [param peer_id].
+func send_packet(_peer_id: int, _packet: PackedByteArray) -> void:
+	push_error("VoipTransport.send_packet() is not implemented by %s." % get_script().resource_path)
+
+
+## Sends [param packet] to every peer in [param peer_ids]. Override it if the
+## transport can send to several peers at once.
+func send_packet_to_peers(peer_ids: Array[int], packet: PackedByteArray) -> void:
+	for peer_id in peer_ids:
+		send_packet(peer_id, packet)
+
+
+## Sends whatever the transport still holds back. [method VoipManager.shutdown]
+## calls it after its last packets, so override it if packets are queued
+## until the next poll or frame. Does nothing by default.
+func flush() -> void:
+	pass
+
+
+## Returns the peers currently reachable through this transport.
+func get_peers() -> Array[int]:
+	return []
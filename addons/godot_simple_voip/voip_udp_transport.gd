@@ -0,0 +1,235 @@
+extends VoipTransport
+class_name VoipUDPTransport
+
+## Carries voice over plain UDP sockets, without Godot's high-level multiplayer.
+##
+## Meant for LAN parties and dedicated servers. Every side calls [method listen]
+## and adds the others with [method add_peer] once their addresses are known,
+## e.g. from a lobby or matchmaking server. Behind NAT, use
+## [method punch_hole] on both sides at the same time instead; the peer
+## counts as connected once packets get through in either direction.
+##[br][br]
+## Each datagram carries [member local_peer_id], so give every participant a
+## unique id. Peers that send nothing, not even keepalives, for
+## [member peer_timeout_sec] are disconnected.
+##[br][br]
+## Packets from a peer's id but a different address are dropped. The transport
+## only moves a peer to a new address, e.g. after its NAT changed the port, once
+## that address answered a hole punching challenge.
+
+## Emitted when [method punch_hole] gave up on [param peer_id].
+signal hole_punch_failed(peer_id: int)
+
+## First byte of every datagram.
+const PACKET_TAG := 0x56
+const PACKET_HEADER_SIZE := 6
+const TYPE_VOICE := 0
+const TYPE_PUNCH := 1
+const TYPE_PUNCH_ACK := 2
+const TYPE_KEEPALIVE := 3
+## Size of the random challenge in punch packets, echoed in their acks.
+const PUNCH_NONCE_SIZE := 8
+## Time between hole punching attempts.
+const PUNCH_INTERVAL_MSEC := 100
+
+## Id other peers know this peer by.
+@export var local_peer_id := 1
+
+## Time between keepalives, which also keep NAT mappings open while nobody talks.
+@export_range(0.1, 30.0, 0.1, "suffix:s") var keepalive_interval_sec := 2.0
+
+## Time without any packet from a connected peer after which it is disconnected.
+@export_range(1.0, 120.0, 0.5, "suffix:s") var peer_timeout_sec := 10.0
+
+var _socket := PacketPeerUDP.new()
+var _crypto := Crypto.new()
+## Peer id -> { "address", "port", "connected", "last_receive_msec",
+## "last_send_msec", "punch_attempts_left", "next_punch_msec", "punch_nonce",
+## "next_challenge_msec" }
+var _peers: Dictionary = {}
+
+
+## Opens the socket on [param port]. Pass 0 to let the system pick a port, see
+## [method get_local_port].
+func listen(port: int, bind_address: String = "*") -> Error:
+	close()
+	return _socket.bind(port, bind_address)
+
+
+## Closes the socket and disconnects all peers.
+func close() -> void:
+	for peer_id in _peers.keys():
+		remove_peer(peer_id)
+	_socket.close()
+
+
+## Returns the port the socket is bound to, or 0 when not listening.
+func get_local_port() -> int:
+	return _socket.get_local_port() if _socket.is_bound() else 0
+
+
+## Adds a peer at a known address and treats it as connected right away.
+func add_peer(peer_id: int, address: String, port: int) -> void:
+	_set_peer_endpoint(peer_id, address, port)
+	_mark_connected(peer_id)
+
+
+## Sends hole punching packets to [param peer_id] for up to
+## [param attempts] times [constant PUNCH_INTERVAL_MSEC]. The other peer has to
+## do the same. Emits [signal VoipTransport.peer_connected] on success and
+## [signal hole_punch_failed] otherwise.
+func punch_hole(peer_id: int, address: String, port: int, attempts: int = 50) -> void:
+	_set_peer_endpoint(peer_id, address, port)
+	var peer: Dictionary = _peers[peer_id]
+	peer["punch_attempts_left"] = maxi(1, attempts)
+	peer["next_punch_msec"] = 0
+
+
+## Forgets [param peer_id].
+func remove_peer(peer_id: int) -> void:
+	if not _peers.has(peer_id):
+		return
+	var was_connected: bool = _peers[peer_id]["connected"]
+	_peers.erase(peer_id)
+	if was_connected:
+		peer_disconnected.emit(peer_id)
+
+
+## Returns true once packets from [param peer_id] got through or it was added
+## with [method add_peer].
+func is_peer_connected(peer_id: int) -> bool:
+	return _peers.has(peer_id) and _peers[peer_id]["connected"]
+
+
+func send_packet(peer_id: int, packet: PackedByteArray) -> void:
+	if is_peer_connected(peer_id):
+		_send(peer_id, TYPE_VOICE, packet)
+
+
+func get_peers() -> Array[int]:
+	var peers: Array[int] = []
+	for peer_id in _peers:
+		if _peers[peer_id]["connected"]:
+			peers.append(peer_id)
+	return peers
+
+
+func _process(_delta: float) -> void:
+	if not _socket.is_bound():
+		return
+	_receive_packets()
+	_service_peers()
+
+
+func _exit_tree() -> void:
+	close()
+
+
+func _receive_packets() -> void:
+	while _socket.get_available_packet_count() > 0:
+		var datagram := _socket.get_packet()
+		if datagram.size() < PACKET_HEADER_SIZE or datagram[0] != PACKET_TAG:
+			continue
+		var peer_id := datagram.decode_u32(2)
+		if not _peers.has(peer_id):
+			continue
+
+		var peer: Dictionary = _peers[peer_id]
+		var address := _socket.get_packet_ip()
+		var port := _socket.get_packet_port()
+		var type: int = datagram[1]
+		var payload := datagram.slice(PACKET_HEADER_SIZE)
+		if type == TYPE_PUNCH:
+			# Echo the challenge where it came from, so the sender can confirm
+			# the address it sees this peer at.
+			_send_to(address, port, TYPE_PUNCH_ACK, payload)
+
+		if address != peer["address"] or port != peer["port"]:
+			# Anyone can write a peer id into a datagram. NAT may still change
+			# the port the peer is seen from, so move to the new address only
+			# once it echoed a challenge sent there.
+			if type == TYPE_PUNCH_ACK and payload == peer["punch_nonce"]:
+				peer["address"] = address
+				peer["port"] = port
+				peer["punch_nonce"] = _crypto.generate_random_bytes(PUNCH_NONCE_SIZE)
+			else:
+				_challenge_endpoint(peer, address, port)
+				continue
+
+		peer["last_receive_msec"] = Time.get_ticks_msec()
+		_mark_connected(peer_id)
+		if type == TYPE_VOICE:
+			packet_received.emit(peer_id, payload)
+
+
+func _service_peers() -> void:
+	var now := Time.get_ticks_msec()
+	for peer_id in _peers.keys():
+		var peer: Dictionary = _peers[peer_id]
+		if not peer["connected"]:
+			if peer["punch_attempts_left"] > 0 and now >= peer["next_punch_msec"]:
+				peer["punch_attempts_left"] -= 1
+				peer["next_punch_msec"] = now + PUNCH_INTERVAL_MSEC
+				_send(peer_id, TYPE_PUNCH, peer["punch_nonce"])
+			elif peer["punch_attempts_left"] == 0 and now >= peer["next_punch_msec"]:
+				_peers.erase(peer_id)
+				hole_punch_failed.emit(peer_id)
+			continue
+
+		if now - int(peer["last_receive_msec"]) > int(peer_timeout_sec * 1000.0):
+			remove_peer(peer_id)
+			continue
+		if now - int(peer["last_send_msec"]) >= int(keepalive_interval_sec * 1000.0):
+			_send(peer_id, TYPE_KEEPALIVE)
+
+
+func _set_peer_endpoint(peer_id: int, address: String, port: int) -> void:
+	if not _peers.has(peer_id):
+		_peers[peer_id] = {
+			"connected": false,
+			"last_receive_msec": Time.get_ticks_msec(),
+			"last_send_msec": 0,
+			"punch_attempts_left": 0,
+			"next_punch_msec": 0,
+			"punch_nonce": _crypto.generate_random_bytes(PUNCH_NONCE_SIZE),
+			"next_challenge_msec": 0,
+		}
+	_peers[peer_id]["address"] = address
+	_peers[peer_id]["port"] = port
+
+
+func _challenge_endpoint(peer: Dictionary, address: String, port: int) -> void:
+	var now := Time.get_ticks_msec()
+	if now < int(peer["next_challenge_msec"]):
+		return
+	peer["next_challenge_msec"] = now + PUNCH_INTERVAL_MSEC
+	_send_to(address, port, TYPE_PUNCH, peer["punch_nonce"])
+
+
+func _mark_connected(peer_id: int) -> void:
+	var peer: Dictionary = _peers[peer_id]
+	if peer["connected"]:
+		return
+	peer["connected"] = true
+	peer["punch_attempts_left"] = 0
+	peer["last_receive_msec"] = Time.get_ticks_msec()
+	peer_connected.emit(peer_id)
+
+
+func _send(peer_id: int, type: int, payload: PackedByteArray = PackedByteArray()) -> void:
+	var peer: Dictionary = _peers[peer_id]
+	_send_to(peer["address"], peer["port"], type, payload)
+	peer["last_send_msec"] = Time.get_ticks_msec()
+
+
+func _send_to(address: String, port: int, type: int, payload: PackedByteArray) -> void:
+	if not _socket.is_bound():
+		return
+	var datagram := PackedByteArray()
+	datagram.resize(PACKET_HEADER_SIZE)
+	datagram[0] = PACKET_TAG
+	datagram[1] = type
+	datagram.encode_u32(2, local_peer_id)
+	datagram.append_array(payload)
+	_socket.set_dest_address(address, port)
+	_socket.put_packet(datagram)
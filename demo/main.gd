@@ -351,7 +351,7 @@ func _read_effect_runtime_config_from_bus() -> Dictionary:
 	var noise_gate: AudioEffectNoiseGate = null
 	var compressor: AudioEffectCompressor = null
 	var amplify: AudioEffectAmplify = null
-	var limiter: AudioEffectHardLimiter = null
+	var limiter: AudioEffect = null
 
 	for i in range(AudioServer.get_bus_effect_count(bus_idx)):
 		var effect := AudioServer.get_bus_effect(bus_idx, i)
@@ -371,8 +371,8 @@ func _read_effect_runtime_config_from_bus() -> Dictionary:
 			var amp := effect as AudioEffectAmplify
 			if amplify == null or amp.volume_db > amplify.volume_db:
 				amplify = amp
-		elif effect is AudioEffectHardLimiter and limiter == null:
-			limiter = effect as AudioEffectHardLimiter
+		elif (effect is AudioEffectVoipLimiter or effect is AudioEffectHardLimiter) and limiter == null:
+			limiter = effect
 
 	return {
 		"high_pass_enabled": _is_bus_effect_enabled(bus_idx, high_pass, true),
@@ -417,7 +417,7 @@ func _apply_effect_runtime_config_to_bus(config: Dictionary) -> void:
 	var noise_gate: AudioEffectNoiseGate = null
 	var compressor: AudioEffectCompressor = null
 	var amplify: AudioEffectAmplify = null
-	var limiter: AudioEffectHardLimiter = null
+	var limiter: AudioEffect = null
 
 	for i in range(AudioServer.get_bus_effect_count(bus_idx)):
 		var effect := AudioServer.get_bus_effect(bus_idx, i)
@@ -437,8 +437,8 @@ func _apply_effect_runtime_config_to_bus(config: Dictionary) -> void:
 			var amp := effect as AudioEffectAmplify
 			if amplify == null or amp.volume_db > amplify.volume_db:
 				amplify = amp
-		elif effect is AudioEffectHardLimiter and limiter == null:
-			limiter = effect as AudioEffectHardLimiter
+		elif (effect is AudioEffectVoipLimiter or effect is AudioEffectHardLimiter) and limiter == null:
+			limiter = effect
 
 	if high_pass != null:
 		high_pass.cutoff_hz = clampf(float(config.get("high_pass_cutoff_hz", high_pass.cutoff_hz)), 20.0, 2000.0)
@@ -695,6 +695,10 @@ func _on_disconnect_pressed() -> void:
 
 
 func disconnect_current_peer(log_reason: bool = true) -> void:
+	if multiplayer.multiplayer_peer and has_node("/root/VOIP"):
+		# Sends the end of the last sentence before the connection closes.
+		VOIP.flush_voice()
+		assert(int(VOIP.get_debug_stats_snapshot()["pending_send_frames"]) == 0, "Voice frames were left unsent.")
 	if multiplayer.multiplayer_peer:
 		multiplayer.multiplayer_peer.close()
 		multiplayer.multiplayer_peer = null
@@ -0,0 +1,467 @@
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{db_to_gain, gain_to_db, ms_to_coeff, one_pole_step};
+
+/// Averaging time of the level detector.
+const DETECTOR_WINDOW_MS: f32 = 100.0;
+/// The AGC never turns loud input down by more than this.
+const MAX_CUT_DB: f32 = -24.0;
+
+#[derive(Debug, Clone)]
+struct AgcParams {
+    target_db: f32,
+    max_gain_db: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    noise_floor_db: f32,
+    gain_rate_db_per_s: f32,
+    peak_protection: bool,
+    peak_ceiling_db: f32,
+}
+
+impl Default for AgcParams {
+    fn default() -> Self {
+        Self {
+            target_db: -18.0,
+            max_gain_db: 18.0,
+            attack_ms: 20.0,
+            release_ms: 800.0,
+            noise_floor_db: -50.0,
+            gain_rate_db_per_s: 10.0,
+            peak_protection: true,
+            peak_ceiling_db: -1.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AgcSharedConfig {
+    params: AgcParams,
+    revision: u64,
+}
+
+type AgcSharedConfigRef = Arc<Mutex<AgcSharedConfig>>;
+
+/// Gain computer of the AGC. Works in dB so gain moves at the same speed
+/// whether it's going up or down by a few dB or many.
+#[derive(Debug, Default)]
+struct AgcState {
+    target_db: f32,
+    max_gain_db: f32,
+    noise_floor_db: f32,
+    detector_coeff: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    /// Most the gain may rise per sample, in dB. 0 for no limit.
+    max_rise_db: f32,
+    /// Highest output peak, in dBFS, if peak protection is on.
+    peak_ceiling_db: Option<f32>,
+    mean_square: f32,
+    gain_db: f32,
+}
+
+impl AgcState {
+    fn configure(&mut self, params: &AgcParams, sample_rate: f32) {
+        self.target_db = params.target_db;
+        self.max_gain_db = params.max_gain_db.max(0.0);
+        self.noise_floor_db = params.noise_floor_db;
+        self.detector_coeff = ms_to_coeff(DETECTOR_WINDOW_MS, sample_rate);
+        self.attack_coeff = ms_to_coeff(params.attack_ms, sample_rate);
+        self.release_coeff = ms_to_coeff(params.release_ms, sample_rate);
+        self.max_rise_db = params.gain_rate_db_per_s.max(0.0) / sample_rate;
+        self.peak_ceiling_db = params
+            .peak_protection
+            .then_some(params.peak_ceiling_db.min(0.0));
+    }
+
+    /// Returns the gain for the next sample, given its mono mix and its
+    /// peak over both channels.
+    fn process(&mut self, sample: f32, peak: f32) -> f32 {
+        self.mean_square = one_pole_step(self.mean_square, sample * sample, self.detector_coeff);
+        let level_db = gain_to_db(self.mean_square.sqrt());
+
+        // Hold the gain through pauses so background noise isn't pumped up.
+        if level_db >= self.noise_floor_db {
+            let wanted_db = (self.target_db - level_db).clamp(MAX_CUT_DB, self.max_gain_db);
+            let coeff = if wanted_db < self.gain_db {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            let gain_db = one_pole_step(self.gain_db, wanted_db, coeff);
+            self.gain_db = if self.max_rise_db > 0.0 {
+                gain_db.min(self.gain_db + self.max_rise_db)
+            } else {
+                gain_db
+            };
+        }
+
+        // Cut at once for peaks the slow gain would push past the ceiling,
+        // e.g. a shout after a quiet stretch; the gain then recovers at the
+        // usual rate.
+        if let Some(ceiling_db) = self.peak_ceiling_db {
+            self.gain_db = self.gain_db.min(ceiling_db - gain_to_db(peak));
+        }
+        db_to_gain(self.gain_db)
+    }
+}
+
+/// Automatic gain control for voice.
+///
+/// Slowly adjusts the gain so speech reaches [member target_db] on average,
+/// making quiet and loud players end up at comparable loudness. Input below
+/// [member noise_floor_db] is treated as a pause and keeps the current gain.
+///
+/// The gain rises by at most [member gain_rate_db_per_s], so a pause
+/// doesn't leave the next word boosted too far, and
+/// [member peak_protection] turns it down instantly for peaks that would
+/// go above [member peak_ceiling_db].
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoipAGC {
+    pub(crate) base: Base<AudioEffect>,
+    /// Average speech level to reach, in dBFS.
+    #[export]
+    #[var(get = get_target_db, set = set_target_db)]
+    target_db: f32,
+    /// Highest gain applied to quiet input, in dB.
+    #[export]
+    #[var(get = get_max_gain_db, set = set_max_gain_db)]
+    max_gain_db: f32,
+    /// Time to turn the gain down when input gets louder, in milliseconds.
+    #[export]
+    #[var(get = get_attack_ms, set = set_attack_ms)]
+    attack_ms: f32,
+    /// Time to turn the gain up when input gets quieter, in milliseconds.
+    #[export]
+    #[var(get = get_release_ms, set = set_release_ms)]
+    release_ms: f32,
+    /// Input below this level doesn't change the gain, in dBFS.
+    #[export]
+    #[var(get = get_noise_floor_db, set = set_noise_floor_db)]
+    noise_floor_db: f32,
+    /// Fastest the gain rises, in dB per second. 0 leaves it to
+    /// [member release_ms] alone.
+    #[export]
+    #[var(get = get_gain_rate_db_per_s, set = set_gain_rate_db_per_s)]
+    gain_rate_db_per_s: f32,
+    /// Turns the gain down instantly when a peak would exceed
+    /// [member peak_ceiling_db].
+    #[export]
+    #[var(get = is_peak_protection, set = set_peak_protection)]
+    peak_protection: bool,
+    /// Highest output peak allowed by [member peak_protection], in dBFS.
+    #[export]
+    #[var(get = get_peak_ceiling_db, set = set_peak_ceiling_db)]
+    peak_ceiling_db: f32,
+    shared_config: AgcSharedConfigRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoipAGC {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = AgcParams::default();
+        Self {
+            base,
+            target_db: params.target_db,
+            max_gain_db: params.max_gain_db,
+            attack_ms: params.attack_ms,
+            release_ms: params.release_ms,
+            noise_floor_db: params.noise_floor_db,
+            gain_rate_db_per_s: params.gain_rate_db_per_s,
+            peak_protection: params.peak_protection,
+            peak_ceiling_db: params.peak_ceiling_db,
+            shared_config: Arc::new(Mutex::new(AgcSharedConfig {
+                params,
+                revision: 0,
+            })),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let mut effect = AudioEffectVoipAGCInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectVoipAGC {
+    fn sanitize_target_db(value: f32) -> f32 {
+        value.min(0.0)
+    }
+
+    fn sanitize_max_gain_db(value: f32) -> f32 {
+        value.max(0.0)
+    }
+
+    fn sanitize_attack_ms(value: f32) -> f32 {
+        value.max(0.0)
+    }
+
+    fn sanitize_release_ms(value: f32) -> f32 {
+        value.max(0.0)
+    }
+
+    fn sanitize_gain_rate_db_per_s(value: f32) -> f32 {
+        value.max(0.0)
+    }
+
+    fn sanitize_peak_ceiling_db(value: f32) -> f32 {
+        value.min(0.0)
+    }
+
+    fn push_config_to_shared(&mut self) {
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params.target_db = self.target_db;
+            cfg.params.max_gain_db = self.max_gain_db;
+            cfg.params.attack_ms = self.attack_ms;
+            cfg.params.release_ms = self.release_ms;
+            cfg.params.noise_floor_db = self.noise_floor_db;
+            cfg.params.gain_rate_db_per_s = self.gain_rate_db_per_s;
+            cfg.params.peak_protection = self.peak_protection;
+            cfg.params.peak_ceiling_db = self.peak_ceiling_db;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_target_db(&self) -> f32 {
+        self.target_db
+    }
+
+    #[func]
+    fn set_target_db(&mut self, value: f32) {
+        self.target_db = Self::sanitize_target_db(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_max_gain_db(&self) -> f32 {
+        self.max_gain_db
+    }
+
+    #[func]
+    fn set_max_gain_db(&mut self, value: f32) {
+        self.max_gain_db = Self::sanitize_max_gain_db(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_attack_ms(&self) -> f32 {
+        self.attack_ms
+    }
+
+    #[func]
+    fn set_attack_ms(&mut self, value: f32) {
+        self.attack_ms = Self::sanitize_attack_ms(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_release_ms(&self) -> f32 {
+        self.release_ms
+    }
+
+    #[func]
+    fn set_release_ms(&mut self, value: f32) {
+        self.release_ms = Self::sanitize_release_ms(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_noise_floor_db(&self) -> f32 {
+        self.noise_floor_db
+    }
+
+    #[func]
+    fn set_noise_floor_db(&mut self, value: f32) {
+        self.noise_floor_db = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_gain_rate_db_per_s(&self) -> f32 {
+        self.gain_rate_db_per_s
+    }
+
+    #[func]
+    fn set_gain_rate_db_per_s(&mut self, value: f32) {
+        self.gain_rate_db_per_s = Self::sanitize_gain_rate_db_per_s(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_peak_protection(&self) -> bool {
+        self.peak_protection
+    }
+
+    #[func]
+    fn set_peak_protection(&mut self, value: bool) {
+        self.peak_protection = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_peak_ceiling_db(&self) -> f32 {
+        self.peak_ceiling_db
+    }
+
+    #[func]
+    fn set_peak_ceiling_db(&mut self, value: f32) {
+        self.peak_ceiling_db = Self::sanitize_peak_ceiling_db(value);
+        self.push_config_to_shared();
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoipAGCInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: AgcSharedConfigRef,
+    applied_revision: u64,
+    state: AgcState,
+}
+
+impl AudioEffectVoipAGCInstance {
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        drop(cfg);
+
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.state.configure(&params, sample_rate);
+        self.applied_revision = revision;
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoipAGCInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            let gain = self.state.process(
+                (in_frame.left + in_frame.right) * 0.5,
+                in_frame.left.abs().max(in_frame.right.abs()),
+            );
+            out_frame.left = in_frame.left * gain;
+            out_frame.right = in_frame.right * gain;
+        }
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let mut state = AgcState::default();
+        state.configure(&AgcParams::default(), sample_rate);
+
+        Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settle(state: &mut AgcState, amplitude: f32, seconds: f32) -> f32 {
+        let mut gain = 1.0;
+        for i in 0..(48_000.0 * seconds) as usize {
+            let sample = if i % 96 < 48 { amplitude } else { -amplitude };
+            gain = state.process(sample, sample.abs());
+        }
+        gain
+    }
+
+    #[test]
+    fn quiet_and_loud_input_reach_target() {
+        let params = AgcParams::default();
+        for amplitude in [db_to_gain(-30.0), db_to_gain(-6.0)] {
+            let mut state = AgcState::default();
+            state.configure(&params, 48_000.0);
+            let gain = settle(&mut state, amplitude, 6.0);
+            let output_db = gain_to_db(amplitude * gain);
+            assert!(
+                (output_db - params.target_db).abs() < 1.0,
+                "output_db={output_db}"
+            );
+        }
+    }
+
+    #[test]
+    fn gain_is_limited_and_held_below_noise_floor() {
+        let params = AgcParams::default();
+        let mut state = AgcState::default();
+        state.configure(&params, 48_000.0);
+
+        // -45 dBFS needs 27 dB, but max gain is 18 dB.
+        let gain = settle(&mut state, db_to_gain(-45.0), 10.0);
+        assert!((gain_to_db(gain) - params.max_gain_db).abs() < 0.5);
+
+        let held = settle(&mut state, 0.0, 2.0);
+        assert!((held - gain).abs() < 1e-3);
+    }
+
+    #[test]
+    fn gain_rises_at_limited_rate() {
+        let params = AgcParams {
+            release_ms: 0.0,
+            ..Default::default()
+        };
+        let mut state = AgcState::default();
+        state.configure(&params, 48_000.0);
+
+        let gain = settle(&mut state, db_to_gain(-30.0), 0.5);
+        let expected_db = params.gain_rate_db_per_s * 0.5;
+        assert!((gain_to_db(gain) - expected_db).abs() < 0.1);
+    }
+
+    #[test]
+    fn peak_protection_catches_sudden_peaks() {
+        let params = AgcParams::default();
+        let mut state = AgcState::default();
+        state.configure(&params, 48_000.0);
+        settle(&mut state, db_to_gain(-36.0), 10.0);
+
+        let peak = db_to_gain(-3.0);
+        let gain = state.process(peak, peak);
+        assert!(gain_to_db(peak * gain) <= params.peak_ceiling_db + 1e-3);
+    }
+}
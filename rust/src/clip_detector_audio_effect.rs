@@ -0,0 +1,230 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use godot::classes::{AudioEffect, AudioEffectInstance, IAudioEffect, IAudioEffectInstance};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+/// Consecutive samples at or above the threshold that count as clipping. A
+/// single full-scale sample is usually just a loud peak.
+const MIN_CLIP_RUN: usize = 3;
+
+/// Counts clip runs in a stream of samples.
+#[derive(Debug, Default)]
+struct ClipCounter {
+    run: usize,
+}
+
+impl ClipCounter {
+    /// Returns how many clip runs reached [`MIN_CLIP_RUN`] samples in
+    /// `samples`. Each run is counted once.
+    fn process(&mut self, samples: impl IntoIterator<Item = f32>, threshold: f32) -> u64 {
+        let mut events = 0;
+        for sample in samples {
+            if sample.abs() >= threshold {
+                self.run += 1;
+                if self.run == MIN_CLIP_RUN {
+                    events += 1;
+                }
+            } else {
+                self.run = 0;
+            }
+        }
+        events
+    }
+}
+
+/// Sum of squares and frame count of the input, taken by
+/// [method AudioEffectClipDetector.take_input_rms].
+#[derive(Debug, Default)]
+struct InputLevel {
+    /// Bits of the f64 sum of squared samples.
+    sum_sq_bits: AtomicU64,
+    frames: AtomicU64,
+}
+
+impl InputLevel {
+    fn add(&self, sum_sq: f64, frames: u64) {
+        let _ = self
+            .sum_sq_bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + sum_sq).to_bits())
+            });
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    fn take_rms(&self) -> f32 {
+        let frames = self.frames.swap(0, Ordering::Relaxed);
+        let sum_sq = f64::from_bits(self.sum_sq_bits.swap(0, Ordering::Relaxed));
+        if frames == 0 {
+            return 0.0;
+        }
+        (sum_sq / frames as f64).sqrt() as f32
+    }
+}
+
+/// Detects clipping without changing the audio.
+///
+/// Place it first on a microphone bus, before any limiter hides the
+/// clipping. Clip events are counted when several consecutive samples reach
+/// [member threshold]; read them with [method take_clip_events].
+///
+/// It also measures the raw input level before the rest of the bus, see
+/// [method take_input_rms], e.g. to tell a silent device from a closed noise
+/// gate.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectClipDetector {
+    pub(crate) base: Base<AudioEffect>,
+    /// Sample level that counts as clipped.
+    #[export]
+    #[var(get = get_threshold, set = set_threshold)]
+    threshold: f32,
+    threshold_bits: Arc<AtomicU32>,
+    clip_events: Arc<AtomicU64>,
+    input_level: Arc<InputLevel>,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectClipDetector {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let threshold = 0.99f32;
+        Self {
+            base,
+            threshold,
+            threshold_bits: Arc::new(AtomicU32::new(threshold.to_bits())),
+            clip_events: Arc::default(),
+            input_level: Arc::default(),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        let mut effect = AudioEffectClipDetectorInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.threshold_bits = self.threshold_bits.clone();
+            effect_mut.clip_events = self.clip_events.clone();
+            effect_mut.input_level = self.input_level.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectClipDetector {
+    #[func]
+    fn get_threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    #[func]
+    fn set_threshold(&mut self, value: f32) {
+        self.threshold = value.clamp(0.0, 1.0);
+        self.threshold_bits
+            .store(self.threshold.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the clip events counted since the last call and resets the
+    /// count.
+    #[func]
+    fn take_clip_events(&self) -> i64 {
+        self.clip_events.swap(0, Ordering::Relaxed) as i64
+    }
+
+    /// Returns the RMS level of the input since the last call, or 0.0 if no
+    /// audio arrived since then.
+    #[func]
+    fn take_input_rms(&self) -> f32 {
+        self.input_level.take_rms()
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectClipDetectorInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    threshold_bits: Arc<AtomicU32>,
+    clip_events: Arc<AtomicU64>,
+    input_level: Arc<InputLevel>,
+    counter: ClipCounter,
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectClipDetectorInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            out_frame.left = in_frame.left;
+            out_frame.right = in_frame.right;
+        }
+
+        let threshold = f32::from_bits(self.threshold_bits.load(Ordering::Relaxed));
+        let samples = input_slice
+            .iter()
+            .map(|frame| frame.left.abs().max(frame.right.abs()));
+        let events = self.counter.process(samples, threshold);
+        if events > 0 {
+            self.clip_events.fetch_add(events, Ordering::Relaxed);
+        }
+
+        let sum_sq: f64 = input_slice
+            .iter()
+            .map(|frame| {
+                let mono = (frame.left + frame.right) as f64 * 0.5;
+                mono * mono
+            })
+            .sum();
+        self.input_level.add(sum_sq, frame_count as u64);
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        Self {
+            base,
+            threshold_bits: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            clip_events: Arc::default(),
+            input_level: Arc::default(),
+            counter: ClipCounter::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_runs_not_single_peaks() {
+        let mut counter = ClipCounter::default();
+        assert_eq!(counter.process([0.2, 1.0, 0.3, -1.0, 0.1], 0.99), 0);
+        assert_eq!(counter.process([1.0, 1.0, 1.0, 1.0, 1.0], 0.99), 1);
+        assert_eq!(counter.process([-1.0, 0.0, 1.0, -1.0, 1.0], 0.99), 1);
+    }
+
+    #[test]
+    fn input_rms_resets_when_taken() {
+        let level = InputLevel::default();
+        level.add(0.25 * 4.0, 4);
+        level.add(0.25 * 12.0, 12);
+        assert!((level.take_rms() - 0.5).abs() < 1e-6);
+        assert_eq!(level.take_rms(), 0.0);
+    }
+
+    #[test]
+    fn runs_continue_across_buffers() {
+        let mut counter = ClipCounter::default();
+        assert_eq!(counter.process([0.0, 1.0, 1.0], 0.99), 0);
+        assert_eq!(counter.process([1.0, 1.0], 0.99), 1);
+    }
+}
@@ -0,0 +1,479 @@
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{db_to_gain, gain_to_db, ms_to_coeff, one_pole_step};
+
+const PRESET_VOICE: i32 = 0;
+const PRESET_GENTLE: i32 = 1;
+const PRESET_BROADCAST: i32 = 2;
+
+/// Attack of the slow gain reduction of the auto release. Compression has
+/// to last about this long before the release slows down.
+const AUTO_RELEASE_BUILD_MS: f32 = 300.0;
+/// How much slower the slow gain reduction of the auto release recovers
+/// than [member AudioEffectVoipCompressor.release_ms].
+const AUTO_RELEASE_SLOW_FACTOR: f32 = 8.0;
+
+#[derive(Debug, Clone, PartialEq)]
+struct CompressorParams {
+    threshold_db: f32,
+    ratio: f32,
+    knee_db: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    auto_release: bool,
+    makeup_db: f32,
+}
+
+impl CompressorParams {
+    /// Returns the settings of a `PRESET_*`, or `None` for an unknown one.
+    fn preset(preset: i32) -> Option<Self> {
+        let params = match preset {
+            PRESET_VOICE => Self::default(),
+            PRESET_GENTLE => Self {
+                threshold_db: -20.0,
+                ratio: 2.0,
+                knee_db: 8.0,
+                attack_ms: 10.0,
+                release_ms: 150.0,
+                auto_release: true,
+                makeup_db: 3.0,
+            },
+            // Dense and even, for voices that have to cut through game audio.
+            PRESET_BROADCAST => Self {
+                threshold_db: -28.0,
+                ratio: 4.0,
+                knee_db: 4.0,
+                attack_ms: 2.0,
+                release_ms: 50.0,
+                auto_release: true,
+                makeup_db: 9.0,
+            },
+            _ => return None,
+        };
+        Some(params)
+    }
+}
+
+impl Default for CompressorParams {
+    fn default() -> Self {
+        // PRESET_VOICE: fast enough to catch plosives, 3:1 so speech stays
+        // natural.
+        Self {
+            threshold_db: -24.0,
+            ratio: 3.0,
+            knee_db: 6.0,
+            attack_ms: 5.0,
+            release_ms: 80.0,
+            auto_release: true,
+            makeup_db: 6.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CompressorSharedConfig {
+    params: CompressorParams,
+    revision: u64,
+}
+
+type CompressorSharedConfigRef = Arc<Mutex<CompressorSharedConfig>>;
+
+/// Gain computer of the compressor, in dB.
+///
+/// With the auto release, a second gain reduction follows the wanted one
+/// slowly, and the larger of both applies. It only builds up during
+/// sustained compression, so the gain comes back quickly after a short
+/// peak but slowly after a loud passage, where a fast release would pump.
+#[derive(Debug, Default)]
+struct CompressorState {
+    threshold_db: f32,
+    slope: f32,
+    knee_db: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    auto_release: bool,
+    slow_attack_coeff: f32,
+    slow_release_coeff: f32,
+    makeup_db: f32,
+    /// Gain reduction, 0 or negative.
+    reduction_db: f32,
+    slow_reduction_db: f32,
+}
+
+impl CompressorState {
+    fn configure(&mut self, params: &CompressorParams, sample_rate: f32) {
+        self.threshold_db = params.threshold_db;
+        self.slope = 1.0 / params.ratio.max(1.0) - 1.0;
+        self.knee_db = params.knee_db.max(0.0);
+        self.attack_coeff = ms_to_coeff(params.attack_ms, sample_rate);
+        self.release_coeff = ms_to_coeff(params.release_ms, sample_rate);
+        self.auto_release = params.auto_release;
+        self.slow_attack_coeff = ms_to_coeff(AUTO_RELEASE_BUILD_MS, sample_rate);
+        self.slow_release_coeff =
+            ms_to_coeff(params.release_ms * AUTO_RELEASE_SLOW_FACTOR, sample_rate);
+        self.makeup_db = params.makeup_db;
+    }
+
+    /// Returns the static gain reduction for a level, with a soft knee
+    /// `knee_db` wide around the threshold.
+    fn static_reduction_db(&self, level_db: f32) -> f32 {
+        let over_db = level_db - self.threshold_db;
+        if 2.0 * over_db <= -self.knee_db {
+            0.0
+        } else if 2.0 * over_db.abs() < self.knee_db {
+            let into_knee = over_db + self.knee_db * 0.5;
+            self.slope * into_knee * into_knee / (2.0 * self.knee_db)
+        } else {
+            self.slope * over_db
+        }
+    }
+
+    /// Returns the gain for the next sample, given its peak over both
+    /// channels.
+    fn process(&mut self, peak: f32) -> f32 {
+        let wanted_db = self.static_reduction_db(gain_to_db(peak));
+        let coeff = if wanted_db < self.reduction_db {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.reduction_db = one_pole_step(self.reduction_db, wanted_db, coeff);
+
+        let mut reduction_db = self.reduction_db;
+        if self.auto_release {
+            let slow_coeff = if wanted_db < self.slow_reduction_db {
+                self.slow_attack_coeff
+            } else {
+                self.slow_release_coeff
+            };
+            self.slow_reduction_db = one_pole_step(self.slow_reduction_db, wanted_db, slow_coeff);
+            reduction_db = reduction_db.min(self.slow_reduction_db);
+        }
+        db_to_gain(reduction_db + self.makeup_db)
+    }
+}
+
+/// Compressor tuned for voice.
+///
+/// Evens out loud and quiet syllables so speech stays intelligible over
+/// game audio. The defaults match [constant PRESET_VOICE], which suits
+/// most microphones; [method apply_preset] switches between presets.
+/// [member auto_release] makes the release depend on the material, so
+/// there's no release time to get wrong.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoipCompressor {
+    pub(crate) base: Base<AudioEffect>,
+    /// Level above which the gain is reduced, in dBFS.
+    #[export(range = (-60.0, 0.0, suffix = "dB"))]
+    #[var(get = get_threshold_db, set = set_threshold_db)]
+    threshold_db: f32,
+    /// Input dB above the threshold for each output dB above it.
+    #[export(range = (1.0, 20.0, 0.1, or_greater))]
+    #[var(get = get_ratio, set = set_ratio)]
+    ratio: f32,
+    /// Width of the soft knee around the threshold, in dB.
+    #[export(range = (0.0, 24.0, suffix = "dB"))]
+    #[var(get = get_knee_db, set = set_knee_db)]
+    knee_db: f32,
+    /// Time to reduce the gain, in milliseconds.
+    #[export(range = (0.0, 100.0, or_greater, suffix = "ms"))]
+    #[var(get = get_attack_ms, set = set_attack_ms)]
+    attack_ms: f32,
+    /// Time for the gain to recover, in milliseconds. With
+    /// [member auto_release], this is the release after short peaks.
+    #[export(range = (1.0, 2000.0, or_greater, suffix = "ms"))]
+    #[var(get = get_release_ms, set = set_release_ms)]
+    release_ms: f32,
+    /// Recovers slower after sustained compression than after short peaks,
+    /// so loud passages don't pump.
+    #[export]
+    #[var(get = is_auto_release, set = set_auto_release)]
+    auto_release: bool,
+    /// Gain added after compression, in dB.
+    #[export(range = (0.0, 24.0, suffix = "dB"))]
+    #[var(get = get_makeup_db, set = set_makeup_db)]
+    makeup_db: f32,
+    shared_config: CompressorSharedConfigRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoipCompressor {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = CompressorParams::default();
+        Self {
+            base,
+            threshold_db: params.threshold_db,
+            ratio: params.ratio,
+            knee_db: params.knee_db,
+            attack_ms: params.attack_ms,
+            release_ms: params.release_ms,
+            auto_release: params.auto_release,
+            makeup_db: params.makeup_db,
+            shared_config: Arc::new(Mutex::new(CompressorSharedConfig {
+                params,
+                revision: 0,
+            })),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let mut effect = AudioEffectVoipCompressorInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectVoipCompressor {
+    /// Fast attack, auto release and 3:1, for most voices.
+    #[constant]
+    const PRESET_VOICE: i32 = PRESET_VOICE;
+    /// 2:1 with a wide knee, for microphones that are already even.
+    #[constant]
+    const PRESET_GENTLE: i32 = PRESET_GENTLE;
+    /// 4:1 with more makeup gain, for voices that have to stand out.
+    #[constant]
+    const PRESET_BROADCAST: i32 = PRESET_BROADCAST;
+
+    /// Sets all parameters from one of the `PRESET_*` constants.
+    #[func]
+    fn apply_preset(&mut self, preset: i32) {
+        let Some(params) = CompressorParams::preset(preset) else {
+            godot_error!("AudioEffectVoipCompressor: Unknown preset {preset}.");
+            return;
+        };
+        self.threshold_db = params.threshold_db;
+        self.ratio = params.ratio;
+        self.knee_db = params.knee_db;
+        self.attack_ms = params.attack_ms;
+        self.release_ms = params.release_ms;
+        self.auto_release = params.auto_release;
+        self.makeup_db = params.makeup_db;
+        self.push_config_to_shared();
+    }
+
+    fn push_config_to_shared(&mut self) {
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params.threshold_db = self.threshold_db;
+            cfg.params.ratio = self.ratio;
+            cfg.params.knee_db = self.knee_db;
+            cfg.params.attack_ms = self.attack_ms;
+            cfg.params.release_ms = self.release_ms;
+            cfg.params.auto_release = self.auto_release;
+            cfg.params.makeup_db = self.makeup_db;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_threshold_db(&self) -> f32 {
+        self.threshold_db
+    }
+
+    #[func]
+    fn set_threshold_db(&mut self, value: f32) {
+        self.threshold_db = value.min(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_ratio(&self) -> f32 {
+        self.ratio
+    }
+
+    #[func]
+    fn set_ratio(&mut self, value: f32) {
+        self.ratio = value.max(1.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_knee_db(&self) -> f32 {
+        self.knee_db
+    }
+
+    #[func]
+    fn set_knee_db(&mut self, value: f32) {
+        self.knee_db = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_attack_ms(&self) -> f32 {
+        self.attack_ms
+    }
+
+    #[func]
+    fn set_attack_ms(&mut self, value: f32) {
+        self.attack_ms = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_release_ms(&self) -> f32 {
+        self.release_ms
+    }
+
+    #[func]
+    fn set_release_ms(&mut self, value: f32) {
+        self.release_ms = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_auto_release(&self) -> bool {
+        self.auto_release
+    }
+
+    #[func]
+    fn set_auto_release(&mut self, value: bool) {
+        self.auto_release = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_makeup_db(&self) -> f32 {
+        self.makeup_db
+    }
+
+    #[func]
+    fn set_makeup_db(&mut self, value: f32) {
+        self.makeup_db = value;
+        self.push_config_to_shared();
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoipCompressorInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: CompressorSharedConfigRef,
+    applied_revision: u64,
+    state: CompressorState,
+}
+
+impl AudioEffectVoipCompressorInstance {
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        drop(cfg);
+
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.state.configure(&params, sample_rate);
+        self.applied_revision = revision;
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoipCompressorInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            let gain = self
+                .state
+                .process(in_frame.left.abs().max(in_frame.right.abs()));
+            out_frame.left = in_frame.left * gain;
+            out_frame.right = in_frame.right * gain;
+        }
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let mut state = CompressorState::default();
+        state.configure(&CompressorParams::default(), sample_rate);
+
+        Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured(params: &CompressorParams) -> CompressorState {
+        let mut state = CompressorState::default();
+        state.configure(params, 48_000.0);
+        state
+    }
+
+    #[test]
+    fn static_curve_follows_ratio() {
+        let state = configured(&CompressorParams {
+            knee_db: 0.0,
+            ..Default::default()
+        });
+        assert_eq!(state.static_reduction_db(-30.0), 0.0);
+        // 12 dB over the threshold at 3:1 comes out 4 dB over it.
+        assert!((state.static_reduction_db(-12.0) + 8.0).abs() < 1e-4);
+
+        // The knee meets both lines at its edges.
+        let state = configured(&CompressorParams::default());
+        assert!(state.static_reduction_db(-27.0).abs() < 1e-4);
+        assert!((state.static_reduction_db(-21.0) + 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn auto_release_recovers_slower_after_sustained_compression() {
+        let params = CompressorParams {
+            makeup_db: 0.0,
+            ..Default::default()
+        };
+        let loud = db_to_gain(-6.0);
+        let recovered_gain = |loud_samples: usize| {
+            let mut state = configured(&params);
+            for _ in 0..loud_samples {
+                state.process(loud);
+            }
+            let mut gain = 0.0;
+            for _ in 0..14_400 {
+                gain = state.process(0.0);
+            }
+            gain
+        };
+
+        let after_burst = recovered_gain(480);
+        let after_sustained = recovered_gain(96_000);
+        assert!(after_burst > 0.9, "after_burst={after_burst}");
+        assert!(after_sustained < 0.5, "after_sustained={after_sustained}");
+    }
+}
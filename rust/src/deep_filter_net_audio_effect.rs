@@ -1,163 +1,2198 @@
+use std::collections::VecDeque;
 use std::ffi::c_void;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
+    atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex, PoisonError, Weak,
 };
-use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
+use std::thread::{self, JoinHandle, Thread, ThreadId};
+use std::time::Instant;
 
 use df::tract::{DfParams, DfTract, ReduceMask, RuntimeParams};
 use godot::classes::{
-    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+    AudioEffect, AudioEffectInstance, AudioServer, FileAccess, IAudioEffect, IAudioEffectInstance,
+    Performance,
 };
 use godot::{classes::native::AudioFrame, prelude::*};
 use ndarray::Array2;
 use ringbuf::{traits::*, HeapCons, HeapProd, HeapRb};
+use thread_priority::{set_current_thread_priority, ThreadPriority, ThreadPriorityValue};
 
-const DFN_RING_CAPACITY_SAMPLES: usize = 48_000;
-const WORKER_IDLE_SLEEP_MICROS: u64 = 250;
+use crate::dsp_util::{
+    gain_to_db, goertzel_power, hann_window, ms_to_samples, FadeIn, LinearResampler,
+};
+use crate::rnnoise_audio_effect::RnnoiseDenoiser;
+
+const DROP_NEWEST: i32 = 0;
+const DROP_OLDEST: i32 = 1;
+const PRIORITY_NORMAL: i32 = 0;
+const PRIORITY_HIGH: i32 = 1;
+const PRIORITY_MAX: i32 = 2;
+/// Weight of the newest chunk in the recent load ratio.
+const RECENT_LOAD_SMOOTHING: f32 = 0.05;
+/// How long the model has to be slower than real time before auto fallback
+/// switches to RNNoise.
+const FALLBACK_AFTER_MS: f32 = 2000.0;
+const FALLBACK_CROSSFADE_MS: f32 = 100.0;
+/// Default length of the crossfade when a worker restarts.
+const RECONFIGURE_CROSSFADE_MS: f32 = 50.0;
+/// Default length of the fade-in when the model starts.
+const FADE_IN_MS: f32 = 10.0;
+/// Workers an instance retires per restart: the one it replaces and one
+/// still fading out from the restart before.
+const RETIRED_PER_RESTART: usize = 2;
+/// Room in an instance's retire queue. The effect empties it before every
+/// restart, so twice what one restart retires never fills up.
+const RETIRED_CAPACITY: usize = 2 * RETIRED_PER_RESTART;
+/// Names of the performance monitors and the methods that read them.
+const PERFORMANCE_MONITORS: [(&str, &str); 3] = [
+    ("dropped_input_samples", "get_monitor_dropped_input_samples"),
+    ("buffer_occupancy", "get_monitor_buffer_occupancy"),
+    ("last_chunk_ms", "get_monitor_last_chunk_ms"),
+];
+/// Bands of the spectrum returned by `get_spectrum`.
+const SPECTRUM_BANDS: usize = 16;
+const SPECTRUM_LOWEST_HZ: f32 = 100.0;
+const SPECTRUM_HIGHEST_HZ: f32 = 16_000.0;
+/// How often the worker analyzes a chunk for the spectrum.
+const SPECTRUM_INTERVAL_MS: u32 = 50;
+/// Share of each new analysis in the shown spectrum.
+const SPECTRUM_SMOOTHING: f32 = 0.5;
+/// Sample rate the DeepFilterNet model runs at.
+const DFN_SAMPLE_RATE: u32 = 48_000;
+
+type RbProd = HeapProd<f32>;
+type RbCons = HeapCons<f32>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct DeepFilterParams {
+    atten_lim_db: f32,
+    min_db_thresh: f32,
+    max_db_erb_thresh: f32,
+    max_db_df_thresh: f32,
+    post_filter_beta: f32,
+    reduce_mask_mode: i32,
+    /// 1 enhances a mono downmix, 2 enhances left and right separately.
+    channels: usize,
+}
+
+impl Default for DeepFilterParams {
+    fn default() -> Self {
+        Self {
+            atten_lim_db: 100.0,
+            min_db_thresh: -10.0,
+            max_db_erb_thresh: 30.0,
+            max_db_df_thresh: 20.0,
+            post_filter_beta: 0.02,
+            reduce_mask_mode: ReduceMask::MEAN as i32,
+            channels: 1,
+        }
+    }
+}
+
+/// Where the worker loads the model from.
+#[derive(Debug, Clone, Copy, Default)]
+enum ModelSource {
+    #[default]
+    Embedded,
+    /// A model tarball. `DfParams::from_bytes` needs it for the rest of the
+    /// program, see [`leak_model`].
+    Tarball(&'static [u8]),
+}
+
+impl PartialEq for ModelSource {
+    /// [`leak_model`] hands out one slice per distinct model, so comparing
+    /// addresses is enough.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Embedded, Self::Embedded) => true,
+            (Self::Tarball(a), Self::Tarball(b)) => std::ptr::eq(*a, *b),
+            _ => false,
+        }
+    }
+}
+
+/// Model tarballs handed out by [`leak_model`].
+static LOADED_MODELS: Mutex<Vec<&'static [u8]>> = Mutex::new(Vec::new());
+
+/// Keeps a model tarball alive for the rest of the program. Loading the same
+/// model again reuses it, so switching between models doesn't grow memory.
+fn leak_model(bytes: &[u8]) -> &'static [u8] {
+    let Ok(mut models) = LOADED_MODELS.lock() else {
+        return Box::leak(bytes.to_vec().into_boxed_slice());
+    };
+    if let Some(model) = models.iter().find(|model| **model == bytes) {
+        return model;
+    }
+    let model: &'static [u8] = Box::leak(bytes.to_vec().into_boxed_slice());
+    models.push(model);
+    model
+}
+
+/// A loaded model that workers clone instead of loading their own. Clones
+/// share the optimized model and only get their own processing state.
+struct LoadedDenoiser {
+    model: ModelSource,
+    channels: usize,
+    prototype: DfTract,
+}
+
+/// Models loaded by any instance, most recently loaded last.
+static LOADED_DENOISERS: Mutex<Vec<LoadedDenoiser>> = Mutex::new(Vec::new());
+/// Distinct models and channel counts kept loaded. Older ones are dropped
+/// once no worker uses them anymore.
+const MAX_LOADED_DENOISERS: usize = 4;
+
+/// A thread started by the effect, joined before the library unloads.
+struct TrackedThread {
+    stop_flag: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+/// Threads started by any instance. When the editor hot-reloads the library,
+/// threads still running would call into code that's gone.
+static TRACKED_THREADS: Mutex<Vec<TrackedThread>> = Mutex::new(Vec::new());
+/// Set once the library is unloading, after which no threads are started.
+static THREADS_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Spawns a thread that [`stop_all_threads`] stops through `stop_flag` and
+/// joins.
+fn spawn_tracked(
+    name: &str,
+    stop_flag: Arc<AtomicBool>,
+    f: impl FnOnce() + Send + 'static,
+) -> std::io::Result<Thread> {
+    if THREADS_STOPPED.load(Ordering::Relaxed) {
+        return Err(std::io::Error::other("the library is unloading"));
+    }
+    let handle = thread::Builder::new().name(name.to_string()).spawn(f)?;
+    let thread = handle.thread().clone();
+
+    let mut threads = TRACKED_THREADS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    // Joining finished threads is instant and keeps the list short.
+    let (finished, running): (Vec<_>, Vec<_>) = std::mem::take(&mut *threads)
+        .into_iter()
+        .partition(|tracked| tracked.handle.is_finished());
+    *threads = running;
+    threads.push(TrackedThread { stop_flag, handle });
+    drop(threads);
+    for tracked in finished {
+        let _ = tracked.handle.join();
+    }
+    Ok(thread)
+}
+
+/// Whether a thread from [`spawn_tracked`] has exited. Doesn't block, so
+/// it's safe on the audio thread, and says no while the list is busy.
+fn tracked_thread_finished(id: ThreadId) -> bool {
+    let Ok(threads) = TRACKED_THREADS.try_lock() else {
+        return false;
+    };
+    threads
+        .iter()
+        .find(|tracked| tracked.handle.thread().id() == id)
+        .is_none_or(|tracked| tracked.handle.is_finished())
+}
+
+/// Stops and joins every thread the effect started and frees the loaded
+/// models. Called when the library unloads.
+pub(crate) fn stop_all_threads() {
+    THREADS_STOPPED.store(true, Ordering::Relaxed);
+    let threads = std::mem::take(
+        &mut *TRACKED_THREADS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    for tracked in &threads {
+        tracked.stop_flag.store(true, Ordering::Relaxed);
+        tracked.handle.thread().unpark();
+    }
+    for tracked in threads {
+        let _ = tracked.handle.join();
+    }
+    LOADED_DENOISERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+/// Loads `model` for `params.channels`, or clones an already loaded one, and
+/// applies the rest of `params` to it. Returns why if the model can't be
+/// loaded.
+fn load_denoiser(params: &DeepFilterParams, model: ModelSource) -> Result<DfTract, String> {
+    let mut denoiser = load_prototype(model, params.channels)?;
+    apply_params(&mut denoiser, params);
+    Ok(denoiser)
+}
+
+/// Sets the thresholds, limits and mask reduction of a loaded model. They
+/// don't change the model itself, so every instance can use its own.
+fn apply_params(denoiser: &mut DfTract, params: &DeepFilterParams) {
+    denoiser.set_atten_lim(params.atten_lim_db);
+    denoiser.set_pf_beta(params.post_filter_beta);
+    denoiser.min_db_thresh = params.min_db_thresh;
+    denoiser.max_db_erb_thresh = params.max_db_erb_thresh;
+    denoiser.max_db_df_thresh = params.max_db_df_thresh;
+    denoiser.reduce_mask = reduce_mask_from_i32(params.reduce_mask_mode);
+}
+
+/// Loads `model` for `channels`, or clones an already loaded one, with the
+/// default settings.
+fn load_prototype(model: ModelSource, channels: usize) -> Result<DfTract, String> {
+    // Held while loading, so instances starting together load only once.
+    let mut loaded = LOADED_DENOISERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some(entry) = loaded
+        .iter()
+        .find(|entry| entry.model == model && entry.channels == channels)
+    {
+        return Ok(entry.prototype.clone());
+    }
+
+    let runtime_params = RuntimeParams::default_with_ch(channels);
+
+    let t0 = Instant::now();
+    let df_params = match model {
+        ModelSource::Embedded => DfParams::default(),
+        ModelSource::Tarball(bytes) => match DfParams::from_bytes(bytes) {
+            Ok(df_params) => df_params,
+            Err(err) => {
+                AudioEffectDeepFilterNetInstance::log_init_error(&err);
+                godot_error!("AudioEffectDeepFilterNet: Falling back to the embedded model.");
+                DfParams::default()
+            }
+        },
+    };
+    let denoiser = match DfTract::new(df_params, &runtime_params) {
+        Ok(denoiser) => {
+            godot_print!(
+                "AudioEffectDeepFilterNet: model initialized (hop_size={}, load_time_ms={}).",
+                denoiser.hop_size,
+                t0.elapsed().as_millis()
+            );
+            denoiser
+        }
+        Err(err) => {
+            AudioEffectDeepFilterNetInstance::log_init_error(&err);
+            godot_error!(
+                "AudioEffectDeepFilterNet: Falling back to passthrough. load_time_ms={}",
+                t0.elapsed().as_millis()
+            );
+            return Err(format!("{:#}", err));
+        }
+    };
+
+    if loaded.len() >= MAX_LOADED_DENOISERS {
+        loaded.remove(0);
+    }
+    loaded.push(LoadedDenoiser {
+        model,
+        channels,
+        prototype: denoiser.clone(),
+    });
+    Ok(denoiser)
+}
+
+/// Emits `signal` on the effect from any thread, on the main thread's next
+/// idle time. Does nothing if the effect was freed meanwhile.
+pub(crate) fn emit_deferred(effect_id: InstanceId, signal: &str, args: &[Variant]) {
+    if let Ok(mut effect) = Gd::<Object>::try_from_instance_id(effect_id) {
+        let mut call_args = vec![signal.to_variant()];
+        call_args.extend_from_slice(args);
+        effect.call_deferred("emit_signal", &call_args);
+    }
+}
+
+/// How much audio may queue up for the worker when it falls behind.
+#[derive(Debug, Clone, PartialEq)]
+struct BufferingParams {
+    capacity_ms: f32,
+    drop_policy: i32,
+    /// 0 for no limit other than `capacity_ms`.
+    max_latency_ms: f32,
+}
+
+impl Default for BufferingParams {
+    fn default() -> Self {
+        Self {
+            capacity_ms: 1000.0,
+            drop_policy: DROP_NEWEST,
+            max_latency_ms: 0.0,
+        }
+    }
+}
+
+impl BufferingParams {
+    /// Longest audio backlog allowed, in milliseconds.
+    fn backlog_limit_ms(&self) -> f32 {
+        if self.max_latency_ms > 0.0 {
+            self.max_latency_ms.min(self.capacity_ms)
+        } else {
+            self.capacity_ms
+        }
+    }
+}
+
+/// Ring buffer size in samples that holds `ms` of interleaved audio.
+fn ms_to_ring_samples(ms: f32, sample_rate: u32, channels: usize) -> usize {
+    (ms * sample_rate as f32 / 1000.0).ceil().max(1.0) as usize * channels
+}
+
+/// Drops whole frames from `pending` until it holds at most `max_len`
+/// samples, from the front with [`DROP_OLDEST`] and from the back otherwise.
+/// Returns the number of dropped samples.
+fn limit_backlog(
+    pending: &mut VecDeque<f32>,
+    max_len: usize,
+    channels: usize,
+    drop_policy: i32,
+) -> usize {
+    let excess = pending.len().saturating_sub(max_len).div_ceil(channels) * channels;
+    let excess = excess.min(pending.len());
+    if excess == 0 {
+        return 0;
+    }
+    if drop_policy == DROP_OLDEST {
+        pending.drain(..excess);
+    } else {
+        pending.truncate(pending.len() - excess);
+    }
+    excess
+}
+
+/// Scheduling of the worker thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WorkerThreadParams {
+    priority: i32,
+    /// Core to pin the worker to, or negative for any.
+    core: i32,
+    /// Runs the model on the audio thread instead of spawning a worker.
+    single_threaded: bool,
+}
+
+impl Default for WorkerThreadParams {
+    fn default() -> Self {
+        Self {
+            priority: PRIORITY_NORMAL,
+            core: -1,
+            single_threaded: false,
+        }
+    }
+}
+
+impl WorkerThreadParams {
+    /// Applies the settings to the calling thread. Failures, e.g. missing
+    /// permissions for a higher priority, only print a warning.
+    fn apply_to_current_thread(&self) {
+        let priority = match self.priority {
+            PRIORITY_HIGH => ThreadPriorityValue::try_from(75u8)
+                .ok()
+                .map(ThreadPriority::Crossplatform),
+            PRIORITY_MAX => Some(ThreadPriority::Max),
+            _ => None,
+        };
+        if let Some(priority) = priority {
+            if let Err(err) = set_current_thread_priority(priority) {
+                godot_warn!(
+                    "AudioEffectDeepFilterNet: can't raise worker thread priority. {:?}",
+                    err
+                );
+            }
+        }
+
+        if self.core >= 0 {
+            let core = core_affinity::get_core_ids()
+                .and_then(|cores| cores.into_iter().find(|core| core.id == self.core as usize));
+            let pinned = core.is_some_and(core_affinity::set_for_current);
+            if !pinned {
+                godot_warn!(
+                    "AudioEffectDeepFilterNet: can't pin worker thread to core {}.",
+                    self.core
+                );
+            }
+        }
+    }
+}
+
+/// Settings workers are started with.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct WorkerConfig {
+    params: DeepFilterParams,
+    model: ModelSource,
+    buffering: BufferingParams,
+    thread: WorkerThreadParams,
+}
+
+impl WorkerConfig {
+    /// Whether workers started with `self` have to restart for `other`. The
+    /// params other than the channel count reach them through
+    /// [`DenoiserTuning`] instead.
+    fn needs_restart(&self, other: &Self) -> bool {
+        self.params.channels != other.params.channels
+            || self.model != other.model
+            || self.buffering != other.buffering
+            || self.thread != other.thread
+    }
+}
+
+/// Params of the model that pipelines apply before their next chunk, so
+/// changing them doesn't restart the worker.
+#[derive(Debug, Default)]
+struct DenoiserTuning {
+    atten_lim_db_bits: AtomicU32,
+    min_db_thresh_bits: AtomicU32,
+    max_db_erb_thresh_bits: AtomicU32,
+    max_db_df_thresh_bits: AtomicU32,
+    post_filter_beta_bits: AtomicU32,
+    reduce_mask_mode: AtomicI32,
+    /// Bumped after each change, so pipelines only apply them then.
+    revision: AtomicU64,
+}
+
+impl DenoiserTuning {
+    fn new(params: &DeepFilterParams) -> Self {
+        let tuning = Self::default();
+        tuning.store(params);
+        tuning
+    }
+
+    fn store(&self, params: &DeepFilterParams) {
+        self.atten_lim_db_bits
+            .store(params.atten_lim_db.to_bits(), Ordering::Relaxed);
+        self.min_db_thresh_bits
+            .store(params.min_db_thresh.to_bits(), Ordering::Relaxed);
+        self.max_db_erb_thresh_bits
+            .store(params.max_db_erb_thresh.to_bits(), Ordering::Relaxed);
+        self.max_db_df_thresh_bits
+            .store(params.max_db_df_thresh.to_bits(), Ordering::Relaxed);
+        self.post_filter_beta_bits
+            .store(params.post_filter_beta.to_bits(), Ordering::Relaxed);
+        self.reduce_mask_mode
+            .store(params.reduce_mask_mode, Ordering::Relaxed);
+        self.revision.fetch_add(1, Ordering::Release);
+    }
+
+    /// Applies the params to `denoiser` and returns the revision applied.
+    /// Doesn't block or allocate, so it's safe on the audio thread.
+    fn apply_to(&self, denoiser: &mut DfTract) -> u64 {
+        let revision = self.revision.load(Ordering::Acquire);
+        let load = |bits: &AtomicU32| f32::from_bits(bits.load(Ordering::Relaxed));
+        let params = DeepFilterParams {
+            atten_lim_db: load(&self.atten_lim_db_bits),
+            min_db_thresh: load(&self.min_db_thresh_bits),
+            max_db_erb_thresh: load(&self.max_db_erb_thresh_bits),
+            max_db_df_thresh: load(&self.max_db_df_thresh_bits),
+            post_filter_beta: load(&self.post_filter_beta_bits),
+            reduce_mask_mode: self.reduce_mask_mode.load(Ordering::Relaxed),
+            ..DeepFilterParams::default()
+        };
+        apply_params(denoiser, &params);
+        revision
+    }
+}
+
+/// Coarse spectrum for visualizations, written by the worker.
+#[derive(Debug, Default)]
+struct SpectrumSnapshot {
+    /// Bits of the input amplitude per band.
+    input_bits: [AtomicU32; SPECTRUM_BANDS],
+    /// Bits of the model's gain per band in dB, 0 where nothing was removed.
+    gain_db_bits: [AtomicU32; SPECTRUM_BANDS],
+}
+
+/// Counters behind [`AudioEffectDeepFilterNet::get_stats`], written by the
+/// instance and its worker.
+#[derive(Debug, Default)]
+struct DeepFilterStats {
+    dropped_input_samples: AtomicU64,
+    chunk_count: AtomicU64,
+    chunk_total_us: AtomicU64,
+    chunk_max_us: AtomicU64,
+    /// Model rate samples per chunk, the real-time budget of one chunk.
+    hop_size: AtomicUsize,
+    last_lsnr_bits: AtomicU32,
+    /// Bits of the smoothed load ratio of the latest chunks.
+    recent_load_bits: AtomicU32,
+    last_chunk_us: AtomicU64,
+    /// Bits of the share of the ring buffers in use, written by the instance.
+    buffer_occupancy_bits: AtomicU32,
+    spectrum: SpectrumSnapshot,
+}
+
+impl DeepFilterStats {
+    fn record_chunk(&self, elapsed_us: u64, lsnr: Option<f32>) {
+        let previous_count = self.chunk_count.fetch_add(1, Ordering::Relaxed);
+        self.chunk_total_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        self.chunk_max_us.fetch_max(elapsed_us, Ordering::Relaxed);
+        self.last_chunk_us.store(elapsed_us, Ordering::Relaxed);
+        if let Some(lsnr) = lsnr {
+            self.last_lsnr_bits.store(lsnr.to_bits(), Ordering::Relaxed);
+        }
+
+        let hop_size = self.hop_size.load(Ordering::Relaxed);
+        if hop_size > 0 {
+            let budget_us = hop_size as f32 * 1_000_000.0 / DFN_SAMPLE_RATE as f32;
+            let load = elapsed_us as f32 / budget_us;
+            let recent = if previous_count == 0 {
+                load
+            } else {
+                let recent = self.recent_load_ratio();
+                recent + (load - recent) * RECENT_LOAD_SMOOTHING
+            };
+            self.recent_load_bits
+                .store(recent.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Load ratio of the latest chunks, see [`Self::load_ratio`].
+    fn recent_load_ratio(&self) -> f32 {
+        f32::from_bits(self.recent_load_bits.load(Ordering::Relaxed))
+    }
+
+    fn reset(&self) {
+        self.dropped_input_samples.store(0, Ordering::Relaxed);
+        self.chunk_count.store(0, Ordering::Relaxed);
+        self.chunk_total_us.store(0, Ordering::Relaxed);
+        self.chunk_max_us.store(0, Ordering::Relaxed);
+        self.last_lsnr_bits.store(0, Ordering::Relaxed);
+        self.recent_load_bits.store(0, Ordering::Relaxed);
+        self.last_chunk_us.store(0, Ordering::Relaxed);
+    }
+
+    fn buffer_occupancy(&self) -> f32 {
+        f32::from_bits(self.buffer_occupancy_bits.load(Ordering::Relaxed))
+    }
+
+    fn last_chunk_ms(&self) -> f32 {
+        self.last_chunk_us.load(Ordering::Relaxed) as f32 / 1000.0
+    }
+
+    fn average_chunk_ms(&self) -> f32 {
+        let count = self.chunk_count.load(Ordering::Relaxed);
+        if count == 0 {
+            return 0.0;
+        }
+        self.chunk_total_us.load(Ordering::Relaxed) as f32 / count as f32 / 1000.0
+    }
+
+    /// Average chunk time relative to the audio duration of a chunk. Above 1
+    /// the worker can't keep up.
+    fn load_ratio(&self) -> f32 {
+        let hop_size = self.hop_size.load(Ordering::Relaxed);
+        if hop_size == 0 {
+            return 0.0;
+        }
+        let budget_ms = hop_size as f32 * 1000.0 / DFN_SAMPLE_RATE as f32;
+        self.average_chunk_ms() / budget_ms
+    }
+}
+
+/// Delays the dry signal by the model latency so it can be blended with the
+/// enhanced signal without comb filtering.
+struct DryWetMixer {
+    dry_line: VecDeque<f32>,
+}
+
+impl DryWetMixer {
+    fn new(latency_samples: usize) -> Self {
+        Self {
+            dry_line: std::iter::repeat(0.0).take(latency_samples).collect(),
+        }
+    }
+
+    /// Writes `wet` parts of `enhanced` and `1 - wet` parts of the dry input
+    /// that was pushed as many samples ago as the model lags behind.
+    fn mix(&mut self, input: &[f32], enhanced: &[f32], wet: f32, out: &mut [f32]) {
+        self.dry_line.extend(input);
+        for (out_sample, enhanced_sample) in out.iter_mut().zip(enhanced) {
+            let dry = self.dry_line.pop_front().unwrap_or(0.0);
+            *out_sample = wet * enhanced_sample + (1.0 - wet) * dry;
+        }
+    }
+
+    /// Writes what the model removed: the delayed dry input minus `enhanced`.
+    fn mix_removed_noise(&mut self, input: &[f32], enhanced: &[f32], out: &mut [f32]) {
+        self.dry_line.extend(input);
+        for (out_sample, enhanced_sample) in out.iter_mut().zip(enhanced) {
+            let dry = self.dry_line.pop_front().unwrap_or(0.0);
+            *out_sample = dry - enhanced_sample;
+        }
+    }
+}
+
+/// Appends interleaved `samples` with `channels` channels as frames. Mono
+/// samples go to both sides.
+fn deinterleave(samples: &[f32], channels: usize, out: &mut Vec<Vector2>) {
+    out.extend(
+        samples
+            .chunks_exact(channels)
+            .map(|frame| Vector2::new(frame[0], frame[channels - 1])),
+    );
+}
+
+/// Appends `frames` as interleaved samples with `channels` channels.
+fn interleave(frames: &[Vector2], channels: usize, out: &mut impl Extend<f32>) {
+    for frame in frames {
+        if channels == 2 {
+            out.extend([frame.x, frame.y]);
+        } else {
+            out.extend([frame.x]);
+        }
+    }
+}
+
+/// Resamples interleaved audio between the mix rate and the model rate on the
+/// worker thread, keeping the audio thread free of it.
+struct WorkerResampler {
+    resampler: LinearResampler<Vector2>,
+    channels: usize,
+    frames: Vec<Vector2>,
+    resampled: Vec<Vector2>,
+}
+
+impl WorkerResampler {
+    /// Returns `None` if the rates match and there's nothing to do.
+    fn new(input_rate: u32, output_rate: u32, channels: usize) -> Option<Self> {
+        (input_rate != output_rate).then(|| Self {
+            resampler: LinearResampler::new(input_rate, output_rate),
+            channels,
+            frames: Vec::new(),
+            resampled: Vec::new(),
+        })
+    }
+
+    fn process(&mut self, input: &[f32], out: &mut impl Extend<f32>) {
+        self.frames.clear();
+        deinterleave(input, self.channels, &mut self.frames);
+        self.resampled.clear();
+        self.resampler.process(&self.frames, &mut self.resampled);
+        interleave(&self.resampled, self.channels, out);
+    }
+}
+
+/// Center frequency of a spectrum band. Bands are spaced logarithmically.
+fn spectrum_band_hz(band: usize) -> f32 {
+    let position = band as f32 / (SPECTRUM_BANDS - 1) as f32;
+    SPECTRUM_LOWEST_HZ * (SPECTRUM_HIGHEST_HZ / SPECTRUM_LOWEST_HZ).powf(position)
+}
+
+/// Compares the model's input and output per band, every
+/// [`SPECTRUM_INTERVAL_MS`], for [`SpectrumSnapshot`].
+struct SpectrumAnalyzer {
+    /// Goertzel coefficients at the band centers.
+    coeffs: [f32; SPECTRUM_BANDS],
+    /// Hann window over one chunk.
+    window: Vec<f32>,
+    /// Interleaved input, delayed to line up with the model's output.
+    dry_line: VecDeque<f32>,
+    noisy: Vec<f32>,
+    enhanced: Vec<f32>,
+    frames_until_update: usize,
+}
+
+impl SpectrumAnalyzer {
+    fn new(chunk_frames: usize, latency_samples: usize) -> Self {
+        let window = hann_window(chunk_frames);
+        Self {
+            coeffs: std::array::from_fn(|band| {
+                2.0 * (2.0 * std::f32::consts::PI * spectrum_band_hz(band) / DFN_SAMPLE_RATE as f32)
+                    .cos()
+            }),
+            window,
+            dry_line: std::iter::repeat(0.0).take(latency_samples).collect(),
+            noisy: Vec::with_capacity(chunk_frames),
+            enhanced: Vec::with_capacity(chunk_frames),
+            frames_until_update: 0,
+        }
+    }
+
+    /// Takes one interleaved chunk of model input and output, updating
+    /// `snapshot` when it's time to.
+    fn process(
+        &mut self,
+        input: &[f32],
+        enhanced: &[f32],
+        channels: usize,
+        snapshot: &SpectrumSnapshot,
+    ) {
+        self.dry_line.extend(input);
+        self.noisy.clear();
+        self.enhanced.clear();
+        for frame in enhanced.chunks_exact(channels) {
+            let dry: f32 = self.dry_line.drain(..channels).sum();
+            self.noisy.push(dry / channels as f32);
+            self.enhanced
+                .push(frame.iter().sum::<f32>() / channels as f32);
+        }
+
+        let frames = self.noisy.len();
+        if self.frames_until_update > frames {
+            self.frames_until_update -= frames;
+            return;
+        }
+        self.frames_until_update = (DFN_SAMPLE_RATE * SPECTRUM_INTERVAL_MS / 1000) as usize;
+
+        // A full-scale sine in the band comes out at amplitude 1.
+        let scale = 4.0 / self.window.len() as f32;
+        for (band, coeff) in self.coeffs.iter().enumerate() {
+            let noisy_power = goertzel_power(&self.noisy, &self.window, *coeff);
+            let enhanced_power = goertzel_power(&self.enhanced, &self.window, *coeff);
+            let input = noisy_power.sqrt() * scale;
+            let gain_db = if noisy_power > 0.0 {
+                gain_to_db((enhanced_power / noisy_power).sqrt())
+            } else {
+                0.0
+            };
+
+            let previous_input = f32::from_bits(snapshot.input_bits[band].load(Ordering::Relaxed));
+            let previous_gain_db =
+                f32::from_bits(snapshot.gain_db_bits[band].load(Ordering::Relaxed));
+            let input = previous_input + (input - previous_input) * SPECTRUM_SMOOTHING;
+            let gain_db = previous_gain_db + (gain_db - previous_gain_db) * SPECTRUM_SMOOTHING;
+            snapshot.input_bits[band].store(input.to_bits(), Ordering::Relaxed);
+            snapshot.gain_db_bits[band].store(gain_db.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Enhances a whole recording of interleaved `samples` at the model rate.
+/// The output lines up with the input and is as long.
+fn enhance_offline(denoiser: &mut DfTract, samples: &[f32], channels: usize, wet: f32) -> Vec<f32> {
+    let hop_size = denoiser.hop_size;
+    let chunk_size = hop_size * channels;
+    let latency = model_latency(denoiser) * channels;
+    let mut dry_wet_mixer = DryWetMixer::new(latency);
+    let mut noisy_frame = Array2::zeros((channels, hop_size));
+    let mut enhanced_frame = Array2::zeros((channels, hop_size));
+    let mut in_chunk = vec![0.0f32; chunk_size];
+    let mut enhanced_chunk = vec![0.0f32; chunk_size];
+    let mut mixed_chunk = vec![0.0f32; chunk_size];
+
+    // Silence after the end flushes what the model still holds back.
+    let padded_len = samples.len() + latency;
+    let mut out = Vec::with_capacity(padded_len + chunk_size);
+    let mut position = 0;
+    while position < padded_len {
+        for (i, sample) in in_chunk.iter_mut().enumerate() {
+            *sample = samples.get(position + i).copied().unwrap_or(0.0);
+            noisy_frame[(i % channels, i / channels)] = *sample;
+        }
+
+        let enhanced: &[f32] = match denoiser.process(noisy_frame.view(), enhanced_frame.view_mut())
+        {
+            Ok(_) => {
+                for (i, sample) in enhanced_chunk.iter_mut().enumerate() {
+                    *sample = enhanced_frame[(i % channels, i / channels)];
+                }
+                &enhanced_chunk
+            }
+            Err(err) => {
+                godot_error!(
+                    "AudioEffectDeepFilterNet: process failed, using dry chunk. {:?}",
+                    err
+                );
+                &in_chunk
+            }
+        };
+        dry_wet_mixer.mix(&in_chunk, enhanced, wet, &mut mixed_chunk);
+        out.extend_from_slice(&mixed_chunk);
+        position += chunk_size;
+    }
+
+    out.drain(..latency.min(out.len()));
+    out.truncate(samples.len());
+    out
+}
+
+/// Settings and stats shared between a pipeline and its effect.
+#[derive(Clone)]
+struct PipelineControls {
+    wet_amount_bits: Arc<AtomicU32>,
+    monitor_noise_flag: Arc<AtomicBool>,
+    /// Bits of the fade-in length in milliseconds, read when a pipeline
+    /// starts.
+    fade_in_ms_bits: Arc<AtomicU32>,
+    tuning: Arc<DenoiserTuning>,
+    stats: Arc<DeepFilterStats>,
+}
+
+/// Enhances audio from the input ring buffer into the output ring buffer,
+/// resampling around the model. Runs on the worker thread, or inline on the
+/// audio thread in single-threaded mode.
+struct ChunkPipeline {
+    denoiser: DfTract,
+    /// Revision of [`PipelineControls::tuning`] applied to `denoiser`.
+    applied_tuning: u64,
+    channels: usize,
+    input_consumer: RbCons,
+    output_producer: RbProd,
+    input_resampler: Option<WorkerResampler>,
+    output_resampler: Option<WorkerResampler>,
+    controls: PipelineControls,
+    received: Vec<f32>,
+    /// Interleaved input at the model rate, waiting for a full chunk.
+    pending_input: VecDeque<f32>,
+    max_pending_input: usize,
+    drop_policy: i32,
+    dry_wet_mixer: DryWetMixer,
+    spectrum: SpectrumAnalyzer,
+    /// Fades the first output in, per frame, over the model's start-up.
+    fade_in: FadeIn,
+    in_chunk: Vec<f32>,
+    enhanced_chunk: Vec<f32>,
+    mixed_chunk: Vec<f32>,
+    noisy_frame: Array2<f32>,
+    enhanced_frame: Array2<f32>,
+    /// Enhanced samples at the mix rate, pushed from `out_written` on.
+    out_samples: Vec<f32>,
+    out_written: usize,
+}
+
+impl ChunkPipeline {
+    fn new(
+        mut denoiser: DfTract,
+        channels: usize,
+        mix_rate: u32,
+        (input_consumer, output_producer): (RbCons, RbProd),
+        buffering: &BufferingParams,
+        controls: PipelineControls,
+    ) -> Self {
+        let hop_size = denoiser.hop_size;
+        let chunk_size = hop_size * channels;
+        let max_pending_input =
+            ms_to_ring_samples(buffering.backlog_limit_ms(), DFN_SAMPLE_RATE, channels)
+                .max(chunk_size);
+        let ring_samples = input_consumer.capacity().get();
+        let latency = model_latency(&denoiser);
+        let fade_in_ms = f32::from_bits(controls.fade_in_ms_bits.load(Ordering::Relaxed));
+        controls.stats.hop_size.store(hop_size, Ordering::Relaxed);
+        let applied_tuning = controls.tuning.apply_to(&mut denoiser);
+        Self {
+            denoiser,
+            applied_tuning,
+            channels,
+            input_consumer,
+            output_producer,
+            input_resampler: WorkerResampler::new(mix_rate, DFN_SAMPLE_RATE, channels),
+            output_resampler: WorkerResampler::new(DFN_SAMPLE_RATE, mix_rate, channels),
+            controls,
+            received: vec![0.0; ring_samples],
+            pending_input: VecDeque::with_capacity(max_pending_input + chunk_size),
+            max_pending_input,
+            drop_policy: buffering.drop_policy,
+            // Chunks are interleaved, so the dry line is too.
+            dry_wet_mixer: DryWetMixer::new(latency * channels),
+            spectrum: SpectrumAnalyzer::new(hop_size, latency * channels),
+            fade_in: FadeIn::new(ms_to_samples(fade_in_ms, DFN_SAMPLE_RATE as f32)),
+            in_chunk: vec![0.0; chunk_size],
+            enhanced_chunk: vec![0.0; chunk_size],
+            mixed_chunk: vec![0.0; chunk_size],
+            noisy_frame: Array2::zeros((channels, hop_size)),
+            enhanced_frame: Array2::zeros((channels, hop_size)),
+            out_samples: Vec::with_capacity(chunk_size * 2),
+            out_written: 0,
+        }
+    }
+
+    /// Moves the input waiting in the ring buffer to the model's queue.
+    fn receive(&mut self) {
+        let channels = self.channels;
+        let available = self.input_consumer.occupied_len() / channels * channels;
+        if available == 0 {
+            return;
+        }
+        let popped = self
+            .input_consumer
+            .pop_slice(&mut self.received[..available]);
+        match self.input_resampler.as_mut() {
+            Some(resampler) => resampler.process(&self.received[..popped], &mut self.pending_input),
+            None => self.pending_input.extend(&self.received[..popped]),
+        }
+        let dropped = limit_backlog(
+            &mut self.pending_input,
+            self.max_pending_input,
+            channels,
+            self.drop_policy,
+        );
+        self.controls
+            .stats
+            .dropped_input_samples
+            .fetch_add(dropped as u64, Ordering::Relaxed);
+    }
+
+    /// Enhances the next chunk, returning false if there's not enough input
+    /// queued for one. Push its output with [`Self::push_output`] first.
+    fn run_chunk(&mut self) -> bool {
+        let channels = self.channels;
+        let chunk_size = self.in_chunk.len();
+        if self.pending_input.len() < chunk_size {
+            return false;
+        }
+
+        for (dst, src) in self
+            .in_chunk
+            .iter_mut()
+            .zip(self.pending_input.drain(..chunk_size))
+        {
+            *dst = src;
+        }
+        for (i, sample) in self.in_chunk.iter().enumerate() {
+            self.noisy_frame[(i % channels, i / channels)] = *sample;
+        }
+
+        if self.controls.tuning.revision.load(Ordering::Relaxed) != self.applied_tuning {
+            self.applied_tuning = self.controls.tuning.apply_to(&mut self.denoiser);
+        }
+
+        let t_chunk = Instant::now();
+        let mut lsnr = None;
+        let out_slice: &[f32] = match self
+            .denoiser
+            .process(self.noisy_frame.view(), self.enhanced_frame.view_mut())
+        {
+            Ok(chunk_lsnr) => {
+                lsnr = Some(chunk_lsnr);
+                for (i, sample) in self.enhanced_chunk.iter_mut().enumerate() {
+                    *sample = self.enhanced_frame[(i % channels, i / channels)];
+                }
+                &self.enhanced_chunk
+            }
+            Err(err) => {
+                godot_error!(
+                    "AudioEffectDeepFilterNet: process failed in worker, using dry chunk. {:?}",
+                    err
+                );
+                &self.in_chunk
+            }
+        };
+
+        self.spectrum.process(
+            &self.in_chunk,
+            out_slice,
+            channels,
+            &self.controls.stats.spectrum,
+        );
+        if self.controls.monitor_noise_flag.load(Ordering::Relaxed) {
+            self.dry_wet_mixer
+                .mix_removed_noise(&self.in_chunk, out_slice, &mut self.mixed_chunk);
+        } else {
+            let wet = f32::from_bits(self.controls.wet_amount_bits.load(Ordering::Relaxed));
+            self.dry_wet_mixer
+                .mix(&self.in_chunk, out_slice, wet, &mut self.mixed_chunk);
+        }
+        if !self.fade_in.is_done() {
+            for frame in self.mixed_chunk.chunks_exact_mut(channels) {
+                let gain = self.fade_in.next_gain();
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            }
+        }
+
+        self.controls
+            .stats
+            .record_chunk(t_chunk.elapsed().as_micros() as u64, lsnr);
+
+        self.out_samples.clear();
+        self.out_written = 0;
+        match self.output_resampler.as_mut() {
+            Some(resampler) => resampler.process(&self.mixed_chunk, &mut self.out_samples),
+            None => self.out_samples.extend_from_slice(&self.mixed_chunk),
+        }
+        true
+    }
+
+    /// Pushes as much of the last chunk's output as fits. Returns true once
+    /// all of it was pushed.
+    fn push_output(&mut self) -> bool {
+        self.out_written += self
+            .output_producer
+            .push_slice(&self.out_samples[self.out_written..]);
+        self.out_written == self.out_samples.len()
+    }
+
+    /// Enhances everything queued without waiting, for the audio thread.
+    fn run_inline(&mut self) {
+        self.receive();
+        while self.push_output() && self.run_chunk() {}
+    }
+}
+
+/// Delay of the model at 48 kHz, in samples per channel.
+fn model_latency(denoiser: &DfTract) -> usize {
+    denoiser.fft_size - denoiser.hop_size + denoiser.lookahead * denoiser.hop_size
+}
+
+struct DeepFilterWorker {
+    input_producer: RbProd,
+    output_consumer: RbCons,
+    stop_flag: Arc<AtomicBool>,
+    /// The worker thread, started with [`spawn_tracked`].
+    thread: Option<Thread>,
+    /// The model running on the audio thread in single-threaded mode,
+    /// instead of on `thread`.
+    inline_pipeline: Option<Box<ChunkPipeline>>,
+    /// Delay of the model at 48 kHz, set by the worker once it's loaded.
+    model_latency_samples: Arc<AtomicUsize>,
+    /// Channels the worker enhances, 1 or 2. Ring buffers hold interleaved
+    /// samples.
+    channels: usize,
+    /// Most enhanced samples kept waiting for output, 0 for no limit.
+    max_output_backlog: usize,
+    /// RNNoise for auto fallback, built with the worker so switching to it
+    /// doesn't allocate on the audio thread. Lent to the instance while
+    /// it's in use.
+    fallback: Option<RnnoiseDenoiser>,
+}
+
+impl DeepFilterWorker {
+    /// Starts a worker for `config`, loading the model on a new thread, or
+    /// on the calling thread in single-threaded mode. Allocates and spawns,
+    /// so it's for the main thread. Returns `None` if no worker can start.
+    fn start(
+        config: &WorkerConfig,
+        mix_rate: u32,
+        controls: PipelineControls,
+        model_active: Arc<AtomicBool>,
+        effect_id: Option<InstanceId>,
+    ) -> Option<Self> {
+        if THREADS_STOPPED.load(Ordering::Relaxed) {
+            // The library is unloading, audio passes through until then.
+            return None;
+        }
+        let channels = config.params.channels.clamp(1, 2);
+        let model = config.model;
+        let buffering = config.buffering.clone();
+        let thread_params = config.thread;
+        model_active.store(false, Ordering::Relaxed);
+        let max_output_backlog = if buffering.max_latency_ms > 0.0 {
+            ms_to_ring_samples(buffering.max_latency_ms, mix_rate, channels)
+        } else {
+            0
+        };
+
+        let ring_samples = ms_to_ring_samples(buffering.capacity_ms, mix_rate, channels);
+        let in_rb = HeapRb::<f32>::new(ring_samples);
+        let out_rb = HeapRb::<f32>::new(ring_samples);
+        let (input_producer, input_consumer) = in_rb.split();
+        let (output_producer, output_consumer) = out_rb.split();
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let model_latency_samples = Arc::new(AtomicUsize::new(0));
+        controls.stats.reset();
+        let mut worker = DeepFilterWorker {
+            input_producer,
+            output_consumer,
+            stop_flag: stop_flag.clone(),
+            thread: None,
+            inline_pipeline: None,
+            model_latency_samples: model_latency_samples.clone(),
+            channels,
+            max_output_backlog,
+            fallback: Some(RnnoiseDenoiser::new()),
+        };
+
+        if thread_params.single_threaded {
+            // Loaded here on the main thread. The audio thread only swaps the
+            // finished pipeline in, see [`WorkerHandoff`].
+            match load_prototype(model, channels) {
+                Ok(denoiser) => {
+                    model_latency_samples.store(model_latency(&denoiser), Ordering::Relaxed);
+                    worker.inline_pipeline = Some(Box::new(ChunkPipeline::new(
+                        denoiser,
+                        channels,
+                        mix_rate,
+                        (input_consumer, output_producer),
+                        &buffering,
+                        controls,
+                    )));
+                    model_active.store(true, Ordering::Relaxed);
+                }
+                Err(reason) => {
+                    if let Some(effect_id) = effect_id {
+                        emit_deferred(effect_id, "model_init_failed", &[reason.to_variant()]);
+                    }
+                }
+            }
+            return Some(worker);
+        }
+
+        let spawned = spawn_tracked("dfn_worker", stop_flag.clone(), move || {
+            thread_params.apply_to_current_thread();
+            let denoiser = match load_prototype(model, channels) {
+                Ok(denoiser) => denoiser,
+                Err(reason) => {
+                    if let Some(effect_id) = effect_id {
+                        emit_deferred(effect_id, "model_init_failed", &[reason.to_variant()]);
+                    }
+                    return;
+                }
+            };
+            model_latency_samples.store(model_latency(&denoiser), Ordering::Relaxed);
+            let mut pipeline = ChunkPipeline::new(
+                denoiser,
+                channels,
+                mix_rate,
+                (input_consumer, output_producer),
+                &buffering,
+                controls,
+            );
+            model_active.store(true, Ordering::Relaxed);
+
+            while !stop_flag.load(Ordering::Relaxed) {
+                pipeline.receive();
+                if !pipeline.run_chunk() {
+                    // Sleeps until the audio thread pushes more input. A
+                    // wake-up sent before parking isn't lost.
+                    thread::park();
+                    continue;
+                }
+                while !pipeline.push_output() && !stop_flag.load(Ordering::Relaxed) {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        match spawned {
+            Ok(thread) => {
+                worker.thread = Some(thread);
+                Some(worker)
+            }
+            Err(err) => {
+                godot_error!(
+                    "AudioEffectDeepFilterNet: failed to spawn worker thread: {}",
+                    err
+                );
+                if let Some(effect_id) = effect_id {
+                    emit_deferred(
+                        effect_id,
+                        "model_init_failed",
+                        &[format!("failed to spawn worker thread: {}", err).to_variant()],
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// Wakes the worker up after input was pushed. Doesn't block, so it's
+    /// safe on the audio thread.
+    fn wake(&self) {
+        if let Some(thread) = self.thread.as_ref() {
+            thread.unpark();
+        }
+    }
+
+    /// Whether the thread has exited, e.g. because the model failed to load.
+    fn is_finished(&self) -> bool {
+        self.inline_pipeline.is_none()
+            && self
+                .thread
+                .as_ref()
+                .is_none_or(|thread| tracked_thread_finished(thread.id()))
+    }
+
+    /// Sends `input` to the worker and writes the enhanced frames that are
+    /// ready to `output`. Frames not enhanced in time get the input as the
+    /// model sees it. Returns how many frames were enhanced.
+    fn exchange(
+        &mut self,
+        input: &[AudioFrame],
+        input_scratch: &mut Vec<f32>,
+        output_scratch: &mut [f32],
+        output: &mut [Vector2],
+        stats: &DeepFilterStats,
+    ) -> usize {
+        let channels = self.channels;
+        input_scratch.clear();
+        for frame in input {
+            if channels == 2 {
+                input_scratch.extend([frame.left, frame.right]);
+            } else {
+                input_scratch.push((frame.left + frame.right) * 0.5);
+            }
+        }
+
+        // Only whole frames, so channels stay in order.
+        let pushable = self.input_producer.vacant_len() / channels * channels;
+        let pushed = self
+            .input_producer
+            .push_slice(&input_scratch[..pushable.min(input_scratch.len())]);
+        if let Some(pipeline) = self.inline_pipeline.as_mut() {
+            pipeline.run_inline();
+        } else if pushed > 0 {
+            self.wake();
+        }
+        if pushed < input_scratch.len() {
+            stats
+                .dropped_input_samples
+                .fetch_add((input_scratch.len() - pushed) as u64, Ordering::Relaxed);
+        }
+
+        let wanted = input.len() * channels;
+        if self.max_output_backlog > 0 {
+            // Skip stale enhanced audio rather than adding more delay.
+            let excess = self
+                .output_consumer
+                .occupied_len()
+                .saturating_sub(self.max_output_backlog + wanted)
+                / channels
+                * channels;
+            self.output_consumer.skip(excess);
+        }
+        let poppable = self.output_consumer.occupied_len().min(wanted) / channels * channels;
+        let popped = self
+            .output_consumer
+            .pop_slice(&mut output_scratch[..poppable]);
+        let processed_frames = popped / channels;
+
+        for (i, (in_frame, out_frame)) in input.iter().zip(output.iter_mut()).enumerate() {
+            *out_frame = if i < processed_frames {
+                Vector2::new(
+                    output_scratch[i * channels],
+                    output_scratch[i * channels + channels - 1],
+                )
+            } else if channels == 2 {
+                Vector2::new(in_frame.left, in_frame.right)
+            } else {
+                Vector2::splat((in_frame.left + in_frame.right) * 0.5)
+            };
+        }
+        processed_frames
+    }
+
+    /// Tells the thread to exit without waiting for it, so it's safe on the
+    /// audio thread. [`spawn_tracked`] joins the thread once it exited.
+    fn stop(&mut self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
+        self.wake();
+    }
+}
+
+impl Drop for DeepFilterWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Passes workers between an effect, which starts them on the main thread,
+/// and its instance, which runs them on the audio thread.
+struct WorkerHandoff {
+    /// A restarted worker and the crossfade to it, not picked up yet.
+    incoming: Mutex<Option<(DeepFilterWorker, f32)>>,
+    /// Receiving end of the queue of workers the instance is done with,
+    /// already stopped, for the effect to free. Only the effect locks it;
+    /// the instance pushes without locking.
+    retired: Mutex<HeapCons<DeepFilterWorker>>,
+}
+
+impl WorkerHandoff {
+    /// Returns a handoff and the sending end of its retire queue, which the
+    /// instance keeps.
+    fn new() -> (Arc<Self>, HeapProd<DeepFilterWorker>) {
+        let (producer, consumer) = HeapRb::new(RETIRED_CAPACITY).split();
+        let handoff = Self {
+            incoming: Mutex::new(None),
+            retired: Mutex::new(consumer),
+        };
+        (Arc::new(handoff), producer)
+    }
+
+    /// Frees the workers the instance retired and hands it `worker`,
+    /// replacing one it didn't pick up yet. For the main thread.
+    fn offer(&self, worker: DeepFilterWorker, crossfade_ms: f32) {
+        self.retired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        let replaced = self
+            .incoming
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace((worker, crossfade_ms));
+        // Stopped but not joined: it may still be loading its model, which
+        // would block the main thread. The thread exits once it's loaded.
+        drop(replaced);
+    }
+
+    /// Takes the worker from [`Self::offer`], if there's one. Doesn't block,
+    /// so it's safe on the audio thread.
+    fn take(&self) -> Option<(DeepFilterWorker, f32)> {
+        self.incoming.try_lock().ok()?.take()
+    }
+}
+
+fn reduce_mask_from_i32(mode: i32) -> ReduceMask {
+    match mode {
+        x if x == ReduceMask::MAX as i32 => ReduceMask::MAX,
+        x if x == ReduceMask::MEAN as i32 => ReduceMask::MEAN,
+        _ => ReduceMask::NONE,
+    }
+}
+
+/// Adds a noise removal effect to an audio bus using DeepFilterNet.
+///
+/// By default the bus is downmixed to mono and the enhanced signal is written to
+/// both output channels. Turn on [member stereo] to enhance left and right
+/// separately, e.g. on music or spatialized content, at about twice the CPU
+/// cost. At mix rates other than 48 kHz, e.g. 44.1 kHz, the worker thread
+/// resamples the audio to 48 kHz for the model and back.
+///
+/// The embedded low-latency model is used unless [member model_path] or
+/// [method load_model_from_buffer] provide another DeepFilterNet model
+/// tarball, e.g. a smaller or newer one.
+///
+/// Models are loaded once per process and shared by every instance with the
+/// same model and channel count, so adding the effect to more buses or
+/// re-instantiating it doesn't load the model again. [method preload_model]
+/// loads it ahead of time.
+///
+/// Turning [member enabled] off passes audio through while the model stays
+/// loaded, so noise suppression can be toggled in settings instantly. The
+/// thresholds, [member attenuation_limit_db], [member post_filter_beta] and
+/// [member reduce_mask_mode] apply from the model's next chunk. Other
+/// settings apply right away too, restarting the model.
+///
+/// On slow devices, [member auto_fallback] switches to the much lighter
+/// RNNoise when the model can't keep up.
+///
+/// The model's output fades in over [member fade_in_ms] when it starts,
+/// hiding its start-up artifacts.
+///
+/// Full suppression can make voice sound processed; lower [member wet_amount]
+/// to blend some of the original signal back in.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectDeepFilterNet {
+    pub(crate) base: Base<AudioEffect>,
+    /// When off, audio passes through unchanged and the model idles.
+    #[export]
+    #[var(get = is_enabled, set = set_enabled)]
+    enabled: bool,
+    #[export]
+    #[var(get = get_attenuation_limit_db, set = set_attenuation_limit_db)]
+    attenuation_limit_db: f32,
+    #[export]
+    #[var(get = get_min_db_threshold, set = set_min_db_threshold)]
+    min_db_threshold: f32,
+    #[export]
+    #[var(get = get_max_db_erb_threshold, set = set_max_db_erb_threshold)]
+    max_db_erb_threshold: f32,
+    #[export]
+    #[var(get = get_max_db_df_threshold, set = set_max_db_df_threshold)]
+    max_db_df_threshold: f32,
+    #[export]
+    #[var(get = get_post_filter_beta, set = set_post_filter_beta)]
+    post_filter_beta: f32,
+    /// 0 = NONE, 1 = MAX, 2 = MEAN
+    #[export]
+    #[var(get = get_reduce_mask_mode, set = set_reduce_mask_mode)]
+    reduce_mask_mode: i32,
+    /// Enhances both channels instead of a mono downmix.
+    #[export]
+    #[var(get = is_stereo, set = set_stereo)]
+    stereo: bool,
+    /// Most audio that can queue up for the model when it falls behind, in
+    /// milliseconds.
+    #[export(range = (50.0, 5000.0, or_greater, suffix = "ms"))]
+    #[var(get = get_buffer_capacity_ms, set = set_buffer_capacity_ms)]
+    buffer_capacity_ms: f32,
+    /// Which audio to give up once the buffer is full: 0 = DROP_NEWEST keeps
+    /// what's queued, 1 = DROP_OLDEST skips ahead so the delay stays low.
+    #[export]
+    #[var(get = get_drop_policy, set = set_drop_policy)]
+    drop_policy: i32,
+    /// Most delay the buffers may add, in milliseconds. Older audio is
+    /// dropped beyond it. 0 limits it by [member buffer_capacity_ms] only.
+    #[export(range = (0.0, 1000.0, or_greater, suffix = "ms"))]
+    #[var(get = get_max_latency_ms, set = set_max_latency_ms)]
+    max_latency_ms: f32,
+    /// Scheduling priority of the worker thread that runs the model:
+    /// 0 = PRIORITY_NORMAL, 1 = PRIORITY_HIGH, 2 = PRIORITY_MAX. Higher
+    /// priorities keep game logic from delaying the model on devices with
+    /// few cores, but may need extra permissions on some platforms.
+    #[export]
+    #[var(get = get_worker_priority, set = set_worker_priority)]
+    worker_priority: i32,
+    /// CPU core to pin the worker thread to, or -1 to let the OS decide.
+    /// Not supported on every platform.
+    #[export(range = (-1.0, 64.0, 1.0, or_greater))]
+    #[var(get = get_worker_core, set = set_worker_core)]
+    worker_core: i32,
+    /// Runs the model on the audio thread instead of a worker thread, for
+    /// platforms that can't spawn threads such as Web exports without
+    /// thread support. The model then has to finish each chunk within the
+    /// audio callback, so use a small model through [member model_path].
+    /// The model is loaded on the main thread when the effect is
+    /// instantiated or a setting restarts it, which stalls the game for as
+    /// long as loading takes unless [method preload_model] loaded it before.
+    #[export]
+    #[var(get = is_single_threaded, set = set_single_threaded)]
+    single_threaded: bool,
+    /// DeepFilterNet model tarball (`.tar.gz`) to use instead of the embedded
+    /// model. Empty uses the embedded model.
+    #[export(file = "*.tar.gz")]
+    #[var(get = get_model_path, set = set_model_path)]
+    model_path: GString,
+    /// Share of the enhanced signal in the output. The rest is the original
+    /// signal, delayed to line up with the enhanced one.
+    #[export(range = (0.0, 1.0))]
+    #[var(get = get_wet_amount, set = set_wet_amount)]
+    wet_amount: f32,
+    /// Outputs only what the model removes instead of the enhanced signal,
+    /// to hear the effect of threshold changes while tuning them.
+    #[export]
+    #[var(get = is_monitoring_noise, set = set_monitoring_noise)]
+    monitor_noise: bool,
+    /// Switches to RNNoise, crossfading, when the model has been slower than
+    /// real time for a while, and emits [signal fallback_activated]. The
+    /// fallback lasts until the effect is instantiated again, its settings
+    /// change or this is turned off.
+    #[export]
+    #[var(get = is_auto_fallback, set = set_auto_fallback)]
+    auto_fallback: bool,
+    /// Length in milliseconds of the crossfade from the old to the new
+    /// output when a settings or model change restarts the worker, e.g. from
+    /// a settings menu while voice chat runs. The old worker keeps
+    /// running until the new one has its model loaded. 0 switches at once.
+    #[export(range = (0.0, 500.0, 1.0, or_greater))]
+    #[var(get = get_reconfigure_crossfade_ms, set = set_reconfigure_crossfade_ms)]
+    reconfigure_crossfade_ms: f32,
+    /// How long the model's output fades in when it starts, in
+    /// milliseconds. 0 starts at full volume. Applies from the next start.
+    #[export(range = (0.0, 500.0, 1.0, or_greater, suffix = "ms"))]
+    #[var(get = get_fade_in_ms, set = set_fade_in_ms)]
+    fade_in_ms: f32,
+    /// Category of the monitors added by [method add_performance_monitors].
+    monitor_category: GString,
+    worker_config: WorkerConfig,
+    tuning: Arc<DenoiserTuning>,
+    /// Handoffs to the instances still alive, for restarts.
+    instances: Vec<Weak<WorkerHandoff>>,
+    /// Bits of [member wet_amount], read by the worker.
+    wet_amount_bits: Arc<AtomicU32>,
+    monitor_noise_flag: Arc<AtomicBool>,
+    fade_in_ms_bits: Arc<AtomicU32>,
+    enabled_flag: Arc<AtomicBool>,
+    /// Bits of the delay the instance currently adds, in milliseconds.
+    latency_ms_bits: Arc<AtomicU32>,
+    stats: Arc<DeepFilterStats>,
+    /// Set while a worker has its model loaded.
+    model_active: Arc<AtomicBool>,
+    auto_fallback_flag: Arc<AtomicBool>,
+    fallback_active: Arc<AtomicBool>,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectDeepFilterNet {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = DeepFilterParams::default();
+        let buffering = BufferingParams::default();
+        let thread = WorkerThreadParams::default();
+        Self {
+            base,
+            enabled: true,
+            attenuation_limit_db: params.atten_lim_db,
+            min_db_threshold: params.min_db_thresh,
+            max_db_erb_threshold: params.max_db_erb_thresh,
+            max_db_df_threshold: params.max_db_df_thresh,
+            post_filter_beta: params.post_filter_beta,
+            reduce_mask_mode: params.reduce_mask_mode,
+            stereo: params.channels == 2,
+            buffer_capacity_ms: buffering.capacity_ms,
+            drop_policy: buffering.drop_policy,
+            max_latency_ms: buffering.max_latency_ms,
+            worker_priority: thread.priority,
+            worker_core: thread.core,
+            single_threaded: thread.single_threaded,
+            model_path: GString::new(),
+            wet_amount: 1.0,
+            wet_amount_bits: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            monitor_noise: false,
+            monitor_noise_flag: Arc::default(),
+            enabled_flag: Arc::new(AtomicBool::new(true)),
+            latency_ms_bits: Arc::default(),
+            stats: Arc::default(),
+            model_active: Arc::default(),
+            auto_fallback: false,
+            auto_fallback_flag: Arc::default(),
+            fallback_active: Arc::default(),
+            reconfigure_crossfade_ms: RECONFIGURE_CROSSFADE_MS,
+            fade_in_ms: FADE_IN_MS,
+            fade_in_ms_bits: Arc::new(AtomicU32::new(FADE_IN_MS.to_bits())),
+            monitor_category: GString::new(),
+            tuning: Arc::new(DenoiserTuning::new(&params)),
+            worker_config: WorkerConfig {
+                params,
+                model: ModelSource::Embedded,
+                buffering,
+                thread,
+            },
+            instances: Vec::new(),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let (handoff, retired) = WorkerHandoff::new();
+        self.instances
+            .retain(|instance| instance.strong_count() > 0);
+        self.instances.push(Arc::downgrade(&handoff));
+
+        let mut effect = AudioEffectDeepFilterNetInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.worker = self.start_worker();
+            effect_mut.handoff = handoff;
+            effect_mut.retired = retired;
+            effect_mut.mix_rate = Self::mix_rate() as f32;
+            effect_mut.enabled_flag = self.enabled_flag.clone();
+            effect_mut.latency_ms_bits = self.latency_ms_bits.clone();
+            effect_mut.stats = self.stats.clone();
+            effect_mut.model_active = self.model_active.clone();
+            effect_mut.auto_fallback_flag = self.auto_fallback_flag.clone();
+            effect_mut.fallback_active = self.fallback_active.clone();
+            effect_mut.effect_id = Some(self.base().instance_id());
+        }
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectDeepFilterNet {
+    #[constant]
+    const DROP_NEWEST: i32 = DROP_NEWEST;
+    #[constant]
+    const DROP_OLDEST: i32 = DROP_OLDEST;
+    #[constant]
+    const PRIORITY_NORMAL: i32 = PRIORITY_NORMAL;
+    #[constant]
+    const PRIORITY_HIGH: i32 = PRIORITY_HIGH;
+    #[constant]
+    const PRIORITY_MAX: i32 = PRIORITY_MAX;
+
+    /// Emitted when [method preload_model] finished loading the model.
+    #[signal]
+    fn model_ready();
+
+    /// Emitted when the model can't be loaded and audio passes through
+    /// unprocessed. [param reason] describes the error, e.g. to fall back to
+    /// [AudioEffectRNNoise] or tell the player.
+    #[signal]
+    fn model_init_failed(reason: GString);
+
+    /// Emitted when [member auto_fallback] switched to RNNoise because the
+    /// model couldn't keep up.
+    #[signal]
+    fn fallback_activated();
+
+    /// Pushes the exported settings to running instances. Their workers
+    /// restart only if a setting [`WorkerConfig::needs_restart`] names
+    /// changed.
+    fn push_config_to_shared(&mut self) {
+        let params = self.current_params();
+        let buffering = BufferingParams {
+            capacity_ms: self.buffer_capacity_ms.max(50.0),
+            drop_policy: self.drop_policy,
+            max_latency_ms: self.max_latency_ms.max(0.0),
+        };
+        let thread = WorkerThreadParams {
+            priority: self.worker_priority,
+            core: self.worker_core,
+            single_threaded: self.single_threaded,
+        };
+        let config = WorkerConfig {
+            params,
+            model: self.worker_config.model,
+            buffering,
+            thread,
+        };
+        if config == self.worker_config {
+            return;
+        }
+        self.tuning.store(&config.params);
+        let restart = self.worker_config.needs_restart(&config);
+        self.worker_config = config;
+        if restart {
+            self.restart_workers();
+        }
+    }
+
+    /// Hands each instance a new worker with the current settings, which it
+    /// crossfades to. Starting them here keeps loading, allocating and
+    /// freeing off the audio thread.
+    fn restart_workers(&mut self) {
+        self.instances
+            .retain(|instance| instance.strong_count() > 0);
+        let crossfade_ms = self.reconfigure_crossfade_ms.max(0.0);
+        for handoff in self.instances.iter().filter_map(Weak::upgrade) {
+            if let Some(worker) = self.start_worker() {
+                handoff.offer(worker, crossfade_ms);
+            }
+        }
+    }
+
+    fn start_worker(&self) -> Option<DeepFilterWorker> {
+        let controls = PipelineControls {
+            wet_amount_bits: self.wet_amount_bits.clone(),
+            monitor_noise_flag: self.monitor_noise_flag.clone(),
+            fade_in_ms_bits: self.fade_in_ms_bits.clone(),
+            tuning: self.tuning.clone(),
+            stats: self.stats.clone(),
+        };
+        DeepFilterWorker::start(
+            &self.worker_config,
+            Self::mix_rate(),
+            controls,
+            self.model_active.clone(),
+            Some(self.base().instance_id()),
+        )
+    }
+
+    fn mix_rate() -> u32 {
+        AudioServer::singleton().get_mix_rate().round().max(1.0) as u32
+    }
+
+    #[func]
+    fn get_attenuation_limit_db(&self) -> f32 {
+        self.attenuation_limit_db
+    }
+
+    #[func]
+    fn set_attenuation_limit_db(&mut self, value: f32) {
+        self.attenuation_limit_db = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_min_db_threshold(&self) -> f32 {
+        self.min_db_threshold
+    }
+
+    #[func]
+    fn set_min_db_threshold(&mut self, value: f32) {
+        self.min_db_threshold = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_max_db_erb_threshold(&self) -> f32 {
+        self.max_db_erb_threshold
+    }
+
+    #[func]
+    fn set_max_db_erb_threshold(&mut self, value: f32) {
+        self.max_db_erb_threshold = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_max_db_df_threshold(&self) -> f32 {
+        self.max_db_df_threshold
+    }
+
+    #[func]
+    fn set_max_db_df_threshold(&mut self, value: f32) {
+        self.max_db_df_threshold = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_post_filter_beta(&self) -> f32 {
+        self.post_filter_beta
+    }
+
+    #[func]
+    fn set_post_filter_beta(&mut self, value: f32) {
+        self.post_filter_beta = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_reduce_mask_mode(&self) -> i32 {
+        self.reduce_mask_mode
+    }
+
+    #[func]
+    fn set_reduce_mask_mode(&mut self, value: i32) {
+        self.reduce_mask_mode = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_stereo(&self) -> bool {
+        self.stereo
+    }
+
+    #[func]
+    fn set_stereo(&mut self, value: bool) {
+        self.stereo = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_buffer_capacity_ms(&self) -> f32 {
+        self.buffer_capacity_ms
+    }
+
+    #[func]
+    fn set_buffer_capacity_ms(&mut self, value: f32) {
+        self.buffer_capacity_ms = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_drop_policy(&self) -> i32 {
+        self.drop_policy
+    }
+
+    #[func]
+    fn set_drop_policy(&mut self, value: i32) {
+        self.drop_policy = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_max_latency_ms(&self) -> f32 {
+        self.max_latency_ms
+    }
+
+    #[func]
+    fn set_max_latency_ms(&mut self, value: f32) {
+        self.max_latency_ms = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_worker_priority(&self) -> i32 {
+        self.worker_priority
+    }
+
+    #[func]
+    fn set_worker_priority(&mut self, value: i32) {
+        self.worker_priority = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_worker_core(&self) -> i32 {
+        self.worker_core
+    }
+
+    #[func]
+    fn set_worker_core(&mut self, value: i32) {
+        self.worker_core = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_single_threaded(&self) -> bool {
+        self.single_threaded
+    }
+
+    #[func]
+    fn set_single_threaded(&mut self, value: bool) {
+        self.single_threaded = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_reconfigure_crossfade_ms(&self) -> f32 {
+        self.reconfigure_crossfade_ms
+    }
+
+    #[func]
+    fn set_reconfigure_crossfade_ms(&mut self, value: f32) {
+        self.reconfigure_crossfade_ms = value;
+    }
+
+    fn current_params(&self) -> DeepFilterParams {
+        DeepFilterParams {
+            atten_lim_db: self.attenuation_limit_db.abs(),
+            min_db_thresh: self.min_db_threshold,
+            max_db_erb_thresh: self.max_db_erb_threshold,
+            max_db_df_thresh: self.max_db_df_threshold,
+            post_filter_beta: self.post_filter_beta.max(0.0),
+            reduce_mask_mode: self.reduce_mask_mode,
+            channels: if self.stereo { 2 } else { 1 },
+        }
+    }
+
+    fn current_model(&self) -> ModelSource {
+        self.worker_config.model
+    }
+
+    /// Loads the model with the current settings on a background thread and
+    /// emits [signal model_ready] when done. Call it e.g. on a loading screen:
+    /// instances created afterwards start enhancing right away instead of
+    /// passing audio through while their model loads.
+    /// [codeblock]
+    /// effect.preload_model()
+    /// await effect.model_ready
+    /// [/codeblock]
+    #[func]
+    fn preload_model(&self) {
+        let params = self.current_params();
+        let model = self.current_model();
+        let instance_id = self.base().instance_id();
+        // Loading can't be interrupted, so the stop flag goes unused.
+        let spawned = spawn_tracked("dfn_preload", Arc::default(), move || {
+            match load_denoiser(&params, model) {
+                Ok(_) => emit_deferred(instance_id, "model_ready", &[]),
+                Err(reason) => {
+                    emit_deferred(instance_id, "model_init_failed", &[reason.to_variant()])
+                }
+            }
+        });
+        if let Err(err) = spawned {
+            godot_error!(
+                "AudioEffectDeepFilterNet: failed to spawn preload thread: {}",
+                err
+            );
+        }
+    }
+
+    /// Removes noise from a whole recording, e.g. voice lines recorded with
+    /// [AudioEffectRecord], with the current settings and returns the result.
+    /// [param pcm] is at the mix rate. The result is as long and lines up
+    /// with it, without the delay real-time processing adds.
+    ///
+    /// Runs synchronously and takes a while for long recordings, so call it
+    /// from a [Thread] outside the editor. Returns [param pcm] unchanged if
+    /// the model can't be loaded.
+    #[func]
+    fn process_buffer(&self, pcm: PackedVector2Array) -> PackedVector2Array {
+        let params = self.current_params();
+        let channels = params.channels;
+        let mut denoiser = match load_denoiser(&params, self.current_model()) {
+            Ok(denoiser) => denoiser,
+            Err(reason) => {
+                godot_error!("AudioEffectDeepFilterNet: can't process buffer. {}", reason);
+                return pcm;
+            }
+        };
+        let mix_rate = Self::mix_rate();
+
+        let mut samples = Vec::with_capacity(pcm.len() * channels);
+        for frame in pcm.as_slice() {
+            if channels == 2 {
+                samples.extend([frame.x, frame.y]);
+            } else {
+                samples.push((frame.x + frame.y) * 0.5);
+            }
+        }
+        if let Some(mut resampler) = WorkerResampler::new(mix_rate, DFN_SAMPLE_RATE, channels) {
+            let mut resampled = Vec::new();
+            resampler.process(&samples, &mut resampled);
+            samples = resampled;
+        }
+
+        let mut enhanced = enhance_offline(&mut denoiser, &samples, channels, self.wet_amount);
+
+        if let Some(mut resampler) = WorkerResampler::new(DFN_SAMPLE_RATE, mix_rate, channels) {
+            let mut resampled = Vec::new();
+            resampler.process(&enhanced, &mut resampled);
+            enhanced = resampled;
+        }
+        let mut frames = Vec::with_capacity(pcm.len());
+        deinterleave(&enhanced, channels, &mut frames);
+        frames.resize(pcm.len(), Vector2::ZERO);
+        PackedVector2Array::from(frames.as_slice())
+    }
+
+    fn set_model_source(&mut self, model: ModelSource) {
+        self.worker_config.model = model;
+        self.restart_workers();
+    }
+
+    #[func]
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns true if audio is actually being enhanced: the effect is
+    /// enabled and its model loaded. False while the model loads, after
+    /// [signal model_init_failed], or while the effect isn't on an active
+    /// bus.
+    #[func]
+    fn is_active(&self) -> bool {
+        self.enabled && self.model_active.load(Ordering::Relaxed)
+    }
+
+    #[func]
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.enabled_flag.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns how much the effect currently delays audio, in milliseconds:
+    /// the model's own latency plus audio waiting in the buffers to and from
+    /// the worker. 0 while disabled or the model isn't loaded yet. Useful to
+    /// delay animations such as lip sync by the same amount.
+    #[func]
+    fn get_latency_ms(&self) -> f32 {
+        f32::from_bits(self.latency_ms_bits.load(Ordering::Relaxed))
+    }
+
+    /// Returns how the model keeps up since it was loaded or the last
+    /// [method reset_stats]: `dropped_input_samples` (input lost because the
+    /// worker fell behind), `avg_chunk_ms`, `max_chunk_ms` and
+    /// `last_chunk_ms` (time to enhance one chunk), `load_ratio` (average
+    /// chunk time relative to the audio it covers, above 1 the worker can't
+    /// keep up), `buffer_occupancy` (share of the buffers to and from the
+    /// worker in use, 0.0 to 1.0) and `last_lsnr` (the model's estimate of
+    /// the local signal-to-noise ratio in dB).
+    #[func]
+    fn get_stats(&self) -> Dictionary {
+        let stats = &self.stats;
+        let mut out = Dictionary::new();
+        out.set(
+            "dropped_input_samples",
+            stats.dropped_input_samples.load(Ordering::Relaxed) as i64,
+        );
+        out.set("avg_chunk_ms", stats.average_chunk_ms());
+        out.set(
+            "max_chunk_ms",
+            stats.chunk_max_us.load(Ordering::Relaxed) as f32 / 1000.0,
+        );
+        out.set("last_chunk_ms", stats.last_chunk_ms());
+        out.set("load_ratio", stats.load_ratio());
+        out.set("buffer_occupancy", stats.buffer_occupancy());
+        out.set(
+            "last_lsnr",
+            f32::from_bits(stats.last_lsnr_bits.load(Ordering::Relaxed)),
+        );
+        out
+    }
 
-type RbProd = HeapProd<f32>;
-type RbCons = HeapCons<f32>;
+    /// Sets all statistics back to zero.
+    #[func]
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
 
-#[derive(Debug, Clone)]
-struct DeepFilterParams {
-    atten_lim_db: f32,
-    min_db_thresh: f32,
-    max_db_erb_thresh: f32,
-    max_db_df_thresh: f32,
-    post_filter_beta: f32,
-    reduce_mask_mode: i32,
-}
+    /// Returns a coarse spectrum of what the model does, to draw in settings
+    /// menus: `frequencies` (band centers in Hz), `input_db` (level of the
+    /// input per band in dBFS) and `gain_db` (how much the model changed each
+    /// band, e.g. -20.0 where it removed noise). The worker updates it about
+    /// every 50 ms while the model runs, so it's cheap to poll every frame.
+    #[func]
+    fn get_spectrum(&self) -> Dictionary {
+        let snapshot = &self.stats.spectrum;
+        let frequencies: PackedFloat32Array = (0..SPECTRUM_BANDS).map(spectrum_band_hz).collect();
+        let input_db: PackedFloat32Array = snapshot
+            .input_bits
+            .iter()
+            .map(|bits| gain_to_db(f32::from_bits(bits.load(Ordering::Relaxed))))
+            .collect();
+        let gain_db: PackedFloat32Array = snapshot
+            .gain_db_bits
+            .iter()
+            .map(|bits| f32::from_bits(bits.load(Ordering::Relaxed)))
+            .collect();
+        let mut out = Dictionary::new();
+        out.set("frequencies", frequencies);
+        out.set("input_db", input_db);
+        out.set("gain_db", gain_db);
+        out
+    }
 
-impl Default for DeepFilterParams {
-    fn default() -> Self {
-        Self {
-            atten_lim_db: 100.0,
-            min_db_thresh: -10.0,
-            max_db_erb_thresh: 30.0,
-            max_db_df_thresh: 20.0,
-            post_filter_beta: 0.02,
-            reduce_mask_mode: ReduceMask::MEAN as i32,
+    /// Adds custom monitors to the debugger's Monitors tab, graphing
+    /// `dropped_input_samples`, `buffer_occupancy` and `last_chunk_ms` from
+    /// [method get_stats] under [param category], e.g. `"Voice/Mic"`. Use a
+    /// different category for each effect. Replaces monitors added before
+    /// by this effect.
+    #[func]
+    fn add_performance_monitors(&mut self, category: GString) {
+        self.remove_performance_monitors();
+        let mut performance = Performance::singleton();
+        let effect = self.to_gd();
+        for (name, method) in PERFORMANCE_MONITORS {
+            let id = StringName::from(format!("{}/{}", category, name).as_str());
+            if performance.has_custom_monitor(&id) {
+                godot_warn!(
+                    "AudioEffectDeepFilterNet: performance monitor {} already exists.",
+                    id
+                );
+                continue;
+            }
+            performance.add_custom_monitor(&id, &Callable::from_object_method(&effect, method));
         }
+        self.monitor_category = category;
     }
-}
 
-#[derive(Debug, Default)]
-struct DeepFilterSharedConfig {
-    params: DeepFilterParams,
-    revision: u64,
-}
+    /// Removes the monitors added by [method add_performance_monitors].
+    #[func]
+    fn remove_performance_monitors(&mut self) {
+        if self.monitor_category.is_empty() {
+            return;
+        }
+        let mut performance = Performance::singleton();
+        for (name, _) in PERFORMANCE_MONITORS {
+            let id = StringName::from(format!("{}/{}", self.monitor_category, name).as_str());
+            if performance.has_custom_monitor(&id) {
+                performance.remove_custom_monitor(&id);
+            }
+        }
+        self.monitor_category = GString::new();
+    }
 
-type DeepFilterSharedConfigRef = Arc<Mutex<DeepFilterSharedConfig>>;
+    #[func]
+    fn get_monitor_dropped_input_samples(&self) -> i64 {
+        self.stats.dropped_input_samples.load(Ordering::Relaxed) as i64
+    }
 
-struct DeepFilterWorker {
-    input_producer: RbProd,
-    output_consumer: RbCons,
-    stop_flag: Arc<AtomicBool>,
-    thread_handle: Option<JoinHandle<()>>,
-}
+    #[func]
+    fn get_monitor_buffer_occupancy(&self) -> f32 {
+        self.stats.buffer_occupancy()
+    }
 
-impl DeepFilterWorker {
-    fn stop(&mut self) {
-        self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(handle) = self.thread_handle.take() {
-            let _ = handle.join();
-        }
+    #[func]
+    fn get_monitor_last_chunk_ms(&self) -> f32 {
+        self.stats.last_chunk_ms()
     }
-}
 
-impl Drop for DeepFilterWorker {
-    fn drop(&mut self) {
-        self.stop();
+    #[func]
+    fn is_auto_fallback(&self) -> bool {
+        self.auto_fallback
     }
-}
 
-fn reduce_mask_from_i32(mode: i32) -> ReduceMask {
-    match mode {
-        x if x == ReduceMask::MAX as i32 => ReduceMask::MAX,
-        x if x == ReduceMask::MEAN as i32 => ReduceMask::MEAN,
-        _ => ReduceMask::NONE,
+    #[func]
+    fn set_auto_fallback(&mut self, enabled: bool) {
+        self.auto_fallback = enabled;
+        self.auto_fallback_flag.store(enabled, Ordering::Relaxed);
     }
-}
 
-/// Adds a noise removal effect to an audio bus using DeepFilterNet.
-///
-/// The effect currently runs single-channel enhancement and writes the enhanced
-/// mono signal to both output channels.
-#[derive(GodotClass)]
-#[class(tool, base=AudioEffect)]
-pub(crate) struct AudioEffectDeepFilterNet {
-    pub(crate) base: Base<AudioEffect>,
-    #[export]
-    attenuation_limit_db: f32,
-    #[export]
-    min_db_threshold: f32,
-    #[export]
-    max_db_erb_threshold: f32,
-    #[export]
-    max_db_df_threshold: f32,
-    #[export]
-    post_filter_beta: f32,
-    /// 0 = NONE, 1 = MAX, 2 = MEAN
-    #[export]
-    reduce_mask_mode: i32,
-    shared_config: DeepFilterSharedConfigRef,
-}
+    /// Returns true while [member auto_fallback] replaced the model with
+    /// RNNoise.
+    #[func]
+    fn is_fallback_active(&self) -> bool {
+        self.fallback_active.load(Ordering::Relaxed)
+    }
 
-#[godot_api]
-impl IAudioEffect for AudioEffectDeepFilterNet {
-    fn init(base: Base<AudioEffect>) -> Self {
-        let params = DeepFilterParams::default();
-        Self {
-            base,
-            attenuation_limit_db: params.atten_lim_db,
-            min_db_threshold: params.min_db_thresh,
-            max_db_erb_threshold: params.max_db_erb_thresh,
-            max_db_df_threshold: params.max_db_df_thresh,
-            post_filter_beta: params.post_filter_beta,
-            reduce_mask_mode: params.reduce_mask_mode,
-            shared_config: Arc::new(Mutex::new(DeepFilterSharedConfig {
-                params,
-                revision: 0,
-            })),
-        }
+    #[func]
+    fn get_wet_amount(&self) -> f32 {
+        self.wet_amount
     }
 
-    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
-        if let Ok(mut cfg) = self.shared_config.lock() {
-            cfg.params.atten_lim_db = self.attenuation_limit_db.abs();
-            cfg.params.min_db_thresh = self.min_db_threshold;
-            cfg.params.max_db_erb_thresh = self.max_db_erb_threshold;
-            cfg.params.max_db_df_thresh = self.max_db_df_threshold;
-            cfg.params.post_filter_beta = self.post_filter_beta.max(0.0);
-            cfg.params.reduce_mask_mode = self.reduce_mask_mode;
-            cfg.revision = cfg.revision.wrapping_add(1);
+    #[func]
+    fn set_wet_amount(&mut self, value: f32) {
+        self.wet_amount = value.clamp(0.0, 1.0);
+        self.wet_amount_bits
+            .store(self.wet_amount.to_bits(), Ordering::Relaxed);
+    }
+
+    #[func]
+    fn is_monitoring_noise(&self) -> bool {
+        self.monitor_noise
+    }
+
+    #[func]
+    fn set_monitoring_noise(&mut self, enabled: bool) {
+        self.monitor_noise = enabled;
+        self.monitor_noise_flag.store(enabled, Ordering::Relaxed);
+    }
+
+    #[func]
+    fn get_fade_in_ms(&self) -> f32 {
+        self.fade_in_ms
+    }
+
+    #[func]
+    fn set_fade_in_ms(&mut self, value: f32) {
+        self.fade_in_ms = value.max(0.0);
+        self.fade_in_ms_bits
+            .store(self.fade_in_ms.to_bits(), Ordering::Relaxed);
+    }
+
+    #[func]
+    fn get_model_path(&self) -> GString {
+        self.model_path.clone()
+    }
+
+    #[func]
+    fn set_model_path(&mut self, path: GString) {
+        self.model_path = path.clone();
+        if path.is_empty() {
+            self.set_model_source(ModelSource::Embedded);
+            return;
         }
 
-        let mut effect = AudioEffectDeepFilterNetInstance::new_gd();
-        {
-            let mut effect_mut = effect.bind_mut();
-            effect_mut.shared_config = self.shared_config.clone();
+        // Read through Godot so res:// paths work in exported projects too.
+        let bytes = FileAccess::get_file_as_bytes(&path);
+        if bytes.is_empty() {
+            godot_error!(
+                "AudioEffectDeepFilterNet: can't read model {}, using the embedded model.",
+                path
+            );
+            self.set_model_source(ModelSource::Embedded);
+            return;
         }
-        Some(effect.upcast::<AudioEffectInstance>())
+        self.set_model_source(ModelSource::Tarball(leak_model(bytes.as_slice())));
+    }
+
+    /// Uses the DeepFilterNet model tarball in [param data], e.g. downloaded
+    /// at runtime, instead of [member model_path]. Models stay in memory
+    /// until the program exits.
+    #[func]
+    fn load_model_from_buffer(&mut self, data: PackedByteArray) {
+        if data.is_empty() {
+            godot_error!("AudioEffectDeepFilterNet: empty model buffer, using the embedded model.");
+            self.set_model_source(ModelSource::Embedded);
+            return;
+        }
+        self.model_path = GString::new();
+        self.set_model_source(ModelSource::Tarball(leak_model(data.as_slice())));
     }
 }
 
-#[godot_api]
-impl AudioEffectDeepFilterNet {}
+impl Drop for AudioEffectDeepFilterNet {
+    fn drop(&mut self) {
+        // Monitors would otherwise keep calling into the freed effect.
+        self.remove_performance_monitors();
+    }
+}
 
 #[derive(GodotClass)]
 #[class(base=AudioEffectInstance)]
 pub(crate) struct AudioEffectDeepFilterNetInstance {
     pub(crate) base: Base<AudioEffectInstance>,
-    shared_config: DeepFilterSharedConfigRef,
-    applied_revision: u64,
+    handoff: Arc<WorkerHandoff>,
+    /// Sending end of the retire queue of [`Self::handoff`].
+    retired: HeapProd<DeepFilterWorker>,
+    /// Retired workers that didn't fit the queue, kept until the next
+    /// handoff, so they're never freed on the audio thread.
+    unretired: Vec<DeepFilterWorker>,
+    enabled_flag: Arc<AtomicBool>,
+    latency_ms_bits: Arc<AtomicU32>,
+    stats: Arc<DeepFilterStats>,
+    model_active: Arc<AtomicBool>,
+    /// The effect that created this instance, for its signals.
+    effect_id: Option<InstanceId>,
+    auto_fallback_flag: Arc<AtomicBool>,
+    fallback_active: Arc<AtomicBool>,
+    /// RNNoise replacing the model while it's too slow, borrowed from the
+    /// worker.
+    fallback: Option<RnnoiseDenoiser>,
+    /// Share of the fallback in the output, rising to 1 over the crossfade.
+    fallback_mix: f32,
+    /// Frames processed in a row while the model was too slow.
+    overload_frames: usize,
+    fallback_input: Vec<f32>,
+    fallback_output: Vec<f32>,
     worker: Option<DeepFilterWorker>,
+    /// Worker replaced by a restart, faded out once the new one has output.
+    retiring_worker: Option<DeepFilterWorker>,
+    /// Share of the new worker in the output during the crossfade.
+    crossfade_progress: f32,
+    /// Increase of [`Self::crossfade_progress`] per frame.
+    crossfade_step: f32,
+    mix_rate: f32,
     input_scratch: Vec<f32>,
     output_scratch: Vec<f32>,
-    last_output_sample: f32,
-    dropped_input_samples: u64,
+    model_frames: Vec<Vector2>,
+    retiring_frames: Vec<Vector2>,
 }
 
 impl AudioEffectDeepFilterNetInstance {
@@ -181,168 +2216,222 @@ impl AudioEffectDeepFilterNetInstance {
             worker.stop();
         }
         self.worker = None;
+        self.model_active.store(false, Ordering::Relaxed);
     }
 
-    fn start_worker_with_params(&mut self, params: DeepFilterParams) {
-        let mix_rate = AudioServer::singleton().get_mix_rate();
-        if (mix_rate as i32) != 48_000 {
-            godot_error!(
-                "AudioEffectDeepFilterNet: unsupported mix rate {} Hz. DeepFilterNet expects 48000 Hz. Falling back to passthrough.",
-                mix_rate
-            );
+    /// Switches to a worker the effect restarted, if there's one waiting.
+    fn take_restarted_worker(&mut self) {
+        let Some((worker, crossfade_ms)) = self.handoff.take() else {
             return;
+        };
+        self.stop_fallback();
+        // The effect just emptied the queue.
+        while let Some(unretired) = self.unretired.pop() {
+            if let Err(unretired) = self.retired.try_push(unretired) {
+                self.unretired.push(unretired);
+                break;
+            }
         }
+        let previous = self.worker.replace(worker);
+        self.retire_worker(previous, crossfade_ms);
+    }
 
-        let in_rb = HeapRb::<f32>::new(DFN_RING_CAPACITY_SAMPLES);
-        let out_rb = HeapRb::<f32>::new(DFN_RING_CAPACITY_SAMPLES);
-        let (input_producer, mut input_consumer) = in_rb.split();
-        let (mut output_producer, output_consumer) = out_rb.split();
-
-        let stop_flag = Arc::new(AtomicBool::new(false));
-        let stop_flag_worker = stop_flag.clone();
-
-        let thread_handle = match thread::Builder::new()
-            .name("dfn_worker".to_string())
-            .spawn(move || {
-                let runtime_params = RuntimeParams::default_with_ch(1)
-                    .with_mask_reduce(reduce_mask_from_i32(params.reduce_mask_mode))
-                    .with_post_filter(params.post_filter_beta)
-                    .with_atten_lim(params.atten_lim_db)
-                    .with_thresholds(
-                        params.min_db_thresh,
-                        params.max_db_erb_thresh,
-                        params.max_db_df_thresh,
-                    );
-
-                let t0 = Instant::now();
-                let mut denoiser = match DfTract::new(DfParams::default(), &runtime_params) {
-                    Ok(model) => {
-                        godot_print!(
-                            "AudioEffectDeepFilterNet: model initialized (hop_size={}, load_time_ms={}).",
-                            model.hop_size,
-                            t0.elapsed().as_millis()
-                        );
-                        model
-                    }
-                    Err(err) => {
-                        AudioEffectDeepFilterNetInstance::log_init_error(&err);
-                        godot_error!(
-                            "AudioEffectDeepFilterNet: Falling back to passthrough. load_time_ms={}",
-                            t0.elapsed().as_millis()
-                        );
-                        return;
-                    }
-                };
-
-                let hop_size = denoiser.hop_size;
-                let mut in_chunk = vec![0.0f32; hop_size];
-                let mut noisy_frame = Array2::zeros((1, hop_size));
-                let mut enhanced_frame = Array2::zeros((1, hop_size));
-
-                let mut chunk_process_count: u64 = 0;
-                let mut chunk_process_total_us: u128 = 0;
-                let mut chunk_process_max_us: u128 = 0;
-
-                while !stop_flag_worker.load(Ordering::Relaxed) {
-                    if input_consumer.occupied_len() < hop_size {
-                        thread::sleep(Duration::from_micros(WORKER_IDLE_SLEEP_MICROS));
-                        continue;
-                    }
+    /// Stops a worker the instance is done with and leaves it for the
+    /// effect to free. Doesn't block, so it's safe on the audio thread.
+    fn retire(&mut self, worker: Option<DeepFilterWorker>) {
+        let Some(mut worker) = worker else {
+            return;
+        };
+        worker.stop();
+        if let Err(worker) = self.retired.try_push(worker) {
+            self.unretired.push(worker);
+        }
+    }
 
-                    let popped = input_consumer.pop_slice(&mut in_chunk);
-                    if popped < hop_size {
-                        in_chunk[popped..hop_size].fill(0.0);
-                    }
+    /// Keeps the worker replaced by a restart running until the new one
+    /// takes over, unless there's nothing to crossfade to.
+    fn retire_worker(&mut self, previous: Option<DeepFilterWorker>, crossfade_ms: f32) {
+        // A worker still fading out from an earlier restart is cut off.
+        self.retire(self.retiring_worker.take());
+        if crossfade_ms <= 0.0
+            || self.worker.is_none()
+            || !self.enabled_flag.load(Ordering::Relaxed)
+        {
+            self.retire(previous);
+            return;
+        }
+        self.retiring_worker = previous;
+        self.crossfade_progress = 0.0;
+        self.crossfade_step = 1000.0 / (crossfade_ms * self.mix_rate);
+    }
 
-                    if let Some(noisy_slice) = noisy_frame.as_slice_mut() {
-                        noisy_slice.copy_from_slice(&in_chunk);
-                    }
+    /// Stores the current delay for [`AudioEffectDeepFilterNet::get_latency_ms`].
+    fn publish_latency(&self) {
+        let occupancy = self.worker.as_ref().map_or(0.0, |worker| {
+            (worker.input_producer.occupied_len() + worker.output_consumer.occupied_len()) as f32
+                / (worker.input_producer.capacity().get() + worker.output_consumer.capacity().get())
+                    as f32
+        });
+        self.stats
+            .buffer_occupancy_bits
+            .store(occupancy.to_bits(), Ordering::Relaxed);
+
+        let latency_ms = match self.worker.as_ref() {
+            Some(worker) if self.enabled_flag.load(Ordering::Relaxed) => {
+                let model_samples = worker.model_latency_samples.load(Ordering::Relaxed);
+                if model_samples == 0 {
+                    0.0
+                } else {
+                    let buffered_frames = (worker.input_producer.occupied_len()
+                        + worker.output_consumer.occupied_len())
+                        / worker.channels;
+                    model_samples as f32 * 1000.0 / DFN_SAMPLE_RATE as f32
+                        + buffered_frames as f32 * 1000.0 / self.mix_rate
+                }
+            }
+            _ => 0.0,
+        };
+        self.latency_ms_bits
+            .store(latency_ms.to_bits(), Ordering::Relaxed);
+    }
 
-                    let t_chunk = Instant::now();
-                    let out_slice: &[f32] = match denoiser
-                        .process(noisy_frame.view(), enhanced_frame.view_mut())
-                    {
-                        Ok(_) => enhanced_frame.as_slice().unwrap_or(&in_chunk),
-                        Err(err) => {
-                            godot_error!(
-                                "AudioEffectDeepFilterNet: process failed in worker, using dry chunk. {:?}",
-                                err
-                            );
-                            &in_chunk
-                        }
-                    };
-
-                    let elapsed_us = t_chunk.elapsed().as_micros();
-                    chunk_process_count = chunk_process_count.saturating_add(1);
-                    chunk_process_total_us = chunk_process_total_us.saturating_add(elapsed_us);
-                    chunk_process_max_us = chunk_process_max_us.max(elapsed_us);
-
-                    if chunk_process_count % 200 == 0 {
-                        let avg_us = chunk_process_total_us / chunk_process_count as u128;
-                        let avg_ms = avg_us as f32 / 1000.0;
-                        let max_ms = chunk_process_max_us as f32 / 1000.0;
-                        let budget_ms = (hop_size as f32 / 48_000.0) * 1000.0;
-                        // godot_print!(
-                        //     "AudioEffectDeepFilterNet: chunk timing avg_ms={:.3} max_ms={:.3} budget_ms={:.3} load_ratio={:.2}",
-                        //     avg_ms,
-                        //     max_ms,
-                        //     budget_ms,
-                        //     avg_ms / budget_ms
-                        // );
-                    }
+    fn process_with_model(&mut self, input_slice: &[AudioFrame], output_slice: &mut [AudioFrame]) {
+        let frame_count = input_slice.len();
+        let Some(worker) = self.worker.as_mut() else {
+            return;
+        };
 
-                    let mut written = 0usize;
-                    while written < hop_size && !stop_flag_worker.load(Ordering::Relaxed) {
-                        written += output_producer.push_slice(&out_slice[written..]);
-                        if written < hop_size {
-                            thread::yield_now();
-                        }
-                    }
+        self.model_frames.resize(frame_count, Vector2::ZERO);
+        let enhanced = worker.exchange(
+            input_slice,
+            &mut self.input_scratch,
+            &mut self.output_scratch,
+            &mut self.model_frames,
+            &self.stats,
+        );
+        // Fading to a worker that never loaded its model would mute the
+        // enhancement, so that only happens once it exited.
+        let takes_over = enhanced == frame_count || worker.is_finished();
+
+        if let Some(retiring) = self.retiring_worker.as_mut() {
+            self.retiring_frames.resize(frame_count, Vector2::ZERO);
+            retiring.exchange(
+                input_slice,
+                &mut self.input_scratch,
+                &mut self.output_scratch,
+                &mut self.retiring_frames,
+                &self.stats,
+            );
+            if takes_over || self.crossfade_progress > 0.0 {
+                for (new_frame, old_frame) in
+                    self.model_frames.iter_mut().zip(&self.retiring_frames)
+                {
+                    self.crossfade_progress =
+                        (self.crossfade_progress + self.crossfade_step).min(1.0);
+                    *new_frame = *old_frame + (*new_frame - *old_frame) * self.crossfade_progress;
                 }
-            })
-        {
-            Ok(handle) => handle,
-            Err(err) => {
-                godot_error!(
-                    "AudioEffectDeepFilterNet: failed to spawn worker thread: {}",
-                    err
-                );
-                return;
+                if self.crossfade_progress >= 1.0 {
+                    self.retire(self.retiring_worker.take());
+                }
+            } else {
+                self.model_frames.copy_from_slice(&self.retiring_frames);
             }
-        };
+        }
 
-        self.worker = Some(DeepFilterWorker {
-            input_producer,
-            output_consumer,
-            stop_flag,
-            thread_handle: Some(thread_handle),
-        });
+        for (out_frame, frame) in output_slice.iter_mut().zip(&self.model_frames) {
+            out_frame.left = frame.x;
+            out_frame.right = frame.y;
+        }
     }
 
-    fn refresh_runtime_config_if_needed(&mut self) {
-        let Ok(cfg) = self.shared_config.lock() else {
+    /// Switches to RNNoise once the model was too slow for
+    /// [`FALLBACK_AFTER_MS`] with auto fallback on, and back when it's off.
+    fn update_fallback(&mut self, frame_count: usize) {
+        if !self.auto_fallback_flag.load(Ordering::Relaxed) {
+            if self.fallback.is_some() {
+                // Whatever the model produced meanwhile is stale.
+                if let Some(worker) = self.worker.as_mut() {
+                    worker.output_consumer.clear();
+                }
+                self.stop_fallback();
+            }
             return;
-        };
+        }
+        if self.fallback.is_some() {
+            return;
+        }
 
-        if self.applied_revision == cfg.revision && self.worker.is_some() {
+        if self.stats.recent_load_ratio() > 1.0 {
+            self.overload_frames += frame_count;
+        } else {
+            self.overload_frames = 0;
+        }
+        if (self.overload_frames as f32) < FALLBACK_AFTER_MS * self.mix_rate / 1000.0 {
             return;
         }
+        let Some(fallback) = self
+            .worker
+            .as_mut()
+            .and_then(|worker| worker.fallback.take())
+        else {
+            return;
+        };
 
-        let revision = cfg.revision;
-        let params = cfg.params.clone();
-        drop(cfg);
+        godot_warn!("AudioEffectDeepFilterNet: model can't keep up, switching to RNNoise.");
+        self.fallback = Some(fallback);
+        self.fallback_active.store(true, Ordering::Relaxed);
+        if let Some(effect_id) = self.effect_id {
+            emit_deferred(effect_id, "fallback_activated", &[]);
+        }
+    }
 
-        self.stop_worker();
-        self.applied_revision = revision;
-        self.start_worker_with_params(params);
+    fn stop_fallback(&mut self) {
+        // Handed back rather than freed on the audio thread.
+        if let (Some(fallback), Some(worker)) = (self.fallback.take(), self.worker.as_mut()) {
+            worker.fallback = Some(fallback);
+        }
+        self.fallback_mix = 0.0;
+        self.overload_frames = 0;
+        self.fallback_active.store(false, Ordering::Relaxed);
     }
 
-    fn ensure_scratch_capacity(&mut self, frame_count: usize) {
-        if self.input_scratch.len() < frame_count {
-            self.input_scratch.resize(frame_count, 0.0);
+    /// Mixes RNNoise into the output, which holds the model output until the
+    /// crossfade completes.
+    fn process_with_fallback(
+        &mut self,
+        input_slice: &[AudioFrame],
+        output_slice: &mut [AudioFrame],
+    ) {
+        let Some(fallback) = self.fallback.as_mut() else {
+            return;
+        };
+
+        self.fallback_input.clear();
+        self.fallback_input.extend(
+            input_slice
+                .iter()
+                .map(|frame| (frame.left + frame.right) * 0.5),
+        );
+        self.fallback_output.resize(input_slice.len(), 0.0);
+        fallback.process(&self.fallback_input, &mut self.fallback_output);
+
+        let step = 1000.0 / (FALLBACK_CROSSFADE_MS * self.mix_rate);
+        for (out_frame, sample) in output_slice.iter_mut().zip(&self.fallback_output) {
+            if self.fallback_mix >= 1.0 {
+                out_frame.left = *sample;
+                out_frame.right = *sample;
+                continue;
+            }
+            self.fallback_mix = (self.fallback_mix + step).min(1.0);
+            let mix = self.fallback_mix;
+            out_frame.left = out_frame.left * (1.0 - mix) + sample * mix;
+            out_frame.right = out_frame.right * (1.0 - mix) + sample * mix;
         }
-        if self.output_scratch.len() < frame_count {
-            self.output_scratch.resize(frame_count, 0.0);
+    }
+
+    fn ensure_scratch_capacity(&mut self, sample_count: usize) {
+        if self.output_scratch.len() < sample_count {
+            self.output_scratch.resize(sample_count, 0.0);
         }
     }
 }
@@ -364,75 +2453,72 @@ impl IAudioEffectInstance for AudioEffectDeepFilterNetInstance {
         let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
         let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
 
-        self.refresh_runtime_config_if_needed();
-        self.ensure_scratch_capacity(frame_count);
+        self.take_restarted_worker();
+        self.ensure_scratch_capacity(frame_count * 2);
 
-        if self.worker.is_none() {
-            for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
-                out_frame.left = in_frame.left;
-                out_frame.right = in_frame.right;
+        let enabled = self.enabled_flag.load(Ordering::Relaxed);
+        if !enabled {
+            // Enhanced audio still in flight would be stale once re-enabled.
+            if let Some(worker) = self.worker.as_mut() {
+                worker.output_consumer.clear();
             }
-            return;
+            self.retire(self.retiring_worker.take());
         }
 
-        let mono_input = &mut self.input_scratch[..frame_count];
-        for (dst, frame) in mono_input.iter_mut().zip(input_slice.iter()) {
-            *dst = (frame.left + frame.right) * 0.5;
-        }
+        self.publish_latency();
 
-        if let Some(worker) = self.worker.as_mut() {
-            let pushed = worker.input_producer.push_slice(mono_input);
-            if pushed < frame_count {
-                self.dropped_input_samples = self
-                    .dropped_input_samples
-                    .saturating_add((frame_count - pushed) as u64);
-                if self.dropped_input_samples % 48_000 == 0 {
-                    godot_print!(
-                        "AudioEffectDeepFilterNet: dropped_input_samples={}",
-                        self.dropped_input_samples
-                    );
-                }
+        if !enabled || self.worker.is_none() {
+            for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+                out_frame.left = in_frame.left;
+                out_frame.right = in_frame.right;
             }
+            return;
         }
 
-        let mut processed_samples = 0usize;
-        if let Some(worker) = self.worker.as_mut() {
-            processed_samples = worker
-                .output_consumer
-                .pop_slice(&mut self.output_scratch[..frame_count]);
-        }
-
-        for i in 0..processed_samples {
-            let sample = self.output_scratch[i];
-            self.last_output_sample = sample;
-            output_slice[i].left = sample;
-            output_slice[i].right = sample;
+        self.update_fallback(frame_count);
+        if self.fallback_mix < 1.0 {
+            self.process_with_model(input_slice, output_slice);
         }
-
-        for i in processed_samples..frame_count {
-            let sample = mono_input[i];
-            self.last_output_sample = sample;
-            output_slice[i].left = sample;
-            output_slice[i].right = sample;
+        if self.fallback.is_some() {
+            self.process_with_fallback(input_slice, output_slice);
         }
     }
 
     fn init(base: Base<AudioEffectInstance>) -> Self {
+        let (handoff, retired) = WorkerHandoff::new();
         Self {
             base,
-            shared_config: Arc::default(),
-            applied_revision: 0,
+            handoff,
+            retired,
+            unretired: Vec::with_capacity(RETIRED_CAPACITY),
+            enabled_flag: Arc::new(AtomicBool::new(true)),
+            latency_ms_bits: Arc::default(),
+            stats: Arc::default(),
+            model_active: Arc::default(),
+            effect_id: None,
+            auto_fallback_flag: Arc::default(),
+            fallback_active: Arc::default(),
+            fallback: None,
+            fallback_mix: 0.0,
+            overload_frames: 0,
+            fallback_input: Vec::with_capacity(2048),
+            fallback_output: Vec::with_capacity(2048),
             worker: None,
-            input_scratch: Vec::with_capacity(2048),
-            output_scratch: Vec::with_capacity(2048),
-            last_output_sample: 0.0,
-            dropped_input_samples: 0,
+            retiring_worker: None,
+            crossfade_progress: 0.0,
+            crossfade_step: 0.0,
+            mix_rate: DFN_SAMPLE_RATE as f32,
+            input_scratch: Vec::with_capacity(4096),
+            output_scratch: Vec::with_capacity(4096),
+            model_frames: Vec::with_capacity(2048),
+            retiring_frames: Vec::with_capacity(2048),
         }
     }
 }
 
 impl Drop for AudioEffectDeepFilterNetInstance {
     fn drop(&mut self) {
+        self.retiring_worker = None;
         self.stop_worker();
     }
 }
@@ -446,6 +2532,139 @@ mod tests {
         (sum_sq / frame.len() as f32).sqrt()
     }
 
+    #[test]
+    fn dry_wet_mixer_lines_up_dry_signal() {
+        let mut mixer = DryWetMixer::new(2);
+        let mut out = [0.0f32; 3];
+        mixer.mix(&[1.0, 2.0, 3.0], &[10.0, 10.0, 10.0], 0.5, &mut out);
+        assert_eq!(out, [5.0, 5.0, 5.5]);
+        mixer.mix(&[4.0, 5.0, 6.0], &[0.0, 0.0, 0.0], 0.0, &mut out);
+        assert_eq!(out, [2.0, 3.0, 4.0]);
+        mixer.mix_removed_noise(&[7.0, 8.0, 9.0], &[1.0, 1.0, 1.0], &mut out);
+        assert_eq!(out, [4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn worker_resampler_keeps_channels_apart() {
+        let mut resampler = WorkerResampler::new(24_000, 48_000, 2).expect("rates differ");
+        let input: Vec<f32> = (0..10).flat_map(|_| [1.0, -1.0]).collect();
+        let mut out = Vec::new();
+        resampler.process(&input, &mut out);
+        assert_eq!(out.len(), 40);
+        assert_eq!(&out[..4], &[0.0, 0.0, 0.5, -0.5]);
+        assert!(out[4..].chunks(2).all(|frame| frame == [1.0, -1.0]));
+
+        assert!(WorkerResampler::new(48_000, 48_000, 1).is_none());
+    }
+
+    #[test]
+    fn stats_relate_chunk_time_to_budget() {
+        let stats = DeepFilterStats::default();
+        assert_eq!(stats.load_ratio(), 0.0);
+
+        stats.hop_size.store(480, Ordering::Relaxed);
+        stats.record_chunk(4_000, Some(12.0));
+        stats.record_chunk(6_000, None);
+        assert_eq!(stats.average_chunk_ms(), 5.0);
+        assert_eq!(stats.chunk_max_us.load(Ordering::Relaxed), 6_000);
+        assert_eq!(stats.load_ratio(), 0.5);
+        assert_eq!(stats.last_chunk_ms(), 6.0);
+        assert_eq!(
+            f32::from_bits(stats.last_lsnr_bits.load(Ordering::Relaxed)),
+            12.0
+        );
+
+        stats.reset();
+        assert_eq!(stats.average_chunk_ms(), 0.0);
+    }
+
+    #[test]
+    fn recent_load_follows_latest_chunks() {
+        let stats = DeepFilterStats::default();
+        stats.hop_size.store(480, Ordering::Relaxed);
+        stats.record_chunk(20_000, None);
+        assert_eq!(stats.recent_load_ratio(), 2.0);
+
+        for _ in 0..200 {
+            stats.record_chunk(5_000, None);
+        }
+        assert!((stats.recent_load_ratio() - 0.5).abs() < 0.01);
+        // The overall average still remembers the slow chunk.
+        assert!(stats.load_ratio() > 0.5);
+    }
+
+    #[test]
+    fn spectrum_analyzer_measures_gain_per_band() {
+        let band = 8;
+        let hz = spectrum_band_hz(band);
+        let chunk: Vec<f32> = (0..480)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * hz * i as f32 / 48_000.0).sin())
+            .collect();
+        let quieter: Vec<f32> = chunk.iter().map(|sample| sample * 0.1).collect();
+        let snapshot = SpectrumSnapshot::default();
+        let mut analyzer = SpectrumAnalyzer::new(480, 0);
+        for _ in 0..60 {
+            analyzer.process(&chunk, &quieter, 1, &snapshot);
+        }
+
+        let input = f32::from_bits(snapshot.input_bits[band].load(Ordering::Relaxed));
+        let gain_db = f32::from_bits(snapshot.gain_db_bits[band].load(Ordering::Relaxed));
+        assert!((input - 0.5).abs() < 0.05, "input {input}");
+        assert!((gain_db + 20.0).abs() < 0.5, "gain {gain_db}");
+        let far_input = f32::from_bits(snapshot.input_bits[2].load(Ordering::Relaxed));
+        assert!(far_input < 0.05, "far band {far_input}");
+    }
+
+    #[test]
+    fn tracked_threads_are_joined() {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let worker_stop_flag = stop_flag.clone();
+        let thread = spawn_tracked("dfn_test", stop_flag.clone(), move || {
+            while !worker_stop_flag.load(Ordering::Relaxed) {
+                thread::park();
+            }
+        })
+        .expect("should spawn");
+        assert!(!tracked_thread_finished(thread.id()));
+
+        stop_flag.store(true, Ordering::Relaxed);
+        thread.unpark();
+        while !tracked_thread_finished(thread.id()) {
+            thread::yield_now();
+        }
+
+        // The next spawn joins the finished thread.
+        spawn_tracked("dfn_test", Arc::default(), || {}).expect("should spawn");
+        let threads = TRACKED_THREADS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        assert!(threads
+            .iter()
+            .all(|tracked| tracked.handle.thread().id() != thread.id()));
+    }
+
+    #[test]
+    fn limit_backlog_drops_whole_frames_by_policy() {
+        let mut pending: VecDeque<f32> = (0..10).map(|i| i as f32).collect();
+        assert_eq!(limit_backlog(&mut pending, 6, 2, DROP_OLDEST), 4);
+        assert_eq!(pending, [4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+
+        // Rounded up to a whole stereo frame.
+        assert_eq!(limit_backlog(&mut pending, 3, 2, DROP_NEWEST), 4);
+        assert_eq!(pending, [4.0, 5.0]);
+
+        assert_eq!(limit_backlog(&mut pending, 3, 2, DROP_NEWEST), 0);
+    }
+
+    #[test]
+    fn leak_model_reuses_identical_models() {
+        let a = leak_model(&[1, 2, 3]);
+        let b = leak_model(&[1, 2, 3]);
+        let c = leak_model(&[1, 2, 4]);
+        assert!(std::ptr::eq(a, b));
+        assert!(!std::ptr::eq(a, c));
+    }
+
     #[test]
     fn dfn_tract_model_initializes() {
         let runtime_params = RuntimeParams::default_with_ch(1).with_mask_reduce(ReduceMask::NONE);
@@ -457,6 +2676,76 @@ mod tests {
         );
     }
 
+    #[test]
+    fn loaded_denoisers_are_shared() {
+        let params = DeepFilterParams {
+            channels: 2,
+            ..DeepFilterParams::default()
+        };
+        let tuned = DeepFilterParams {
+            post_filter_beta: 0.5,
+            ..params.clone()
+        };
+        let first = load_denoiser(&params, ModelSource::Embedded).expect("should load");
+        let second = load_denoiser(&tuned, ModelSource::Embedded).expect("should load");
+        assert_eq!(first.hop_size, second.hop_size);
+        assert_eq!(second.post_filter_beta, 0.5);
+
+        let loaded = LOADED_DENOISERS.lock().expect("not poisoned");
+        let matching = loaded
+            .iter()
+            .filter(|entry| entry.channels == 2 && entry.model == ModelSource::Embedded)
+            .count();
+        assert_eq!(matching, 1);
+    }
+
+    #[test]
+    fn enhance_offline_lines_up_with_input() {
+        let mut denoiser = load_denoiser(&DeepFilterParams::default(), ModelSource::Embedded)
+            .expect("should load");
+        let samples: Vec<f32> = (0..10_000)
+            .map(|i| 0.1 * (2.0 * std::f32::consts::PI * 220.0 * i as f32 / 48_000.0).sin())
+            .collect();
+
+        // Fully dry output is the input itself, so nothing is shifted.
+        let dry = enhance_offline(&mut denoiser, &samples, 1, 0.0);
+        assert_eq!(dry, samples);
+
+        let enhanced = enhance_offline(&mut denoiser, &samples, 1, 1.0);
+        assert_eq!(enhanced.len(), samples.len());
+        assert!(enhanced.iter().all(|sample| sample.is_finite()));
+    }
+
+    #[test]
+    fn chunk_pipeline_enhances_inline() {
+        let denoiser = load_denoiser(&DeepFilterParams::default(), ModelSource::Embedded)
+            .expect("should load");
+        let hop_size = denoiser.hop_size;
+        let (mut input_producer, input_consumer) = HeapRb::<f32>::new(hop_size * 8).split();
+        let (output_producer, mut output_consumer) = HeapRb::<f32>::new(hop_size * 8).split();
+        let controls = PipelineControls {
+            wet_amount_bits: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            monitor_noise_flag: Arc::default(),
+            fade_in_ms_bits: Arc::default(),
+            tuning: Arc::new(DenoiserTuning::new(&DeepFilterParams::default())),
+            stats: Arc::default(),
+        };
+        let mut pipeline = ChunkPipeline::new(
+            denoiser,
+            1,
+            DFN_SAMPLE_RATE,
+            (input_consumer, output_producer),
+            &BufferingParams::default(),
+            controls.clone(),
+        );
+
+        input_producer.push_slice(&vec![0.01f32; hop_size * 3 + 10]);
+        pipeline.run_inline();
+        assert_eq!(output_consumer.occupied_len(), hop_size * 3);
+        assert_eq!(controls.stats.chunk_count.load(Ordering::Relaxed), 3);
+        assert_eq!(controls.stats.hop_size.load(Ordering::Relaxed), hop_size);
+    }
+
     #[test]
     fn dfn_tract_processes_simulated_audio() {
         let runtime_params = RuntimeParams::default_with_ch(1).with_mask_reduce(ReduceMask::NONE);
@@ -0,0 +1,396 @@
+use godot::prelude::*;
+
+/// Lowest level reported by [`gain_to_db`], to keep silence finite.
+pub(crate) const MIN_DB: f32 = -120.0;
+
+pub(crate) fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+pub(crate) fn gain_to_db(gain: f32) -> f32 {
+    if gain <= 0.0 {
+        return MIN_DB;
+    }
+    (20.0 * gain.log10()).max(MIN_DB)
+}
+
+/// One-pole smoothing coefficient for a time constant in milliseconds.
+/// Returns 0.0 (no smoothing) for non-positive times.
+pub(crate) fn ms_to_coeff(ms: f32, sample_rate: f32) -> f32 {
+    let ms = ms.max(0.0);
+    if ms <= 0.0 || sample_rate <= 0.0 {
+        return 0.0;
+    }
+
+    let seconds = ms * 0.001;
+    (-1.0 / (seconds * sample_rate)).exp()
+}
+
+pub(crate) fn ms_to_samples(ms: f32, sample_rate: f32) -> usize {
+    (ms.max(0.0) * 0.001 * sample_rate).round().max(0.0) as usize
+}
+
+/// Moves `current` one sample toward `target` using the given coefficient.
+pub(crate) fn one_pole_step(current: f32, target: f32, coeff: f32) -> f32 {
+    target + coeff * (current - target)
+}
+
+/// Equal-power gains for a crossfade position in range (0.0, 1.0).
+/// Returns `(from_gain, to_gain)`.
+pub(crate) fn equal_power_crossfade(position: f32) -> (f32, f32) {
+    let angle = position.clamp(0.0, 1.0) * std::f32::consts::FRAC_PI_2;
+    (angle.cos(), angle.sin())
+}
+
+/// Linear blend between two samples for a position in range (0.0, 1.0).
+pub(crate) fn linear_crossfade(from: f32, to: f32, position: f32) -> f32 {
+    let position = position.clamp(0.0, 1.0);
+    from * (1.0 - position) + to * position
+}
+
+/// Hann window over `len` samples.
+pub(crate) fn hann_window(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / len as f32).cos())
+        .collect()
+}
+
+/// Squared magnitude of `samples`, weighted by `window`, at the frequency of
+/// the Goertzel coefficient `coeff`.
+pub(crate) fn goertzel_power(samples: &[f32], window: &[f32], coeff: f32) -> f32 {
+    let (mut s1, mut s2) = (0.0f32, 0.0f32);
+    for (sample, weight) in samples.iter().zip(window) {
+        let s0 = sample * weight + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    s1 * s1 + s2 * s2 - coeff * s1 * s2
+}
+
+/// Advances `state` and returns 64 well-mixed pseudo-random bits.
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Maps random bits to [0.0, 1.0).
+pub(crate) fn unit_from_bits(bits: u64) -> f32 {
+    (bits >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Second-order IIR filter in transposed direct form II, with coefficients
+/// normalized by `a0`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Biquad {
+    pub(crate) b0: f32,
+    pub(crate) b1: f32,
+    pub(crate) b2: f32,
+    pub(crate) a1: f32,
+    pub(crate) a2: f32,
+    pub(crate) z1: f32,
+    pub(crate) z2: f32,
+}
+
+impl Biquad {
+    /// Butterworth high pass with the corner at `cutoff_hz`.
+    pub(crate) fn high_pass(cutoff_hz: f32, sample_rate: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(cutoff_hz, sample_rate);
+        Self::normalized(
+            [(1.0 + cos) * 0.5, -(1.0 + cos), (1.0 + cos) * 0.5],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Butterworth low pass with the corner at `cutoff_hz`.
+    pub(crate) fn low_pass(cutoff_hz: f32, sample_rate: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(cutoff_hz, sample_rate);
+        Self::normalized(
+            [(1.0 - cos) * 0.5, 1.0 - cos, (1.0 - cos) * 0.5],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Cosine and alpha of the RBJ cookbook formulas at Q = 1/sqrt(2).
+    fn prewarp(cutoff_hz: f32, sample_rate: f32) -> (f32, f32) {
+        let cutoff_hz = cutoff_hz.clamp(1.0, sample_rate * 0.49);
+        let w0 = 2.0 * std::f32::consts::PI * cutoff_hz / sample_rate;
+        (w0.cos(), w0.sin() * std::f32::consts::FRAC_1_SQRT_2)
+    }
+
+    fn normalized([b0, b1, b2]: [f32; 3], [a0, a1, a2]: [f32; 3]) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Attack/release envelope follower.
+///
+/// Uses the attack coefficient while the input rises above the envelope and
+/// the release coefficient while it falls, so it serves both as a level
+/// detector and as a gain smoother.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EnvelopeFollower {
+    pub(crate) attack_coeff: f32,
+    pub(crate) release_coeff: f32,
+    pub(crate) value: f32,
+}
+
+impl EnvelopeFollower {
+    pub(crate) fn new(attack_ms: f32, release_ms: f32, sample_rate: f32) -> Self {
+        let mut follower = Self::default();
+        follower.set_times(attack_ms, release_ms, sample_rate);
+        follower
+    }
+
+    pub(crate) fn set_times(&mut self, attack_ms: f32, release_ms: f32, sample_rate: f32) {
+        self.attack_coeff = ms_to_coeff(attack_ms, sample_rate);
+        self.release_coeff = ms_to_coeff(release_ms, sample_rate);
+    }
+
+    pub(crate) fn process(&mut self, input: f32) -> f32 {
+        let coeff = if input > self.value {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.value = one_pole_step(self.value, input, coeff);
+        self.value
+    }
+}
+
+/// Linear fade from silence over a number of samples, e.g. to hide a
+/// denoiser's start-up artifacts.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FadeIn {
+    length: usize,
+    position: usize,
+}
+
+impl FadeIn {
+    pub(crate) fn new(length: usize) -> Self {
+        Self {
+            length,
+            position: 0,
+        }
+    }
+
+    /// Changes the length, keeping how far the fade got.
+    pub(crate) fn set_length(&mut self, length: usize) {
+        self.length = length;
+    }
+
+    /// Starts fading in again from silence.
+    pub(crate) fn restart(&mut self) {
+        self.position = 0;
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    /// Returns the gain for the next sample, 1.0 once faded in.
+    pub(crate) fn next_gain(&mut self) -> f32 {
+        if self.is_done() {
+            return 1.0;
+        }
+        self.position += 1;
+        self.position as f32 / self.length as f32
+    }
+}
+
+/// Samples a [`LinearResampler`] can interpolate.
+pub(crate) trait Interpolate: Copy + Default {
+    fn lerp(a: Self, b: Self, fraction: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn lerp(a: Self, b: Self, fraction: f32) -> Self {
+        a + (b - a) * fraction
+    }
+}
+
+impl Interpolate for Vector2 {
+    fn lerp(a: Self, b: Self, fraction: f32) -> Self {
+        Vector2::new(f32::lerp(a.x, b.x, fraction), f32::lerp(a.y, b.y, fraction))
+    }
+}
+
+/// Linear resampler for a continuous stream that keeps its phase across
+/// calls, so chunk borders don't click.
+#[derive(Debug, Clone)]
+pub(crate) struct LinearResampler<T> {
+    step: f64,
+    /// Read position, where 0.0 is `previous` and 1.0 the next input sample.
+    position: f64,
+    previous: T,
+}
+
+impl<T: Interpolate> LinearResampler<T> {
+    pub(crate) fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            step: input_rate.max(1) as f64 / output_rate.max(1) as f64,
+            position: 0.0,
+            previous: T::default(),
+        }
+    }
+
+    /// Appends the resampled `input` to `out`.
+    pub(crate) fn process(&mut self, input: &[T], out: &mut Vec<T>) {
+        let Some(last) = input.last().copied() else {
+            return;
+        };
+
+        let len = input.len() as f64;
+        while self.position < len {
+            let index = self.position.floor() as usize;
+            let fraction = (self.position - index as f64) as f32;
+            let a = if index == 0 {
+                self.previous
+            } else {
+                input[index - 1]
+            };
+            out.push(T::lerp(a, input[index], fraction));
+            self.position += self.step;
+        }
+        self.position -= len;
+        self.previous = last;
+    }
+}
+
+/// Unit conversions shared by the voice effects.
+///
+/// Use these in settings UIs so sliders and meters use exactly the same math
+/// as the audio processing.
+#[derive(GodotClass)]
+#[class(no_init, base=Object)]
+pub(crate) struct VoipDsp {}
+
+#[godot_api]
+impl VoipDsp {
+    /// Converts decibels to linear gain.
+    #[func]
+    fn db_to_gain(db: f32) -> f32 {
+        db_to_gain(db)
+    }
+
+    /// Converts linear gain to decibels. Silence maps to -120 dB.
+    #[func]
+    fn gain_to_db(gain: f32) -> f32 {
+        gain_to_db(gain)
+    }
+
+    /// Returns the per-sample smoothing coefficient the effects use for a
+    /// time constant in milliseconds.
+    #[func]
+    fn ms_to_coeff(ms: f32, sample_rate: f32) -> f32 {
+        ms_to_coeff(ms, sample_rate)
+    }
+
+    /// Converts milliseconds to a sample count.
+    #[func]
+    fn ms_to_samples(ms: f32, sample_rate: f32) -> i64 {
+        ms_to_samples(ms, sample_rate) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn db_gain_round_trip() {
+        for db in [-60.0f32, -12.0, 0.0, 6.0] {
+            assert!((gain_to_db(db_to_gain(db)) - db).abs() < 1e-3);
+        }
+        assert_eq!(gain_to_db(0.0), MIN_DB);
+    }
+
+    #[test]
+    fn ms_to_coeff_handles_zero_and_reaches_time_constant() {
+        assert_eq!(ms_to_coeff(0.0, 48_000.0), 0.0);
+        assert_eq!(ms_to_coeff(10.0, 0.0), 0.0);
+
+        // After one time constant a step response reaches ~63%.
+        let mut follower = EnvelopeFollower::new(10.0, 10.0, 48_000.0);
+        for _ in 0..480 {
+            follower.process(1.0);
+        }
+        assert!((follower.value - 0.632).abs() < 0.01);
+    }
+
+    #[test]
+    fn envelope_uses_release_when_falling() {
+        let mut follower = EnvelopeFollower::new(0.0, 100.0, 48_000.0);
+        assert_eq!(follower.process(1.0), 1.0);
+        let after = follower.process(0.0);
+        assert!(after > 0.99 && after < 1.0);
+    }
+
+    #[test]
+    fn equal_power_crossfade_keeps_power() {
+        for i in 0..=10 {
+            let (a, b) = equal_power_crossfade(i as f32 / 10.0);
+            assert!((a * a + b * b - 1.0).abs() < 1e-5);
+        }
+        assert_eq!(linear_crossfade(0.0, 1.0, 0.25), 0.25);
+    }
+
+    #[test]
+    fn biquads_pass_and_stop_bands() {
+        let sample_rate = 48_000.0;
+        let peak_after_settling = |mut filter: Biquad, freq: f32| {
+            (0..9_600)
+                .map(|i| {
+                    let phase = 2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate;
+                    filter.process(phase.sin())
+                })
+                .skip(4_800)
+                .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+        };
+
+        let high_pass = Biquad::high_pass(150.0, sample_rate);
+        assert!(peak_after_settling(high_pass, 30.0) < 0.05);
+        assert!(peak_after_settling(high_pass, 1_000.0) > 0.95);
+        let low_pass = Biquad::low_pass(6_000.0, sample_rate);
+        assert!(peak_after_settling(low_pass, 1_000.0) > 0.95);
+        assert!(peak_after_settling(low_pass, 20_000.0) < 0.1);
+    }
+
+    #[test]
+    fn fade_in_ramps_to_unity() {
+        let mut fade = FadeIn::new(4);
+        let gains: Vec<f32> = (0..6).map(|_| fade.next_gain()).collect();
+        assert_eq!(gains, [0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+
+        fade.restart();
+        assert_eq!(fade.next_gain(), 0.25);
+        assert_eq!(FadeIn::new(0).next_gain(), 1.0);
+    }
+
+    #[test]
+    fn resampler_keeps_rate_across_calls() {
+        let mut resampler = LinearResampler::<f32>::new(44_100, 48_000);
+        let mut out = Vec::new();
+        for _ in 0..100 {
+            resampler.process(&[0.5; 441], &mut out);
+        }
+        assert!((out.len() as i64 - 48_000).abs() <= 1, "len={}", out.len());
+        assert!(out[10..].iter().all(|s| (s - 0.5).abs() < 1e-6));
+    }
+}
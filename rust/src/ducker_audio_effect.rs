@@ -0,0 +1,296 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{db_to_gain, ms_to_coeff, one_pole_step};
+
+#[derive(Debug, Clone)]
+struct DuckerParams {
+    amount_db: f32,
+    attack_ms: f32,
+    release_ms: f32,
+}
+
+impl Default for DuckerParams {
+    fn default() -> Self {
+        Self {
+            amount_db: -12.0,
+            attack_ms: 50.0,
+            release_ms: 400.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DuckerSharedConfig {
+    params: DuckerParams,
+    revision: u64,
+}
+
+type DuckerSharedConfigRef = Arc<Mutex<DuckerSharedConfig>>;
+
+/// Gain smoother of the ducker. Works in dB so ducking sounds even at any
+/// depth.
+#[derive(Debug, Default)]
+struct DuckState {
+    attack_coeff: f32,
+    release_coeff: f32,
+    gain_db: f32,
+}
+
+impl DuckState {
+    fn configure(&mut self, params: &DuckerParams, sample_rate: f32) {
+        self.attack_coeff = ms_to_coeff(params.attack_ms, sample_rate);
+        self.release_coeff = ms_to_coeff(params.release_ms, sample_rate);
+    }
+
+    /// Moves the gain one sample toward `target_db` and returns it.
+    fn process(&mut self, target_db: f32) -> f32 {
+        let coeff = if target_db < self.gain_db {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.gain_db = one_pole_step(self.gain_db, target_db, coeff);
+        db_to_gain(self.gain_db)
+    }
+}
+
+/// Turns a bus down while [method set_ducked] is on, e.g. music while a
+/// priority speaker talks.
+///
+/// The gain moves toward [member amount_db] over [member attack_ms] and back
+/// over [member release_ms], sample by sample, so ducking never clicks
+/// regardless of how often it is toggled.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoipDucker {
+    pub(crate) base: Base<AudioEffect>,
+    /// Gain while ducked, in dB.
+    #[export]
+    #[var(get = get_amount_db, set = set_amount_db)]
+    amount_db: f32,
+    /// Time to duck, in milliseconds.
+    #[export]
+    #[var(get = get_attack_ms, set = set_attack_ms)]
+    attack_ms: f32,
+    /// Time to come back up, in milliseconds.
+    #[export]
+    #[var(get = get_release_ms, set = set_release_ms)]
+    release_ms: f32,
+    shared_config: DuckerSharedConfigRef,
+    ducked: Arc<AtomicBool>,
+    /// Bits of the current gain in dB, written by the instance.
+    current_gain_db: Arc<AtomicU32>,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoipDucker {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = DuckerParams::default();
+        Self {
+            base,
+            amount_db: params.amount_db,
+            attack_ms: params.attack_ms,
+            release_ms: params.release_ms,
+            shared_config: Arc::new(Mutex::new(DuckerSharedConfig {
+                params,
+                revision: 0,
+            })),
+            ducked: Arc::default(),
+            current_gain_db: Arc::default(),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let mut effect = AudioEffectVoipDuckerInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+            effect_mut.ducked = self.ducked.clone();
+            effect_mut.current_gain_db = self.current_gain_db.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectVoipDucker {
+    fn push_config_to_shared(&mut self) {
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params.amount_db = self.amount_db;
+            cfg.params.attack_ms = self.attack_ms;
+            cfg.params.release_ms = self.release_ms;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_amount_db(&self) -> f32 {
+        self.amount_db
+    }
+
+    #[func]
+    pub(crate) fn set_amount_db(&mut self, value: f32) {
+        self.amount_db = value.min(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_attack_ms(&self) -> f32 {
+        self.attack_ms
+    }
+
+    #[func]
+    pub(crate) fn set_attack_ms(&mut self, value: f32) {
+        self.attack_ms = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_release_ms(&self) -> f32 {
+        self.release_ms
+    }
+
+    #[func]
+    pub(crate) fn set_release_ms(&mut self, value: f32) {
+        self.release_ms = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    /// Starts or stops ducking.
+    #[func]
+    pub(crate) fn set_ducked(&self, ducked: bool) {
+        self.ducked.store(ducked, Ordering::Relaxed);
+    }
+
+    #[func]
+    fn is_ducked(&self) -> bool {
+        self.ducked.load(Ordering::Relaxed)
+    }
+
+    /// Returns the gain currently applied, in dB.
+    #[func]
+    fn get_current_gain_db(&self) -> f32 {
+        f32::from_bits(self.current_gain_db.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoipDuckerInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: DuckerSharedConfigRef,
+    applied_revision: u64,
+    amount_db: f32,
+    ducked: Arc<AtomicBool>,
+    current_gain_db: Arc<AtomicU32>,
+    state: DuckState,
+}
+
+impl AudioEffectVoipDuckerInstance {
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        drop(cfg);
+
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.state.configure(&params, sample_rate);
+        self.amount_db = params.amount_db;
+        self.applied_revision = revision;
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoipDuckerInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        let target_db = if self.ducked.load(Ordering::Relaxed) {
+            self.amount_db
+        } else {
+            0.0
+        };
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            let gain = self.state.process(target_db);
+            out_frame.left = in_frame.left * gain;
+            out_frame.right = in_frame.right * gain;
+        }
+        self.current_gain_db
+            .store(self.state.gain_db.to_bits(), Ordering::Relaxed);
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let params = DuckerParams::default();
+        let mut state = DuckState::default();
+        state.configure(&params, sample_rate);
+
+        Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            amount_db: params.amount_db,
+            ducked: Arc::default(),
+            current_gain_db: Arc::default(),
+            state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ducks_fast_and_recovers_slowly() {
+        let params = DuckerParams::default();
+        let mut state = DuckState::default();
+        state.configure(&params, 48_000.0);
+
+        // Five attack time constants get within 1% of the target.
+        for _ in 0..(48 * 250) {
+            state.process(params.amount_db);
+        }
+        assert!((state.gain_db - params.amount_db).abs() < 0.15);
+
+        // One release time constant later it's still well below unity.
+        for _ in 0..(48 * 400) {
+            state.process(0.0);
+        }
+        assert!(
+            state.gain_db < -3.0 && state.gain_db > -6.0,
+            "{}",
+            state.gain_db
+        );
+    }
+}
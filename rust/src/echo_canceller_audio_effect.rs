@@ -0,0 +1,597 @@
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{ms_to_coeff, ms_to_samples, one_pole_step};
+
+/// Longest echo tail the filter can model, as its cost grows with it.
+const MAX_FILTER_LENGTH_MS: f32 = 100.0;
+/// Longest delay between the reference and its echo in the microphone.
+const MAX_REFERENCE_DELAY_MS: f32 = 500.0;
+/// Microphone peaks above this fraction of the recent reference peak are
+/// taken as the local player talking (Geigel detector), which must not be
+/// learned as echo.
+const DOUBLE_TALK_THRESHOLD: f32 = 0.5;
+/// Time adaptation stays off after double talk was detected.
+const DOUBLE_TALK_HOLD_MS: f32 = 50.0;
+/// Averaging time of the powers the suppressor compares.
+const SUPPRESSOR_WINDOW_MS: f32 = 10.0;
+/// Smoothing of the suppressor gain while it rises.
+const SUPPRESSOR_RELEASE_MS: f32 = 50.0;
+/// Reference power per tap below which the filter doesn't adapt, as
+/// normalizing by silence would blow up the step.
+const MIN_REFERENCE_POWER: f32 = 1e-7;
+
+/// Reference samples handed from the output bus to the microphone bus.
+/// Both are processed on the audio thread, so the lock is uncontended.
+#[derive(Debug, Default)]
+struct EchoReferenceBuffer {
+    samples: Mutex<VecDeque<f32>>,
+}
+
+type EchoReferenceBufferRef = Arc<EchoReferenceBuffer>;
+
+#[derive(Debug, Clone)]
+struct EchoCancellerParams {
+    reference_delay_ms: f32,
+    filter_length_ms: f32,
+    adaptation_rate: f32,
+    suppression: f32,
+}
+
+impl Default for EchoCancellerParams {
+    fn default() -> Self {
+        Self {
+            reference_delay_ms: 10.0,
+            filter_length_ms: 30.0,
+            adaptation_rate: 0.5,
+            suppression: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct EchoCancellerSharedConfig {
+    params: EchoCancellerParams,
+    /// Reference of the bus in [member AudioEffectVoipEchoCanceller.reference_bus].
+    reference: Option<EchoReferenceBufferRef>,
+    revision: u64,
+}
+
+type EchoCancellerSharedConfigRef = Arc<Mutex<EchoCancellerSharedConfig>>;
+
+/// Removes the echo of a reference signal from the microphone.
+///
+/// An NLMS filter learns the echo path from the delayed reference to the
+/// microphone and subtracts its estimate. Adaptation pauses while the
+/// microphone is louder than the echo could be, so the local player's voice
+/// isn't learned. A suppressor then turns down what's left while the
+/// estimated echo dominates.
+#[derive(Debug, Default)]
+struct EchoCanceller {
+    /// Filter taps, oldest reference sample first.
+    weights: Vec<f32>,
+    /// Last `weights.len()` reference samples, stored twice so they can be
+    /// read as one slice from `history_position + 1`.
+    history: Vec<f32>,
+    history_position: usize,
+    /// Sum of squares of the reference samples in `history`.
+    reference_power: f32,
+    delay: Vec<f32>,
+    delay_position: usize,
+    adaptation_rate: f32,
+    reference_peak: f32,
+    peak_decay: f32,
+    double_talk_hold_samples: usize,
+    double_talk_remaining: usize,
+    suppression: f32,
+    power_coeff: f32,
+    release_coeff: f32,
+    error_power: f32,
+    echo_power: f32,
+    suppressor_gain: f32,
+}
+
+impl EchoCanceller {
+    fn configure(&mut self, params: &EchoCancellerParams, sample_rate: f32) {
+        let taps = ms_to_samples(
+            params.filter_length_ms.clamp(1.0, MAX_FILTER_LENGTH_MS),
+            sample_rate,
+        )
+        .max(1);
+        if taps != self.weights.len() {
+            self.weights = vec![0.0; taps];
+            self.history = vec![0.0; taps * 2];
+            self.history_position = 0;
+            self.reference_power = 0.0;
+        }
+        let delay = ms_to_samples(
+            params.reference_delay_ms.clamp(0.0, MAX_REFERENCE_DELAY_MS),
+            sample_rate,
+        );
+        if delay != self.delay.len() {
+            self.delay = vec![0.0; delay];
+            self.delay_position = 0;
+        }
+
+        self.adaptation_rate = params.adaptation_rate.clamp(0.0, 1.0);
+        // The peak has to last as long as the echo tail.
+        self.peak_decay = ms_to_coeff(params.filter_length_ms, sample_rate);
+        self.double_talk_hold_samples = ms_to_samples(DOUBLE_TALK_HOLD_MS, sample_rate);
+        self.suppression = params.suppression.max(0.0);
+        self.power_coeff = ms_to_coeff(SUPPRESSOR_WINDOW_MS, sample_rate);
+        self.release_coeff = ms_to_coeff(SUPPRESSOR_RELEASE_MS, sample_rate);
+        if self.suppressor_gain == 0.0 {
+            self.suppressor_gain = 1.0;
+        }
+    }
+
+    /// Returns `microphone` without the echo of `reference`.
+    fn process(&mut self, microphone: f32, reference: f32) -> f32 {
+        let reference = if self.delay.is_empty() {
+            reference
+        } else {
+            let delayed = std::mem::replace(&mut self.delay[self.delay_position], reference);
+            self.delay_position = (self.delay_position + 1) % self.delay.len();
+            delayed
+        };
+
+        let taps = self.weights.len();
+        let oldest = self.history[self.history_position];
+        self.history[self.history_position] = reference;
+        self.history[self.history_position + taps] = reference;
+        self.history_position = (self.history_position + 1) % taps;
+        self.reference_power =
+            (self.reference_power + reference * reference - oldest * oldest).max(0.0);
+        let window = &self.history[self.history_position..self.history_position + taps];
+
+        let echo: f32 = self.weights.iter().zip(window).map(|(w, x)| w * x).sum();
+        let error = microphone - echo;
+
+        self.reference_peak = reference.abs().max(self.reference_peak * self.peak_decay);
+        if microphone.abs() > DOUBLE_TALK_THRESHOLD * self.reference_peak {
+            self.double_talk_remaining = self.double_talk_hold_samples;
+        } else if self.double_talk_remaining > 0 {
+            self.double_talk_remaining -= 1;
+        }
+
+        if self.double_talk_remaining == 0
+            && self.reference_power > MIN_REFERENCE_POWER * taps as f32
+        {
+            let step = self.adaptation_rate * error / self.reference_power;
+            for (weight, x) in self.weights.iter_mut().zip(window) {
+                *weight += step * x;
+            }
+        }
+
+        if self.suppression <= 0.0 {
+            return error;
+        }
+        self.error_power = one_pole_step(self.error_power, error * error, self.power_coeff);
+        self.echo_power = one_pole_step(self.echo_power, echo * echo, self.power_coeff);
+        let residual = self.suppression * self.echo_power;
+        let target = self.error_power / (self.error_power + residual + f32::EPSILON);
+        self.suppressor_gain = if target < self.suppressor_gain {
+            target
+        } else {
+            one_pole_step(self.suppressor_gain, target, self.release_coeff)
+        };
+        error * self.suppressor_gain
+    }
+}
+
+/// Records what a bus plays, as the reference of an
+/// [AudioEffectVoipEchoCanceller]. Passes the audio through unchanged.
+///
+/// [AudioEffectVoipEchoCanceller] adds it to its
+/// [member AudioEffectVoipEchoCanceller.reference_bus] if it's missing.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoipEchoReference {
+    pub(crate) base: Base<AudioEffect>,
+    buffer: EchoReferenceBufferRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoipEchoReference {
+    fn init(base: Base<AudioEffect>) -> Self {
+        Self {
+            base,
+            buffer: Arc::default(),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        let mut effect = AudioEffectVoipEchoReferenceInstance::new_gd();
+        effect.bind_mut().buffer = self.buffer.clone();
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoipEchoReferenceInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    buffer: EchoReferenceBufferRef,
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoipEchoReferenceInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        if let Ok(mut samples) = self.buffer.samples.lock() {
+            // Nothing reads it while the microphone bus is idle.
+            let max_len = AudioServer::singleton().get_mix_rate().max(1.0) as usize;
+            let excess = (samples.len() + frame_count).saturating_sub(max_len);
+            samples.drain(..excess.min(samples.len()));
+            samples.extend(
+                input_slice
+                    .iter()
+                    .map(|frame| (frame.left + frame.right) * 0.5),
+            );
+        }
+        output_slice.copy_from_slice(input_slice);
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        Self {
+            base,
+            buffer: Arc::default(),
+        }
+    }
+}
+
+/// Acoustic echo cancellation for the microphone bus.
+///
+/// Removes what the game plays on [member reference_bus] from the
+/// microphone, so players without headphones don't send everyone's voice
+/// back. An [AudioEffectVoipEchoReference] on the reference bus records
+/// what's played; one is added there if it's missing.
+///
+/// [member reference_delay_ms] should roughly match the time from the
+/// reference bus to the microphone, i.e. the output and input latency. The
+/// filter then only has to cover [member filter_length_ms] of echo around
+/// it, which costs less CPU than a longer filter.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoipEchoCanceller {
+    pub(crate) base: Base<AudioEffect>,
+    /// Bus whose output is removed from the microphone, usually the one
+    /// voices and game audio end up on.
+    #[export]
+    #[var(get = get_reference_bus, set = set_reference_bus)]
+    reference_bus: StringName,
+    /// Time between the reference bus playing a sound and its echo
+    /// reaching this effect, in milliseconds.
+    #[export(range = (0.0, 500.0, suffix = "ms"))]
+    #[var(get = get_reference_delay_ms, set = set_reference_delay_ms)]
+    reference_delay_ms: f32,
+    /// Length of the echo modeled after [member reference_delay_ms], in
+    /// milliseconds. Longer covers more reverberant rooms and delay
+    /// errors, at more CPU.
+    #[export(range = (1.0, 100.0, suffix = "ms"))]
+    #[var(get = get_filter_length_ms, set = set_filter_length_ms)]
+    filter_length_ms: f32,
+    /// How fast the filter follows changes of the echo path, from 0.0
+    /// (frozen) to 1.0. Lower is more stable while both sides talk.
+    #[export(range = (0.0, 1.0, 0.01))]
+    #[var(get = get_adaptation_rate, set = set_adaptation_rate)]
+    adaptation_rate: f32,
+    /// How strongly the echo left after the filter is suppressed. 0.0
+    /// turns the suppressor off; higher removes more echo but makes the
+    /// local player's voice duck while the far end talks.
+    #[export(range = (0.0, 2.0, 0.01, or_greater))]
+    #[var(get = get_suppression, set = set_suppression)]
+    suppression: f32,
+    shared_config: EchoCancellerSharedConfigRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoipEchoCanceller {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = EchoCancellerParams::default();
+        Self {
+            base,
+            reference_bus: StringName::from("Master"),
+            reference_delay_ms: params.reference_delay_ms,
+            filter_length_ms: params.filter_length_ms,
+            adaptation_rate: params.adaptation_rate,
+            suppression: params.suppression,
+            shared_config: Arc::new(Mutex::new(EchoCancellerSharedConfig {
+                params,
+                reference: None,
+                revision: 0,
+            })),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+        // The bus layout may still be loading, so the reference is linked
+        // once it's done.
+        self.base_mut().call_deferred("_link_reference", &[]);
+
+        let mut effect = AudioEffectVoipEchoCancellerInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectVoipEchoCanceller {
+    /// Uses the first [AudioEffectVoipEchoReference] on
+    /// [member reference_bus], adding one if there's none.
+    #[func]
+    fn _link_reference(&mut self) {
+        let reference = self.find_or_add_reference();
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.reference = reference;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    fn find_or_add_reference(&self) -> Option<EchoReferenceBufferRef> {
+        if self.reference_bus.is_empty() {
+            return None;
+        }
+        let mut server = AudioServer::singleton();
+        let bus_idx = server.get_bus_index(&self.reference_bus);
+        if bus_idx < 0 {
+            return None;
+        }
+
+        let existing = (0..server.get_bus_effect_count(bus_idx)).find_map(|i| {
+            server
+                .get_bus_effect(bus_idx, i)
+                .and_then(|effect| effect.try_cast::<AudioEffectVoipEchoReference>().ok())
+        });
+        let reference = existing.unwrap_or_else(|| {
+            let reference = AudioEffectVoipEchoReference::new_gd();
+            server.add_bus_effect(bus_idx, &reference);
+            reference
+        });
+        let buffer = reference.bind().buffer.clone();
+        Some(buffer)
+    }
+
+    fn push_config_to_shared(&mut self) {
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params.reference_delay_ms = self.reference_delay_ms;
+            cfg.params.filter_length_ms = self.filter_length_ms;
+            cfg.params.adaptation_rate = self.adaptation_rate;
+            cfg.params.suppression = self.suppression;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_reference_bus(&self) -> StringName {
+        self.reference_bus.clone()
+    }
+
+    #[func]
+    fn set_reference_bus(&mut self, value: StringName) {
+        if value == self.reference_bus {
+            return;
+        }
+        self.reference_bus = value;
+        self.base_mut().call_deferred("_link_reference", &[]);
+    }
+
+    #[func]
+    fn get_reference_delay_ms(&self) -> f32 {
+        self.reference_delay_ms
+    }
+
+    #[func]
+    fn set_reference_delay_ms(&mut self, value: f32) {
+        self.reference_delay_ms = value.clamp(0.0, MAX_REFERENCE_DELAY_MS);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_filter_length_ms(&self) -> f32 {
+        self.filter_length_ms
+    }
+
+    #[func]
+    fn set_filter_length_ms(&mut self, value: f32) {
+        self.filter_length_ms = value.clamp(1.0, MAX_FILTER_LENGTH_MS);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_adaptation_rate(&self) -> f32 {
+        self.adaptation_rate
+    }
+
+    #[func]
+    fn set_adaptation_rate(&mut self, value: f32) {
+        self.adaptation_rate = value.clamp(0.0, 1.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_suppression(&self) -> f32 {
+        self.suppression
+    }
+
+    #[func]
+    fn set_suppression(&mut self, value: f32) {
+        self.suppression = value.max(0.0);
+        self.push_config_to_shared();
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoipEchoCancellerInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: EchoCancellerSharedConfigRef,
+    applied_revision: u64,
+    reference: Option<EchoReferenceBufferRef>,
+    canceller: EchoCanceller,
+    /// Reference samples of the current block.
+    reference_block: Vec<f32>,
+}
+
+impl AudioEffectVoipEchoCancellerInstance {
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        self.reference = cfg.reference.clone();
+        drop(cfg);
+
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.canceller.configure(&params, sample_rate);
+        self.applied_revision = revision;
+    }
+
+    /// Fills `reference_block` with the next `frame_count` reference
+    /// samples, or silence if there are none yet.
+    fn take_reference_block(&mut self, frame_count: usize) {
+        self.reference_block.clear();
+        if let Some(Ok(mut samples)) = self.reference.as_ref().map(|r| r.samples.lock()) {
+            // The reference bus usually runs after this one, so one block
+            // is normally waiting. Drop anything older to stay in sync.
+            let excess = samples.len().saturating_sub(frame_count * 2);
+            samples.drain(..excess);
+            let available = samples.len().min(frame_count);
+            self.reference_block.extend(samples.drain(..available));
+        }
+        self.reference_block.resize(frame_count, 0.0);
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoipEchoCancellerInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        self.take_reference_block(frame_count);
+        for ((in_frame, out_frame), &reference) in input_slice
+            .iter()
+            .zip(output_slice.iter_mut())
+            .zip(&self.reference_block)
+        {
+            let cleaned = self
+                .canceller
+                .process((in_frame.left + in_frame.right) * 0.5, reference);
+            out_frame.left = cleaned;
+            out_frame.right = cleaned;
+        }
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let mut canceller = EchoCanceller::default();
+        canceller.configure(&EchoCancellerParams::default(), sample_rate);
+
+        Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            reference: None,
+            canceller,
+            reference_block: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic white noise in [-0.5, 0.5).
+    fn noise(seed: &mut u32) -> f32 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+    }
+
+    #[test]
+    fn echo_is_cancelled_but_near_end_speech_kept() {
+        let sample_rate = 16_000.0;
+        let params = EchoCancellerParams {
+            reference_delay_ms: 5.0,
+            filter_length_ms: 10.0,
+            suppression: 0.0,
+            ..Default::default()
+        };
+        let mut canceller = EchoCanceller::default();
+        canceller.configure(&params, sample_rate);
+
+        // Echo arrives 8 ms late: 5 ms of bulk delay plus 3 ms inside the
+        // filter, at -12 dB.
+        let mut echo_path = VecDeque::from(vec![0.0; 128]);
+        let mut seed = 1;
+        let (mut echo_power, mut residual_power) = (0.0, 0.0);
+        for i in 0..32_000 {
+            let reference = noise(&mut seed);
+            echo_path.push_back(reference);
+            let echo = 0.25 * echo_path.pop_front().unwrap_or(0.0);
+            let out = canceller.process(echo, reference);
+            if i >= 24_000 {
+                echo_power += echo * echo;
+                residual_power += out * out;
+            }
+        }
+        let erle_db = 10.0 * (echo_power / residual_power).log10();
+        assert!(erle_db > 25.0, "erle_db={erle_db}");
+
+        // Loud local speech passes, and isn't learned as echo.
+        let mut speech_power = 0.0;
+        let mut out_power = 0.0;
+        for i in 0..1_600 {
+            let reference = noise(&mut seed);
+            echo_path.push_back(reference);
+            let echo = 0.25 * echo_path.pop_front().unwrap_or(0.0);
+            let speech = if i % 40 < 20 { 0.8 } else { -0.8 };
+            let out = canceller.process(echo + speech, reference);
+            speech_power += speech * speech;
+            out_power += (out - speech) * (out - speech);
+        }
+        assert!(out_power < speech_power * 0.01);
+    }
+}
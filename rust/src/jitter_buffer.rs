@@ -0,0 +1,445 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use godot::prelude::*;
+
+use crate::voip_packet::sequence_delta;
+
+/// Upper bound on held packets, so a long gap can't grow the queue forever.
+const MAX_HELD_PACKETS: usize = 64;
+/// Released sequences remembered for duplicate detection.
+const HISTORY_PACKETS: i16 = 64;
+/// Sequence jumps larger than this are treated as a restarted sender.
+const RESYNC_DISTANCE: i16 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct JitterStats {
+    received: u64,
+    released: u64,
+    lost: u64,
+    late: u64,
+    duplicate: u64,
+    reordered: u64,
+    recovered: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PushOutcome {
+    Queued,
+    Late,
+    Duplicate,
+}
+
+#[derive(Debug)]
+struct Slot {
+    payload: Option<Vec<u8>>,
+    /// Arrival time for packets; for gaps, the time the gap was noticed.
+    since_ms: u64,
+}
+
+/// A packet leaving the buffer in sequence order. `payload` is `None` for
+/// packets that never arrived in time.
+#[derive(Debug, PartialEq, Eq)]
+struct ReleasedPacket {
+    sequence: u16,
+    payload: Option<Vec<u8>>,
+}
+
+/// Puts packets back into sequence order and waits a bounded time for
+/// missing ones.
+#[derive(Debug, Default)]
+struct ReorderQueue {
+    /// Sequence of the front slot.
+    next_sequence: Option<u16>,
+    slots: VecDeque<Slot>,
+    /// Bit `i` is set if sequence `next_sequence - 1 - i` was released with
+    /// a payload.
+    history: u64,
+    /// Sequences that went missing since the last [`Self::take_missing`].
+    missing: Vec<u16>,
+    stats: JitterStats,
+}
+
+impl ReorderQueue {
+    /// Queues a packet. `retransmitted` marks packets that were re-requested
+    /// after going missing, which fill their gap as recovered rather than
+    /// reordered.
+    fn push(
+        &mut self,
+        sequence: u16,
+        payload: Vec<u8>,
+        retransmitted: bool,
+        now_ms: u64,
+    ) -> PushOutcome {
+        self.stats.received += 1;
+        let Some(next) = self.next_sequence else {
+            self.restart(sequence, payload, now_ms);
+            return PushOutcome::Queued;
+        };
+
+        let delta = sequence_delta(sequence, next);
+        if delta < -RESYNC_DISTANCE || delta > RESYNC_DISTANCE {
+            self.restart(sequence, payload, now_ms);
+            return PushOutcome::Queued;
+        }
+
+        if delta < 0 {
+            let age = -delta - 1;
+            if age < HISTORY_PACKETS && self.history & (1 << age) != 0 {
+                self.stats.duplicate += 1;
+                return PushOutcome::Duplicate;
+            }
+            self.stats.late += 1;
+            return PushOutcome::Late;
+        }
+
+        let index = delta as usize;
+        if let Some(slot) = self.slots.get_mut(index) {
+            if slot.payload.is_some() {
+                self.stats.duplicate += 1;
+                return PushOutcome::Duplicate;
+            }
+            // A newer packet arrived before this one.
+            slot.payload = Some(payload);
+            slot.since_ms = now_ms;
+            if retransmitted {
+                self.stats.recovered += 1;
+            } else {
+                self.stats.reordered += 1;
+            }
+            return PushOutcome::Queued;
+        }
+
+        while self.slots.len() < index {
+            self.missing
+                .push(next.wrapping_add(self.slots.len() as u16));
+            self.slots.push_back(Slot {
+                payload: None,
+                since_ms: now_ms,
+            });
+        }
+        self.slots.push_back(Slot {
+            payload: Some(payload),
+            since_ms: now_ms,
+        });
+        PushOutcome::Queued
+    }
+
+    /// Releases packets that are ready, giving up on gaps older than
+    /// `max_hold_ms`.
+    fn poll(&mut self, now_ms: u64, max_hold_ms: u64) -> Vec<ReleasedPacket> {
+        let mut released = Vec::new();
+        while let Some(front) = self.slots.front() {
+            let gap_expired = now_ms.saturating_sub(front.since_ms) >= max_hold_ms
+                || self.slots.len() > MAX_HELD_PACKETS;
+            if front.payload.is_none() && !gap_expired {
+                break;
+            }
+
+            let Some(slot) = self.slots.pop_front() else {
+                break;
+            };
+            let Some(sequence) = self.next_sequence else {
+                break;
+            };
+            if slot.payload.is_some() {
+                self.stats.released += 1;
+            } else {
+                self.stats.lost += 1;
+            }
+            self.history = (self.history << 1) | slot.payload.is_some() as u64;
+            self.next_sequence = Some(sequence.wrapping_add(1));
+            released.push(ReleasedPacket {
+                sequence,
+                payload: slot.payload,
+            });
+        }
+        released
+    }
+
+    fn take_missing(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.missing)
+    }
+
+    fn held_count(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.payload.is_some())
+            .count()
+    }
+
+    fn restart(&mut self, sequence: u16, payload: Vec<u8>, now_ms: u64) {
+        self.next_sequence = Some(sequence);
+        self.history = 0;
+        self.missing.clear();
+        self.slots.clear();
+        self.slots.push_back(Slot {
+            payload: Some(payload),
+            since_ms: now_ms,
+        });
+    }
+
+    fn clear(&mut self) {
+        self.next_sequence = None;
+        self.history = 0;
+        self.missing.clear();
+        self.slots.clear();
+    }
+}
+
+/// Restores the order of one peer's voice packets.
+///
+/// [method push] every received packet with its sequence number, then
+/// [method poll] for the packets that are ready to decode. Packets arriving
+/// out of order are held until the missing ones arrive or
+/// [member max_hold_ms] passes; a missing packet is then released with an
+/// empty payload, so the decoder can conceal it.
+///
+/// Late, duplicate and reordered packets are counted in [method get_stats],
+/// which helps telling network problems apart from audio pipeline bugs.
+///
+/// With [member nack_enabled], [signal packet_missing] is emitted as soon as
+/// a gap is noticed and gaps are held for [member nack_hold_ms], leaving time
+/// to re-request the packet over a reliable channel. Pass the answer to
+/// [method push_retransmitted]; it is merged in order if the gap is still
+/// held.
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub(crate) struct VoipJitterBuffer {
+    /// How long a missing packet is waited for before it's given up, in
+    /// milliseconds.
+    #[var]
+    max_hold_ms: i64,
+    /// Emit [signal packet_missing] and hold gaps for [member nack_hold_ms].
+    #[var]
+    nack_enabled: bool,
+    /// How long gaps are held while [member nack_enabled] is set, in
+    /// milliseconds. Should cover one round trip of the reliable channel.
+    #[var]
+    nack_hold_ms: i64,
+    queue: ReorderQueue,
+    epoch: Instant,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for VoipJitterBuffer {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            max_hold_ms: 60,
+            nack_enabled: false,
+            nack_hold_ms: 120,
+            queue: ReorderQueue::default(),
+            epoch: Instant::now(),
+            base,
+        }
+    }
+}
+
+impl VoipJitterBuffer {
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    fn push_packet(
+        &mut self,
+        sequence: i64,
+        payload: PackedByteArray,
+        retransmitted: bool,
+    ) -> bool {
+        let now_ms = self.now_ms();
+        let outcome = self
+            .queue
+            .push(sequence as u16, payload.to_vec(), retransmitted, now_ms);
+
+        let missing = self.queue.take_missing();
+        if self.nack_enabled {
+            for sequence in missing {
+                self.signals().packet_missing().emit(sequence as i64);
+            }
+        }
+        outcome == PushOutcome::Queued
+    }
+
+    fn to_dictionaries(released: Vec<ReleasedPacket>) -> Array<Dictionary> {
+        let mut out = Array::new();
+        for packet in released {
+            let lost = packet.payload.is_none();
+            let payload = PackedByteArray::from(packet.payload.unwrap_or_default());
+            let mut item = Dictionary::new();
+            item.set("sequence", packet.sequence as i64);
+            item.set("payload", payload);
+            item.set("lost", lost);
+            out.push(&item);
+        }
+        out
+    }
+}
+
+#[godot_api]
+impl VoipJitterBuffer {
+    /// Emitted with [member nack_enabled] when a packet is noticed missing.
+    #[signal]
+    fn packet_missing(sequence: i64);
+
+    /// Adds a received packet. Returns false if it was dropped because it
+    /// arrived too late or was a duplicate.
+    #[func]
+    fn push(&mut self, sequence: i64, payload: PackedByteArray) -> bool {
+        self.push_packet(sequence, payload, false)
+    }
+
+    /// Adds a packet that was re-requested after [signal packet_missing].
+    /// Returns false if its gap was already given up.
+    #[func]
+    fn push_retransmitted(&mut self, sequence: i64, payload: PackedByteArray) -> bool {
+        self.push_packet(sequence, payload, true)
+    }
+
+    /// Returns the packets that are ready, in sequence order, as dictionaries
+    /// with `sequence`, `payload` and `lost` keys. Call this after every
+    /// [method push] and once per frame, so gaps time out.
+    #[func]
+    fn poll(&mut self) -> Array<Dictionary> {
+        let now_ms = self.now_ms();
+        let mut max_hold_ms = self.max_hold_ms.max(0) as u64;
+        if self.nack_enabled {
+            max_hold_ms = max_hold_ms.max(self.nack_hold_ms.max(0) as u64);
+        }
+        Self::to_dictionaries(self.queue.poll(now_ms, max_hold_ms))
+    }
+
+    /// Releases everything that is held, giving up on all missing packets.
+    #[func]
+    fn flush(&mut self) -> Array<Dictionary> {
+        let now_ms = self.now_ms();
+        Self::to_dictionaries(self.queue.poll(now_ms, 0))
+    }
+
+    /// Drops held packets and forgets the sequence position. Statistics are
+    /// kept.
+    #[func]
+    fn reset(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Returns how many packets are waiting for a missing predecessor.
+    #[func]
+    fn get_held_count(&self) -> i64 {
+        self.queue.held_count() as i64
+    }
+
+    /// Returns packet counters since creation or the last
+    /// [method reset_stats]: `received`, `released`, `lost`, `late`,
+    /// `duplicate`, `reordered` and `recovered` (retransmitted packets that
+    /// filled their gap in time).
+    #[func]
+    fn get_stats(&self) -> Dictionary {
+        let stats = self.queue.stats;
+        let mut out = Dictionary::new();
+        out.set("received", stats.received as i64);
+        out.set("released", stats.released as i64);
+        out.set("lost", stats.lost as i64);
+        out.set("late", stats.late as i64);
+        out.set("duplicate", stats.duplicate as i64);
+        out.set("reordered", stats.reordered as i64);
+        out.set("recovered", stats.recovered as i64);
+        out
+    }
+
+    /// Sets all packet counters back to zero.
+    #[func]
+    fn reset_stats(&mut self) {
+        self.queue.stats = JitterStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequences(released: &[ReleasedPacket]) -> Vec<(u16, bool)> {
+        released
+            .iter()
+            .map(|packet| (packet.sequence, packet.payload.is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn restores_order_of_reordered_packets() {
+        let mut queue = ReorderQueue::default();
+        queue.push(10, vec![10], false, 0);
+        assert_eq!(sequences(&queue.poll(0, 60)), vec![(10, true)]);
+
+        queue.push(12, vec![12], false, 5);
+        assert!(queue.poll(5, 60).is_empty());
+        assert_eq!(queue.push(11, vec![11], false, 10), PushOutcome::Queued);
+        assert_eq!(sequences(&queue.poll(10, 60)), vec![(11, true), (12, true)]);
+        assert_eq!(queue.stats.reordered, 1);
+        assert_eq!(queue.stats.released, 3);
+    }
+
+    #[test]
+    fn gives_up_on_missing_packets_after_hold_time() {
+        let mut queue = ReorderQueue::default();
+        queue.push(1, vec![1], false, 0);
+        queue.poll(0, 60);
+        queue.push(3, vec![3], false, 0);
+        assert!(queue.poll(59, 60).is_empty());
+        assert_eq!(sequences(&queue.poll(60, 60)), vec![(2, false), (3, true)]);
+        assert_eq!(queue.stats.lost, 1);
+
+        // The missing packet finally shows up.
+        assert_eq!(queue.push(2, vec![2], false, 70), PushOutcome::Late);
+        assert_eq!(queue.stats.late, 1);
+    }
+
+    #[test]
+    fn counts_duplicates_before_and_after_release() {
+        let mut queue = ReorderQueue::default();
+        queue.push(100, vec![1], false, 0);
+        queue.push(102, vec![3], false, 0);
+        assert_eq!(queue.push(102, vec![3], false, 0), PushOutcome::Duplicate);
+        queue.poll(0, 60);
+        assert_eq!(queue.push(100, vec![1], false, 0), PushOutcome::Duplicate);
+        assert_eq!(queue.stats.duplicate, 2);
+        assert_eq!(queue.stats.late, 0);
+    }
+
+    #[test]
+    fn handles_wraparound_and_sender_restart() {
+        let mut queue = ReorderQueue::default();
+        queue.push(65_535, vec![1], false, 0);
+        queue.push(0, vec![2], false, 0);
+        assert_eq!(
+            sequences(&queue.poll(0, 60)),
+            vec![(65_535, true), (0, true)]
+        );
+
+        queue.push(30_000, vec![3], false, 0);
+        assert_eq!(sequences(&queue.poll(0, 60)), vec![(30_000, true)]);
+        assert_eq!(queue.stats.lost, 0);
+    }
+
+    #[test]
+    fn reports_gaps_and_merges_retransmissions() {
+        let mut queue = ReorderQueue::default();
+        queue.push(1, vec![1], false, 0);
+        queue.poll(0, 120);
+        queue.push(4, vec![4], false, 10);
+        assert_eq!(queue.take_missing(), vec![2, 3]);
+        assert!(queue.take_missing().is_empty());
+
+        assert_eq!(queue.push(2, vec![2], true, 80), PushOutcome::Queued);
+        assert_eq!(sequences(&queue.poll(80, 120)), vec![(2, true)]);
+        assert_eq!(
+            sequences(&queue.poll(130, 120)),
+            vec![(3, false), (4, true)]
+        );
+        assert_eq!(queue.stats.recovered, 1);
+        assert_eq!(queue.stats.reordered, 0);
+
+        assert_eq!(queue.push(3, vec![3], true, 140), PushOutcome::Late);
+    }
+}
@@ -0,0 +1,131 @@
+use godot::prelude::*;
+
+use crate::dsp_util::{gain_to_db, ms_to_coeff, one_pole_step, EnvelopeFollower};
+
+/// Peak meters jump up instantly and fall back over this time.
+const PEAK_RELEASE_MS: f32 = 300.0;
+/// Averaging time of the RMS meter, close to VU ballistics.
+const RMS_WINDOW_MS: f32 = 300.0;
+
+#[derive(Debug, Default)]
+struct MeterState {
+    sample_rate: f32,
+    peak: EnvelopeFollower,
+    mean_square_coeff: f32,
+    mean_square: f32,
+}
+
+impl MeterState {
+    fn process(&mut self, frames: &[Vector2], sample_rate: f32) {
+        if self.sample_rate != sample_rate {
+            self.sample_rate = sample_rate;
+            self.peak.set_times(0.0, PEAK_RELEASE_MS, sample_rate);
+            self.mean_square_coeff = ms_to_coeff(RMS_WINDOW_MS, sample_rate);
+        }
+
+        for frame in frames {
+            let sample = frame.x.abs().max(frame.y.abs());
+            self.peak.process(sample);
+            self.mean_square =
+                one_pole_step(self.mean_square, sample * sample, self.mean_square_coeff);
+        }
+    }
+
+    fn peak(&self) -> f32 {
+        self.peak.value
+    }
+
+    fn rms(&self) -> f32 {
+        self.mean_square.max(0.0).sqrt()
+    }
+}
+
+/// Smoothed peak and RMS levels of an audio signal, for drawing meters.
+///
+/// Feed it audio with [method process] and read the levels at any rate.
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub(crate) struct VoipLevelMeter {
+    state: MeterState,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for VoipLevelMeter {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            state: MeterState::default(),
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl VoipLevelMeter {
+    /// Updates the levels with new audio.
+    #[func]
+    fn process(&mut self, pcm: PackedVector2Array, sample_rate: i32) {
+        self.state
+            .process(pcm.as_slice(), sample_rate.max(1) as f32);
+    }
+
+    /// Returns the smoothed peak level in dBFS.
+    #[func]
+    fn get_peak_db(&self) -> f32 {
+        gain_to_db(self.state.peak())
+    }
+
+    /// Returns the smoothed RMS level in dBFS.
+    #[func]
+    fn get_rms_db(&self) -> f32 {
+        gain_to_db(self.state.rms())
+    }
+
+    /// Returns the smoothed peak level as linear amplitude (0.0 to 1.0).
+    #[func]
+    fn get_peak(&self) -> f32 {
+        self.state.peak()
+    }
+
+    /// Returns the smoothed RMS level as linear amplitude (0.0 to 1.0).
+    #[func]
+    fn get_rms(&self) -> f32 {
+        self.state.rms()
+    }
+
+    /// Drops back to silence.
+    #[func]
+    fn reset(&mut self) {
+        self.state.peak.value = 0.0;
+        self.state.mean_square = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peak_is_instant_and_decays() {
+        let mut meter = MeterState::default();
+        meter.process(&[Vector2::new(0.2, -0.8)], 48_000.0);
+        assert_eq!(meter.peak(), 0.8);
+
+        meter.process(&vec![Vector2::new(0.0, 0.0); 14_400], 48_000.0);
+        assert!(meter.peak() < 0.8 * 0.4 && meter.peak() > 0.0);
+    }
+
+    #[test]
+    fn rms_settles_on_square_wave_level() {
+        let mut meter = MeterState::default();
+        let square: Vec<Vector2> = (0..96_000)
+            .map(|i| {
+                let s = if i % 100 < 50 { 0.5 } else { -0.5 };
+                Vector2::new(s, s)
+            })
+            .collect();
+        meter.process(&square, 48_000.0);
+        assert!((meter.rms() - 0.5).abs() < 0.01, "rms={}", meter.rms());
+    }
+}
@@ -1,12 +1,37 @@
 use godot::prelude::*;
 
+mod agc_audio_effect;
+mod clip_detector_audio_effect;
+mod compressor_audio_effect;
 mod deep_filter_net_audio_effect;
+mod dsp_util;
+mod ducker_audio_effect;
+mod echo_canceller_audio_effect;
+mod jitter_buffer;
+mod level_meter;
+mod limiter_audio_effect;
 mod noise_gate_audio_effect;
+mod noise_gate_preset;
 mod opus_codec;
+mod radio_voice_audio_effect;
 mod resampler;
 mod rnnoise_audio_effect;
+mod transmit_gate;
+mod voice_anonymizer_audio_effect;
+mod voice_clip;
+mod voice_recorder;
+mod voip_cipher;
+mod voip_packet;
 
 struct MyExtension;
 
 #[gdextension]
-unsafe impl ExtensionLibrary for MyExtension {}
+unsafe impl ExtensionLibrary for MyExtension {
+    fn on_level_deinit(level: InitLevel) {
+        if level == InitLevel::Scene {
+            // Worker threads must not outlive the library, e.g. when the
+            // editor hot-reloads it.
+            deep_filter_net_audio_effect::stop_all_threads();
+        }
+    }
+}
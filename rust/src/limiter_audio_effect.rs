@@ -0,0 +1,458 @@
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{db_to_gain, ms_to_coeff, ms_to_samples, one_pole_step};
+
+/// Longest lookahead, as the delay adds to the voice latency.
+const MAX_LOOKAHEAD_MS: f32 = 10.0;
+
+#[derive(Debug, Clone)]
+struct LimiterParams {
+    ceiling_db: f32,
+    knee_db: f32,
+    lookahead_ms: f32,
+    release_ms: f32,
+}
+
+impl Default for LimiterParams {
+    fn default() -> Self {
+        Self {
+            ceiling_db: -1.0,
+            knee_db: 2.0,
+            lookahead_ms: 3.0,
+            release_ms: 80.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterSharedConfig {
+    params: LimiterParams,
+    revision: u64,
+}
+
+type LimiterSharedConfigRef = Arc<Mutex<LimiterSharedConfig>>;
+
+/// Estimates the level halfway between the middle two of four samples,
+/// which can exceed both of them once the signal is converted to analog.
+fn midpoint_peak(samples: [f32; 4]) -> f32 {
+    ((9.0 * (samples[1] + samples[2]) - samples[0] - samples[3]) / 16.0).abs()
+}
+
+/// Passes samples below `knee_start` unchanged and bends larger ones
+/// smoothly toward `ceiling`, which they never exceed.
+fn soft_clip(sample: f32, knee_start: f32, ceiling: f32) -> f32 {
+    let magnitude = sample.abs();
+    if knee_start >= ceiling {
+        return sample.clamp(-ceiling, ceiling);
+    }
+    if magnitude <= knee_start {
+        return sample;
+    }
+    let range = ceiling - knee_start;
+    (knee_start + range * ((magnitude - knee_start) / range).tanh()).copysign(sample)
+}
+
+/// Lookahead limiter. Each sample's gain is the minimum gain any peak
+/// needs within the lookahead, averaged over the lookahead so it ramps
+/// down before the peak instead of jumping. The audio is delayed so the
+/// peak arrives when the gain has reached it. The soft clipper catches
+/// whatever the interpolated peak estimate misses.
+#[derive(Debug)]
+struct LimiterState {
+    ceiling: f32,
+    knee_start: f32,
+    release_coeff: f32,
+    /// Lookahead in samples, at least 1.
+    window: usize,
+    /// Last three input frames, for the inter-sample peak estimate.
+    history: [Vector2; 3],
+    /// Required gains that are still the minimum of some later window,
+    /// with their sample index, rising from front to back.
+    minima: VecDeque<(usize, f32)>,
+    sample_index: usize,
+    /// Window minimums of the last `window` samples, in the first `window`
+    /// entries.
+    held: Vec<f32>,
+    held_position: usize,
+    held_sum: f64,
+    /// Delayed frames in the first `window + 1` entries. One sample more
+    /// than the window, as the peak estimate between two samples is only
+    /// known one sample after the later one.
+    delay: Vec<Vector2>,
+    delay_position: usize,
+    gain: f32,
+}
+
+/// Lookahead in samples, at least 1.
+fn window_samples(lookahead_ms: f32, sample_rate: f32) -> usize {
+    ms_to_samples(lookahead_ms.clamp(0.0, MAX_LOOKAHEAD_MS), sample_rate).max(1)
+}
+
+impl LimiterState {
+    /// Allocates for [`MAX_LOOKAHEAD_MS`] at `sample_rate` up front, so
+    /// [`Self::configure`] never allocates on the audio thread.
+    fn new(sample_rate: f32) -> Self {
+        let max_window = window_samples(MAX_LOOKAHEAD_MS, sample_rate);
+        Self {
+            ceiling: 1.0,
+            knee_start: 1.0,
+            release_coeff: 0.0,
+            window: 0,
+            history: [Vector2::ZERO; 3],
+            // The window, the two samples before it and the new one.
+            minima: VecDeque::with_capacity(max_window + 3),
+            sample_index: 0,
+            held: vec![1.0; max_window],
+            held_position: 0,
+            held_sum: 0.0,
+            delay: vec![Vector2::ZERO; max_window + 1],
+            delay_position: 0,
+            gain: 1.0,
+        }
+    }
+
+    fn configure(&mut self, params: &LimiterParams, sample_rate: f32) {
+        let ceiling_db = params.ceiling_db.min(0.0);
+        self.ceiling = db_to_gain(ceiling_db);
+        self.knee_start = db_to_gain(ceiling_db - params.knee_db.max(0.0));
+        self.release_coeff = ms_to_coeff(params.release_ms, sample_rate);
+
+        let window = window_samples(params.lookahead_ms, sample_rate).min(self.held.len());
+        if window != self.window {
+            self.window = window;
+            self.minima.clear();
+            self.held[..window].fill(1.0);
+            self.held_position = 0;
+            self.held_sum = window as f64;
+            self.delay[..=window].fill(Vector2::ZERO);
+            self.delay_position = 0;
+            self.gain = 1.0;
+        }
+    }
+
+    fn latency_samples(&self) -> usize {
+        self.window + 1
+    }
+
+    fn process(&mut self, frame: Vector2) -> Vector2 {
+        let [a, b, c] = self.history;
+        let peak = frame
+            .x
+            .abs()
+            .max(frame.y.abs())
+            .max(midpoint_peak([a.x, b.x, c.x, frame.x]))
+            .max(midpoint_peak([a.y, b.y, c.y, frame.y]));
+        self.history = [b, c, frame];
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        // Minimum over the window, plus the two samples the midpoint
+        // estimate of this sample lies between.
+        while self
+            .minima
+            .back()
+            .is_some_and(|&(_, gain)| gain >= required)
+        {
+            self.minima.pop_back();
+        }
+        self.minima.push_back((self.sample_index, required));
+        while self
+            .minima
+            .front()
+            .is_some_and(|&(index, _)| index + self.window + 2 <= self.sample_index)
+        {
+            self.minima.pop_front();
+        }
+        let minimum = self.minima.front().map_or(1.0, |&(_, gain)| gain);
+        self.sample_index += 1;
+
+        self.held_sum += (minimum - self.held[self.held_position]) as f64;
+        self.held[self.held_position] = minimum;
+        self.held_position = (self.held_position + 1) % self.window;
+        let target = (self.held_sum / self.window as f64) as f32;
+        self.gain = if target < self.gain {
+            target
+        } else {
+            one_pole_step(self.gain, target, self.release_coeff)
+        };
+
+        let delayed = std::mem::replace(&mut self.delay[self.delay_position], frame);
+        self.delay_position = (self.delay_position + 1) % (self.window + 1);
+        Vector2::new(
+            soft_clip(delayed.x * self.gain, self.knee_start, self.ceiling),
+            soft_clip(delayed.y * self.gain, self.knee_start, self.ceiling),
+        )
+    }
+}
+
+/// Brickwall limiter for the end of a voice bus.
+///
+/// Keeps the output below [member ceiling_db], including the peaks
+/// between samples, so a denoiser followed by AGC boost can't clip. It
+/// looks ahead [member lookahead_ms] to turn the gain down smoothly before
+/// a peak, and soft-clips within [member knee_db] of the ceiling.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoipLimiter {
+    pub(crate) base: Base<AudioEffect>,
+    /// Highest output level, in dBFS.
+    #[export(range = (-24.0, 0.0, suffix = "dB"))]
+    #[var(get = get_ceiling_db, set = set_ceiling_db)]
+    ceiling_db: f32,
+    /// How far below [member ceiling_db] the soft clipping starts, in dB.
+    /// 0 clips hard.
+    #[export(range = (0.0, 12.0, suffix = "dB"))]
+    #[var(get = get_knee_db, set = set_knee_db)]
+    knee_db: f32,
+    /// How far ahead peaks are detected, in milliseconds. Delays the audio
+    /// by as much.
+    #[export(range = (0.0, 10.0, suffix = "ms"))]
+    #[var(get = get_lookahead_ms, set = set_lookahead_ms)]
+    lookahead_ms: f32,
+    /// Time for the gain to recover after a peak, in milliseconds.
+    #[export(range = (1.0, 1000.0, or_greater, suffix = "ms"))]
+    #[var(get = get_release_ms, set = set_release_ms)]
+    release_ms: f32,
+    shared_config: LimiterSharedConfigRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoipLimiter {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = LimiterParams::default();
+        Self {
+            base,
+            ceiling_db: params.ceiling_db,
+            knee_db: params.knee_db,
+            lookahead_ms: params.lookahead_ms,
+            release_ms: params.release_ms,
+            shared_config: Arc::new(Mutex::new(LimiterSharedConfig {
+                params,
+                revision: 0,
+            })),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let mut effect = AudioEffectVoipLimiterInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectVoipLimiter {
+    /// Returns how much the limiter delays audio, in milliseconds.
+    #[func]
+    fn get_latency_ms(&self) -> f32 {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        // Same as LimiterState::latency_samples.
+        let latency_samples = window_samples(self.lookahead_ms, sample_rate) + 1;
+        latency_samples as f32 * 1000.0 / sample_rate
+    }
+
+    fn current_params(&self) -> LimiterParams {
+        LimiterParams {
+            ceiling_db: self.ceiling_db,
+            knee_db: self.knee_db,
+            lookahead_ms: self.lookahead_ms,
+            release_ms: self.release_ms,
+        }
+    }
+
+    fn push_config_to_shared(&mut self) {
+        let params = self.current_params();
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params = params;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_ceiling_db(&self) -> f32 {
+        self.ceiling_db
+    }
+
+    #[func]
+    fn set_ceiling_db(&mut self, value: f32) {
+        self.ceiling_db = value.min(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_knee_db(&self) -> f32 {
+        self.knee_db
+    }
+
+    #[func]
+    fn set_knee_db(&mut self, value: f32) {
+        self.knee_db = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_lookahead_ms(&self) -> f32 {
+        self.lookahead_ms
+    }
+
+    #[func]
+    fn set_lookahead_ms(&mut self, value: f32) {
+        self.lookahead_ms = value.clamp(0.0, MAX_LOOKAHEAD_MS);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_release_ms(&self) -> f32 {
+        self.release_ms
+    }
+
+    #[func]
+    fn set_release_ms(&mut self, value: f32) {
+        self.release_ms = value.max(0.0);
+        self.push_config_to_shared();
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoipLimiterInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: LimiterSharedConfigRef,
+    applied_revision: u64,
+    state: LimiterState,
+}
+
+impl AudioEffectVoipLimiterInstance {
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        drop(cfg);
+
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.state.configure(&params, sample_rate);
+        self.applied_revision = revision;
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoipLimiterInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            let limited = self
+                .state
+                .process(Vector2::new(in_frame.left, in_frame.right));
+            out_frame.left = limited.x;
+            out_frame.right = limited.y;
+        }
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let mut state = LimiterState::new(sample_rate);
+        state.configure(&LimiterParams::default(), sample_rate);
+
+        Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midpoint_peak_exceeds_samples() {
+        assert!(midpoint_peak([-0.5, 1.0, 1.0, -0.5]) > 1.1);
+        assert_eq!(soft_clip(0.5, 0.8, 0.9), 0.5);
+        assert!(soft_clip(-4.0, 0.8, 0.9) >= -0.9);
+    }
+
+    #[test]
+    fn gain_ramps_down_before_a_step() {
+        let params = LimiterParams {
+            knee_db: 0.0,
+            ..Default::default()
+        };
+        let mut state = LimiterState::new(48_000.0);
+        state.configure(&params, 48_000.0);
+        let ceiling = state.ceiling;
+
+        let mut outputs = Vec::new();
+        for i in 0..4_800 {
+            let level = if i < 2_400 { 0.5 } else { 2.0 };
+            let out = state.process(Vector2::new(level, level));
+            if i == 2_400 + state.latency_samples() {
+                // The step arrives with the gain already down, not clipped.
+                assert!(2.0 * state.gain <= ceiling * 1.0001);
+            }
+            outputs.push(out.x);
+        }
+
+        assert!(outputs.iter().all(|&out| out <= ceiling));
+        // From the first delayed sample to the step.
+        let latency = state.latency_samples();
+        let ramp = &outputs[latency..2_400 + latency];
+        assert!(ramp.windows(2).all(|pair| (pair[1] - pair[0]).abs() < 0.05));
+    }
+
+    #[test]
+    fn configure_keeps_the_buffers() {
+        let mut state = LimiterState::new(48_000.0);
+        let (held, delay) = (state.held.as_ptr(), state.delay.as_ptr());
+        for lookahead_ms in [MAX_LOOKAHEAD_MS, 0.0, 2.5] {
+            let params = LimiterParams {
+                lookahead_ms,
+                ..Default::default()
+            };
+            state.configure(&params, 48_000.0);
+            for _ in 0..1_000 {
+                state.process(Vector2::new(2.0, -2.0));
+            }
+        }
+        assert_eq!(state.latency_samples(), 121);
+        assert_eq!((state.held.as_ptr(), state.delay.as_ptr()), (held, delay));
+    }
+}
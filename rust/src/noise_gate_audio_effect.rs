@@ -1,12 +1,90 @@
 use std::ffi::c_void;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
 
 use godot::classes::{
     AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
 };
 use godot::{classes::native::AudioFrame, prelude::*};
 
-#[derive(Debug, Clone)]
+use crate::dsp_util::{
+    db_to_gain, gain_to_db, ms_to_coeff, ms_to_samples, one_pole_step, Biquad, EnvelopeFollower,
+};
+use crate::ducker_audio_effect::AudioEffectVoipDucker;
+use crate::noise_gate_preset::NoiseGatePreset;
+
+const DETECTOR_PEAK: i32 = 0;
+const DETECTOR_RMS: i32 = 1;
+/// How far above the measured noise floor calibration puts the threshold.
+const CALIBRATION_MARGIN_DB: f32 = 10.0;
+/// Hysteresis set by calibration, so the gate closes halfway to the floor.
+const CALIBRATION_HYSTERESIS_DB: f32 = 5.0;
+/// Longest lookahead, as the delay adds to the voice latency.
+const MAX_LOOKAHEAD_MS: f32 = 10.0;
+/// Openings up to this long get the shortest adaptive release.
+const ADAPTIVE_BURST_MS: f32 = 100.0;
+/// Openings from this long get the longest adaptive release.
+const ADAPTIVE_SUSTAINED_MS: f32 = 1000.0;
+/// Range the adaptive release scales [member release_ms] by.
+const ADAPTIVE_RELEASE_MIN_SCALE: f32 = 0.5;
+const ADAPTIVE_RELEASE_MAX_SCALE: f32 = 2.0;
+
+/// Returns the release time after the gate was open for `open_ms`: short
+/// for clicks and single syllables, so noise doesn't leak after them, and
+/// long after sustained speech, so word tails aren't chopped.
+fn adaptive_release_ms(release_ms: f32, open_ms: f32) -> f32 {
+    let t = ((open_ms - ADAPTIVE_BURST_MS) / (ADAPTIVE_SUSTAINED_MS - ADAPTIVE_BURST_MS))
+        .clamp(0.0, 1.0);
+    release_ms
+        * (ADAPTIVE_RELEASE_MIN_SCALE
+            + (ADAPTIVE_RELEASE_MAX_SCALE - ADAPTIVE_RELEASE_MIN_SCALE) * t)
+}
+
+/// Delays the audio behind the level detector, so the gate can open before
+/// a transient reaches the output.
+#[derive(Debug)]
+struct LookaheadDelay {
+    /// Room for [`MAX_LOOKAHEAD_MS`], of which the first `len` frames are used.
+    buffer: Vec<Vector2>,
+    len: usize,
+    position: usize,
+}
+
+impl LookaheadDelay {
+    /// Allocates the longest lookahead at `sample_rate` up front, so
+    /// [`Self::set_len`] never allocates on the audio thread.
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            buffer: vec![Vector2::ZERO; ms_to_samples(MAX_LOOKAHEAD_MS, sample_rate)],
+            len: 0,
+            position: 0,
+        }
+    }
+
+    /// Sets the delay in frames, at most what was allocated.
+    fn set_len(&mut self, len: usize) {
+        let len = len.min(self.buffer.len());
+        if len != self.len {
+            self.buffer[..len].fill(Vector2::ZERO);
+            self.len = len;
+            self.position = 0;
+        }
+    }
+
+    fn process(&mut self, frame: Vector2) -> Vector2 {
+        if self.len == 0 {
+            return frame;
+        }
+        let delayed = std::mem::replace(&mut self.buffer[self.position], frame);
+        self.position = (self.position + 1) % self.len;
+        delayed
+    }
+}
+
+/// Number of words in [`NoiseGateParams::to_words`].
+const PARAM_WORDS: usize = 15;
+
+#[derive(Debug, Clone, PartialEq)]
 struct NoiseGateParams {
     threshold_db: f32,
     hysteresis_db: f32,
@@ -14,6 +92,15 @@ struct NoiseGateParams {
     release_ms: f32,
     hold_ms: f32,
     floor_db: f32,
+    detector_mode: i32,
+    rms_window_ms: f32,
+    lookahead_ms: f32,
+    detector_filter: bool,
+    detector_low_cut_hz: f32,
+    detector_high_cut_hz: f32,
+    expander_ratio: f32,
+    stereo_link: bool,
+    adaptive_release: bool,
 }
 
 impl Default for NoiseGateParams {
@@ -25,36 +112,222 @@ impl Default for NoiseGateParams {
             release_ms: 120.0,
             hold_ms: 35.0,
             floor_db: -80.0,
+            detector_mode: DETECTOR_PEAK,
+            rms_window_ms: 10.0,
+            lookahead_ms: 0.0,
+            detector_filter: false,
+            detector_low_cut_hz: 150.0,
+            detector_high_cut_hz: 6000.0,
+            expander_ratio: 0.0,
+            stereo_link: true,
+            adaptive_release: false,
         }
     }
 }
 
+impl NoiseGateParams {
+    fn to_words(&self) -> [u32; PARAM_WORDS] {
+        [
+            self.threshold_db.to_bits(),
+            self.hysteresis_db.to_bits(),
+            self.attack_ms.to_bits(),
+            self.release_ms.to_bits(),
+            self.hold_ms.to_bits(),
+            self.floor_db.to_bits(),
+            self.detector_mode as u32,
+            self.rms_window_ms.to_bits(),
+            self.lookahead_ms.to_bits(),
+            self.detector_filter as u32,
+            self.detector_low_cut_hz.to_bits(),
+            self.detector_high_cut_hz.to_bits(),
+            self.expander_ratio.to_bits(),
+            self.stereo_link as u32,
+            self.adaptive_release as u32,
+        ]
+    }
+
+    fn from_words(words: [u32; PARAM_WORDS]) -> Self {
+        Self {
+            threshold_db: f32::from_bits(words[0]),
+            hysteresis_db: f32::from_bits(words[1]),
+            attack_ms: f32::from_bits(words[2]),
+            release_ms: f32::from_bits(words[3]),
+            hold_ms: f32::from_bits(words[4]),
+            floor_db: f32::from_bits(words[5]),
+            detector_mode: words[6] as i32,
+            rms_window_ms: f32::from_bits(words[7]),
+            lookahead_ms: f32::from_bits(words[8]),
+            detector_filter: words[9] != 0,
+            detector_low_cut_hz: f32::from_bits(words[10]),
+            detector_high_cut_hz: f32::from_bits(words[11]),
+            expander_ratio: f32::from_bits(words[12]),
+            stereo_link: words[13] != 0,
+            adaptive_release: words[14] != 0,
+        }
+    }
+}
+
+/// Hands parameters from the effect to its instances without a lock, so
+/// the audio thread never waits for the main thread. The revision is odd
+/// while the effect writes, and readers keep their parameters if it was odd
+/// or changed while they read.
 #[derive(Debug, Default)]
 struct NoiseGateSharedConfig {
-    params: NoiseGateParams,
-    revision: u64,
+    revision: AtomicU64,
+    words: [AtomicU32; PARAM_WORDS],
 }
 
-type NoiseGateSharedConfigRef = Arc<Mutex<NoiseGateSharedConfig>>;
+impl NoiseGateSharedConfig {
+    /// Publishes `params`. Only the effect writes, so writes never overlap.
+    fn store(&self, params: &NoiseGateParams) {
+        let revision = self.revision.load(Ordering::Relaxed);
+        self.revision
+            .store(revision.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in self.words.iter().zip(params.to_words()) {
+            word.store(value, Ordering::Relaxed);
+        }
+        self.revision
+            .store(revision.wrapping_add(2), Ordering::Release);
+    }
 
-fn db_to_gain(db: f32) -> f32 {
-    10.0f32.powf(db / 20.0)
+    /// Returns the parameters and their revision if they changed since
+    /// `applied_revision` and were read whole.
+    fn load_if_changed(&self, applied_revision: u64) -> Option<(u64, NoiseGateParams)> {
+        let revision = self.revision.load(Ordering::Acquire);
+        if revision == applied_revision || revision % 2 == 1 {
+            return None;
+        }
+        let words = std::array::from_fn(|i| self.words[i].load(Ordering::Relaxed));
+        fence(Ordering::Acquire);
+        if self.revision.load(Ordering::Relaxed) != revision {
+            return None;
+        }
+        Some((revision, NoiseGateParams::from_words(words)))
+    }
 }
 
-fn ms_to_coeff(ms: f32, sample_rate: f32) -> f32 {
-    let ms = ms.max(0.0);
-    if ms <= 0.0 || sample_rate <= 0.0 {
-        return 0.0;
+type NoiseGateSharedConfigRef = Arc<NoiseGateSharedConfig>;
+
+/// Gate parameters in the form the audio thread uses.
+#[derive(Debug, Clone, Default)]
+struct GateSettings {
+    threshold_open_lin: f32,
+    threshold_close_lin: f32,
+    floor_gain: f32,
+    hold_samples: usize,
+    detector_mode: i32,
+    rms_coeff: f32,
+    /// Exponent of the expander gain, 0 for a hard gate.
+    expansion: f32,
+    release_ms: f32,
+    adaptive_release: bool,
+    sample_rate: f32,
+}
+
+/// Detector and gain state of one gated signal: the mono mix while the
+/// gate is stereo linked, otherwise one per channel.
+#[derive(Debug, Clone, Default)]
+struct GateChannel {
+    mean_square: f32,
+    /// High and low pass of the detector path, if enabled.
+    detector_filters: Option<(Biquad, Biquad)>,
+    envelope: EnvelopeFollower,
+    gain: EnvelopeFollower,
+    hold_counter: usize,
+    open: bool,
+    /// Samples since the gate last opened, for the adaptive release.
+    open_samples: usize,
+}
+
+impl GateChannel {
+    /// Returns `sample` as the level detector hears it.
+    fn filter(&mut self, sample: f32) -> f32 {
+        match self.detector_filters.as_mut() {
+            Some((high_pass, low_pass)) => low_pass.process(high_pass.process(sample)),
+            None => sample,
+        }
     }
 
-    let seconds = ms * 0.001;
-    (-1.0 / (seconds * sample_rate)).exp()
+    /// Advances the gate by a filtered detector sample and returns the gain
+    /// to apply.
+    fn process(&mut self, detected: f32, settings: &GateSettings) -> f32 {
+        let level = if settings.detector_mode == DETECTOR_RMS {
+            self.mean_square =
+                one_pole_step(self.mean_square, detected * detected, settings.rms_coeff);
+            self.mean_square.sqrt()
+        } else {
+            detected.abs()
+        };
+        let envelope = self.envelope.process(level);
+
+        if self.open {
+            if envelope < settings.threshold_close_lin {
+                if self.hold_counter < settings.hold_samples {
+                    self.hold_counter += 1;
+                } else {
+                    self.close(settings);
+                }
+            } else {
+                self.hold_counter = 0;
+            }
+            self.open_samples = self.open_samples.saturating_add(1);
+        } else if envelope >= settings.threshold_open_lin {
+            self.open = true;
+            self.hold_counter = 0;
+            self.open_samples = 0;
+        }
+
+        let target_gain = if self.open {
+            1.0
+        } else if settings.expansion > 0.0 {
+            (envelope / settings.threshold_open_lin)
+                .powf(settings.expansion)
+                .clamp(settings.floor_gain, 1.0)
+        } else {
+            settings.floor_gain
+        };
+        self.gain.process(target_gain)
+    }
+
+    fn close(&mut self, settings: &GateSettings) {
+        self.open = false;
+        if settings.adaptive_release {
+            let open_ms = self.open_samples as f32 * 1000.0 / settings.sample_rate;
+            self.gain.release_coeff = ms_to_coeff(
+                adaptive_release_ms(settings.release_ms, open_ms),
+                settings.sample_rate,
+            );
+        }
+    }
+}
+
+/// Levels for drawing a gate meter, written by the instance once per block.
+#[derive(Debug, Default)]
+struct NoiseGateMeters {
+    /// Bits of the detector envelope, linear.
+    envelope_bits: AtomicU32,
+    /// Bits of the applied gain, linear.
+    gain_bits: AtomicU32,
 }
 
 /// Adds a configurable noise gate to an audio bus.
 ///
-/// The gate uses mono level detection and applies the same gain envelope to
-/// both channels to avoid stereo image drifting.
+/// By default the gate uses mono level detection and applies the same gain
+/// envelope to both channels to avoid stereo image drifting. Turn off
+/// [member stereo_link] to gate each channel on its own, e.g. on non-voice
+/// buses.
+///
+/// [signal gate_opened] and [signal gate_closed] follow the gate, e.g. to
+/// light up a transmit indicator while it passes audio.
+///
+/// [method calibrate] sets the threshold from the measured background noise;
+/// [method measure_noise_floor] only measures it.
+/// [method apply_preset] sets the main parameters at once, e.g. from
+/// [method NoiseGatePreset.get_builtin_presets].
+///
+/// Set [member duck_bus] to turn another bus down while the gate is open,
+/// e.g. music while the local player speaks.
 #[derive(GodotClass)]
 #[class(tool, base=AudioEffect)]
 pub(crate) struct AudioEffectNoiseGate {
@@ -83,7 +356,87 @@ pub(crate) struct AudioEffectNoiseGate {
     #[export]
     #[var(get = get_floor_db, set = set_floor_db)]
     floor_db: f32,
+    /// How the level is measured: 0 = DETECTOR_PEAK follows each sample,
+    /// 1 = DETECTOR_RMS averages over [member rms_window_ms], which chatters
+    /// less on plosives in speech.
+    #[export]
+    #[var(get = get_detector_mode, set = set_detector_mode)]
+    detector_mode: i32,
+    /// Averaging time of the RMS detector, in milliseconds.
+    #[export]
+    #[var(get = get_rms_window_ms, set = set_rms_window_ms)]
+    rms_window_ms: f32,
+    /// Delays the audio by up to 10 ms so the gate opens before a word
+    /// starts instead of clipping its first syllable, at the cost of that
+    /// much latency.
+    #[export(range = (0.0, 10.0, suffix = "ms"))]
+    #[var(get = get_lookahead_ms, set = set_lookahead_ms)]
+    lookahead_ms: f32,
+    /// Band-limits the level detector to [member detector_low_cut_hz] to
+    /// [member detector_high_cut_hz], so rumble and keyboard thumps don't
+    /// open the gate. The audio itself isn't filtered.
+    #[export]
+    #[var(get = is_detector_filter, set = set_detector_filter)]
+    detector_filter: bool,
+    /// Lower edge of the detector band, in Hz.
+    #[export(range = (20.0, 1000.0, suffix = "Hz"))]
+    #[var(get = get_detector_low_cut_hz, set = set_detector_low_cut_hz)]
+    detector_low_cut_hz: f32,
+    /// Upper edge of the detector band, in Hz.
+    #[export(range = (1000.0, 20000.0, suffix = "Hz"))]
+    #[var(get = get_detector_high_cut_hz, set = set_detector_high_cut_hz)]
+    detector_high_cut_hz: f32,
+    /// Above 1.0, turns the gate into a downward expander: while closed,
+    /// every dB the level falls below the threshold lowers the gain by
+    /// ratio - 1 dB, down to [member floor_db], which sounds less abrupt
+    /// than muting. At 1.0 or below, the gate drops straight to the floor.
+    #[export(range = (0.0, 10.0, 0.1, or_greater))]
+    #[var(get = get_expander_ratio, set = set_expander_ratio)]
+    expander_ratio: f32,
+    /// Gates both channels together from their mono mix. When off, each
+    /// channel opens and closes on its own level, and the gate counts as
+    /// open while either channel is.
+    #[export]
+    #[var(get = is_stereo_link, set = set_stereo_link)]
+    stereo_link: bool,
+    /// Scales the release with how long the gate was open: down to half of
+    /// [member release_ms] after short bursts, so noise doesn't leak after
+    /// them, and up to double after sustained speech, so word tails aren't
+    /// chopped.
+    #[export]
+    #[var(get = is_adaptive_release, set = set_adaptive_release)]
+    adaptive_release: bool,
+    /// Bus to duck while the gate is open, e.g. `&"Music"`. The gate adds
+    /// its own [AudioEffectVoipDucker] to it, so other duckers on the bus,
+    /// e.g. the one of [member VoipManager.duck_music_bus], keep their
+    /// settings. Empty turns ducking off.
+    #[export]
+    #[var(get = get_duck_bus, set = set_duck_bus)]
+    duck_bus: StringName,
+    /// Gain of [member duck_bus] while ducked, in dB.
+    #[export(range = (-60.0, 0.0, suffix = "dB"))]
+    #[var(get = get_duck_amount_db, set = set_duck_amount_db)]
+    duck_amount_db: f32,
+    /// Time to duck [member duck_bus], in milliseconds.
+    #[export(range = (0.0, 1000.0, or_greater, suffix = "ms"))]
+    #[var(get = get_duck_attack_ms, set = set_duck_attack_ms)]
+    duck_attack_ms: f32,
+    /// Time for [member duck_bus] to come back up, in milliseconds.
+    #[export(range = (0.0, 2000.0, or_greater, suffix = "ms"))]
+    #[var(get = get_duck_release_ms, set = set_duck_release_ms)]
+    duck_release_ms: f32,
     shared_config: NoiseGateSharedConfigRef,
+    /// Whether the gate is open, written by the instance.
+    open_flag: Arc<AtomicBool>,
+    meters: Arc<NoiseGateMeters>,
+    /// Bits of the seconds [method calibrate] asked for, taken by the
+    /// instance. 0 when there's no request.
+    calibration_seconds_bits: Arc<AtomicU32>,
+    /// Whether the requested measurement sets the threshold, false for
+    /// [method measure_noise_floor].
+    calibration_applies: Arc<AtomicBool>,
+    /// Ducker the gate added to [member duck_bus].
+    ducker: Option<Gd<AudioEffectVoipDucker>>,
 }
 
 #[godot_api]
@@ -98,10 +451,25 @@ impl IAudioEffect for AudioEffectNoiseGate {
             release_ms: params.release_ms,
             hold_ms: params.hold_ms,
             floor_db: params.floor_db,
-            shared_config: Arc::new(Mutex::new(NoiseGateSharedConfig {
-                params,
-                revision: 0,
-            })),
+            detector_mode: params.detector_mode,
+            rms_window_ms: params.rms_window_ms,
+            lookahead_ms: params.lookahead_ms,
+            detector_filter: params.detector_filter,
+            detector_low_cut_hz: params.detector_low_cut_hz,
+            detector_high_cut_hz: params.detector_high_cut_hz,
+            expander_ratio: params.expander_ratio,
+            stereo_link: params.stereo_link,
+            adaptive_release: params.adaptive_release,
+            duck_bus: StringName::default(),
+            duck_amount_db: -8.0,
+            duck_attack_ms: 50.0,
+            duck_release_ms: 400.0,
+            shared_config: Arc::default(),
+            open_flag: Arc::default(),
+            meters: Arc::default(),
+            calibration_seconds_bits: Arc::default(),
+            calibration_applies: Arc::default(),
+            ducker: None,
         }
     }
 
@@ -112,6 +480,11 @@ impl IAudioEffect for AudioEffectNoiseGate {
         {
             let mut effect_mut = effect.bind_mut();
             effect_mut.shared_config = self.shared_config.clone();
+            effect_mut.open_flag = self.open_flag.clone();
+            effect_mut.meters = self.meters.clone();
+            effect_mut.calibration_seconds_bits = self.calibration_seconds_bits.clone();
+            effect_mut.calibration_applies = self.calibration_applies.clone();
+            effect_mut.effect_id = Some(self.base().instance_id());
         }
 
         Some(effect.upcast::<AudioEffectInstance>())
@@ -120,6 +493,200 @@ impl IAudioEffect for AudioEffectNoiseGate {
 
 #[godot_api]
 impl AudioEffectNoiseGate {
+    #[constant]
+    const DETECTOR_PEAK: i32 = DETECTOR_PEAK;
+    #[constant]
+    const DETECTOR_RMS: i32 = DETECTOR_RMS;
+
+    /// Emitted on the main thread after the gate opened.
+    #[signal]
+    fn gate_opened();
+
+    /// Emitted on the main thread after the gate closed, once the hold time
+    /// passed.
+    #[signal]
+    fn gate_closed();
+
+    /// Emitted on the main thread when [method calibrate] finished, after
+    /// [member threshold_db] was set to [param threshold_db].
+    #[signal]
+    fn calibration_finished(threshold_db: f32);
+
+    /// Emitted on the main thread when [method measure_noise_floor] finished,
+    /// with the RMS and peak level of the gate's input in dBFS.
+    #[signal]
+    fn noise_floor_measured(noise_floor_db: f32, peak_db: f32);
+
+    /// Measures the background noise for [param seconds], through the
+    /// detector filter if enabled, then sets [member threshold_db] 10 dB
+    /// above it and [member hysteresis_db] to 5 dB, and emits
+    /// [signal calibration_finished]. Stay quiet meanwhile. The effect has
+    /// to be on a bus that's processing, e.g. the microphone's.
+    #[func]
+    fn calibrate(&mut self, seconds: f32) {
+        self.request_calibration(seconds, true);
+    }
+
+    /// Measures the gate's input like [method calibrate] without changing
+    /// any setting, and emits [signal noise_floor_measured].
+    #[func]
+    fn measure_noise_floor(&mut self, seconds: f32) {
+        self.request_calibration(seconds, false);
+    }
+
+    fn request_calibration(&mut self, seconds: f32, applies: bool) {
+        self.calibration_applies.store(applies, Ordering::Relaxed);
+        // Published after the flag, which the instance reads once it took the
+        // seconds.
+        self.calibration_seconds_bits
+            .store(seconds.max(0.01).to_bits(), Ordering::Release);
+    }
+
+    /// Sets the threshold, hysteresis, attack, release, hold and floor from
+    /// [param preset].
+    #[func]
+    fn apply_preset(&mut self, preset: Gd<NoiseGatePreset>) {
+        let preset = preset.bind();
+        self.threshold_db = preset.threshold_db;
+        self.hysteresis_db = Self::sanitize_hysteresis_db(preset.hysteresis_db);
+        self.attack_ms = Self::sanitize_attack_ms(preset.attack_ms);
+        self.release_ms = Self::sanitize_release_ms(preset.release_ms);
+        self.hold_ms = Self::sanitize_hold_ms(preset.hold_ms);
+        self.floor_db = Self::sanitize_floor_db(preset.floor_db);
+        self.push_config_to_shared();
+    }
+
+    /// Returns the current threshold, hysteresis, attack, release, hold and
+    /// floor as a preset called [param name], e.g. to store with
+    /// [method ResourceSaver.save].
+    #[func]
+    fn save_preset(&self, name: GString) -> Gd<NoiseGatePreset> {
+        let mut preset = NoiseGatePreset::new_gd();
+        {
+            let mut preset_mut = preset.bind_mut();
+            preset_mut.threshold_db = self.threshold_db;
+            preset_mut.hysteresis_db = self.hysteresis_db;
+            preset_mut.attack_ms = self.attack_ms;
+            preset_mut.release_ms = self.release_ms;
+            preset_mut.hold_ms = self.hold_ms;
+            preset_mut.floor_db = self.floor_db;
+        }
+        preset.set_name(&name);
+        preset
+    }
+
+    /// Emits the gate signals and ducks [member duck_bus]. Called by the
+    /// instance.
+    #[func]
+    fn _on_gate_changed(&mut self, open: bool) {
+        if open {
+            self.signals().gate_opened().emit();
+        } else {
+            self.signals().gate_closed().emit();
+        }
+        self.duck(open);
+    }
+
+    /// Ducks or restores [member duck_bus], adding the gate's ducker on
+    /// first use.
+    fn duck(&mut self, ducked: bool) {
+        if self.ducker.is_none() && ducked {
+            self.add_ducker();
+        }
+        if let Some(ducker) = self.ducker.as_ref() {
+            ducker.bind().set_ducked(ducked);
+        }
+    }
+
+    fn add_ducker(&mut self) {
+        if self.duck_bus.is_empty() {
+            return;
+        }
+        let mut server = AudioServer::singleton();
+        let bus_idx = server.get_bus_index(&self.duck_bus);
+        if bus_idx < 0 {
+            return;
+        }
+
+        let ducker = AudioEffectVoipDucker::new_gd();
+        server.add_bus_effect(bus_idx, &ducker);
+        self.ducker = Some(ducker);
+        self.configure_ducker();
+    }
+
+    /// Takes the gate's ducker off [member duck_bus].
+    fn remove_ducker(&mut self) {
+        let Some(ducker) = self.ducker.take() else {
+            return;
+        };
+        let mut server = AudioServer::singleton();
+        let bus_idx = server.get_bus_index(&self.duck_bus);
+        if bus_idx < 0 {
+            return;
+        }
+
+        let ducker_id = ducker.instance_id();
+        let index = (0..server.get_bus_effect_count(bus_idx)).find(|i| {
+            server
+                .get_bus_effect(bus_idx, *i)
+                .is_some_and(|effect| effect.instance_id() == ducker_id)
+        });
+        if let Some(index) = index {
+            server.remove_bus_effect(bus_idx, index);
+        }
+    }
+
+    fn configure_ducker(&mut self) {
+        let Some(ducker) = self.ducker.as_mut() else {
+            return;
+        };
+        let mut ducker_mut = ducker.bind_mut();
+        ducker_mut.set_amount_db(self.duck_amount_db);
+        ducker_mut.set_attack_ms(self.duck_attack_ms);
+        ducker_mut.set_release_ms(self.duck_release_ms);
+    }
+
+    /// Applies or reports a calibration measurement. Called by the instance.
+    #[func]
+    fn _finish_calibration(&mut self, noise_floor_db: f32, peak_db: f32, applies: bool) {
+        if !applies {
+            self.signals()
+                .noise_floor_measured()
+                .emit(noise_floor_db, peak_db);
+            return;
+        }
+        self.threshold_db = noise_floor_db + CALIBRATION_MARGIN_DB;
+        self.hysteresis_db = CALIBRATION_HYSTERESIS_DB;
+        self.push_config_to_shared();
+        let threshold_db = self.threshold_db;
+        self.signals().calibration_finished().emit(threshold_db);
+    }
+
+    /// Returns whether the gate currently passes audio.
+    #[func]
+    fn is_open(&self) -> bool {
+        self.open_flag.load(Ordering::Relaxed)
+    }
+
+    /// Returns the level the gate compares with [member threshold_db], in
+    /// dBFS, after the detector's attack and release. Safe to poll every
+    /// frame, e.g. to draw a meter with a threshold line.
+    #[func]
+    fn get_envelope_db(&self) -> f32 {
+        gain_to_db(f32::from_bits(
+            self.meters.envelope_bits.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// Returns the gain the gate currently applies, in dB: 0 while open,
+    /// down to [member floor_db] while closed.
+    #[func]
+    fn get_gain_db(&self) -> f32 {
+        gain_to_db(f32::from_bits(
+            self.meters.gain_bits.load(Ordering::Relaxed),
+        ))
+    }
+
     fn sanitize_hysteresis_db(value: f32) -> f32 {
         value.max(0.0)
     }
@@ -140,16 +707,36 @@ impl AudioEffectNoiseGate {
         value.min(0.0)
     }
 
+    fn sanitize_rms_window_ms(value: f32) -> f32 {
+        value.max(0.0)
+    }
+
+    fn sanitize_lookahead_ms(value: f32) -> f32 {
+        value.clamp(0.0, MAX_LOOKAHEAD_MS)
+    }
+
+    fn sanitize_cut_hz(value: f32) -> f32 {
+        value.max(1.0)
+    }
+
     fn push_config_to_shared(&mut self) {
-        if let Ok(mut cfg) = self.shared_config.lock() {
-            cfg.params.threshold_db = self.threshold_db;
-            cfg.params.hysteresis_db = self.hysteresis_db;
-            cfg.params.attack_ms = self.attack_ms;
-            cfg.params.release_ms = self.release_ms;
-            cfg.params.hold_ms = self.hold_ms;
-            cfg.params.floor_db = self.floor_db;
-            cfg.revision = cfg.revision.wrapping_add(1);
-        }
+        self.shared_config.store(&NoiseGateParams {
+            threshold_db: self.threshold_db,
+            hysteresis_db: self.hysteresis_db,
+            attack_ms: self.attack_ms,
+            release_ms: self.release_ms,
+            hold_ms: self.hold_ms,
+            floor_db: self.floor_db,
+            detector_mode: self.detector_mode,
+            rms_window_ms: self.rms_window_ms,
+            lookahead_ms: self.lookahead_ms,
+            detector_filter: self.detector_filter,
+            detector_low_cut_hz: self.detector_low_cut_hz,
+            detector_high_cut_hz: self.detector_high_cut_hz,
+            expander_ratio: self.expander_ratio,
+            stereo_link: self.stereo_link,
+            adaptive_release: self.adaptive_release,
+        });
     }
 
     #[func]
@@ -217,6 +804,170 @@ impl AudioEffectNoiseGate {
         self.floor_db = Self::sanitize_floor_db(value);
         self.push_config_to_shared();
     }
+
+    #[func]
+    fn get_detector_mode(&self) -> i32 {
+        self.detector_mode
+    }
+
+    #[func]
+    fn set_detector_mode(&mut self, value: i32) {
+        self.detector_mode = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_rms_window_ms(&self) -> f32 {
+        self.rms_window_ms
+    }
+
+    #[func]
+    fn set_rms_window_ms(&mut self, value: f32) {
+        self.rms_window_ms = Self::sanitize_rms_window_ms(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_lookahead_ms(&self) -> f32 {
+        self.lookahead_ms
+    }
+
+    #[func]
+    fn set_lookahead_ms(&mut self, value: f32) {
+        self.lookahead_ms = Self::sanitize_lookahead_ms(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_detector_filter(&self) -> bool {
+        self.detector_filter
+    }
+
+    #[func]
+    fn set_detector_filter(&mut self, value: bool) {
+        self.detector_filter = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_detector_low_cut_hz(&self) -> f32 {
+        self.detector_low_cut_hz
+    }
+
+    #[func]
+    fn set_detector_low_cut_hz(&mut self, value: f32) {
+        self.detector_low_cut_hz = Self::sanitize_cut_hz(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_detector_high_cut_hz(&self) -> f32 {
+        self.detector_high_cut_hz
+    }
+
+    #[func]
+    fn set_detector_high_cut_hz(&mut self, value: f32) {
+        self.detector_high_cut_hz = Self::sanitize_cut_hz(value);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_expander_ratio(&self) -> f32 {
+        self.expander_ratio
+    }
+
+    #[func]
+    fn set_expander_ratio(&mut self, value: f32) {
+        self.expander_ratio = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_stereo_link(&self) -> bool {
+        self.stereo_link
+    }
+
+    #[func]
+    fn set_stereo_link(&mut self, value: bool) {
+        self.stereo_link = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn is_adaptive_release(&self) -> bool {
+        self.adaptive_release
+    }
+
+    #[func]
+    fn set_adaptive_release(&mut self, value: bool) {
+        self.adaptive_release = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_duck_bus(&self) -> StringName {
+        self.duck_bus.clone()
+    }
+
+    #[func]
+    fn set_duck_bus(&mut self, value: StringName) {
+        if value == self.duck_bus {
+            return;
+        }
+        // The old bus would otherwise stay ducked.
+        self.remove_ducker();
+        self.duck_bus = value;
+        if self.open_flag.load(Ordering::Relaxed) {
+            self.duck(true);
+        }
+    }
+
+    #[func]
+    fn get_duck_amount_db(&self) -> f32 {
+        self.duck_amount_db
+    }
+
+    #[func]
+    fn set_duck_amount_db(&mut self, value: f32) {
+        self.duck_amount_db = value.min(0.0);
+        self.configure_ducker();
+    }
+
+    #[func]
+    fn get_duck_attack_ms(&self) -> f32 {
+        self.duck_attack_ms
+    }
+
+    #[func]
+    fn set_duck_attack_ms(&mut self, value: f32) {
+        self.duck_attack_ms = value.max(0.0);
+        self.configure_ducker();
+    }
+
+    #[func]
+    fn get_duck_release_ms(&self) -> f32 {
+        self.duck_release_ms
+    }
+
+    #[func]
+    fn set_duck_release_ms(&mut self, value: f32) {
+        self.duck_release_ms = value.max(0.0);
+        self.configure_ducker();
+    }
+
+    /// Returns how much the gate delays audio, in milliseconds, which is
+    /// [member lookahead_ms].
+    #[func]
+    fn get_latency_ms(&self) -> f32 {
+        self.lookahead_ms
+    }
+}
+
+impl Drop for AudioEffectNoiseGate {
+    fn drop(&mut self) {
+        // The duck bus would otherwise keep a ducker nobody releases.
+        self.remove_ducker();
+    }
 }
 
 #[derive(GodotClass)]
@@ -226,46 +977,137 @@ pub(crate) struct AudioEffectNoiseGateInstance {
     shared_config: NoiseGateSharedConfigRef,
     applied_revision: u64,
 
-    threshold_open_lin: f32,
-    threshold_close_lin: f32,
-    floor_gain: f32,
-    attack_coeff: f32,
-    release_coeff: f32,
-    hold_samples: usize,
-
-    envelope: f32,
-    gain: f32,
-    hold_counter: usize,
+    settings: GateSettings,
+    stereo_link: bool,
+    /// Only the first channel is used while stereo linked.
+    channels: [GateChannel; 2],
+    lookahead: LookaheadDelay,
+    /// Whether any channel is open, as last reported to the effect.
     gate_open: bool,
+    open_flag: Arc<AtomicBool>,
+    meters: Arc<NoiseGateMeters>,
+    /// The effect that created this instance, for its signals.
+    effect_id: Option<InstanceId>,
+    calibration_seconds_bits: Arc<AtomicU32>,
+    calibration_applies: Arc<AtomicBool>,
+    /// Samples left to measure, 0 while not calibrating.
+    calibration_remaining: usize,
+    calibration_square_sum: f64,
+    calibration_peak: f32,
+    calibration_samples: usize,
+    /// Whether the running measurement sets the threshold.
+    calibration_apply: bool,
 }
 
 impl AudioEffectNoiseGateInstance {
     fn apply_config(&mut self, params: &NoiseGateParams) {
         let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
 
-        self.threshold_open_lin = db_to_gain(params.threshold_db);
-        self.threshold_close_lin = db_to_gain(params.threshold_db - params.hysteresis_db.max(0.0));
-        self.floor_gain = db_to_gain(params.floor_db.min(0.0));
+        self.settings = GateSettings {
+            threshold_open_lin: db_to_gain(params.threshold_db),
+            threshold_close_lin: db_to_gain(params.threshold_db - params.hysteresis_db.max(0.0)),
+            floor_gain: db_to_gain(params.floor_db.min(0.0)),
+            hold_samples: ms_to_samples(params.hold_ms, sample_rate),
+            detector_mode: params.detector_mode,
+            rms_coeff: ms_to_coeff(params.rms_window_ms, sample_rate),
+            expansion: (params.expander_ratio - 1.0).max(0.0),
+            release_ms: params.release_ms,
+            adaptive_release: params.adaptive_release,
+            sample_rate,
+        };
 
-        self.attack_coeff = ms_to_coeff(params.attack_ms, sample_rate);
-        self.release_coeff = ms_to_coeff(params.release_ms, sample_rate);
+        if self.stereo_link && !params.stereo_link {
+            // Both channels carry on from where the mono gate was.
+            self.channels[1] = self.channels[0].clone();
+        }
+        self.stereo_link = params.stereo_link;
 
-        let hold_samples_f = (params.hold_ms.max(0.0) * 0.001 * sample_rate).round();
-        self.hold_samples = hold_samples_f.max(0.0) as usize;
+        let detector_filters = params.detector_filter.then(|| {
+            (
+                Biquad::high_pass(params.detector_low_cut_hz, sample_rate),
+                Biquad::low_pass(params.detector_high_cut_hz, sample_rate),
+            )
+        });
+        for channel in &mut self.channels {
+            channel
+                .envelope
+                .set_times(params.attack_ms, params.release_ms, sample_rate);
+            channel
+                .gain
+                .set_times(params.attack_ms, params.release_ms, sample_rate);
+            channel.detector_filters = detector_filters;
+        }
+        self.lookahead.set_len(ms_to_samples(
+            params.lookahead_ms.clamp(0.0, MAX_LOOKAHEAD_MS),
+            sample_rate,
+        ));
     }
 
-    fn refresh_runtime_config_if_needed(&mut self) {
-        let Ok(cfg) = self.shared_config.lock() else {
+    /// Returns the channels in use: one while stereo linked, else both.
+    fn active_channels(&self) -> &[GateChannel] {
+        if self.stereo_link {
+            &self.channels[..1]
+        } else {
+            &self.channels
+        }
+    }
+
+    fn set_gate_open(&mut self, open: bool) {
+        self.gate_open = open;
+        self.open_flag.store(open, Ordering::Relaxed);
+        self.call_effect_deferred("_on_gate_changed", &[open.to_variant()]);
+    }
+
+    /// Calls `method` on the effect on the main thread's next idle time.
+    fn call_effect_deferred(&self, method: &str, args: &[Variant]) {
+        let Some(effect_id) = self.effect_id else {
             return;
         };
+        if let Ok(mut effect) = Gd::<Object>::try_from_instance_id(effect_id) {
+            effect.call_deferred(method, args);
+        }
+    }
+
+    fn start_calibration_if_requested(&mut self) {
+        let seconds = f32::from_bits(self.calibration_seconds_bits.swap(0, Ordering::Acquire));
+        if seconds <= 0.0 {
+            return;
+        }
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.calibration_remaining = ((seconds * sample_rate) as usize).max(1);
+        self.calibration_square_sum = 0.0;
+        self.calibration_peak = 0.0;
+        self.calibration_samples = 0;
+        self.calibration_apply = self.calibration_applies.load(Ordering::Relaxed);
+    }
 
-        if self.applied_revision == cfg.revision {
+    /// Adds a detector sample to the calibration, finishing it when the
+    /// requested time is measured.
+    fn measure_calibration(&mut self, level: f32) {
+        self.calibration_square_sum += (level * level) as f64;
+        self.calibration_peak = self.calibration_peak.max(level.abs());
+        self.calibration_samples += 1;
+        self.calibration_remaining -= 1;
+        if self.calibration_remaining > 0 {
             return;
         }
 
-        let revision = cfg.revision;
-        let params = cfg.params.clone();
-        drop(cfg);
+        let rms = (self.calibration_square_sum / self.calibration_samples as f64).sqrt() as f32;
+        self.call_effect_deferred(
+            "_finish_calibration",
+            &[
+                gain_to_db(rms).to_variant(),
+                gain_to_db(self.calibration_peak).to_variant(),
+                self.calibration_apply.to_variant(),
+            ],
+        );
+    }
+
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Some((revision, params)) = self.shared_config.load_if_changed(self.applied_revision)
+        else {
+            return;
+        };
 
         self.apply_config(&params);
         self.applied_revision = revision;
@@ -285,75 +1127,185 @@ impl IAudioEffectInstance for AudioEffectNoiseGateInstance {
         }
 
         self.refresh_runtime_config_if_needed();
+        self.start_calibration_if_requested();
 
         let frame_count = frame_count as usize;
         let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
         let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
 
         for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
-            let level = ((in_frame.left + in_frame.right) * 0.5).abs();
-
-            let detector_coeff = if level > self.envelope {
-                self.attack_coeff
-            } else {
-                self.release_coeff
-            };
-            self.envelope = level + detector_coeff * (self.envelope - level);
-
-            if self.gate_open {
-                if self.envelope < self.threshold_close_lin {
-                    if self.hold_counter < self.hold_samples {
-                        self.hold_counter += 1;
-                    } else {
-                        self.gate_open = false;
-                    }
-                } else {
-                    self.hold_counter = 0;
+            let (left_gain, right_gain) = if self.stereo_link {
+                let mono = self.channels[0].filter((in_frame.left + in_frame.right) * 0.5);
+                if self.calibration_remaining > 0 {
+                    self.measure_calibration(mono);
                 }
-            } else if self.envelope >= self.threshold_open_lin {
-                self.gate_open = true;
-                self.hold_counter = 0;
-            }
-
-            let target_gain = if self.gate_open { 1.0 } else { self.floor_gain };
-            let gain_coeff = if target_gain > self.gain {
-                self.attack_coeff
+                let gain = self.channels[0].process(mono, &self.settings);
+                (gain, gain)
             } else {
-                self.release_coeff
+                let [left, right] = &mut self.channels;
+                let left_detected = left.filter(in_frame.left);
+                let right_detected = right.filter(in_frame.right);
+                if self.calibration_remaining > 0 {
+                    self.measure_calibration((left_detected + right_detected) * 0.5);
+                }
+                let [left, right] = &mut self.channels;
+                (
+                    left.process(left_detected, &self.settings),
+                    right.process(right_detected, &self.settings),
+                )
             };
-            self.gain = target_gain + gain_coeff * (self.gain - target_gain);
 
-            out_frame.left = in_frame.left * self.gain;
-            out_frame.right = in_frame.right * self.gain;
+            let open = self.active_channels().iter().any(|channel| channel.open);
+            if open != self.gate_open {
+                self.set_gate_open(open);
+            }
+
+            let delayed = self
+                .lookahead
+                .process(Vector2::new(in_frame.left, in_frame.right));
+            out_frame.left = delayed.x * left_gain;
+            out_frame.right = delayed.y * right_gain;
         }
+
+        let channels = self.active_channels();
+        let envelope = channels
+            .iter()
+            .map(|channel| channel.envelope.value)
+            .fold(0.0, f32::max);
+        let gain = channels
+            .iter()
+            .map(|channel| channel.gain.value)
+            .fold(0.0, f32::max);
+        self.meters
+            .envelope_bits
+            .store(envelope.to_bits(), Ordering::Relaxed);
+        self.meters
+            .gain_bits
+            .store(gain.to_bits(), Ordering::Relaxed);
     }
 
     fn init(base: Base<AudioEffectInstance>) -> Self {
         let defaults = NoiseGateParams::default();
         let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
 
-        let threshold_open_lin = db_to_gain(defaults.threshold_db);
-        let threshold_close_lin =
-            db_to_gain(defaults.threshold_db - defaults.hysteresis_db.max(0.0));
-        let floor_gain = db_to_gain(defaults.floor_db.min(0.0));
-        let attack_coeff = ms_to_coeff(defaults.attack_ms, sample_rate);
-        let release_coeff = ms_to_coeff(defaults.release_ms, sample_rate);
-        let hold_samples = ((defaults.hold_ms * 0.001 * sample_rate).round()).max(0.0) as usize;
+        let settings = GateSettings {
+            threshold_open_lin: db_to_gain(defaults.threshold_db),
+            threshold_close_lin: db_to_gain(
+                defaults.threshold_db - defaults.hysteresis_db.max(0.0),
+            ),
+            floor_gain: db_to_gain(defaults.floor_db.min(0.0)),
+            hold_samples: ms_to_samples(defaults.hold_ms, sample_rate),
+            detector_mode: defaults.detector_mode,
+            rms_coeff: ms_to_coeff(defaults.rms_window_ms, sample_rate),
+            expansion: 0.0,
+            release_ms: defaults.release_ms,
+            adaptive_release: defaults.adaptive_release,
+            sample_rate,
+        };
+        let mut gain = EnvelopeFollower::new(defaults.attack_ms, defaults.release_ms, sample_rate);
+        gain.value = settings.floor_gain;
+        let channel = GateChannel {
+            envelope: EnvelopeFollower::new(defaults.attack_ms, defaults.release_ms, sample_rate),
+            gain,
+            ..Default::default()
+        };
 
         Self {
             base,
             shared_config: Arc::default(),
             applied_revision: 0,
-            threshold_open_lin,
-            threshold_close_lin,
-            floor_gain,
-            attack_coeff,
-            release_coeff,
-            hold_samples,
-            envelope: 0.0,
-            gain: floor_gain,
-            hold_counter: 0,
+            settings,
+            stereo_link: defaults.stereo_link,
+            channels: [channel.clone(), channel],
+            lookahead: LookaheadDelay::new(sample_rate),
             gate_open: false,
+            open_flag: Arc::default(),
+            meters: Arc::default(),
+            effect_id: None,
+            calibration_seconds_bits: Arc::default(),
+            calibration_applies: Arc::default(),
+            calibration_remaining: 0,
+            calibration_square_sum: 0.0,
+            calibration_peak: 0.0,
+            calibration_samples: 0,
+            calibration_apply: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_config_hands_over_changes_once() {
+        let shared = NoiseGateSharedConfig::default();
+        assert!(shared.load_if_changed(0).is_none());
+
+        let params = NoiseGateParams {
+            detector_mode: DETECTOR_RMS,
+            detector_filter: true,
+            expander_ratio: 2.0,
+            stereo_link: false,
+            ..Default::default()
+        };
+        shared.store(&params);
+        let (revision, loaded) = shared.load_if_changed(0).expect("should change");
+        assert_eq!(loaded, params);
+        assert!(shared.load_if_changed(revision).is_none());
+
+        // A write in progress is skipped until it completes.
+        shared.revision.fetch_add(1, Ordering::Relaxed);
+        assert!(shared.load_if_changed(revision).is_none());
+    }
+
+    #[test]
+    fn unlinked_channels_gate_independently() {
+        let sample_rate = 48_000.0;
+        let settings = GateSettings {
+            threshold_open_lin: db_to_gain(-40.0),
+            threshold_close_lin: db_to_gain(-46.0),
+            floor_gain: 0.0,
+            ..Default::default()
+        };
+        let channel = GateChannel {
+            envelope: EnvelopeFollower::new(1.0, 50.0, sample_rate),
+            gain: EnvelopeFollower::new(1.0, 50.0, sample_rate),
+            ..Default::default()
+        };
+        let (mut loud, mut quiet) = (channel.clone(), channel);
+
+        let (mut loud_gain, mut quiet_gain) = (0.0, 0.0);
+        for _ in 0..4_800 {
+            loud_gain = loud.process(0.5, &settings);
+            quiet_gain = quiet.process(0.001, &settings);
+        }
+        assert!(loud.open && loud_gain > 0.99);
+        assert!(!quiet.open && quiet_gain < 0.01);
+    }
+
+    #[test]
+    fn lookahead_delay_resizes_in_place() {
+        let mut delay = LookaheadDelay::new(48_000.0);
+        let allocation = delay.buffer.as_ptr();
+        let frame = Vector2::new(1.0, -1.0);
+
+        delay.set_len(2);
+        assert_eq!(delay.process(frame), Vector2::ZERO);
+        assert_eq!(delay.process(Vector2::ZERO), Vector2::ZERO);
+        assert_eq!(delay.process(Vector2::ZERO), frame);
+
+        delay.set_len(100_000);
+        assert_eq!(delay.len, delay.buffer.len());
+        delay.set_len(0);
+        assert_eq!(delay.process(frame), frame);
+        assert_eq!(delay.buffer.as_ptr(), allocation);
+    }
+
+    #[test]
+    fn adaptive_release_follows_open_time() {
+        assert_eq!(adaptive_release_ms(100.0, 20.0), 50.0);
+        assert_eq!(adaptive_release_ms(100.0, 550.0), 125.0);
+        assert_eq!(adaptive_release_ms(100.0, 5000.0), 200.0);
+    }
+}
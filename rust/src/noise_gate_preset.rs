@@ -0,0 +1,127 @@
+use godot::classes::{IResource, Resource};
+use godot::prelude::*;
+
+/// Values of a built-in preset.
+struct BuiltinPreset {
+    name: &'static str,
+    threshold_db: f32,
+    hysteresis_db: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    hold_ms: f32,
+    floor_db: f32,
+}
+
+const BUILTIN_PRESETS: [BuiltinPreset; 3] = [
+    // Little background noise, so a low threshold keeps quiet speech.
+    BuiltinPreset {
+        name: "Quiet room",
+        threshold_db: -55.0,
+        hysteresis_db: 6.0,
+        attack_ms: 5.0,
+        release_ms: 150.0,
+        hold_ms: 50.0,
+        floor_db: -80.0,
+    },
+    // Fan noise and a distant mic: a higher threshold, a long hold for the
+    // quieter speech, and a shallow floor so the noise doesn't pump.
+    BuiltinPreset {
+        name: "Laptop mic",
+        threshold_db: -40.0,
+        hysteresis_db: 8.0,
+        attack_ms: 3.0,
+        release_ms: 200.0,
+        hold_ms: 100.0,
+        floor_db: -30.0,
+    },
+    // Close mic next to a loud keyboard: a fast, tight gate.
+    BuiltinPreset {
+        name: "Streamer",
+        threshold_db: -35.0,
+        hysteresis_db: 6.0,
+        attack_ms: 1.0,
+        release_ms: 100.0,
+        hold_ms: 30.0,
+        floor_db: -80.0,
+    },
+];
+
+/// Settings for [AudioEffectNoiseGate], applied with
+/// [method AudioEffectNoiseGate.apply_preset].
+///
+/// [method get_builtin_presets] returns presets for common setups, so the
+/// gate can be set up without tuning each parameter. Presets can be saved
+/// as resources, e.g. from [method AudioEffectNoiseGate.save_preset]. The
+/// name is the [member Resource.resource_name].
+#[derive(GodotClass)]
+#[class(base=Resource)]
+pub(crate) struct NoiseGatePreset {
+    /// See [member AudioEffectNoiseGate.threshold_db].
+    #[export]
+    pub(crate) threshold_db: f32,
+    /// See [member AudioEffectNoiseGate.hysteresis_db].
+    #[export]
+    pub(crate) hysteresis_db: f32,
+    /// See [member AudioEffectNoiseGate.attack_ms].
+    #[export]
+    pub(crate) attack_ms: f32,
+    /// See [member AudioEffectNoiseGate.release_ms].
+    #[export]
+    pub(crate) release_ms: f32,
+    /// See [member AudioEffectNoiseGate.hold_ms].
+    #[export]
+    pub(crate) hold_ms: f32,
+    /// See [member AudioEffectNoiseGate.floor_db].
+    #[export]
+    pub(crate) floor_db: f32,
+    base: Base<Resource>,
+}
+
+#[godot_api]
+impl IResource for NoiseGatePreset {
+    fn init(base: Base<Resource>) -> Self {
+        Self {
+            threshold_db: -45.0,
+            hysteresis_db: 6.0,
+            attack_ms: 5.0,
+            release_ms: 120.0,
+            hold_ms: 35.0,
+            floor_db: -80.0,
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl NoiseGatePreset {
+    /// Returns the presets that ship with the plugin: "Quiet room",
+    /// "Laptop mic" and "Streamer".
+    #[func]
+    fn get_builtin_presets() -> Array<Gd<NoiseGatePreset>> {
+        BUILTIN_PRESETS.iter().map(Self::from_builtin).collect()
+    }
+
+    /// Returns the built-in preset called [param name], or null if there's
+    /// none.
+    #[func]
+    fn get_builtin_preset(name: GString) -> Option<Gd<NoiseGatePreset>> {
+        BUILTIN_PRESETS
+            .iter()
+            .find(|preset| name == GString::from(preset.name))
+            .map(Self::from_builtin)
+    }
+
+    fn from_builtin(preset: &BuiltinPreset) -> Gd<Self> {
+        let mut gd = Gd::from_init_fn(|base| Self {
+            threshold_db: preset.threshold_db,
+            hysteresis_db: preset.hysteresis_db,
+            attack_ms: preset.attack_ms,
+            release_ms: preset.release_ms,
+            hold_ms: preset.hold_ms,
+            floor_db: preset.floor_db,
+            base,
+        });
+        gd.set_name(preset.name);
+        gd
+    }
+}
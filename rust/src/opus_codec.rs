@@ -1,3 +1,7 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
 use godot::prelude::*;
 use opus::{Decoder, Encoder};
 
@@ -15,13 +19,115 @@ struct OpusStream {}
 ///
 /// PCM data is assumed to be in the format used by Godot, PackedVector2Array
 /// with values in range (-1.0, 1.0).
+///
+/// For many peers, the codec can also run on a worker thread: queue work with
+/// [method submit_encode] / [method submit_decode] and collect results with
+/// [method poll_encoded] / [method poll_decoded]. Synchronous and queued calls
+/// share the same encoder and decoder state.
 pub(crate) struct OpusCodec {
+    state: CodecStateRef,
+    worker: Option<CodecWorker>,
+    next_ticket: i64,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+/// Encoder, decoder and their resamplers. Shared between the synchronous API
+/// and the worker thread so that Opus prediction state stays continuous.
+#[derive(Debug)]
+struct CodecState {
     encoder: Encoder,
     decoder: Decoder,
     encode_resampler: StreamingStereoResampler,
     decode_resampler: StreamingStereoResampler,
-    #[allow(dead_code)]
-    base: Base<RefCounted>,
+}
+
+type CodecStateRef = Arc<Mutex<CodecState>>;
+
+#[derive(Debug)]
+enum CodecJob {
+    Encode {
+        ticket: i64,
+        pcm: Vec<Vector2>,
+        input_sample_rate: i32,
+    },
+    Decode {
+        ticket: i64,
+        packet: Vec<u8>,
+        output_sample_rate: i32,
+    },
+}
+
+#[derive(Debug)]
+struct CodecWorker {
+    job_sender: Option<Sender<CodecJob>>,
+    encoded_receiver: Receiver<(i64, Vec<u8>)>,
+    decoded_receiver: Receiver<(i64, Vec<Vector2>)>,
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl CodecWorker {
+    fn spawn(shared_state: CodecStateRef) -> std::io::Result<Self> {
+        let (job_sender, job_receiver) = mpsc::channel::<CodecJob>();
+        let (encoded_sender, encoded_receiver) = mpsc::channel();
+        let (decoded_sender, decoded_receiver) = mpsc::channel();
+
+        let thread_handle = thread::Builder::new()
+            .name("opus_worker".to_string())
+            .spawn(move || {
+                // Exits once the job sender is dropped.
+                while let Ok(job) = job_receiver.recv() {
+                    let Ok(mut state) = shared_state.lock() else {
+                        return;
+                    };
+                    match job {
+                        CodecJob::Encode {
+                            ticket,
+                            pcm,
+                            input_sample_rate,
+                        } => {
+                            let packet = state.encode(&pcm, input_sample_rate);
+                            let _ = encoded_sender.send((ticket, packet));
+                        }
+                        CodecJob::Decode {
+                            ticket,
+                            packet,
+                            output_sample_rate,
+                        } => {
+                            let pcm = state.decode(&packet, output_sample_rate);
+                            let _ = decoded_sender.send((ticket, pcm));
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            job_sender: Some(job_sender),
+            encoded_receiver,
+            decoded_receiver,
+            thread_handle: Some(thread_handle),
+        })
+    }
+
+    fn submit(&self, job: CodecJob) -> bool {
+        match &self.job_sender {
+            Some(sender) => sender.send(job).is_ok(),
+            None => false,
+        }
+    }
+
+    fn stop(&mut self) {
+        self.job_sender = None;
+        if let Some(handle) = self.thread_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for CodecWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
 
 #[derive(Debug)]
@@ -130,9 +236,8 @@ fn frame_count_for_output_rate(output_sample_rate: usize) -> usize {
     ((output_sample_rate as f32 * FRAME_SIZE as f32) / MIX_RATE as f32).round() as usize
 }
 
-#[godot_api]
-impl IRefCounted for OpusCodec {
-    fn init(base: Base<RefCounted>) -> Self {
+impl CodecState {
+    fn new() -> Self {
         let mut en = Encoder::new(
             MIX_RATE as u32,
             opus::Channels::Mono,
@@ -145,11 +250,122 @@ impl IRefCounted for OpusCodec {
             decoder: Decoder::new(MIX_RATE as u32, opus::Channels::Mono).unwrap(),
             encode_resampler: StreamingStereoResampler::new(MIX_RATE, MIX_RATE),
             decode_resampler: StreamingStereoResampler::new(MIX_RATE, MIX_RATE),
+        }
+    }
+
+    fn encode(&mut self, pcm_data: &[Vector2], input_sample_rate: i32) -> Vec<u8> {
+        let input_rate = sanitize_sample_rate(input_sample_rate);
+        self.encode_resampler.set_rates(input_rate, MIX_RATE);
+
+        let resampled = self.encode_resampler.process(pcm_data, FRAME_SIZE);
+
+        // Convert stereo to mono by averaging left and right channels
+        let vec: Vec<f32> = resampled.iter().map(|vec| (vec.x + vec.y) * 0.5).collect();
+
+        // Ensure we have exactly FRAME_SIZE samples
+        if vec.len() != FRAME_SIZE {
+            godot_error!(
+                "OpusCodec: Expected {} samples, got {}. Returning nothing...",
+                FRAME_SIZE,
+                vec.len()
+            );
+            return Vec::new();
+        }
+
+        // Use a reasonable max size (should be much larger than needed for most cases)
+        let max_size = 4000;
+        match self.encoder.encode_vec_float(&vec, max_size) {
+            Ok(value) => value,
+            Err(e) => {
+                godot_error!("Opus encode error: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn decode(&mut self, opus_packet: &[u8], output_sample_rate: i32) -> Vec<Vector2> {
+        let mut output: Vec<f32> = vec![0.; FRAME_SIZE];
+
+        // TODO lost packet handling with fec
+        let result = self
+            .decoder
+            .decode_float(opus_packet, output.as_mut_slice(), false);
+
+        match result {
+            Ok(decoded_samples) => {
+                let decoded_samples = decoded_samples.min(FRAME_SIZE);
+                let decoded_stereo: Vec<Vector2> = output[..decoded_samples]
+                    .iter()
+                    .map(|num| Vector2::new(*num, *num))
+                    .collect();
+
+                let out_rate = sanitize_sample_rate(output_sample_rate);
+                if out_rate == MIX_RATE {
+                    return decoded_stereo;
+                }
+
+                self.decode_resampler.set_rates(MIX_RATE, out_rate);
+                let target_frames = frame_count_for_output_rate(out_rate).max(1);
+                self.decode_resampler
+                    .process(decoded_stereo.as_slice(), target_frames)
+            }
+            Err(e) => {
+                godot_error!("Opus decode error: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[godot_api]
+impl IRefCounted for OpusCodec {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(CodecState::new())),
+            worker: None,
+            next_ticket: 0,
             base,
         }
     }
 }
 
+impl OpusCodec {
+    fn ensure_worker(&mut self) -> bool {
+        if self.worker.is_some() {
+            return true;
+        }
+
+        match CodecWorker::spawn(self.state.clone()) {
+            Ok(worker) => {
+                self.worker = Some(worker);
+                true
+            }
+            Err(err) => {
+                godot_error!("OpusCodec: failed to spawn worker thread: {}", err);
+                false
+            }
+        }
+    }
+
+    fn submit_job(&mut self, make_job: impl FnOnce(i64) -> CodecJob) -> i64 {
+        if !self.ensure_worker() {
+            return -1;
+        }
+
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        let submitted = self
+            .worker
+            .as_ref()
+            .is_some_and(|worker| worker.submit(make_job(ticket)));
+        if submitted {
+            ticket
+        } else {
+            -1
+        }
+    }
+}
+
 #[godot_api]
 impl OpusCodec {
     /// Get the frame size. This is how large the Opus packets are.
@@ -164,6 +380,35 @@ impl OpusCodec {
         MIX_RATE as i32
     }
 
+    /// Sets the encoder bitrate in bits per second. Zero or less lets Opus
+    /// choose the bitrate.
+    #[func]
+    fn set_bitrate(&mut self, bits_per_second: i32) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let bitrate = if bits_per_second <= 0 {
+            opus::Bitrate::Auto
+        } else {
+            opus::Bitrate::Bits(bits_per_second.clamp(500, 512_000))
+        };
+        if let Err(e) = state.encoder.set_bitrate(bitrate) {
+            godot_error!("Opus set_bitrate error: {:?}", e);
+        }
+    }
+
+    /// Returns the encoder bitrate in bits per second, or 0 if Opus chooses it.
+    #[func]
+    fn get_bitrate(&self) -> i32 {
+        let Ok(mut state) = self.state.lock() else {
+            return 0;
+        };
+        match state.encoder.get_bitrate() {
+            Ok(opus::Bitrate::Bits(bits)) => bits,
+            _ => 0,
+        }
+    }
+
     /// Encode PCM data to Opus. Input should be exactly get_frame_size long.
     #[func]
     fn encode(&mut self, pcm_data: PackedVector2Array) -> PackedByteArray {
@@ -177,36 +422,10 @@ impl OpusCodec {
         pcm_data: PackedVector2Array,
         input_sample_rate: i32,
     ) -> PackedByteArray {
-        let input_rate = sanitize_sample_rate(input_sample_rate);
-        self.encode_resampler.set_rates(input_rate, MIX_RATE);
-
-        let resampled = self
-            .encode_resampler
-            .process(pcm_data.as_slice(), FRAME_SIZE);
-
-        // Convert stereo to mono by averaging left and right channels
-        let vec: Vec<f32> = resampled.iter().map(|vec| (vec.x + vec.y) * 0.5).collect();
-
-        // Ensure we have exactly FRAME_SIZE samples
-        if vec.len() != FRAME_SIZE {
-            godot_error!(
-                "OpusCodec: Expected {} samples, got {}. Returning nothing...",
-                FRAME_SIZE,
-                vec.len()
-            );
+        let Ok(mut state) = self.state.lock() else {
             return PackedByteArray::new();
-        }
-
-        // Use a reasonable max size (should be much larger than needed for most cases)
-        let max_size = 4000;
-        let res = self.encoder.encode_vec_float(&vec, max_size);
-        match res {
-            Ok(value) => return PackedByteArray::from(value),
-            Err(e) => {
-                godot_error!("Opus encode error: {:?}", e);
-            }
-        }
-        PackedByteArray::new()
+        };
+        PackedByteArray::from(state.encode(pcm_data.as_slice(), input_sample_rate))
     }
 
     /// Decode a Opus packet to PCM data.
@@ -222,37 +441,108 @@ impl OpusCodec {
         opus_packet: PackedByteArray,
         output_sample_rate: i32,
     ) -> PackedVector2Array {
-        let mut output: Vec<f32> = vec![0.; FRAME_SIZE];
+        let Ok(mut state) = self.state.lock() else {
+            return PackedVector2Array::new();
+        };
+        PackedVector2Array::from(state.decode(opus_packet.as_slice(), output_sample_rate))
+    }
 
-        // TODO lost packet handling with fec
-        let result =
-            self.decoder
-                .decode_float(opus_packet.as_slice(), output.as_mut_slice(), false);
+    /// Queue PCM data for encoding on the worker thread, starting the worker
+    /// if needed. Returns a ticket identifying the result in
+    /// [method poll_encoded], or -1 if the worker could not be started.
+    #[func]
+    fn submit_encode(&mut self, pcm_data: PackedVector2Array) -> i64 {
+        self.submit_encode_with_sample_rate(pcm_data, MIX_RATE as i32)
+    }
 
-        match result {
-            Ok(decoded_samples) => {
-                let decoded_samples = decoded_samples.min(FRAME_SIZE);
-                let decoded_stereo: Vec<Vector2> = output[..decoded_samples]
-                    .iter()
-                    .map(|num| Vector2::new(*num, *num))
-                    .collect();
+    /// Like [method submit_encode], accepting arbitrary input sample rates.
+    #[func]
+    fn submit_encode_with_sample_rate(
+        &mut self,
+        pcm_data: PackedVector2Array,
+        input_sample_rate: i32,
+    ) -> i64 {
+        let pcm = pcm_data.to_vec();
+        self.submit_job(|ticket| CodecJob::Encode {
+            ticket,
+            pcm,
+            input_sample_rate,
+        })
+    }
 
-                let out_rate = sanitize_sample_rate(output_sample_rate);
-                if out_rate == MIX_RATE {
-                    return PackedVector2Array::from(decoded_stereo);
-                }
+    /// Queue an Opus packet for decoding on the worker thread, starting the
+    /// worker if needed. Returns a ticket identifying the result in
+    /// [method poll_decoded], or -1 if the worker could not be started.
+    #[func]
+    fn submit_decode(&mut self, opus_packet: PackedByteArray) -> i64 {
+        self.submit_decode_with_sample_rate(opus_packet, MIX_RATE as i32)
+    }
 
-                self.decode_resampler.set_rates(MIX_RATE, out_rate);
-                let target_frames = frame_count_for_output_rate(out_rate).max(1);
-                let resampled = self
-                    .decode_resampler
-                    .process(decoded_stereo.as_slice(), target_frames);
-                return PackedVector2Array::from(resampled);
-            }
-            Err(e) => {
-                godot_error!("Opus decode error: {:?}", e);
-                return PackedVector2Array::new();
-            }
+    /// Like [method submit_decode], resampling to the requested output sample rate.
+    #[func]
+    fn submit_decode_with_sample_rate(
+        &mut self,
+        opus_packet: PackedByteArray,
+        output_sample_rate: i32,
+    ) -> i64 {
+        let packet = opus_packet.to_vec();
+        self.submit_job(|ticket| CodecJob::Decode {
+            ticket,
+            packet,
+            output_sample_rate,
+        })
+    }
+
+    /// Returns all finished encode jobs, in submission order, as dictionaries
+    /// with [code]ticket[/code] and [code]packet[/code] keys. The packet is
+    /// empty if encoding failed.
+    #[func]
+    fn poll_encoded(&mut self) -> Array<Dictionary> {
+        let mut results = Array::new();
+        let Some(worker) = self.worker.as_ref() else {
+            return results;
+        };
+
+        while let Ok((ticket, packet)) = worker.encoded_receiver.try_recv() {
+            let mut entry = Dictionary::new();
+            entry.set("ticket", ticket);
+            entry.set("packet", PackedByteArray::from(packet));
+            results.push(&entry);
+        }
+        results
+    }
+
+    /// Returns all finished decode jobs, in submission order, as dictionaries
+    /// with [code]ticket[/code] and [code]pcm[/code] keys. The PCM data is
+    /// empty if decoding failed.
+    #[func]
+    fn poll_decoded(&mut self) -> Array<Dictionary> {
+        let mut results = Array::new();
+        let Some(worker) = self.worker.as_ref() else {
+            return results;
+        };
+
+        while let Ok((ticket, pcm)) = worker.decoded_receiver.try_recv() {
+            let mut entry = Dictionary::new();
+            entry.set("ticket", ticket);
+            entry.set("pcm", PackedVector2Array::from(pcm));
+            results.push(&entry);
+        }
+        results
+    }
+
+    /// Returns true while the worker thread is running.
+    #[func]
+    fn is_worker_running(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    /// Stops the worker thread after it finishes the queued jobs. Results
+    /// that were not polled yet are discarded.
+    #[func]
+    fn stop_worker(&mut self) {
+        if let Some(mut worker) = self.worker.take() {
+            worker.stop();
         }
     }
 }
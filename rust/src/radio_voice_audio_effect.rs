@@ -0,0 +1,415 @@
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{
+    db_to_gain, ms_to_samples, splitmix64, unit_from_bits, Biquad, EnvelopeFollower,
+};
+
+/// Attack of the level detector that keys the radio.
+const KEY_ATTACK_MS: f32 = 1.0;
+/// Release of the level detector that keys the radio.
+const KEY_RELEASE_MS: f32 = 50.0;
+/// Time the radio stays keyed after the voice falls below
+/// [member AudioEffectRadioVoice.squelch_threshold_db], so pauses between
+/// words don't trigger the squelch tail.
+const KEY_HOLD_MS: f32 = 250.0;
+
+#[derive(Debug, Clone)]
+struct RadioParams {
+    low_cut_hz: f32,
+    high_cut_hz: f32,
+    drive_db: f32,
+    noise_db: f32,
+    squelch_threshold_db: f32,
+    squelch_db: f32,
+    squelch_tail_ms: f32,
+}
+
+impl Default for RadioParams {
+    fn default() -> Self {
+        Self {
+            low_cut_hz: 300.0,
+            high_cut_hz: 3000.0,
+            drive_db: 12.0,
+            noise_db: -42.0,
+            squelch_threshold_db: -50.0,
+            squelch_db: -24.0,
+            squelch_tail_ms: 120.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RadioSharedConfig {
+    params: RadioParams,
+    revision: u64,
+}
+
+type RadioSharedConfigRef = Arc<Mutex<RadioSharedConfig>>;
+
+/// Two high passes and two low passes, for steeper band edges.
+#[derive(Debug, Clone, Copy, Default)]
+struct BandPass([Biquad; 4]);
+
+impl BandPass {
+    fn new(low_cut_hz: f32, high_cut_hz: f32, sample_rate: f32) -> Self {
+        let high_pass = Biquad::high_pass(low_cut_hz, sample_rate);
+        let low_pass = Biquad::low_pass(high_cut_hz, sample_rate);
+        Self([high_pass, high_pass, low_pass, low_pass])
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.0
+            .iter_mut()
+            .fold(sample, |sample, filter| filter.process(sample))
+    }
+}
+
+/// The radio sound of a mono voice signal.
+#[derive(Debug, Default)]
+struct RadioState {
+    voice_filter: BandPass,
+    noise_filter: BandPass,
+    drive: f32,
+    noise_gain: f32,
+    squelch_threshold: f32,
+    squelch_gain: f32,
+    hold_samples: usize,
+    tail_samples: usize,
+    detector: EnvelopeFollower,
+    keyed: bool,
+    hold_remaining: usize,
+    tail_remaining: usize,
+    rng_state: u64,
+}
+
+impl RadioState {
+    fn configure(&mut self, params: &RadioParams, sample_rate: f32) {
+        self.voice_filter = BandPass::new(params.low_cut_hz, params.high_cut_hz, sample_rate);
+        self.noise_filter = self.voice_filter;
+        self.drive = db_to_gain(params.drive_db.max(0.0));
+        self.noise_gain = db_to_gain(params.noise_db);
+        self.squelch_threshold = db_to_gain(params.squelch_threshold_db);
+        self.squelch_gain = db_to_gain(params.squelch_db);
+        self.hold_samples = ms_to_samples(KEY_HOLD_MS, sample_rate);
+        self.tail_samples = ms_to_samples(params.squelch_tail_ms, sample_rate);
+        self.detector
+            .set_times(KEY_ATTACK_MS, KEY_RELEASE_MS, sample_rate);
+    }
+
+    /// Returns the noise level for the next sample: the bed while keyed,
+    /// then the squelch burst fading out.
+    fn next_noise_level(&mut self, level: f32) -> f32 {
+        if level >= self.squelch_threshold {
+            self.keyed = true;
+            self.hold_remaining = self.hold_samples;
+            self.tail_remaining = 0;
+        } else if self.keyed {
+            if self.hold_remaining > 0 {
+                self.hold_remaining -= 1;
+            } else {
+                self.keyed = false;
+                self.tail_remaining = self.tail_samples;
+            }
+        }
+
+        if self.keyed {
+            self.noise_gain
+        } else if self.tail_remaining > 0 {
+            self.tail_remaining -= 1;
+            self.squelch_gain * self.tail_remaining as f32 / self.tail_samples as f32
+        } else {
+            0.0
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let level = self.detector.process(sample.abs());
+        let noise_level = self.next_noise_level(level);
+
+        // Soft saturation that leaves quiet parts alone.
+        let voice = (self.voice_filter.process(sample) * self.drive).tanh() / self.drive;
+        if noise_level <= 0.0 {
+            return voice;
+        }
+        let white = unit_from_bits(splitmix64(&mut self.rng_state)) * 2.0 - 1.0;
+        voice + self.noise_filter.process(white) * noise_level
+    }
+}
+
+/// Walkie-talkie sound for voice, e.g. on a peer's bus for team radio.
+///
+/// Narrows the voice to [member low_cut_hz] to [member high_cut_hz],
+/// saturates it by [member drive_db], adds a hiss of [member noise_db]
+/// while the voice is keyed, and a burst of [member squelch_db] noise when
+/// it stops. The output is mono.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectRadioVoice {
+    pub(crate) base: Base<AudioEffect>,
+    /// Lower edge of the radio band, in Hz.
+    #[export(range = (20.0, 2000.0, suffix = "Hz"))]
+    #[var(get = get_low_cut_hz, set = set_low_cut_hz)]
+    low_cut_hz: f32,
+    /// Upper edge of the radio band, in Hz.
+    #[export(range = (1000.0, 10000.0, suffix = "Hz"))]
+    #[var(get = get_high_cut_hz, set = set_high_cut_hz)]
+    high_cut_hz: f32,
+    /// Gain into the saturation, in dB. Higher distorts more.
+    #[export(range = (0.0, 36.0, suffix = "dB"))]
+    #[var(get = get_drive_db, set = set_drive_db)]
+    drive_db: f32,
+    /// Level of the hiss under the voice, in dB.
+    #[export(range = (-80.0, 0.0, suffix = "dB"))]
+    #[var(get = get_noise_db, set = set_noise_db)]
+    noise_db: f32,
+    /// Voice level that keys the radio, in dBFS.
+    #[export(range = (-80.0, 0.0, suffix = "dB"))]
+    #[var(get = get_squelch_threshold_db, set = set_squelch_threshold_db)]
+    squelch_threshold_db: f32,
+    /// Level of the noise burst when the radio unkeys, in dB.
+    #[export(range = (-80.0, 0.0, suffix = "dB"))]
+    #[var(get = get_squelch_db, set = set_squelch_db)]
+    squelch_db: f32,
+    /// Length of the noise burst when the radio unkeys, in milliseconds.
+    /// 0 turns it off.
+    #[export(range = (0.0, 500.0, suffix = "ms"))]
+    #[var(get = get_squelch_tail_ms, set = set_squelch_tail_ms)]
+    squelch_tail_ms: f32,
+    shared_config: RadioSharedConfigRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectRadioVoice {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = RadioParams::default();
+        Self {
+            base,
+            low_cut_hz: params.low_cut_hz,
+            high_cut_hz: params.high_cut_hz,
+            drive_db: params.drive_db,
+            noise_db: params.noise_db,
+            squelch_threshold_db: params.squelch_threshold_db,
+            squelch_db: params.squelch_db,
+            squelch_tail_ms: params.squelch_tail_ms,
+            shared_config: Arc::new(Mutex::new(RadioSharedConfig {
+                params,
+                revision: 0,
+            })),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let mut effect = AudioEffectRadioVoiceInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectRadioVoice {
+    fn push_config_to_shared(&mut self) {
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params.low_cut_hz = self.low_cut_hz;
+            cfg.params.high_cut_hz = self.high_cut_hz;
+            cfg.params.drive_db = self.drive_db;
+            cfg.params.noise_db = self.noise_db;
+            cfg.params.squelch_threshold_db = self.squelch_threshold_db;
+            cfg.params.squelch_db = self.squelch_db;
+            cfg.params.squelch_tail_ms = self.squelch_tail_ms;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_low_cut_hz(&self) -> f32 {
+        self.low_cut_hz
+    }
+
+    #[func]
+    fn set_low_cut_hz(&mut self, value: f32) {
+        self.low_cut_hz = value.max(1.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_high_cut_hz(&self) -> f32 {
+        self.high_cut_hz
+    }
+
+    #[func]
+    fn set_high_cut_hz(&mut self, value: f32) {
+        self.high_cut_hz = value.max(1.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_drive_db(&self) -> f32 {
+        self.drive_db
+    }
+
+    #[func]
+    fn set_drive_db(&mut self, value: f32) {
+        self.drive_db = value.max(0.0);
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_noise_db(&self) -> f32 {
+        self.noise_db
+    }
+
+    #[func]
+    fn set_noise_db(&mut self, value: f32) {
+        self.noise_db = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_squelch_threshold_db(&self) -> f32 {
+        self.squelch_threshold_db
+    }
+
+    #[func]
+    fn set_squelch_threshold_db(&mut self, value: f32) {
+        self.squelch_threshold_db = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_squelch_db(&self) -> f32 {
+        self.squelch_db
+    }
+
+    #[func]
+    fn set_squelch_db(&mut self, value: f32) {
+        self.squelch_db = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_squelch_tail_ms(&self) -> f32 {
+        self.squelch_tail_ms
+    }
+
+    #[func]
+    fn set_squelch_tail_ms(&mut self, value: f32) {
+        self.squelch_tail_ms = value.max(0.0);
+        self.push_config_to_shared();
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectRadioVoiceInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: RadioSharedConfigRef,
+    applied_revision: u64,
+    state: RadioState,
+}
+
+impl AudioEffectRadioVoiceInstance {
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        drop(cfg);
+
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        self.state.configure(&params, sample_rate);
+        self.applied_revision = revision;
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectRadioVoiceInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            let radio = self.state.process((in_frame.left + in_frame.right) * 0.5);
+            out_frame.left = radio;
+            out_frame.right = radio;
+        }
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let mut state = RadioState::default();
+        state.configure(&RadioParams::default(), sample_rate);
+
+        Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn squelch_tail_follows_the_voice_then_stops() {
+        let sample_rate = 48_000.0;
+        let params = RadioParams::default();
+        let mut state = RadioState::default();
+        state.configure(&params, sample_rate);
+
+        // Silence stays silent.
+        assert!((0..4_800).all(|_| state.process(0.0) == 0.0));
+
+        for i in 0..24_000 {
+            let phase = 2.0 * std::f32::consts::PI * 1_000.0 * i as f32 / sample_rate;
+            let out = state.process(0.3 * phase.sin());
+            assert!(out.abs() < 1.0);
+        }
+
+        let hold = ms_to_samples(KEY_HOLD_MS + KEY_RELEASE_MS * 5.0, sample_rate);
+        let tail = ms_to_samples(params.squelch_tail_ms, sample_rate);
+        let after: Vec<f32> = (0..hold + tail + 4_800)
+            .map(|_| state.process(0.0))
+            .collect();
+        let burst_peak = after[hold..hold + tail / 2]
+            .iter()
+            .fold(0.0f32, |peak, out| peak.max(out.abs()));
+        assert!(burst_peak > db_to_gain(params.noise_db));
+        // Only the filters ring out after the tail.
+        assert!(after[hold + tail + 2_400..]
+            .iter()
+            .all(|out| out.abs() < 1e-4));
+    }
+}
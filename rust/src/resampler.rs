@@ -1,5 +1,7 @@
 use godot::prelude::*;
 
+use crate::dsp_util::LinearResampler;
+
 #[derive(GodotClass)]
 #[class(base=RefCounted)]
 pub struct Resampler {
@@ -32,6 +34,73 @@ impl Resampler {
     }
 }
 
+/// Resamples a continuous audio stream chunk by chunk.
+///
+/// Unlike [Resampler], it keeps its position between calls, so a stream cut
+/// into chunks of any size comes out without clicks at the chunk borders and
+/// at exactly the output rate on average.
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub struct StreamingResampler {
+    input_rate: i32,
+    output_rate: i32,
+    resampler: LinearResampler<Vector2>,
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for StreamingResampler {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            input_rate: 48_000,
+            output_rate: 48_000,
+            resampler: LinearResampler::new(48_000, 48_000),
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl StreamingResampler {
+    /// Sets the sample rates and starts a new stream.
+    #[func]
+    pub fn set_rates(&mut self, input_rate: i32, output_rate: i32) {
+        self.input_rate = input_rate.max(1);
+        self.output_rate = output_rate.max(1);
+        self.reset();
+    }
+
+    #[func]
+    pub fn get_input_rate(&self) -> i32 {
+        self.input_rate
+    }
+
+    #[func]
+    pub fn get_output_rate(&self) -> i32 {
+        self.output_rate
+    }
+
+    /// Resamples the next chunk of the stream.
+    #[func]
+    pub fn process(&mut self, input_samples: PackedVector2Array) -> PackedVector2Array {
+        if self.input_rate == self.output_rate {
+            return input_samples;
+        }
+        let mut output = Vec::with_capacity(
+            input_samples.len() * self.output_rate as usize / self.input_rate as usize + 1,
+        );
+        self.resampler
+            .process(input_samples.as_slice(), &mut output);
+        PackedVector2Array::from(output)
+    }
+
+    /// Forgets the stream position, e.g. after a gap in the input.
+    #[func]
+    pub fn reset(&mut self) {
+        self.resampler = LinearResampler::new(self.input_rate as u32, self.output_rate as u32);
+    }
+}
+
 /// Linear interpolation resampling function for stereo audio
 fn linear_resample_stereo(input: &[Vector2], input_rate: i32, output_rate: i32) -> Vec<Vector2> {
     if input.is_empty() || input_rate <= 0 || output_rate <= 0 {
@@ -1,37 +1,845 @@
 use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
 
-use godot::classes::{AudioEffect, AudioEffectInstance, IAudioEffect, IAudioEffectInstance};
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
 
 use godot::{classes::native::AudioFrame, prelude::*};
 use nnnoiseless::DenoiseState;
 
+use crate::deep_filter_net_audio_effect::emit_deferred;
+use crate::dsp_util::{
+    db_to_gain, gain_to_db, goertzel_power, hann_window, ms_to_coeff, ms_to_samples, one_pole_step,
+    EnvelopeFollower, FadeIn, LinearResampler,
+};
+
+/// Sample rate RNNoise is trained for.
+const RNNOISE_SAMPLE_RATE: u32 = 48_000;
+/// Time constant of the smoothed voice probability.
+const VOICE_PROBABILITY_SMOOTHING_MS: f32 = 100.0;
+/// Block size the buffers are allocated for up front, so processing doesn't
+/// allocate on the audio thread. Larger blocks grow them once.
+const RESERVED_BLOCK_FRAMES: usize = 4096;
+/// How far the smoothed voice probability has to move before
+/// `voice_probability_changed` is emitted again.
+const VOICE_PROBABILITY_SIGNAL_STEP: f32 = 0.05;
+
+/// Default length of the fade-in after the denoiser starts, one frame.
+const FADE_IN_MS: f32 = 10.0;
+/// Bands RNNoise computes its gains in.
+const RNNOISE_BANDS: usize = 22;
+/// Centers of RNNoise's bands in units of 200 Hz.
+const RNNOISE_BAND_CENTERS: [usize; RNNOISE_BANDS] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 34, 40, 48, 60, 78, 100,
+];
+/// How often band gains are measured while they're polled.
+const BAND_GAINS_INTERVAL_MS: f32 = 50.0;
+/// Share of each new measurement in the reported band gains.
+const BAND_GAINS_SMOOTHING: f32 = 0.5;
+/// How fast the voice gate opens.
+const VOICE_GATE_ATTACK_MS: f32 = 5.0;
+
+/// Settings of the voice gate, shared with the instances.
+#[derive(Debug)]
+struct VoiceGateSettings {
+    enabled: AtomicBool,
+    threshold_bits: AtomicU32,
+    hold_ms_bits: AtomicU32,
+    release_ms_bits: AtomicU32,
+    floor_db_bits: AtomicU32,
+}
+
+impl Default for VoiceGateSettings {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            threshold_bits: AtomicU32::new(0.5f32.to_bits()),
+            hold_ms_bits: AtomicU32::new(300.0f32.to_bits()),
+            release_ms_bits: AtomicU32::new(150.0f32.to_bits()),
+            floor_db_bits: AtomicU32::new((-40.0f32).to_bits()),
+        }
+    }
+}
+
+impl VoiceGateSettings {
+    fn load(bits: &AtomicU32) -> f32 {
+        f32::from_bits(bits.load(Ordering::Relaxed))
+    }
+
+    fn store(bits: &AtomicU32, value: f32) {
+        bits.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Attenuates audio RNNoise doesn't think contains speech.
+///
+/// The gate opens when a frame's voice probability reaches the threshold and
+/// closes once it stayed below for the hold time.
+pub(crate) struct VoiceGate {
+    gain: EnvelopeFollower,
+    /// Samples left before the gate closes.
+    hold_remaining: usize,
+    target_gain: f32,
+}
+
+impl VoiceGate {
+    pub(crate) fn new() -> Self {
+        Self {
+            gain: EnvelopeFollower {
+                value: 1.0,
+                ..Default::default()
+            },
+            hold_remaining: 0,
+            target_gain: 1.0,
+        }
+    }
+
+    /// Opens the gate, e.g. after the denoiser was reset.
+    pub(crate) fn reset(&mut self) {
+        self.gain.value = 1.0;
+        self.hold_remaining = 0;
+        self.target_gain = 1.0;
+    }
+
+    /// Decides whether the gate is open for the next `frame_count` samples.
+    /// `voice_probability` is `None` when no RNNoise frame completed, which
+    /// keeps the gate open or closed but counts down the hold time.
+    pub(crate) fn begin_block(
+        &mut self,
+        voice_probability: Option<f32>,
+        threshold: f32,
+        hold_samples: usize,
+        floor_gain: f32,
+        frame_count: usize,
+    ) {
+        if voice_probability.is_some_and(|p| p >= threshold) {
+            self.hold_remaining = hold_samples.max(frame_count);
+        } else {
+            self.hold_remaining = self.hold_remaining.saturating_sub(frame_count);
+        }
+        self.target_gain = if self.hold_remaining > 0 {
+            1.0
+        } else {
+            floor_gain
+        };
+    }
+
+    pub(crate) fn set_release_ms(&mut self, release_ms: f32, sample_rate: f32) {
+        self.gain
+            .set_times(VOICE_GATE_ATTACK_MS, release_ms.max(0.0), sample_rate);
+    }
+
+    /// Returns the gain for the next sample.
+    pub(crate) fn next_gain(&mut self) -> f32 {
+        self.gain.process(self.target_gain)
+    }
+}
+
+/// Counters behind [`AudioEffectRNNoise::get_stats`], written by the
+/// instance.
+#[derive(Debug, Default)]
+struct RnnoiseStats {
+    frames_processed: AtomicU64,
+    /// Bits of the sum of squared input samples, as `f64`.
+    input_square_sum_bits: AtomicU64,
+    /// Bits of the sum of squared output samples, as `f64`.
+    output_square_sum_bits: AtomicU64,
+}
+
+impl RnnoiseStats {
+    fn record_block(&self, input: &[AudioFrame], output: &[AudioFrame]) {
+        let square_sum = |frames: &[AudioFrame]| {
+            frames
+                .iter()
+                .map(|frame| (frame.left as f64).powi(2) * 0.5 + (frame.right as f64).powi(2) * 0.5)
+                .sum::<f64>()
+        };
+        // Only the instance writes, so adding needs no compare-exchange.
+        for (bits, added) in [
+            (&self.input_square_sum_bits, square_sum(input)),
+            (&self.output_square_sum_bits, square_sum(output)),
+        ] {
+            let total = f64::from_bits(bits.load(Ordering::Relaxed)) + added;
+            bits.store(total.to_bits(), Ordering::Relaxed);
+        }
+        self.frames_processed
+            .fetch_add(input.len() as u64, Ordering::Relaxed);
+    }
+
+    /// RMS level since the last reset from one of the square sums.
+    fn average_rms(&self, square_sum_bits: &AtomicU64) -> f32 {
+        let frames = self.frames_processed.load(Ordering::Relaxed);
+        if frames == 0 {
+            return 0.0;
+        }
+        (f64::from_bits(square_sum_bits.load(Ordering::Relaxed)) / frames as f64).sqrt() as f32
+    }
+
+    fn reset(&self) {
+        self.frames_processed.store(0, Ordering::Relaxed);
+        self.input_square_sum_bits.store(0, Ordering::Relaxed);
+        self.output_square_sum_bits.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Measures how much RNNoise attenuates each of its bands, by comparing a
+/// frame of output with the input it came from. nnnoiseless keeps the gains
+/// it computes to itself.
+pub(crate) struct BandGainAnalyzer {
+    /// Band of each DFT bin of a frame, by the nearest band center.
+    bin_bands: Vec<usize>,
+    /// Goertzel coefficient of each bin.
+    bin_coeffs: Vec<f32>,
+    window: Vec<f32>,
+    /// Set to measure the next frame.
+    pending: bool,
+    /// Set when `gains_db` changed since it was last taken.
+    updated: bool,
+    gains_db: [f32; RNNOISE_BANDS],
+}
+
+impl BandGainAnalyzer {
+    pub(crate) fn new() -> Self {
+        let frame_size = DenoiseState::FRAME_SIZE;
+        // Bins are 100 Hz apart at 48 kHz, band centers in units of 200 Hz.
+        let bin_bands = (0..=frame_size / 2)
+            .map(|bin| {
+                let position = bin as f32 * 0.5;
+                (0..RNNOISE_BANDS)
+                    .min_by(|a, b| {
+                        let distance_a = (RNNOISE_BAND_CENTERS[*a] as f32 - position).abs();
+                        let distance_b = (RNNOISE_BAND_CENTERS[*b] as f32 - position).abs();
+                        distance_a.total_cmp(&distance_b)
+                    })
+                    .unwrap_or(0)
+            })
+            .collect();
+        let bin_coeffs = (0..=frame_size / 2)
+            .map(|bin| 2.0 * (2.0 * std::f32::consts::PI * bin as f32 / frame_size as f32).cos())
+            .collect();
+        Self {
+            bin_bands,
+            bin_coeffs,
+            window: hann_window(frame_size),
+            pending: false,
+            updated: false,
+            gains_db: [0.0; RNNOISE_BANDS],
+        }
+    }
+
+    /// Measures the gain per band from `input` to `output`, which are one
+    /// frame each.
+    fn analyze(&mut self, input: &[f32], output: &[f32]) {
+        let mut input_power = [0.0f32; RNNOISE_BANDS];
+        let mut output_power = [0.0f32; RNNOISE_BANDS];
+        for (band, coeff) in self.bin_bands.iter().zip(&self.bin_coeffs) {
+            input_power[*band] += goertzel_power(input, &self.window, *coeff);
+            output_power[*band] += goertzel_power(output, &self.window, *coeff);
+        }
+
+        for ((gain_db, input), output) in self
+            .gains_db
+            .iter_mut()
+            .zip(&input_power)
+            .zip(&output_power)
+        {
+            let measured = if *input > 0.0 {
+                gain_to_db((output / input).sqrt())
+            } else {
+                0.0
+            };
+            *gain_db += (measured - *gain_db) * BAND_GAINS_SMOOTHING;
+        }
+        self.pending = false;
+        self.updated = true;
+    }
+}
+
+/// Mono RNNoise that takes blocks of any size.
+pub(crate) struct RnnoiseDenoiser {
+    denoise: Box<DenoiseState<'static>>,
+    input_buffer: Vec<f32>,
+    output_buffer: Vec<f32>,
+    first_frame: bool,
+    /// The frame before the one being processed. RNNoise output lags its
+    /// input by a frame, so this is the dry signal that lines up with it.
+    previous_frame: [f32; DenoiseState::FRAME_SIZE],
+    /// Share of the denoised signal in the output.
+    amount: f32,
+    /// Amount the last frame ended at, ramped from to avoid clicks.
+    applied_amount: f32,
+    /// Fades the output in after starting, over RNNoise's start-up artifacts.
+    fade_in: FadeIn,
+    band_gains: BandGainAnalyzer,
+}
+
+impl RnnoiseDenoiser {
+    pub(crate) fn new() -> Self {
+        Self {
+            denoise: Box::new(*DenoiseState::new()),
+            input_buffer: Vec::with_capacity(RESERVED_BLOCK_FRAMES + DenoiseState::FRAME_SIZE),
+            output_buffer: Vec::with_capacity(RESERVED_BLOCK_FRAMES + DenoiseState::FRAME_SIZE),
+            first_frame: true,
+            previous_frame: [0.0; DenoiseState::FRAME_SIZE],
+            amount: 1.0,
+            applied_amount: 1.0,
+            fade_in: FadeIn::new(DenoiseState::FRAME_SIZE),
+            band_gains: BandGainAnalyzer::new(),
+        }
+    }
+
+    /// Measures the band gains of the next frame, see
+    /// [`Self::take_band_gains_db`].
+    pub(crate) fn request_band_gains(&mut self) {
+        self.band_gains.pending = true;
+    }
+
+    /// Returns the gain per RNNoise band in dB, smoothed over the frames
+    /// measured so far, if a new frame was measured since the last call.
+    pub(crate) fn take_band_gains_db(&mut self) -> Option<&[f32; RNNOISE_BANDS]> {
+        if !self.band_gains.updated {
+            return None;
+        }
+        self.band_gains.updated = false;
+        Some(&self.band_gains.gains_db)
+    }
+
+    /// Sets how many samples the output fades in over after starting or
+    /// [`Self::reset`]. 0 starts at full volume.
+    pub(crate) fn set_fade_in_samples(&mut self, samples: usize) {
+        self.fade_in.set_length(samples);
+    }
+
+    /// Sets how much of the denoised signal is used, from 0.0 (the original
+    /// signal, delayed like the denoised one) to 1.0.
+    pub(crate) fn set_amount(&mut self, amount: f32) {
+        self.amount = amount.clamp(0.0, 1.0);
+    }
+
+    /// Drops buffered audio, e.g. stale after the effect was bypassed, and
+    /// passes input through again until new output is ready.
+    pub(crate) fn reset(&mut self) {
+        self.input_buffer.clear();
+        self.output_buffer.clear();
+        self.first_frame = true;
+        self.previous_frame = [0.0; DenoiseState::FRAME_SIZE];
+        self.fade_in.restart();
+    }
+
+    /// How many samples the next output sample lags behind the next input:
+    /// RNNoise's own frame of delay plus the samples waiting in the buffers.
+    pub(crate) fn latency_samples(&self) -> usize {
+        if self.first_frame {
+            // Input still passes through.
+            return 0;
+        }
+        DenoiseState::FRAME_SIZE + self.input_buffer.len() + self.output_buffer.len()
+    }
+
+    /// Denoises `input` into `output`, which must be as long. Samples are in
+    /// -1.0 to 1.0. Until RNNoise has output, the input is passed through.
+    /// Returns the voice probability of the last processed RNNoise frame, if
+    /// any was completed.
+    pub(crate) fn process(&mut self, input: &[f32], output: &mut [f32]) -> Option<f32> {
+        // Scale to i16 range
+        self.input_buffer
+            .extend(input.iter().map(|sample| sample * i16::MAX as f32));
+
+        // Process complete frames
+        let mut voice_probability = None;
+        let mut processed = 0;
+        while self.input_buffer.len() - processed >= DenoiseState::FRAME_SIZE {
+            let mut out_buf = [0.0; DenoiseState::FRAME_SIZE];
+            let frame = &self.input_buffer[processed..processed + DenoiseState::FRAME_SIZE];
+
+            // Process one frame
+            voice_probability = Some(self.denoise.process_frame(&mut out_buf[..], frame));
+            if self.band_gains.pending && !self.first_frame {
+                self.band_gains.analyze(&self.previous_frame, &out_buf);
+            }
+
+            let step = (self.amount - self.applied_amount) / DenoiseState::FRAME_SIZE as f32;
+            for (i, (denoised, dry)) in out_buf.iter().zip(&self.previous_frame).enumerate() {
+                let amount = self.applied_amount + step * (i + 1) as f32;
+                let gain = self.fade_in.next_gain();
+                self.output_buffer
+                    .push(gain * (amount * denoised + (1.0 - amount) * dry));
+            }
+            self.applied_amount = self.amount;
+            self.first_frame = false;
+            self.previous_frame.copy_from_slice(frame);
+            processed += DenoiseState::FRAME_SIZE;
+        }
+
+        // Remove processed samples from input buffer
+        self.input_buffer.drain(..processed);
+
+        // Fill output with available processed samples
+        for (i, (out_sample, in_sample)) in output.iter_mut().zip(input).enumerate() {
+            *out_sample = match self.output_buffer.get(i) {
+                Some(denoised_sample) => denoised_sample / i16::MAX as f32,
+                // If we don't have enough processed samples, use original input
+                None => *in_sample,
+            };
+        }
+
+        // Remove consumed output samples
+        let consumed = input.len().min(self.output_buffer.len());
+        self.output_buffer.drain(..consumed);
+        voice_probability
+    }
+}
+
+/// Denoises a whole mono clip, resampling to and from RNNoise's 48 kHz.
+/// The result lines up with `samples` and is as long.
+pub(crate) fn denoise_clip(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    let frame_size = DenoiseState::FRAME_SIZE;
+    let mut input = Vec::with_capacity(samples.len() + frame_size * 2);
+    if sample_rate == RNNOISE_SAMPLE_RATE {
+        input.extend_from_slice(samples);
+    } else {
+        LinearResampler::new(sample_rate, RNNOISE_SAMPLE_RATE).process(samples, &mut input);
+    }
+    let len = input.len();
+    // Silence after the clip flushes out its last frame, and whole frames
+    // keep the end from passing through undenoised.
+    input.resize((len / frame_size + 2) * frame_size, 0.0);
+
+    let mut denoiser = RnnoiseDenoiser::new();
+    // The frame that would be faded in is cut below.
+    denoiser.set_fade_in_samples(0);
+    let mut output = vec![0.0; input.len()];
+    denoiser.process(&input, &mut output);
+    output.drain(..frame_size);
+    output.truncate(len);
+
+    if sample_rate == RNNOISE_SAMPLE_RATE {
+        return output;
+    }
+    let mut resampled = Vec::with_capacity(samples.len() + 1);
+    LinearResampler::new(RNNOISE_SAMPLE_RATE, sample_rate).process(&output, &mut resampled);
+    resampled.resize(samples.len(), 0.0);
+    resampled
+}
+
 /// Adds a noise removal effect to an audio bus using RNNoise[^rnnoise].
 ///
 /// Uses both traditional signal processing and a recurrent neural network to
-/// remove noise from audio. The effect is fairly aggressive and can't be configured.
+/// remove noise from audio. The effect is fairly aggressive.
+///
+/// By default the bus is downmixed to mono and the denoised signal is written
+/// to both output channels. Turn on [member stereo] to denoise left and right
+/// separately, keeping the stereo image on buses other than voice, at twice
+/// the CPU cost.
+///
+/// Turning [member enabled] off passes audio through, so noise suppression
+/// can be toggled in settings without changing the bus effects.
+///
+/// The output fades in over [member fade_in_ms] after starting, hiding
+/// RNNoise's start-up artifacts.
+///
+/// Lower [member suppression_amount] to blend some of the original signal
+/// back in where full suppression dulls a voice.
+///
+/// The network also estimates how likely the audio contains speech, see
+/// [method get_voice_probability] and [signal voice_probability_changed].
+/// [method get_band_gains] shows how much it suppresses each band, for
+/// spectral meters in audio settings.
+///
+/// Turn on [member voice_gate] to also mute what the network doesn't think
+/// is speech, like a noise gate driven by the voice probability instead of
+/// the level, sparing a separate [AudioEffectNoiseGate].
 /// [^rnnoise]: https://github.com/xiph/rnnoise
 #[derive(GodotClass, Debug)]
-#[class(tool, init, base=AudioEffect)]
+#[class(tool, base=AudioEffect)]
 pub(crate) struct AudioEffectRNNoise {
     pub(crate) base: Base<AudioEffect>,
+    /// When off, audio passes through unchanged.
+    #[export]
+    #[var(get = is_enabled, set = set_enabled)]
+    enabled: bool,
+    enabled_flag: Arc<AtomicBool>,
+    /// Share of the denoised signal in the output. The rest is the original
+    /// signal, delayed to line up with the denoised one.
+    #[export(range = (0.0, 1.0))]
+    #[var(get = get_suppression_amount, set = set_suppression_amount)]
+    suppression_amount: f32,
+    /// Bits of [member suppression_amount], read by the instance.
+    suppression_amount_bits: Arc<AtomicU32>,
+    /// Denoises both channels instead of a mono downmix.
+    #[export]
+    #[var(get = is_stereo, set = set_stereo)]
+    stereo: bool,
+    stereo_flag: Arc<AtomicBool>,
+    /// How long the output fades in after the effect starts or is enabled
+    /// again, in milliseconds. 0 starts at full volume.
+    #[export(range = (0.0, 500.0, 1.0, or_greater, suffix = "ms"))]
+    #[var(get = get_fade_in_ms, set = set_fade_in_ms)]
+    fade_in_ms: f32,
+    fade_in_ms_bits: Arc<AtomicU32>,
+    /// Attenuates audio whose voice probability is below
+    /// [member voice_gate_threshold].
+    #[export]
+    #[var(get = is_voice_gate, set = set_voice_gate)]
+    voice_gate: bool,
+    /// Voice probability at which the gate opens.
+    #[export(range = (0.0, 1.0))]
+    #[var(get = get_voice_gate_threshold, set = set_voice_gate_threshold)]
+    voice_gate_threshold: f32,
+    /// How long the gate stays open after the voice probability dropped
+    /// below the threshold, bridging pauses between words.
+    #[export(range = (0.0, 2000.0, suffix = "ms"))]
+    #[var(get = get_voice_gate_hold_ms, set = set_voice_gate_hold_ms)]
+    voice_gate_hold_ms: f32,
+    /// How long the gate takes to close.
+    #[export(range = (1.0, 2000.0, suffix = "ms"))]
+    #[var(get = get_voice_gate_release_ms, set = set_voice_gate_release_ms)]
+    voice_gate_release_ms: f32,
+    /// Gain while the gate is closed.
+    #[export(range = (-80.0, 0.0, suffix = "dB"))]
+    #[var(get = get_voice_gate_floor_db, set = set_voice_gate_floor_db)]
+    voice_gate_floor_db: f32,
+    voice_gate_settings: Arc<VoiceGateSettings>,
+    /// Bits of the latest voice probability, written by the instance.
+    voice_probability: Arc<AtomicU32>,
+    /// Bits of the smoothed voice probability, written by the instance.
+    smoothed_voice_probability: Arc<AtomicU32>,
+    /// Bits of the delay the instance currently adds, in milliseconds.
+    latency_ms_bits: Arc<AtomicU32>,
+    /// Bits of the gain per band in dB, written by the instance.
+    band_gains_db_bits: Arc<[AtomicU32; RNNOISE_BANDS]>,
+    /// Set by [method get_band_gains], so they're only measured when used.
+    band_gains_polled: Arc<AtomicBool>,
+    stats: Arc<RnnoiseStats>,
 }
 
 #[godot_api]
 impl IAudioEffect for AudioEffectRNNoise {
+    fn init(base: Base<AudioEffect>) -> Self {
+        Self {
+            base,
+            enabled: true,
+            enabled_flag: Arc::new(AtomicBool::new(true)),
+            suppression_amount: 1.0,
+            suppression_amount_bits: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            stereo: false,
+            stereo_flag: Arc::default(),
+            fade_in_ms: FADE_IN_MS,
+            fade_in_ms_bits: Arc::new(AtomicU32::new(FADE_IN_MS.to_bits())),
+            voice_gate: false,
+            voice_gate_threshold: 0.5,
+            voice_gate_hold_ms: 300.0,
+            voice_gate_release_ms: 150.0,
+            voice_gate_floor_db: -40.0,
+            voice_gate_settings: Arc::default(),
+            voice_probability: Arc::default(),
+            smoothed_voice_probability: Arc::default(),
+            latency_ms_bits: Arc::default(),
+            band_gains_db_bits: Arc::default(),
+            band_gains_polled: Arc::default(),
+            stats: Arc::default(),
+        }
+    }
+
     fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
-        let rnnoise = AudioEffectRNNoiseInstance::new_gd();
+        let mut rnnoise = AudioEffectRNNoiseInstance::new_gd();
+        {
+            let mut rnnoise_mut = rnnoise.bind_mut();
+            rnnoise_mut.enabled_flag = self.enabled_flag.clone();
+            rnnoise_mut.suppression_amount_bits = self.suppression_amount_bits.clone();
+            rnnoise_mut.stereo_flag = self.stereo_flag.clone();
+            rnnoise_mut.fade_in_ms_bits = self.fade_in_ms_bits.clone();
+            rnnoise_mut.voice_gate_settings = self.voice_gate_settings.clone();
+            rnnoise_mut.voice_probability = self.voice_probability.clone();
+            rnnoise_mut.smoothed_voice_probability = self.smoothed_voice_probability.clone();
+            rnnoise_mut.latency_ms_bits = self.latency_ms_bits.clone();
+            rnnoise_mut.band_gains_db_bits = self.band_gains_db_bits.clone();
+            rnnoise_mut.band_gains_polled = self.band_gains_polled.clone();
+            rnnoise_mut.stats = self.stats.clone();
+            rnnoise_mut.effect_id = Some(self.base().instance_id());
+        }
         return Some(rnnoise.upcast::<AudioEffectInstance>());
     }
 }
 
+#[godot_api]
+impl AudioEffectRNNoise {
+    /// Emitted when the smoothed voice probability, see
+    /// [method get_smoothed_voice_probability], moved by at least 0.05 since
+    /// the last emission. Emitted on the main thread.
+    #[signal]
+    fn voice_probability_changed(probability: f32);
+
+    /// Returns the probability (0.0 to 1.0) that the most recently processed
+    /// 10 ms of audio contained speech. With [member stereo], the higher
+    /// probability of the two channels.
+    #[func]
+    fn get_voice_probability(&self) -> f32 {
+        f32::from_bits(self.voice_probability.load(Ordering::Relaxed))
+    }
+
+    /// Returns the voice probability smoothed over about 100 ms, steadier
+    /// than [method get_voice_probability] for gates and indicators.
+    #[func]
+    fn get_smoothed_voice_probability(&self) -> f32 {
+        f32::from_bits(self.smoothed_voice_probability.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many samples RNNoise processes at a time, 480. Its
+    /// output lags by at least one such frame, 10 ms at 48 kHz.
+    #[func]
+    fn get_frame_size() -> i32 {
+        DenoiseState::FRAME_SIZE as i32
+    }
+
+    /// Returns how much the effect currently delays audio, in milliseconds:
+    /// RNNoise's frame plus audio waiting to be processed or output. Useful
+    /// to delay lip sync or to correct latency measurements.
+    #[func]
+    fn get_latency_ms(&self) -> f32 {
+        f32::from_bits(self.latency_ms_bits.load(Ordering::Relaxed))
+    }
+
+    /// Returns diagnostics since the effect was created or the last
+    /// [method reset_stats]: `frames_processed` (audio frames denoised,
+    /// bypassed ones not counted), `avg_input_rms` and `avg_output_rms`
+    /// (average levels before and after the effect, 0.0 to 1.0) and
+    /// `voice_probability` (see [method get_voice_probability]).
+    #[func]
+    fn get_stats(&self) -> Dictionary {
+        let stats = &self.stats;
+        let mut out = Dictionary::new();
+        out.set(
+            "frames_processed",
+            stats.frames_processed.load(Ordering::Relaxed) as i64,
+        );
+        out.set(
+            "avg_input_rms",
+            stats.average_rms(&stats.input_square_sum_bits),
+        );
+        out.set(
+            "avg_output_rms",
+            stats.average_rms(&stats.output_square_sum_bits),
+        );
+        out.set("voice_probability", self.get_voice_probability());
+        out
+    }
+
+    /// Sets all statistics back to zero.
+    #[func]
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// Returns how much RNNoise changes each of its 22 bands in dB, e.g.
+    /// -20.0 where it removed noise, to draw a spectral suppression meter.
+    /// Bands are measured about every 50 ms while this is polled, smoothed,
+    /// so the first calls return zeros. With [member stereo], of the left
+    /// channel. See [method get_band_frequencies].
+    #[func]
+    fn get_band_gains(&self) -> PackedFloat32Array {
+        self.band_gains_polled.store(true, Ordering::Relaxed);
+        let gains_db: Vec<f32> = self
+            .band_gains_db_bits
+            .iter()
+            .map(|bits| f32::from_bits(bits.load(Ordering::Relaxed)))
+            .collect();
+        PackedFloat32Array::from(gains_db)
+    }
+
+    /// Returns the center frequencies in Hz of the bands of
+    /// [method get_band_gains], from 0 to 20000.
+    #[func]
+    fn get_band_frequencies() -> PackedFloat32Array {
+        let frequencies: Vec<f32> = RNNOISE_BAND_CENTERS
+            .iter()
+            .map(|center| *center as f32 * 200.0)
+            .collect();
+        PackedFloat32Array::from(frequencies)
+    }
+
+    #[func]
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    #[func]
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.enabled_flag.store(enabled, Ordering::Relaxed);
+    }
+
+    #[func]
+    fn get_suppression_amount(&self) -> f32 {
+        self.suppression_amount
+    }
+
+    #[func]
+    fn set_suppression_amount(&mut self, value: f32) {
+        self.suppression_amount = value.clamp(0.0, 1.0);
+        self.suppression_amount_bits
+            .store(self.suppression_amount.to_bits(), Ordering::Relaxed);
+    }
+
+    #[func]
+    fn is_stereo(&self) -> bool {
+        self.stereo
+    }
+
+    #[func]
+    fn set_stereo(&mut self, value: bool) {
+        self.stereo = value;
+        self.stereo_flag.store(value, Ordering::Relaxed);
+    }
+
+    #[func]
+    fn get_fade_in_ms(&self) -> f32 {
+        self.fade_in_ms
+    }
+
+    #[func]
+    fn set_fade_in_ms(&mut self, value: f32) {
+        self.fade_in_ms = value.max(0.0);
+        self.fade_in_ms_bits
+            .store(self.fade_in_ms.to_bits(), Ordering::Relaxed);
+    }
+
+    #[func]
+    fn is_voice_gate(&self) -> bool {
+        self.voice_gate
+    }
+
+    #[func]
+    fn set_voice_gate(&mut self, value: bool) {
+        self.voice_gate = value;
+        self.voice_gate_settings
+            .enabled
+            .store(value, Ordering::Relaxed);
+    }
+
+    #[func]
+    fn get_voice_gate_threshold(&self) -> f32 {
+        self.voice_gate_threshold
+    }
+
+    #[func]
+    fn set_voice_gate_threshold(&mut self, value: f32) {
+        self.voice_gate_threshold = value.clamp(0.0, 1.0);
+        VoiceGateSettings::store(
+            &self.voice_gate_settings.threshold_bits,
+            self.voice_gate_threshold,
+        );
+    }
+
+    #[func]
+    fn get_voice_gate_hold_ms(&self) -> f32 {
+        self.voice_gate_hold_ms
+    }
+
+    #[func]
+    fn set_voice_gate_hold_ms(&mut self, value: f32) {
+        self.voice_gate_hold_ms = value.max(0.0);
+        VoiceGateSettings::store(
+            &self.voice_gate_settings.hold_ms_bits,
+            self.voice_gate_hold_ms,
+        );
+    }
+
+    #[func]
+    fn get_voice_gate_release_ms(&self) -> f32 {
+        self.voice_gate_release_ms
+    }
+
+    #[func]
+    fn set_voice_gate_release_ms(&mut self, value: f32) {
+        self.voice_gate_release_ms = value.max(1.0);
+        VoiceGateSettings::store(
+            &self.voice_gate_settings.release_ms_bits,
+            self.voice_gate_release_ms,
+        );
+    }
+
+    #[func]
+    fn get_voice_gate_floor_db(&self) -> f32 {
+        self.voice_gate_floor_db
+    }
+
+    #[func]
+    fn set_voice_gate_floor_db(&mut self, value: f32) {
+        self.voice_gate_floor_db = value.min(0.0);
+        VoiceGateSettings::store(
+            &self.voice_gate_settings.floor_db_bits,
+            self.voice_gate_floor_db,
+        );
+    }
+}
+
 #[derive(GodotClass)]
 #[class(base=AudioEffectInstance)]
 pub(crate) struct AudioEffectRNNoiseInstance {
     pub(crate) base: Base<AudioEffectInstance>,
-    denoise: Box<DenoiseState<'static>>,
-    input_buffer: Vec<f32>,
-    output_buffer: Vec<f32>,
-    first_frame: bool,
+    /// Denoises the mono downmix, or the left channel in stereo.
+    denoiser: RnnoiseDenoiser,
+    /// Denoises the right channel in stereo. Created up front, so turning
+    /// stereo on doesn't allocate on the audio thread.
+    right_denoiser: RnnoiseDenoiser,
+    /// Whether the previous block was processed in stereo.
+    was_stereo: bool,
+    left_input: Vec<f32>,
+    left_output: Vec<f32>,
+    right_input: Vec<f32>,
+    right_output: Vec<f32>,
+    enabled_flag: Arc<AtomicBool>,
+    /// Whether the previous block was processed, to reset on re-enabling.
+    was_enabled: bool,
+    suppression_amount_bits: Arc<AtomicU32>,
+    stereo_flag: Arc<AtomicBool>,
+    fade_in_ms_bits: Arc<AtomicU32>,
+    voice_gate_settings: Arc<VoiceGateSettings>,
+    voice_gate: VoiceGate,
+    voice_probability: Arc<AtomicU32>,
+    smoothed_voice_probability: Arc<AtomicU32>,
+    latency_ms_bits: Arc<AtomicU32>,
+    band_gains_db_bits: Arc<[AtomicU32; RNNOISE_BANDS]>,
+    band_gains_polled: Arc<AtomicBool>,
+    /// Samples until the band gains may be measured again.
+    band_gains_countdown: usize,
+    stats: Arc<RnnoiseStats>,
+    mix_rate: f32,
+    /// The effect that created this instance, for its signal.
+    effect_id: Option<InstanceId>,
+    smoothed: f32,
+    /// Smoothed probability last sent with `voice_probability_changed`.
+    last_emitted: f32,
+    /// Smoothing coefficient per block and the block size it was made for.
+    smoothing_coeff: f32,
+    smoothing_frames: usize,
+}
+
+impl AudioEffectRNNoiseInstance {
+    fn update_smoothed_probability(&mut self, voice_probability: f32, frame_count: usize) {
+        if self.smoothing_frames != frame_count {
+            let blocks_per_second = self.mix_rate / frame_count as f32;
+            self.smoothing_coeff = ms_to_coeff(VOICE_PROBABILITY_SMOOTHING_MS, blocks_per_second);
+            self.smoothing_frames = frame_count;
+        }
+        self.smoothed = one_pole_step(self.smoothed, voice_probability, self.smoothing_coeff);
+        self.smoothed_voice_probability
+            .store(self.smoothed.to_bits(), Ordering::Relaxed);
+
+        if (self.smoothed - self.last_emitted).abs() >= VOICE_PROBABILITY_SIGNAL_STEP {
+            self.last_emitted = self.smoothed;
+            if let Some(effect_id) = self.effect_id {
+                emit_deferred(
+                    effect_id,
+                    "voice_probability_changed",
+                    &[self.smoothed.to_variant()],
+                );
+            }
+        }
+    }
 }
 
 #[godot_api]
@@ -47,64 +855,275 @@ impl IAudioEffectInstance for AudioEffectRNNoiseInstance {
         let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
         let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
 
-        // Convert input to mono and scale to i16 range
-        let scaled_input: Vec<f32> = input_slice
-            .iter()
-            .map(|frame| ((frame.left + frame.right) / 2.0) * i16::MAX as f32)
-            .collect();
-
-        // Add new input to buffer
-        self.input_buffer.extend_from_slice(&scaled_input);
-
-        // Process complete frames
-        while self.input_buffer.len() >= DenoiseState::FRAME_SIZE {
-            let mut out_buf = [0.0; DenoiseState::FRAME_SIZE];
+        let enabled = self.enabled_flag.load(Ordering::Relaxed);
+        if !enabled {
+            for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+                out_frame.left = in_frame.left;
+                out_frame.right = in_frame.right;
+            }
+            self.latency_ms_bits.store(0, Ordering::Relaxed);
+            self.was_enabled = false;
+            return;
+        }
+        if !self.was_enabled {
+            // Audio buffered before bypassing would be stale.
+            self.denoiser.reset();
+            self.right_denoiser.reset();
+            self.voice_gate.reset();
+            self.was_enabled = true;
+        }
 
-            // Process one frame
-            self.denoise.process_frame(
-                &mut out_buf[..],
-                &self.input_buffer[..DenoiseState::FRAME_SIZE],
+        let stereo = self.stereo_flag.load(Ordering::Relaxed);
+        if self.was_stereo && !stereo {
+            // Start from scratch when stereo is turned on again.
+            self.right_denoiser.reset();
+        }
+        self.was_stereo = stereo;
+        self.left_input.clear();
+        self.right_input.clear();
+        if stereo {
+            self.left_input
+                .extend(input_slice.iter().map(|frame| frame.left));
+            self.right_input
+                .extend(input_slice.iter().map(|frame| frame.right));
+        } else {
+            // Convert input to mono
+            self.left_input.extend(
+                input_slice
+                    .iter()
+                    .map(|frame| (frame.left + frame.right) / 2.0),
             );
+        }
+        self.left_output.resize(frame_count, 0.0);
+        let amount = f32::from_bits(self.suppression_amount_bits.load(Ordering::Relaxed));
+        let fade_in_ms = f32::from_bits(self.fade_in_ms_bits.load(Ordering::Relaxed));
+        let fade_in_samples = ms_to_samples(fade_in_ms, self.mix_rate);
+        self.denoiser.set_amount(amount);
+        self.denoiser.set_fade_in_samples(fade_in_samples);
+
+        self.band_gains_countdown = self.band_gains_countdown.saturating_sub(frame_count);
+        if self.band_gains_countdown == 0 && self.band_gains_polled.swap(false, Ordering::Relaxed) {
+            self.denoiser.request_band_gains();
+            self.band_gains_countdown = ms_to_samples(BAND_GAINS_INTERVAL_MS, self.mix_rate);
+        }
 
-            // Skip first frame output due to fade-in artifacts
-            if !self.first_frame {
-                self.output_buffer.extend_from_slice(&out_buf[..]);
+        let mut voice_probability = self
+            .denoiser
+            .process(&self.left_input, &mut self.left_output);
+        if let Some(gains_db) = self.denoiser.take_band_gains_db() {
+            for (bits, gain_db) in self.band_gains_db_bits.iter().zip(gains_db) {
+                bits.store(gain_db.to_bits(), Ordering::Relaxed);
             }
-            self.first_frame = false;
+        }
+        if stereo {
+            let right_denoiser = &mut self.right_denoiser;
+            right_denoiser.set_amount(amount);
+            right_denoiser.set_fade_in_samples(fade_in_samples);
+            self.right_output.resize(frame_count, 0.0);
+            if let Some(right_probability) =
+                right_denoiser.process(&self.right_input, &mut self.right_output)
+            {
+                voice_probability =
+                    Some(voice_probability.map_or(right_probability, |p| p.max(right_probability)));
+            }
+        }
 
-            // Remove processed samples from input buffer
-            self.input_buffer.drain(..DenoiseState::FRAME_SIZE);
+        let latency_ms = self.denoiser.latency_samples() as f32 * 1000.0 / self.mix_rate;
+        self.latency_ms_bits
+            .store(latency_ms.to_bits(), Ordering::Relaxed);
+
+        if let Some(voice_probability) = voice_probability {
+            self.voice_probability
+                .store(voice_probability.to_bits(), Ordering::Relaxed);
+            self.update_smoothed_probability(voice_probability, frame_count);
         }
 
-        // Fill output with available processed samples
-        for (i, output_frame) in output_slice.iter_mut().enumerate() {
-            if i < self.output_buffer.len() {
-                let denoised_sample = self.output_buffer[i] / i16::MAX as f32;
-                output_frame.left = denoised_sample;
-                output_frame.right = denoised_sample;
-            } else {
-                // If we don't have enough processed samples, use original input
-                let original_sample = (input_slice[i].left + input_slice[i].right) / 2.0;
-                output_frame.left = original_sample;
-                output_frame.right = original_sample;
-            }
+        let settings = &self.voice_gate_settings;
+        let gate_enabled = settings.enabled.load(Ordering::Relaxed);
+        if gate_enabled {
+            let hold_ms = VoiceGateSettings::load(&settings.hold_ms_bits);
+            let floor_db = VoiceGateSettings::load(&settings.floor_db_bits);
+            self.voice_gate.set_release_ms(
+                VoiceGateSettings::load(&settings.release_ms_bits),
+                self.mix_rate,
+            );
+            self.voice_gate.begin_block(
+                voice_probability,
+                VoiceGateSettings::load(&settings.threshold_bits),
+                ms_to_samples(hold_ms, self.mix_rate),
+                db_to_gain(floor_db),
+                frame_count,
+            );
+        } else {
+            self.voice_gate.reset();
         }
 
-        // Remove consumed output samples
-        if frame_count <= self.output_buffer.len() {
-            self.output_buffer.drain(..frame_count);
+        let right_output = if stereo {
+            &self.right_output
         } else {
-            self.output_buffer.clear();
+            &self.left_output
+        };
+        for ((output_frame, left), right) in output_slice
+            .iter_mut()
+            .zip(&self.left_output)
+            .zip(right_output)
+        {
+            let gain = if gate_enabled {
+                self.voice_gate.next_gain()
+            } else {
+                1.0
+            };
+            output_frame.left = *left * gain;
+            output_frame.right = *right * gain;
         }
+        self.stats.record_block(input_slice, output_slice);
     }
 
     fn init(base: Base<AudioEffectInstance>) -> Self {
         AudioEffectRNNoiseInstance {
             base,
-            denoise: Box::new(*DenoiseState::new()),
-            input_buffer: Vec::new(),
-            output_buffer: Vec::new(),
-            first_frame: true,
+            denoiser: RnnoiseDenoiser::new(),
+            right_denoiser: RnnoiseDenoiser::new(),
+            was_stereo: false,
+            left_input: Vec::with_capacity(RESERVED_BLOCK_FRAMES),
+            left_output: Vec::with_capacity(RESERVED_BLOCK_FRAMES),
+            right_input: Vec::with_capacity(RESERVED_BLOCK_FRAMES),
+            right_output: Vec::with_capacity(RESERVED_BLOCK_FRAMES),
+            enabled_flag: Arc::new(AtomicBool::new(true)),
+            was_enabled: true,
+            suppression_amount_bits: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            stereo_flag: Arc::default(),
+            fade_in_ms_bits: Arc::new(AtomicU32::new(FADE_IN_MS.to_bits())),
+            voice_gate_settings: Arc::default(),
+            voice_gate: VoiceGate::new(),
+            voice_probability: Arc::default(),
+            smoothed_voice_probability: Arc::default(),
+            latency_ms_bits: Arc::default(),
+            band_gains_db_bits: Arc::default(),
+            band_gains_polled: Arc::default(),
+            band_gains_countdown: 0,
+            stats: Arc::default(),
+            mix_rate: AudioServer::singleton().get_mix_rate().max(1.0),
+            effect_id: None,
+            smoothed: 0.0,
+            last_emitted: 0.0,
+            smoothing_coeff: 0.0,
+            smoothing_frames: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_amount_outputs_input_a_frame_late() {
+        let mut denoiser = RnnoiseDenoiser::new();
+        denoiser.set_amount(0.0);
+        denoiser.applied_amount = 0.0;
+        let input: Vec<f32> = (0..DenoiseState::FRAME_SIZE * 6)
+            .map(|i| 0.3 * (i as f32 * 0.05).sin())
+            .collect();
+        let mut output = vec![0.0; input.len()];
+        for (in_block, out_block) in input
+            .chunks(DenoiseState::FRAME_SIZE)
+            .zip(output.chunks_mut(DenoiseState::FRAME_SIZE))
+        {
+            denoiser.process(in_block, out_block);
+        }
+
+        assert_eq!(denoiser.latency_samples(), DenoiseState::FRAME_SIZE);
+        // Output lags by one frame from the start, with silence before.
+        assert!(output[..DenoiseState::FRAME_SIZE].iter().all(|s| *s == 0.0));
+        for (out, expected) in output[DenoiseState::FRAME_SIZE..].iter().zip(&input) {
+            assert!((out - expected).abs() < 1e-4, "{out} != {expected}");
+        }
+    }
+
+    #[test]
+    fn band_gains_measure_attenuation() {
+        let mut analyzer = BandGainAnalyzer::new();
+        // 1 kHz sits on the center of band 5.
+        let input: Vec<f32> = (0..DenoiseState::FRAME_SIZE)
+            .map(|i| (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 48_000.0).sin())
+            .collect();
+        let output: Vec<f32> = input.iter().map(|sample| sample * 0.1).collect();
+        for _ in 0..30 {
+            analyzer.analyze(&input, &output);
+        }
+
+        assert!(analyzer.updated && !analyzer.pending);
+        assert!(
+            (analyzer.gains_db[5] + 20.0).abs() < 0.1,
+            "{}",
+            analyzer.gains_db[5]
+        );
+    }
+
+    #[test]
+    fn stats_average_rms_over_blocks() {
+        let stats = RnnoiseStats::default();
+        let loud = vec![
+            AudioFrame {
+                left: 0.5,
+                right: 0.5,
+            };
+            100
+        ];
+        let quiet = vec![
+            AudioFrame {
+                left: 0.1,
+                right: -0.1,
+            };
+            100
+        ];
+        stats.record_block(&loud, &quiet);
+        stats.record_block(&quiet, &quiet);
+
+        assert_eq!(stats.frames_processed.load(Ordering::Relaxed), 200);
+        let input_rms = stats.average_rms(&stats.input_square_sum_bits);
+        assert!((input_rms - 0.13f32.sqrt()).abs() < 1e-5, "{input_rms}");
+        let output_rms = stats.average_rms(&stats.output_square_sum_bits);
+        assert!((output_rms - 0.1).abs() < 1e-5, "{output_rms}");
+
+        stats.reset();
+        assert_eq!(stats.average_rms(&stats.input_square_sum_bits), 0.0);
+    }
+
+    #[test]
+    fn denoised_clip_keeps_length() {
+        for (sample_rate, len) in [(48_000, 1_000), (44_100, 44_100), (16_000, 7)] {
+            let clip = vec![0.0; len];
+            let denoised = denoise_clip(&clip, sample_rate);
+            assert_eq!(denoised.len(), len);
+            assert!(denoised.iter().all(|sample| sample.abs() < 1e-3));
+        }
+        assert!(denoise_clip(&[], 48_000).is_empty());
+    }
+
+    #[test]
+    fn voice_gate_closes_after_hold() {
+        let sample_rate = 48000.0;
+        let floor_gain = 0.01;
+        let mut gate = VoiceGate::new();
+        gate.set_release_ms(10.0, sample_rate);
+        let run_block = |gate: &mut VoiceGate, probability: Option<f32>| {
+            gate.begin_block(probability, 0.5, 4800, floor_gain, 480);
+            (0..480).map(|_| gate.next_gain()).last().unwrap()
+        };
+
+        assert!(run_block(&mut gate, Some(0.9)) > 0.99);
+        // Held open for 100 ms, through blocks without a finished frame.
+        for _ in 0..4 {
+            assert!(run_block(&mut gate, Some(0.1)) > 0.99);
+            assert!(run_block(&mut gate, None) > 0.99);
+        }
+        for _ in 0..20 {
+            run_block(&mut gate, Some(0.1));
         }
+        assert!(run_block(&mut gate, Some(0.1)) < floor_gain * 1.01);
+        // Mostly open again after two attack time constants.
+        assert!(run_block(&mut gate, Some(0.6)) > 0.85);
     }
 }
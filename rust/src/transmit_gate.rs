@@ -0,0 +1,309 @@
+use godot::prelude::*;
+
+use crate::dsp_util::{db_to_gain, ms_to_samples, EnvelopeFollower};
+
+const MODE_OPEN: i32 = 0;
+const MODE_PUSH_TO_TALK: i32 = 1;
+const MODE_VOICE_ACTIVATED: i32 = 2;
+
+/// Level detector times for voice-activated transmission.
+const DETECTOR_ATTACK_MS: f32 = 1.0;
+const DETECTOR_RELEASE_MS: f32 = 60.0;
+
+#[derive(Debug, Clone, Copy)]
+struct GateSettings {
+    mode: i32,
+    threshold: f32,
+    hangover_ms: f32,
+    ptt_pressed: bool,
+    /// Latest speech probability from a neural detector, or negative if
+    /// none is available.
+    voice_probability: f32,
+    min_voice_probability: f32,
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    sample_rate: f32,
+    detector: EnvelopeFollower,
+    hangover_counter: usize,
+    speaking: bool,
+    open: bool,
+}
+
+impl GateState {
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        if self.sample_rate != sample_rate {
+            self.sample_rate = sample_rate;
+            self.detector
+                .set_times(DETECTOR_ATTACK_MS, DETECTOR_RELEASE_MS, sample_rate);
+        }
+    }
+
+    /// Updates the voice activity decision with one frame and returns it.
+    fn detect(
+        &mut self,
+        frame: Vector2,
+        threshold: f32,
+        hangover_samples: usize,
+        voice_likely: bool,
+    ) -> bool {
+        let level = ((frame.x + frame.y) * 0.5).abs();
+        let envelope = self.detector.process(level);
+
+        if envelope >= threshold && voice_likely {
+            self.hangover_counter = hangover_samples;
+            self.speaking = true;
+        } else if self.hangover_counter > 0 {
+            self.hangover_counter -= 1;
+        } else {
+            self.speaking = false;
+        }
+        self.speaking
+    }
+
+    /// Decides per frame whether it is transmitted and appends the
+    /// transmitted frames to `out`.
+    fn process(
+        &mut self,
+        settings: &GateSettings,
+        frames: &[Vector2],
+        sample_rate: f32,
+        out: &mut Vec<Vector2>,
+    ) {
+        self.set_sample_rate(sample_rate);
+        let hangover_samples = ms_to_samples(settings.hangover_ms, sample_rate);
+        // Loud noise like keyboard clicks passes the energy check but not
+        // the speech probability check.
+        let voice_likely = settings.voice_probability < 0.0
+            || settings.voice_probability >= settings.min_voice_probability;
+
+        for frame in frames {
+            self.detect(*frame, settings.threshold, hangover_samples, voice_likely);
+
+            self.open = match settings.mode {
+                MODE_PUSH_TO_TALK => settings.ptt_pressed,
+                MODE_VOICE_ACTIVATED => self.speaking,
+                _ => true,
+            };
+
+            if self.open {
+                out.push(*frame);
+            }
+        }
+    }
+}
+
+/// Returns per frame whether [`VoipTransmitGate`] detects voice activity in
+/// `frames` at `threshold_db`, without hangover or speech probability.
+pub(crate) fn detect_voice_activity(
+    frames: &[Vector2],
+    sample_rate: f32,
+    threshold_db: f32,
+) -> Vec<bool> {
+    let mut state = GateState::default();
+    state.set_sample_rate(sample_rate);
+    let threshold = db_to_gain(threshold_db);
+    frames
+        .iter()
+        .map(|frame| state.detect(*frame, threshold, 0, true))
+        .collect()
+}
+
+/// Decides which captured microphone frames are transmitted.
+///
+/// [member transmit_mode] selects open mic, push-to-talk or voice-activated
+/// transmission. The decision is made per sample, so voice-activated
+/// transmission starts on the first loud sample and keeps going for
+/// [member hangover_ms] once the level falls below the threshold.
+///
+/// Voice activity is detected in every mode, see [method is_speaking]. It
+/// combines the input level with the speech probability passed to
+/// [method set_voice_probability], e.g. from
+/// [method AudioEffectRNNoise.get_voice_probability].
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub(crate) struct VoipTransmitGate {
+    /// 0 = OPEN, 1 = PUSH_TO_TALK, 2 = VOICE_ACTIVATED.
+    #[var]
+    transmit_mode: i32,
+    /// Level that opens the gate in voice-activated mode, in dBFS.
+    #[var]
+    vad_threshold_db: f32,
+    /// Time the gate stays open after the level falls below
+    /// [member vad_threshold_db], in milliseconds. Keeps word endings and
+    /// short pauses from being cut off.
+    #[var]
+    hangover_ms: f32,
+    /// Speech probability required for voice activity, see
+    /// [method set_voice_probability].
+    #[var]
+    min_voice_probability: f32,
+    ptt_pressed: bool,
+    voice_probability: f32,
+    state: GateState,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for VoipTransmitGate {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            transmit_mode: MODE_OPEN,
+            vad_threshold_db: -40.0,
+            hangover_ms: 300.0,
+            min_voice_probability: 0.5,
+            ptt_pressed: false,
+            voice_probability: -1.0,
+            state: GateState::default(),
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl VoipTransmitGate {
+    #[constant]
+    const MODE_OPEN: i32 = MODE_OPEN;
+    #[constant]
+    const MODE_PUSH_TO_TALK: i32 = MODE_PUSH_TO_TALK;
+    #[constant]
+    const MODE_VOICE_ACTIVATED: i32 = MODE_VOICE_ACTIVATED;
+
+    /// Sets whether the push-to-talk key is held.
+    #[func]
+    fn set_ptt_pressed(&mut self, pressed: bool) {
+        self.ptt_pressed = pressed;
+    }
+
+    #[func]
+    fn is_ptt_pressed(&self) -> bool {
+        self.ptt_pressed
+    }
+
+    /// Sets the current speech probability (0.0 to 1.0) from a neural
+    /// detector. Pass a negative value to detect voice by level only.
+    #[func]
+    fn set_voice_probability(&mut self, probability: f32) {
+        self.voice_probability = probability;
+    }
+
+    /// Returns true while voice activity is detected, regardless of
+    /// [member transmit_mode].
+    #[func]
+    fn is_speaking(&self) -> bool {
+        self.state.speaking
+    }
+
+    /// Returns true if the last processed frame was transmitted.
+    #[func]
+    fn is_open(&self) -> bool {
+        self.state.open
+    }
+
+    /// Returns the frames of [param pcm] that should be transmitted.
+    #[func]
+    fn process(&mut self, pcm: PackedVector2Array, sample_rate: i32) -> PackedVector2Array {
+        let settings = GateSettings {
+            mode: self.transmit_mode,
+            threshold: db_to_gain(self.vad_threshold_db),
+            hangover_ms: self.hangover_ms.max(0.0),
+            ptt_pressed: self.ptt_pressed,
+            voice_probability: self.voice_probability,
+            min_voice_probability: self.min_voice_probability,
+        };
+
+        let mut out = Vec::with_capacity(pcm.len());
+        self.state.process(
+            &settings,
+            pcm.as_slice(),
+            sample_rate.max(1) as f32,
+            &mut out,
+        );
+        PackedVector2Array::from(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: i32) -> GateSettings {
+        GateSettings {
+            mode,
+            threshold: db_to_gain(-40.0),
+            hangover_ms: 10.0,
+            ptt_pressed: false,
+            voice_probability: -1.0,
+            min_voice_probability: 0.5,
+        }
+    }
+
+    #[test]
+    fn push_to_talk_follows_key() {
+        let mut state = GateState::default();
+        let mut gate = settings(MODE_PUSH_TO_TALK);
+        let frames = vec![Vector2::new(0.5, 0.5); 100];
+        let mut out = Vec::new();
+
+        state.process(&gate, &frames, 48_000.0, &mut out);
+        assert!(out.is_empty());
+
+        gate.ptt_pressed = true;
+        state.process(&gate, &frames, 48_000.0, &mut out);
+        assert_eq!(out.len(), 100);
+    }
+
+    #[test]
+    fn voice_activation_holds_for_hangover() {
+        let mut state = GateState::default();
+        let gate = settings(MODE_VOICE_ACTIVATED);
+        let mut out = Vec::new();
+
+        state.process(
+            &gate,
+            &vec![Vector2::new(0.0, 0.0); 480],
+            48_000.0,
+            &mut out,
+        );
+        assert!(out.is_empty());
+
+        state.process(
+            &gate,
+            &vec![Vector2::new(0.5, 0.5); 480],
+            48_000.0,
+            &mut out,
+        );
+        assert_eq!(out.len(), 480);
+
+        // The detector releases slowly, then 10 ms of hangover follow.
+        out.clear();
+        state.process(
+            &gate,
+            &vec![Vector2::new(0.0, 0.0); 48_000],
+            48_000.0,
+            &mut out,
+        );
+        assert!(out.len() > 480 && out.len() < 48_000, "len={}", out.len());
+        assert!(!state.open);
+    }
+
+    #[test]
+    fn low_voice_probability_vetoes_loud_noise() {
+        let mut state = GateState::default();
+        let mut gate = settings(MODE_VOICE_ACTIVATED);
+        gate.voice_probability = 0.1;
+        let loud = vec![Vector2::new(0.5, 0.5); 480];
+        let mut out = Vec::new();
+
+        state.process(&gate, &loud, 48_000.0, &mut out);
+        assert!(out.is_empty());
+        assert!(!state.speaking);
+
+        gate.voice_probability = 0.9;
+        state.process(&gate, &loud, 48_000.0, &mut out);
+        assert_eq!(out.len(), 480);
+        assert!(state.speaking);
+    }
+}
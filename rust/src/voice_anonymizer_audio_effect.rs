@@ -0,0 +1,459 @@
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+use godot::classes::{
+    AudioEffect, AudioEffectInstance, AudioServer, IAudioEffect, IAudioEffectInstance,
+};
+use godot::{classes::native::AudioFrame, prelude::*};
+
+use crate::dsp_util::{splitmix64, unit_from_bits};
+
+/// Length of the pitch shifter delay window. Long enough for speech pitch
+/// periods, short enough not to smear consonants.
+const PITCH_WINDOW_MS: f32 = 40.0;
+/// Corner frequency of the tilt filter used for the formant colouring.
+const FORMANT_TILT_CORNER_HZ: f32 = 1500.0;
+/// Pitch offset range at full strength, in semitones.
+const MAX_PITCH_SEMITONES: f32 = 5.0;
+/// Formant tilt range at full strength (0 = flat, 1 = +/-6 dB around the corner).
+const MAX_FORMANT_TILT: f32 = 0.8;
+/// Pitch offsets closer to zero than this are pushed outward so that every
+/// seed audibly changes the voice.
+const MIN_PITCH_SEMITONES: f32 = 1.5;
+
+#[derive(Debug, Clone)]
+struct VoiceAnonymizerParams {
+    enabled: bool,
+    seed: i64,
+    strength: f32,
+}
+
+impl Default for VoiceAnonymizerParams {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seed: 0,
+            strength: 0.7,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct VoiceAnonymizerSharedConfig {
+    params: VoiceAnonymizerParams,
+    revision: u64,
+}
+
+type VoiceAnonymizerSharedConfigRef = Arc<Mutex<VoiceAnonymizerSharedConfig>>;
+
+/// Voice transform derived from a seed. The same seed and strength always
+/// produce the same transform, on every machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct VoiceTransform {
+    pitch_semitones: f32,
+    formant_tilt: f32,
+}
+
+impl VoiceTransform {
+    fn from_seed(seed: i64, strength: f32) -> Self {
+        let strength = strength.clamp(0.0, 1.0);
+        let mut state = seed as u64;
+        let pitch_roll = unit_from_bits(splitmix64(&mut state));
+        let tilt_roll = unit_from_bits(splitmix64(&mut state));
+
+        // Map the roll to [-1, 1] and keep it away from zero so no seed is
+        // (nearly) the identity transform.
+        let signed = pitch_roll * 2.0 - 1.0;
+        let min = MIN_PITCH_SEMITONES / MAX_PITCH_SEMITONES;
+        let magnitude = min + (1.0 - min) * signed.abs();
+        let pitch_semitones = signed.signum() * magnitude * MAX_PITCH_SEMITONES * strength;
+
+        // Lower voices get a brighter tilt and vice versa, which masks the
+        // original timbre better than moving both in the same direction.
+        let tilt_magnitude = 0.5 + 0.5 * tilt_roll;
+        let formant_tilt = -signed.signum() * tilt_magnitude * MAX_FORMANT_TILT * strength;
+
+        Self {
+            pitch_semitones,
+            formant_tilt,
+        }
+    }
+
+    fn pitch_ratio(&self) -> f32 {
+        2.0f32.powf(self.pitch_semitones / 12.0)
+    }
+}
+
+/// Delay-line pitch shifter with two crossfaded read heads.
+///
+/// The read heads sweep through a short window at a rate set by the pitch
+/// ratio; their sin^2 gains sum to one, so the output level stays constant.
+#[derive(Debug)]
+struct PitchShifter {
+    buffer: Vec<f32>,
+    write_pos: usize,
+    window: f32,
+    phase: f32,
+    phase_step: f32,
+}
+
+impl PitchShifter {
+    fn new(sample_rate: f32) -> Self {
+        let window = (PITCH_WINDOW_MS * 0.001 * sample_rate).max(16.0);
+        Self {
+            buffer: vec![0.0; window.ceil() as usize + 2],
+            write_pos: 0,
+            window,
+            phase: 0.0,
+            phase_step: 0.0,
+        }
+    }
+
+    fn set_ratio(&mut self, ratio: f32) {
+        self.phase_step = (1.0 - ratio) / self.window;
+    }
+
+    fn read_delayed(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let delay_floor = delay.floor();
+        let fraction = delay - delay_floor;
+        let index_a = (self.write_pos + len - delay_floor as usize % len) % len;
+        let index_b = (index_a + len - 1) % len;
+        self.buffer[index_a] * (1.0 - fraction) + self.buffer[index_b] * fraction
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.buffer[self.write_pos] = sample;
+
+        let phase_a = self.phase;
+        let phase_b = (self.phase + 0.5).fract();
+        let gain_a = (std::f32::consts::PI * phase_a).sin().powi(2);
+        let gain_b = (std::f32::consts::PI * phase_b).sin().powi(2);
+        let out = self.read_delayed(phase_a * self.window) * gain_a
+            + self.read_delayed(phase_b * self.window) * gain_b;
+
+        self.phase = (self.phase + self.phase_step).rem_euclid(1.0);
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+        out
+    }
+}
+
+/// First-order spectral tilt around a fixed corner frequency.
+#[derive(Debug)]
+struct TiltFilter {
+    coeff: f32,
+    low: f32,
+    low_gain: f32,
+    high_gain: f32,
+}
+
+impl TiltFilter {
+    fn new(sample_rate: f32) -> Self {
+        let coeff = (-2.0 * std::f32::consts::PI * FORMANT_TILT_CORNER_HZ / sample_rate).exp();
+        Self {
+            coeff,
+            low: 0.0,
+            low_gain: 1.0,
+            high_gain: 1.0,
+        }
+    }
+
+    /// Sets the tilt, where +/-1 lifts one side of the corner by 6 dB and
+    /// cuts the other by 6 dB.
+    fn set_tilt(&mut self, tilt: f32) {
+        self.low_gain = (-tilt).exp2();
+        self.high_gain = tilt.exp2();
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.low = sample + self.coeff * (self.low - sample);
+        let high = sample - self.low;
+        self.low * self.low_gain + high * self.high_gain
+    }
+}
+
+/// Masks the speaker's identity by shifting pitch and tilting the spectral
+/// envelope by an amount derived from [member seed].
+///
+/// Use the same seed for a player for the whole match so they sound
+/// consistent, and a fresh seed per match so voices can't be linked between
+/// sessions. The effect writes the transformed mono signal to both channels.
+#[derive(GodotClass)]
+#[class(tool, base=AudioEffect)]
+pub(crate) struct AudioEffectVoiceAnonymizer {
+    pub(crate) base: Base<AudioEffect>,
+    /// When disabled the input is passed through untouched.
+    #[export]
+    #[var(get = get_enabled, set = set_enabled)]
+    enabled: bool,
+    /// Seed the voice transform is derived from.
+    #[export]
+    #[var(get = get_seed, set = set_seed)]
+    seed: i64,
+    /// How far the voice is moved away from the original, in range (0.0, 1.0).
+    #[export(range = (0.0, 1.0))]
+    #[var(get = get_strength, set = set_strength)]
+    strength: f32,
+    shared_config: VoiceAnonymizerSharedConfigRef,
+}
+
+#[godot_api]
+impl IAudioEffect for AudioEffectVoiceAnonymizer {
+    fn init(base: Base<AudioEffect>) -> Self {
+        let params = VoiceAnonymizerParams::default();
+        Self {
+            base,
+            enabled: params.enabled,
+            seed: params.seed,
+            strength: params.strength,
+            shared_config: Arc::new(Mutex::new(VoiceAnonymizerSharedConfig {
+                params,
+                revision: 0,
+            })),
+        }
+    }
+
+    fn instantiate(&mut self) -> Option<Gd<AudioEffectInstance>> {
+        self.push_config_to_shared();
+
+        let mut effect = AudioEffectVoiceAnonymizerInstance::new_gd();
+        {
+            let mut effect_mut = effect.bind_mut();
+            effect_mut.shared_config = self.shared_config.clone();
+        }
+
+        Some(effect.upcast::<AudioEffectInstance>())
+    }
+}
+
+#[godot_api]
+impl AudioEffectVoiceAnonymizer {
+    fn push_config_to_shared(&mut self) {
+        if let Ok(mut cfg) = self.shared_config.lock() {
+            cfg.params.enabled = self.enabled;
+            cfg.params.seed = self.seed;
+            cfg.params.strength = self.strength;
+            cfg.revision = cfg.revision.wrapping_add(1);
+        }
+    }
+
+    #[func]
+    fn get_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    #[func]
+    fn set_enabled(&mut self, value: bool) {
+        self.enabled = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_seed(&self) -> i64 {
+        self.seed
+    }
+
+    #[func]
+    fn set_seed(&mut self, value: i64) {
+        self.seed = value;
+        self.push_config_to_shared();
+    }
+
+    #[func]
+    fn get_strength(&self) -> f32 {
+        self.strength
+    }
+
+    #[func]
+    fn set_strength(&mut self, value: f32) {
+        self.strength = value.clamp(0.0, 1.0);
+        self.push_config_to_shared();
+    }
+
+    /// Derives a seed from a match/session id and a player id, so every
+    /// client computes the same voice for the same player without having to
+    /// synchronize seeds over the network.
+    #[func]
+    fn make_seed(session_id: i64, peer_id: i64) -> i64 {
+        let mut state = (session_id as u64) ^ (peer_id as u64).rotate_left(32);
+        splitmix64(&mut state) as i64
+    }
+
+    /// Returns the pitch offset in semitones the current seed and strength produce.
+    #[func]
+    fn get_pitch_semitones(&self) -> f32 {
+        VoiceTransform::from_seed(self.seed, self.strength).pitch_semitones
+    }
+
+    /// Returns the spectral tilt the current seed and strength produce, in
+    /// range (-1.0, 1.0). Positive values brighten the voice.
+    #[func]
+    fn get_formant_tilt(&self) -> f32 {
+        VoiceTransform::from_seed(self.seed, self.strength).formant_tilt
+    }
+}
+
+#[derive(GodotClass)]
+#[class(base=AudioEffectInstance)]
+pub(crate) struct AudioEffectVoiceAnonymizerInstance {
+    pub(crate) base: Base<AudioEffectInstance>,
+    shared_config: VoiceAnonymizerSharedConfigRef,
+    applied_revision: u64,
+    enabled: bool,
+    pitch_shifter: PitchShifter,
+    tilt_filter: TiltFilter,
+}
+
+impl AudioEffectVoiceAnonymizerInstance {
+    fn apply_config(&mut self, params: &VoiceAnonymizerParams) {
+        let transform = VoiceTransform::from_seed(params.seed, params.strength);
+        self.enabled = params.enabled;
+        self.pitch_shifter.set_ratio(transform.pitch_ratio());
+        self.tilt_filter.set_tilt(transform.formant_tilt);
+    }
+
+    fn refresh_runtime_config_if_needed(&mut self) {
+        let Ok(cfg) = self.shared_config.lock() else {
+            return;
+        };
+
+        if self.applied_revision == cfg.revision {
+            return;
+        }
+
+        let revision = cfg.revision;
+        let params = cfg.params.clone();
+        drop(cfg);
+
+        self.apply_config(&params);
+        self.applied_revision = revision;
+    }
+}
+
+#[godot_api]
+impl IAudioEffectInstance for AudioEffectVoiceAnonymizerInstance {
+    unsafe fn process_rawptr(
+        &mut self,
+        input: *const c_void,
+        output: *mut AudioFrame,
+        frame_count: i32,
+    ) {
+        if frame_count <= 0 {
+            return;
+        }
+
+        self.refresh_runtime_config_if_needed();
+
+        let frame_count = frame_count as usize;
+        let input_slice = std::slice::from_raw_parts(input as *const AudioFrame, frame_count);
+        let output_slice = std::slice::from_raw_parts_mut(output, frame_count);
+
+        if !self.enabled {
+            for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+                out_frame.left = in_frame.left;
+                out_frame.right = in_frame.right;
+            }
+            return;
+        }
+
+        for (in_frame, out_frame) in input_slice.iter().zip(output_slice.iter_mut()) {
+            let mono = (in_frame.left + in_frame.right) * 0.5;
+            let shifted = self.pitch_shifter.process(mono);
+            let sample = self.tilt_filter.process(shifted);
+            out_frame.left = sample;
+            out_frame.right = sample;
+        }
+    }
+
+    fn init(base: Base<AudioEffectInstance>) -> Self {
+        let sample_rate = AudioServer::singleton().get_mix_rate().max(1.0);
+        let mut instance = Self {
+            base,
+            shared_config: Arc::default(),
+            applied_revision: 0,
+            enabled: true,
+            pitch_shifter: PitchShifter::new(sample_rate),
+            tilt_filter: TiltFilter::new(sample_rate),
+        };
+        instance.apply_config(&VoiceAnonymizerParams::default());
+        instance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_is_deterministic_per_seed() {
+        let a = VoiceTransform::from_seed(1234, 0.7);
+        let b = VoiceTransform::from_seed(1234, 0.7);
+        let c = VoiceTransform::from_seed(1235, 0.7);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn transform_always_moves_pitch_at_full_strength() {
+        for seed in 0..256 {
+            let transform = VoiceTransform::from_seed(seed, 1.0);
+            assert!(transform.pitch_semitones.abs() >= MIN_PITCH_SEMITONES - 1e-4);
+            assert!(transform.pitch_semitones.abs() <= MAX_PITCH_SEMITONES + 1e-4);
+            assert!(transform.formant_tilt.abs() <= MAX_FORMANT_TILT + 1e-4);
+        }
+    }
+
+    #[test]
+    fn zero_strength_is_identity_transform() {
+        let transform = VoiceTransform::from_seed(99, 0.0);
+        assert_eq!(transform.pitch_semitones, 0.0);
+        assert_eq!(transform.formant_tilt, 0.0);
+    }
+
+    #[test]
+    fn pitch_shifter_keeps_level_and_stays_finite() {
+        let sample_rate = 48_000.0;
+        let mut shifter = PitchShifter::new(sample_rate);
+        shifter.set_ratio(2.0f32.powf(4.0 / 12.0));
+
+        let mut in_sum_sq = 0.0f32;
+        let mut out_sum_sq = 0.0f32;
+        for i in 0..48_000 {
+            let t = i as f32 / sample_rate;
+            let sample = 0.3 * (2.0 * std::f32::consts::PI * 220.0 * t).sin();
+            let out = shifter.process(sample);
+            assert!(out.is_finite());
+            if i >= 4_800 {
+                in_sum_sq += sample * sample;
+                out_sum_sq += out * out;
+            }
+        }
+
+        let ratio = (out_sum_sq / in_sum_sq).sqrt();
+        assert!(
+            ratio > 0.6 && ratio < 1.4,
+            "level ratio out of range: {ratio}"
+        );
+    }
+
+    #[test]
+    fn full_tilt_is_six_db_each_side() {
+        let mut filter = TiltFilter::new(48_000.0);
+        filter.set_tilt(1.0);
+
+        let mut dc = 0.0;
+        for _ in 0..4_800 {
+            dc = filter.process(1.0);
+        }
+        assert!((dc - 0.5).abs() < 1e-3, "low shelf gain: {dc}");
+
+        let mut filter = TiltFilter::new(48_000.0);
+        filter.set_tilt(1.0);
+        let mut nyquist = 0.0f32;
+        for i in 0..4_800 {
+            let sample = if i % 2 == 0 { 1.0 } else { -1.0 };
+            nyquist = filter.process(sample).abs();
+        }
+        assert!((nyquist - 2.0).abs() < 0.15, "high shelf gain: {nyquist}");
+    }
+}
@@ -0,0 +1,326 @@
+use godot::prelude::*;
+
+use crate::dsp_util::{db_to_gain, gain_to_db, ms_to_samples, Biquad, MIN_DB};
+use crate::rnnoise_audio_effect::denoise_clip;
+use crate::transmit_gate::detect_voice_activity;
+
+/// BS.1770 gating block length and hop.
+const LOUDNESS_BLOCK_MS: f32 = 400.0;
+const LOUDNESS_HOP_MS: f32 = 100.0;
+const LOUDNESS_ABSOLUTE_GATE_LUFS: f32 = -70.0;
+const LOUDNESS_RELATIVE_GATE_LU: f32 = -10.0;
+
+/// BS.1770 K-weighting filter (high shelf followed by high pass) for an
+/// arbitrary sample rate.
+#[derive(Debug, Clone, Copy)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: f32) -> Self {
+        let f0 = 1681.974_5_f32;
+        let gain_db = 3.999_843_9_f32;
+        let q = 0.707_175_25_f32;
+        let k = (std::f32::consts::PI * f0 / sample_rate).tan();
+        let vh = 10.0f32.powf(gain_db / 20.0);
+        let vb = vh.powf(0.499_666_77);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b0: (vh + vb * k / q + k * k) / a0,
+            b1: 2.0 * (k * k - vh) / a0,
+            b2: (vh - vb * k / q + k * k) / a0,
+            a1: 2.0 * (k * k - 1.0) / a0,
+            a2: (1.0 - k / q + k * k) / a0,
+            ..Default::default()
+        };
+
+        let f0 = 38.135_47_f32;
+        let q = 0.500_327_04_f32;
+        let k = (std::f32::consts::PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b0: 1.0,
+            b1: -2.0,
+            b2: 1.0,
+            a1: 2.0 * (k * k - 1.0) / a0,
+            a2: (1.0 - k / q + k * k) / a0,
+            ..Default::default()
+        };
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// Returns the range of frames from the first to the last voice activity
+/// at `threshold_db`, as the transmit gate detects it, extended by
+/// `padding_ms` on both sides. Returns `None` if no voice was detected.
+fn find_sound_range(
+    pcm: &[Vector2],
+    sample_rate: f32,
+    threshold_db: f32,
+    padding_ms: f32,
+) -> Option<(usize, usize)> {
+    let activity = detect_voice_activity(pcm, sample_rate, threshold_db);
+    let first = activity.iter().position(|active| *active)?;
+    let last = activity.iter().rposition(|active| *active)?;
+    let padding = ms_to_samples(padding_ms, sample_rate);
+    let start = first.saturating_sub(padding);
+    let end = (last + 1 + padding).min(pcm.len());
+    Some((start, end))
+}
+
+fn peak_level(pcm: &[Vector2]) -> f32 {
+    pcm.iter().fold(0.0f32, |peak, frame| {
+        peak.max(frame.x.abs()).max(frame.y.abs())
+    })
+}
+
+/// Integrated loudness in LUFS following ITU-R BS.1770 (K-weighting, 400 ms
+/// blocks with 75% overlap, absolute and relative gating).
+fn integrated_loudness(pcm: &[Vector2], sample_rate: f32) -> f32 {
+    let block = ms_to_samples(LOUDNESS_BLOCK_MS, sample_rate).max(1);
+    let hop = ms_to_samples(LOUDNESS_HOP_MS, sample_rate).max(1);
+    if pcm.len() < block {
+        // Too short to gate; measure the whole clip as one block.
+        return block_loudness(&k_weighted_power(pcm, sample_rate), 0, pcm.len());
+    }
+
+    let power = k_weighted_power(pcm, sample_rate);
+    let blocks: Vec<f32> = (0..=(pcm.len() - block) / hop)
+        .map(|i| block_mean(&power, i * hop, block))
+        .collect();
+
+    let absolute_gate = loudness_to_power(LOUDNESS_ABSOLUTE_GATE_LUFS);
+    let above_absolute: Vec<f32> = blocks.into_iter().filter(|p| *p > absolute_gate).collect();
+    if above_absolute.is_empty() {
+        return MIN_DB;
+    }
+
+    let relative_gate =
+        loudness_to_power(power_to_loudness(mean(&above_absolute)) + LOUDNESS_RELATIVE_GATE_LU);
+    let gated: Vec<f32> = above_absolute
+        .into_iter()
+        .filter(|p| *p > relative_gate)
+        .collect();
+    if gated.is_empty() {
+        return MIN_DB;
+    }
+    power_to_loudness(mean(&gated))
+}
+
+/// Per-frame K-weighted power, summed over both channels.
+fn k_weighted_power(pcm: &[Vector2], sample_rate: f32) -> Vec<f32> {
+    let mut left = KWeighting::new(sample_rate);
+    let mut right = KWeighting::new(sample_rate);
+    pcm.iter()
+        .map(|frame| {
+            let l = left.process(frame.x);
+            let r = right.process(frame.y);
+            l * l + r * r
+        })
+        .collect()
+}
+
+fn block_mean(power: &[f32], start: usize, len: usize) -> f32 {
+    mean(&power[start..start + len])
+}
+
+fn block_loudness(power: &[f32], start: usize, len: usize) -> f32 {
+    if len == 0 {
+        return MIN_DB;
+    }
+    power_to_loudness(block_mean(power, start, len))
+}
+
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+fn power_to_loudness(power: f32) -> f32 {
+    if power <= 0.0 {
+        return MIN_DB;
+    }
+    (-0.691 + 10.0 * power.log10()).max(MIN_DB)
+}
+
+fn loudness_to_power(loudness: f32) -> f32 {
+    10.0f32.powf((loudness + 0.691) / 10.0)
+}
+
+fn apply_gain(pcm: &mut [Vector2], gain: f32) {
+    for frame in pcm.iter_mut() {
+        frame.x *= gain;
+        frame.y *= gain;
+    }
+}
+
+/// Post-processing for saved voice clips.
+///
+/// All functions work on whole buffers and are meant to run outside the
+/// audio thread, e.g. after a voice message has been recorded.
+#[derive(GodotClass)]
+#[class(no_init, base=Object)]
+pub(crate) struct VoiceClip {}
+
+#[godot_api]
+impl VoiceClip {
+    /// Removes leading and trailing audio without voice activity, keeping
+    /// [param padding_ms] of lead-in and tail around the speech. Voice is
+    /// detected like [VoipTransmitGate] does with
+    /// [member VoipTransmitGate.vad_threshold_db] set to [param threshold_db].
+    /// Returns an empty array if the clip contains no voice.
+    #[func]
+    fn trim_silence(
+        pcm: PackedVector2Array,
+        sample_rate: i32,
+        threshold_db: f32,
+        padding_ms: f32,
+    ) -> PackedVector2Array {
+        let frames = pcm.as_slice();
+        match find_sound_range(frames, sample_rate.max(1) as f32, threshold_db, padding_ms) {
+            Some((start, end)) => PackedVector2Array::from(&frames[start..end]),
+            None => PackedVector2Array::new(),
+        }
+    }
+
+    /// Scales the clip so its highest sample reaches [param target_db] dBFS.
+    #[func]
+    fn normalize_peak(pcm: PackedVector2Array, target_db: f32) -> PackedVector2Array {
+        let mut frames = pcm.to_vec();
+        let peak = peak_level(&frames);
+        if peak > 0.0 {
+            apply_gain(&mut frames, db_to_gain(target_db) / peak);
+        }
+        PackedVector2Array::from(frames)
+    }
+
+    /// Scales the clip to an integrated loudness of [param target_lufs],
+    /// without letting any sample exceed [param peak_limit_db] dBFS.
+    #[func]
+    fn normalize_loudness(
+        pcm: PackedVector2Array,
+        sample_rate: i32,
+        target_lufs: f32,
+        peak_limit_db: f32,
+    ) -> PackedVector2Array {
+        let mut frames = pcm.to_vec();
+        let loudness = integrated_loudness(&frames, sample_rate.max(1) as f32);
+        if loudness <= MIN_DB {
+            return PackedVector2Array::from(frames);
+        }
+
+        let mut gain = db_to_gain(target_lufs - loudness);
+        let peak = peak_level(&frames);
+        if peak > 0.0 {
+            gain = gain.min(db_to_gain(peak_limit_db) / peak);
+        }
+        apply_gain(&mut frames, gain);
+        PackedVector2Array::from(frames)
+    }
+
+    /// Removes background noise from the clip with RNNoise, like
+    /// [AudioEffectRNNoise] but each channel separately and without its
+    /// delay. Long clips take a moment, so consider a [WorkerThreadPool]
+    /// task for them.
+    #[func]
+    fn denoise(pcm: PackedVector2Array, sample_rate: i32) -> PackedVector2Array {
+        let frames = pcm.as_slice();
+        let sample_rate = sample_rate.max(1) as u32;
+        let left: Vec<f32> = frames.iter().map(|frame| frame.x).collect();
+        let right: Vec<f32> = frames.iter().map(|frame| frame.y).collect();
+        let left = denoise_clip(&left, sample_rate);
+        let right = denoise_clip(&right, sample_rate);
+        let frames: Vec<Vector2> = left
+            .into_iter()
+            .zip(right)
+            .map(|(x, y)| Vector2::new(x, y))
+            .collect();
+        PackedVector2Array::from(frames)
+    }
+
+    /// Shortens the clip to at most [param max_duration_sec] seconds.
+    #[func]
+    fn cap_duration(
+        pcm: PackedVector2Array,
+        sample_rate: i32,
+        max_duration_sec: f32,
+    ) -> PackedVector2Array {
+        let max_frames = (max_duration_sec.max(0.0) * sample_rate.max(1) as f32) as usize;
+        if pcm.len() <= max_frames {
+            return pcm;
+        }
+        PackedVector2Array::from(&pcm.as_slice()[..max_frames])
+    }
+
+    /// Returns the integrated loudness of the clip in LUFS.
+    #[func]
+    fn get_loudness(pcm: PackedVector2Array, sample_rate: i32) -> f32 {
+        integrated_loudness(pcm.as_slice(), sample_rate.max(1) as f32)
+    }
+
+    /// Returns the highest sample level of the clip in dBFS.
+    #[func]
+    fn get_peak_db(pcm: PackedVector2Array) -> f32 {
+        gain_to_db(peak_level(pcm.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32, freq: f32, sample_rate: f32, frames: usize) -> Vec<Vector2> {
+        (0..frames)
+            .map(|i| {
+                let s =
+                    amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate).sin();
+                Vector2::new(s, s)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trims_silence_with_padding() {
+        let sample_rate = 48_000.0;
+        let mut pcm = vec![Vector2::new(0.0, 0.0); 48_000];
+        pcm.extend(sine(0.5, 440.0, sample_rate, 4_800));
+        pcm.extend(vec![Vector2::new(0.0, 0.0); 48_000]);
+
+        let (start, end) = find_sound_range(&pcm, sample_rate, -40.0, 20.0).unwrap();
+        assert!((47_000..=48_000).contains(&start), "start={start}");
+        // The detector releases like the live transmit gate, which keeps
+        // about 200 ms of the decay after the sound before the padding.
+        assert!((53_760..=66_000).contains(&end), "end={end}");
+
+        let silence = vec![Vector2::new(0.0, 0.0); 4_800];
+        assert!(find_sound_range(&silence, sample_rate, -40.0, 20.0).is_none());
+    }
+
+    #[test]
+    fn full_scale_sine_measures_zero_lufs() {
+        // BS.1770: a 0 dBFS 997 Hz sine in both channels reads 0 LUFS.
+        let sample_rate = 48_000.0;
+        let pcm = sine(1.0, 997.0, sample_rate, 48_000 * 3);
+        let loudness = integrated_loudness(&pcm, sample_rate);
+        assert!((loudness - 0.0).abs() < 0.2, "loudness={loudness}");
+
+        let quieter = sine(0.1, 997.0, 44_100.0, 44_100 * 3);
+        let loudness = integrated_loudness(&quieter, 44_100.0);
+        assert!((loudness + 20.0).abs() < 0.2, "loudness={loudness}");
+    }
+
+    #[test]
+    fn silence_is_gated_out() {
+        let pcm = vec![Vector2::new(0.0, 0.0); 48_000];
+        assert_eq!(integrated_loudness(&pcm, 48_000.0), MIN_DB);
+    }
+}
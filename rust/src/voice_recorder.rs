@@ -0,0 +1,625 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use godot::classes::ProjectSettings;
+use godot::global::Error;
+use godot::prelude::*;
+use opus::{Application, Bitrate, Channels, Encoder};
+
+use crate::dsp_util::{ms_to_samples, LinearResampler};
+
+const OPUS_RATE: u32 = 48_000;
+const OPUS_FRAME_SIZE: usize = 960;
+/// Samples a player drops from the start of the stream, the libopus
+/// encoder delay at 48 kHz.
+const OPUS_PRE_SKIP: u16 = 312;
+const OPUS_MAX_PACKET: usize = 4000;
+const OPUS_BITRATE: i32 = 32_000;
+
+const OGG_FLAG_BOS: u8 = 0x02;
+const OGG_FLAG_EOS: u8 = 0x04;
+
+fn sample_to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// Header of a 16-bit stereo PCM WAV file.
+fn wav_header(sample_rate: u32, data_bytes: u32) -> [u8; 44] {
+    const CHANNELS: u16 = 2;
+    const BITS: u16 = 16;
+    let block_align = CHANNELS * BITS / 8;
+
+    let mut header = [0u8; 44];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&data_bytes.saturating_add(36).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&CHANNELS.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&BITS.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_bytes.to_le_bytes());
+    header
+}
+
+/// CRC used by Ogg pages: polynomial 0x04c11db7, no reflection, zero
+/// initial value.
+fn ogg_crc(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Builds an Ogg page holding exactly one packet. Packets must be shorter
+/// than 255 * 255 bytes.
+fn ogg_page(packet: &[u8], granule: u64, serial: u32, sequence: u32, flags: u8) -> Vec<u8> {
+    let mut lacing = vec![255u8; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+
+    let mut page = Vec::with_capacity(27 + lacing.len() + packet.len());
+    page.extend_from_slice(b"OggS");
+    page.push(0);
+    page.push(flags);
+    page.extend_from_slice(&granule.to_le_bytes());
+    page.extend_from_slice(&serial.to_le_bytes());
+    page.extend_from_slice(&sequence.to_le_bytes());
+    page.extend_from_slice(&[0; 4]);
+    page.push(lacing.len() as u8);
+    page.extend_from_slice(&lacing);
+    page.extend_from_slice(packet);
+
+    let crc = ogg_crc(&page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+fn opus_head(input_sample_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(1);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+fn opus_tags() -> Vec<u8> {
+    let vendor = b"godot-simple-voip";
+    let mut tags = Vec::with_capacity(16 + vendor.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    fn create(path: &str, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&wav_header(sample_rate, 0))?;
+        Ok(Self {
+            file,
+            sample_rate,
+            data_bytes: 0,
+        })
+    }
+
+    fn write(&mut self, frames: &[Vector2]) -> io::Result<()> {
+        for frame in frames {
+            self.file.write_all(&sample_to_i16(frame.x).to_le_bytes())?;
+            self.file.write_all(&sample_to_i16(frame.y).to_le_bytes())?;
+        }
+        self.data_bytes = self.data_bytes.saturating_add(frames.len() as u32 * 4);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        // Sizes are only known now; players reject headers that claim more
+        // data than the file holds.
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file
+            .write_all(&wav_header(self.sample_rate, self.data_bytes))?;
+        self.file.flush()
+    }
+}
+
+struct OggOpusWriter {
+    file: BufWriter<File>,
+    serial: u32,
+    page_sequence: u32,
+    encoder: Encoder,
+    resampler: LinearResampler<f32>,
+    pending: Vec<f32>,
+    /// 48 kHz samples passed to the encoder, without padding.
+    input_samples: u64,
+    encoded_samples: u64,
+    /// The newest packet is held back so the last page can be marked as the
+    /// end of the stream.
+    held_packet: Option<(Vec<u8>, u64)>,
+}
+
+impl OggOpusWriter {
+    fn create(path: &str, sample_rate: u32) -> io::Result<Self> {
+        let mut encoder =
+            Encoder::new(OPUS_RATE, Channels::Mono, Application::Voip).map_err(io::Error::other)?;
+        encoder
+            .set_bitrate(Bitrate::Bits(OPUS_BITRATE))
+            .map_err(io::Error::other)?;
+
+        let serial = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or(1);
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            serial,
+            page_sequence: 0,
+            encoder,
+            resampler: LinearResampler::new(sample_rate, OPUS_RATE),
+            pending: Vec::new(),
+            input_samples: 0,
+            encoded_samples: 0,
+            held_packet: None,
+        };
+        writer.write_page(&opus_head(sample_rate), 0, OGG_FLAG_BOS)?;
+        writer.write_page(&opus_tags(), 0, 0)?;
+        Ok(writer)
+    }
+
+    fn write_page(&mut self, packet: &[u8], granule: u64, flags: u8) -> io::Result<()> {
+        let page = ogg_page(packet, granule, self.serial, self.page_sequence, flags);
+        self.page_sequence = self.page_sequence.wrapping_add(1);
+        self.file.write_all(&page)
+    }
+
+    fn write(&mut self, frames: &[Vector2]) -> io::Result<()> {
+        let mono: Vec<f32> = frames
+            .iter()
+            .map(|frame| (frame.x + frame.y) * 0.5)
+            .collect();
+        let before = self.pending.len();
+        self.resampler.process(&mono, &mut self.pending);
+        self.input_samples += (self.pending.len() - before) as u64;
+        self.encode_pending()
+    }
+
+    fn encode_pending(&mut self) -> io::Result<()> {
+        let mut packet = [0u8; OPUS_MAX_PACKET];
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= OPUS_FRAME_SIZE {
+            let frame = &self.pending[consumed..consumed + OPUS_FRAME_SIZE];
+            let len = self
+                .encoder
+                .encode_float(frame, &mut packet)
+                .map_err(io::Error::other)?;
+            consumed += OPUS_FRAME_SIZE;
+            self.encoded_samples += OPUS_FRAME_SIZE as u64;
+
+            let granule = OPUS_PRE_SKIP as u64 + self.encoded_samples;
+            if let Some((held, held_granule)) =
+                self.held_packet.replace((packet[..len].to_vec(), granule))
+            {
+                self.write_page(&held, held_granule, 0)?;
+            }
+        }
+        self.pending.drain(..consumed);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.pending.resize(OPUS_FRAME_SIZE, 0.0);
+            self.encode_pending()?;
+        }
+
+        // The final granule position trims the padding of the last frame.
+        let end_granule = OPUS_PRE_SKIP as u64 + self.input_samples;
+        match self.held_packet.take() {
+            Some((packet, _)) => self.write_page(&packet, end_granule, OGG_FLAG_EOS)?,
+            None => self.write_page(&[], end_granule, OGG_FLAG_EOS)?,
+        }
+        self.file.flush()
+    }
+}
+
+enum RecordingSink {
+    Wav(WavWriter),
+    OggOpus(OggOpusWriter),
+}
+
+impl RecordingSink {
+    fn write(&mut self, frames: &[Vector2]) -> io::Result<()> {
+        match self {
+            Self::Wav(writer) => writer.write(frames),
+            Self::OggOpus(writer) => writer.write(frames),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Wav(writer) => writer.finish(),
+            Self::OggOpus(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Audio from several sources waiting to be written, summed by position.
+#[derive(Debug, Default)]
+struct MixBuffer {
+    /// Frame position of the first buffered frame.
+    start: u64,
+    frames: VecDeque<Vector2>,
+}
+
+impl MixBuffer {
+    /// Adds `pcm` on top of what's buffered at `position`. Frames before
+    /// [`Self::start`] were already taken and are dropped.
+    fn mix_at(&mut self, pcm: &[Vector2], position: u64) {
+        let skip = self.start.saturating_sub(position) as usize;
+        if skip >= pcm.len() {
+            return;
+        }
+        let offset = (position + skip as u64 - self.start) as usize;
+        let end = offset + pcm.len() - skip;
+        if self.frames.len() < end {
+            self.frames.resize(end, Vector2::ZERO);
+        }
+        for (i, frame) in pcm[skip..].iter().enumerate() {
+            self.frames[offset + i] += *frame;
+        }
+    }
+
+    /// Removes and returns everything before `position`, silent where
+    /// nothing was mixed.
+    fn take_until(&mut self, position: u64) -> Vec<Vector2> {
+        let count = position.saturating_sub(self.start) as usize;
+        if self.frames.len() < count {
+            self.frames.resize(count, Vector2::ZERO);
+        }
+        self.start += count as u64;
+        self.frames.drain(..count).collect()
+    }
+}
+
+/// Leaves out silence before the first and after the last speech of a
+/// recording, keeping `padding` frames around the speech.
+#[derive(Debug, Default)]
+struct SilenceTrimmer {
+    padding: usize,
+    heard_speech: bool,
+    /// Before the first speech, the latest `padding` frames. After it, the
+    /// silence since the last speech, which is only written if speech
+    /// follows.
+    held: VecDeque<Vector2>,
+}
+
+impl SilenceTrimmer {
+    fn new(padding: usize) -> Self {
+        Self {
+            padding,
+            ..Default::default()
+        }
+    }
+
+    /// Takes frames with the voice activity decision for them and returns
+    /// the frames that can be written now.
+    fn push(&mut self, frames: &[Vector2], speaking: bool) -> Vec<Vector2> {
+        if speaking {
+            self.heard_speech = true;
+            let mut out: Vec<Vector2> = self.held.drain(..).collect();
+            out.extend_from_slice(frames);
+            return out;
+        }
+
+        self.held.extend(frames);
+        if !self.heard_speech {
+            let excess = self.held.len().saturating_sub(self.padding);
+            self.held.drain(..excess);
+        }
+        Vec::new()
+    }
+
+    /// Returns the tail to write when the recording ends.
+    fn finish(&mut self) -> Vec<Vector2> {
+        let keep = if self.heard_speech {
+            self.padding.min(self.held.len())
+        } else {
+            0
+        };
+        let tail = self.held.drain(..keep).collect();
+        self.held.clear();
+        tail
+    }
+}
+
+/// Writes audio to a file as it's captured.
+///
+/// The format follows the file extension of the path passed to
+/// [method start]: `.ogg` and `.opus` write mono Ogg Opus, anything else
+/// writes 16-bit stereo WAV. The file is written while recording, so long
+/// recordings don't grow memory use.
+///
+/// Instead of appending with [method write], audio of several sources can be
+/// placed on a shared timeline with [method mix_at] and written with
+/// [method commit_until], e.g. to record a whole conversation in one file.
+///
+/// With [member trim_silence], audio passed to [method write_voice] is only
+/// written from the first to the last speech.
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub(crate) struct VoipVoiceRecorder {
+    /// Leave out silence before the first and after the last speech passed
+    /// to [method write_voice]. Takes effect with the next [method start].
+    #[var]
+    trim_silence: bool,
+    /// Silence kept before and after the speech with [member trim_silence],
+    /// in milliseconds.
+    #[var]
+    trim_padding_ms: f32,
+    sink: Option<RecordingSink>,
+    trimmer: Option<SilenceTrimmer>,
+    sample_rate: i32,
+    recorded_frames: u64,
+    mix_buffer: MixBuffer,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for VoipVoiceRecorder {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            trim_silence: false,
+            trim_padding_ms: 200.0,
+            sink: None,
+            trimmer: None,
+            sample_rate: OPUS_RATE as i32,
+            recorded_frames: 0,
+            mix_buffer: MixBuffer::default(),
+            base,
+        }
+    }
+}
+
+impl Drop for VoipVoiceRecorder {
+    fn drop(&mut self) {
+        self.finish_sink();
+    }
+}
+
+impl VoipVoiceRecorder {
+    fn finish_sink(&mut self) -> Error {
+        if !self.mix_buffer.frames.is_empty() {
+            let end = self.mix_buffer.start + self.mix_buffer.frames.len() as u64;
+            self.commit_until(end as i64);
+        }
+        if let Some(mut trimmer) = self.trimmer.take() {
+            self.write_frames(&trimmer.finish());
+        }
+        let Some(sink) = self.sink.take() else {
+            return Error::OK;
+        };
+        match sink.finish() {
+            Ok(()) => Error::OK,
+            Err(err) => {
+                godot_error!("VoipVoiceRecorder: failed to finish recording: {}", err);
+                Error::ERR_FILE_CANT_WRITE
+            }
+        }
+    }
+
+    fn write_frames(&mut self, frames: &[Vector2]) {
+        if frames.is_empty() {
+            return;
+        }
+        let Some(sink) = self.sink.as_mut() else {
+            return;
+        };
+        match sink.write(frames) {
+            Ok(()) => self.recorded_frames += frames.len() as u64,
+            Err(err) => {
+                godot_error!("VoipVoiceRecorder: write failed, stopping: {}", err);
+                self.trimmer = None;
+                self.finish_sink();
+            }
+        }
+    }
+}
+
+#[godot_api]
+impl VoipVoiceRecorder {
+    /// Starts writing to [param path]. Audio passed to [method write] has
+    /// [param sample_rate] frames per second.
+    #[func]
+    fn start(&mut self, path: GString, sample_rate: i32) -> Error {
+        if self.sink.is_some() {
+            return Error::ERR_ALREADY_IN_USE;
+        }
+        if sample_rate <= 0 {
+            return Error::ERR_INVALID_PARAMETER;
+        }
+
+        let file_path = ProjectSettings::singleton()
+            .globalize_path(&path)
+            .to_string();
+        let extension = path.get_extension().to_lower().to_string();
+        let sink = match extension.as_str() {
+            "ogg" | "opus" => {
+                OggOpusWriter::create(&file_path, sample_rate as u32).map(RecordingSink::OggOpus)
+            }
+            _ => WavWriter::create(&file_path, sample_rate as u32).map(RecordingSink::Wav),
+        };
+
+        match sink {
+            Ok(sink) => {
+                self.sink = Some(sink);
+                self.sample_rate = sample_rate;
+                self.recorded_frames = 0;
+                self.mix_buffer = MixBuffer::default();
+                self.trimmer = self.trim_silence.then(|| {
+                    SilenceTrimmer::new(ms_to_samples(
+                        self.trim_padding_ms.max(0.0),
+                        sample_rate as f32,
+                    ))
+                });
+                Error::OK
+            }
+            Err(err) => {
+                godot_error!("VoipVoiceRecorder: can't open {}: {}", file_path, err);
+                Error::ERR_FILE_CANT_OPEN
+            }
+        }
+    }
+
+    /// Appends audio to the recording. Does nothing when not recording.
+    #[func]
+    fn write(&mut self, pcm: PackedVector2Array) {
+        self.write_frames(pcm.as_slice());
+    }
+
+    /// Appends audio like [method write], together with whether it contains
+    /// speech, e.g. from [method VoipTransmitGate.is_speaking]. With
+    /// [member trim_silence], silence after the latest speech is held back
+    /// until more speech follows, and left out if none does.
+    #[func]
+    fn write_voice(&mut self, pcm: PackedVector2Array, speaking: bool) {
+        if self.sink.is_none() {
+            return;
+        }
+        match self.trimmer.as_mut() {
+            Some(trimmer) => {
+                let frames = trimmer.push(pcm.as_slice(), speaking);
+                self.write_frames(&frames);
+            }
+            None => self.write_frames(pcm.as_slice()),
+        }
+    }
+
+    /// Mixes [param pcm] into the recording, starting [param frame_position]
+    /// frames after its start. Nothing is written until
+    /// [method commit_until] passes it, and audio before the last committed
+    /// position is dropped. Does nothing when not recording.
+    #[func]
+    fn mix_at(&mut self, pcm: PackedVector2Array, frame_position: i64) {
+        if self.sink.is_some() {
+            self.mix_buffer
+                .mix_at(pcm.as_slice(), frame_position.max(0) as u64);
+        }
+    }
+
+    /// Writes everything mixed before [param frame_position], filling gaps
+    /// with silence.
+    #[func]
+    fn commit_until(&mut self, frame_position: i64) {
+        if self.sink.is_none() {
+            return;
+        }
+        let frames = self.mix_buffer.take_until(frame_position.max(0) as u64);
+        if !frames.is_empty() {
+            self.write(PackedVector2Array::from(frames.as_slice()));
+        }
+    }
+
+    /// Finishes the file, including audio mixed but not committed yet.
+    /// Recordings that aren't stopped are finished when
+    /// the recorder is freed.
+    #[func]
+    fn stop(&mut self) -> Error {
+        self.finish_sink()
+    }
+
+    #[func]
+    fn is_recording(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Returns the length of the current or last recording in seconds.
+    #[func]
+    fn get_recorded_seconds(&self) -> f64 {
+        self.recorded_frames as f64 / self.sample_rate as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mix_buffer_sums_sources_and_fills_gaps() {
+        let mut buffer = MixBuffer::default();
+        buffer.mix_at(&[Vector2::new(0.25, 0.25); 4], 2);
+        buffer.mix_at(&[Vector2::new(0.5, 0.5); 2], 4);
+
+        let taken = buffer.take_until(5);
+        assert_eq!(taken.len(), 5);
+        assert_eq!(taken[1], Vector2::ZERO);
+        assert_eq!(taken[2], Vector2::new(0.25, 0.25));
+        assert_eq!(taken[4], Vector2::new(0.75, 0.75));
+
+        // Frames before the committed position are dropped.
+        buffer.mix_at(&[Vector2::ONE; 3], 3);
+        let taken = buffer.take_until(7);
+        assert_eq!(taken, vec![Vector2::new(1.75, 1.75), Vector2::ZERO]);
+    }
+
+    #[test]
+    fn trimmer_keeps_padding_around_speech() {
+        let silence = [Vector2::ZERO; 10];
+        let voice = [Vector2::ONE; 5];
+        let mut trimmer = SilenceTrimmer::new(4);
+
+        assert!(trimmer.push(&silence, false).is_empty());
+        assert_eq!(trimmer.push(&voice, true).len(), 4 + 5);
+        // A pause between speech is kept whole.
+        assert!(trimmer.push(&silence, false).is_empty());
+        assert_eq!(trimmer.push(&voice, true).len(), 10 + 5);
+        assert!(trimmer.push(&silence, false).is_empty());
+        assert_eq!(trimmer.finish().len(), 4);
+
+        let mut silent = SilenceTrimmer::new(4);
+        silent.push(&silence, false);
+        assert!(silent.finish().is_empty());
+    }
+
+    #[test]
+    fn ogg_crc_matches_reference() {
+        assert_eq!(ogg_crc(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn ogg_page_laces_packet() {
+        let packet = vec![7u8; 600];
+        let page = ogg_page(&packet, 960, 1, 2, OGG_FLAG_EOS);
+        assert_eq!(&page[0..4], b"OggS");
+        assert_eq!(page[5], OGG_FLAG_EOS);
+        assert_eq!(page[26], 3);
+        assert_eq!(&page[27..30], &[255, 255, 90]);
+        assert_eq!(page.len(), 30 + packet.len());
+
+        let mut unsigned = page.clone();
+        unsigned[22..26].fill(0);
+        assert_eq!(ogg_crc(&unsigned).to_le_bytes(), page[22..26]);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use godot::global::Error;
+use godot::prelude::*;
+
+const KEY_SIZE: usize = 32;
+/// Random per-sender prefix of every nonce.
+const SALT_SIZE: usize = 16;
+/// salt(16) + counter(8)
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// How far behind the newest packet of a sender a packet may arrive.
+const REPLAY_WINDOW_SIZE: u64 = 64;
+/// Senders remembered for replay protection. The least recently heard one is
+/// forgotten beyond this.
+const MAX_TRACKED_SENDERS: usize = 256;
+
+/// Remembers which counters of one sender were already accepted.
+#[derive(Debug, Clone, Copy)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `n` is set if `highest - n` was accepted.
+    seen: u64,
+    last_heard: u64,
+}
+
+impl ReplayWindow {
+    fn new(counter: u64, now: u64) -> Self {
+        Self {
+            highest: counter,
+            seen: 1,
+            last_heard: now,
+        }
+    }
+
+    /// Returns true if `counter` is new and not too old to tell.
+    fn allows(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            return true;
+        }
+        let age = self.highest - counter;
+        age < REPLAY_WINDOW_SIZE && self.seen & (1 << age) == 0
+    }
+
+    fn accept(&mut self, counter: u64, now: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.highest = counter;
+        }
+        self.seen |= 1 << (self.highest - counter);
+        self.last_heard = now;
+    }
+}
+
+/// XChaCha20-Poly1305 with a session key, nonces that are never reused and
+/// replay protection.
+///
+/// Every nonce is this sender's random salt followed by a packet counter, so
+/// any number of peers can share the key without coordinating nonces, and
+/// receivers reject a (salt, counter) pair they already accepted.
+struct SessionCipher {
+    aead: XChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    next_counter: u64,
+    windows: HashMap<[u8; SALT_SIZE], ReplayWindow>,
+    opened_count: u64,
+}
+
+impl SessionCipher {
+    fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+            salt,
+            next_counter: 0,
+            windows: HashMap::new(),
+            opened_count: 0,
+        }
+    }
+
+    /// Encrypts `plaintext` and returns nonce, ciphertext and tag. `aad` is
+    /// authenticated but not sent.
+    fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let counter = self.next_counter;
+        // A nonce must never repeat for a key.
+        self.next_counter = counter.checked_add(1)?;
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..SALT_SIZE].copy_from_slice(&self.salt);
+        nonce[SALT_SIZE..].copy_from_slice(&counter.to_le_bytes());
+        let ciphertext = self
+            .aead
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .ok()?;
+
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Some(out)
+    }
+
+    /// Decrypts data produced by [`Self::seal`] with the same key and `aad`.
+    /// Returns `None` if it was tampered with, replayed, or sealed by this
+    /// cipher itself.
+    fn open(&mut self, data: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let salt: [u8; SALT_SIZE] = nonce[..SALT_SIZE].try_into().ok()?;
+        let counter = u64::from_le_bytes(nonce[SALT_SIZE..].try_into().ok()?);
+        if salt == self.salt {
+            return None;
+        }
+        if let Some(window) = self.windows.get(&salt) {
+            if !window.allows(counter) {
+                return None;
+            }
+        }
+
+        let plaintext = self
+            .aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()?;
+
+        // Only authenticated packets may move the window.
+        self.opened_count += 1;
+        let now = self.opened_count;
+        match self.windows.get_mut(&salt) {
+            Some(window) => window.accept(counter, now),
+            None => {
+                if self.windows.len() >= MAX_TRACKED_SENDERS {
+                    self.forget_least_recent_sender();
+                }
+                self.windows.insert(salt, ReplayWindow::new(counter, now));
+            }
+        }
+        Some(plaintext)
+    }
+
+    fn forget_least_recent_sender(&mut self) {
+        let oldest = self
+            .windows
+            .iter()
+            .min_by_key(|(_, window)| window.last_heard)
+            .map(|(salt, _)| *salt);
+        if let Some(salt) = oldest {
+            self.windows.remove(&salt);
+        }
+    }
+}
+
+/// Authenticated encryption of voice payloads with a per-session key.
+///
+/// Give every participant of a session the same key from
+/// [method generate_key], e.g. over your reliable, already secured game
+/// connection, and pass it to [method set_key]. Encrypted voice can't be
+/// listened to or forged by anyone without the key, including relays that
+/// only forward it.
+///
+/// Nonces and replay protection are handled internally. Use one cipher per
+/// session on each peer, and call [method set_key] again with a fresh key for
+/// every new session rather than reusing one.
+#[derive(GodotClass)]
+#[class(base=RefCounted)]
+pub(crate) struct VoipCipher {
+    session: Option<SessionCipher>,
+    rejected_count: i64,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for VoipCipher {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            session: None,
+            rejected_count: 0,
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl VoipCipher {
+    /// Bytes [method encrypt] adds to every payload.
+    #[constant]
+    const OVERHEAD: i64 = (NONCE_SIZE + TAG_SIZE) as i64;
+
+    /// Returns a new random 32-byte key.
+    #[func]
+    fn generate_key() -> PackedByteArray {
+        let mut key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        PackedByteArray::from(&key[..])
+    }
+
+    /// Starts a session with [param key], which must be 32 bytes. Replay
+    /// protection starts over.
+    #[func]
+    fn set_key(&mut self, key: PackedByteArray) -> Error {
+        let Ok(key) = <[u8; KEY_SIZE]>::try_from(key.as_slice()) else {
+            godot_error!("VoipCipher: key must be {} bytes", KEY_SIZE);
+            return Error::ERR_INVALID_PARAMETER;
+        };
+        self.session = Some(SessionCipher::new(&key));
+        self.rejected_count = 0;
+        Error::OK
+    }
+
+    /// Ends the session. [method encrypt] and [method decrypt] return empty
+    /// arrays until a key is set again.
+    #[func]
+    fn clear_key(&mut self) {
+        self.session = None;
+    }
+
+    #[func]
+    fn has_key(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Encrypts [param plaintext]. [param aad] is authenticated along with it
+    /// but not included, so the receiver has to pass the same bytes to
+    /// [method decrypt]. Returns an empty array without a key.
+    #[func]
+    pub(crate) fn encrypt(
+        &mut self,
+        plaintext: PackedByteArray,
+        aad: PackedByteArray,
+    ) -> PackedByteArray {
+        self.session
+            .as_mut()
+            .and_then(|session| session.seal(plaintext.as_slice(), aad.as_slice()))
+            .map(PackedByteArray::from)
+            .unwrap_or_default()
+    }
+
+    /// Decrypts data from [method encrypt] of another peer. Returns an empty
+    /// array if there's no key or the data is forged, modified, replayed or
+    /// was encrypted by this cipher.
+    #[func]
+    pub(crate) fn decrypt(
+        &mut self,
+        ciphertext: PackedByteArray,
+        aad: PackedByteArray,
+    ) -> PackedByteArray {
+        let plaintext = self
+            .session
+            .as_mut()
+            .and_then(|session| session.open(ciphertext.as_slice(), aad.as_slice()));
+        match plaintext {
+            Some(plaintext) => PackedByteArray::from(plaintext),
+            None => {
+                self.rejected_count += 1;
+                PackedByteArray::new()
+            }
+        }
+    }
+
+    /// Returns how many payloads [method decrypt] rejected since the key was
+    /// set. A growing count hints at a wrong key or someone injecting packets.
+    #[func]
+    fn get_rejected_count(&self) -> i64 {
+        self.rejected_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_between_peers_and_rejects_tampering() {
+        let key = [7u8; KEY_SIZE];
+        let mut alice = SessionCipher::new(&key);
+        let mut bob = SessionCipher::new(&key);
+
+        let sealed = alice.seal(b"voice", b"header").expect("should seal");
+        assert_eq!(sealed.len(), NONCE_SIZE + 5 + TAG_SIZE);
+        assert!(bob.open(&sealed, b"other header").is_none());
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert!(bob.open(&tampered, b"header").is_none());
+
+        assert_eq!(bob.open(&sealed, b"header").as_deref(), Some(&b"voice"[..]));
+        // Own packets reflected back aren't accepted either.
+        assert!(alice.open(&sealed, b"header").is_none());
+
+        let mut eve = SessionCipher::new(&[8u8; KEY_SIZE]);
+        let forged = eve.seal(b"voice", b"header").expect("should seal");
+        assert!(bob.open(&forged, b"header").is_none());
+    }
+
+    #[test]
+    fn rejects_replays_but_accepts_reordering() {
+        let key = [1u8; KEY_SIZE];
+        let mut sender = SessionCipher::new(&key);
+        let mut receiver = SessionCipher::new(&key);
+        let packets: Vec<_> = (0..100)
+            .map(|_| sender.seal(b"x", b"").expect("should seal"))
+            .collect();
+
+        assert!(receiver.open(&packets[10], b"").is_some());
+        assert!(receiver.open(&packets[12], b"").is_some());
+        assert!(receiver.open(&packets[11], b"").is_some());
+        assert!(receiver.open(&packets[11], b"").is_none());
+        assert!(receiver.open(&packets[12], b"").is_none());
+
+        assert!(receiver.open(&packets[90], b"").is_some());
+        // Too far behind to know whether it was seen.
+        assert!(receiver.open(&packets[20], b"").is_none());
+        assert!(receiver.open(&packets[89], b"").is_some());
+    }
+}
@@ -0,0 +1,392 @@
+use godot::prelude::*;
+
+use crate::voip_cipher::VoipCipher;
+
+/// Current wire format version. Bump when the header layout changes.
+const PACKET_VERSION: u8 = 1;
+/// version(1) + flags(1) + peer_id(4) + sequence(2) + timestamp(4)
+const HEADER_SIZE: usize = 12;
+/// First byte of a bundle. Never a valid packet version.
+const BUNDLE_MARKER: u8 = 0xB1;
+/// marker(1) + count(1)
+const BUNDLE_HEADER_SIZE: usize = 2;
+/// Every bundled packet is prefixed by its length as u16.
+const BUNDLE_ENTRY_PREFIX_SIZE: usize = 2;
+
+/// Fixed-size header that precedes every voice payload on the wire.
+///
+/// All multi-byte fields are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct VoipPacketHeader {
+    version: u8,
+    flags: u8,
+    peer_id: u32,
+    sequence: u16,
+    timestamp: u32,
+}
+
+impl VoipPacketHeader {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(self.version);
+        out.push(self.flags);
+        out.extend_from_slice(&self.peer_id.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+    }
+
+    /// Parses the header and returns it together with the remaining payload.
+    /// Returns `None` for truncated packets and unknown versions.
+    fn parse(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < HEADER_SIZE || bytes[0] != PACKET_VERSION {
+            return None;
+        }
+
+        let header = Self {
+            version: bytes[0],
+            flags: bytes[1],
+            peer_id: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+            sequence: u16::from_le_bytes([bytes[6], bytes[7]]),
+            timestamp: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        };
+        Some((header, &bytes[HEADER_SIZE..]))
+    }
+
+    /// Header fields authenticated along with an encrypted payload. The peer
+    /// id and flags are left out because relays rewrite them.
+    fn associated_data(&self) -> [u8; 7] {
+        let mut aad = [0u8; 7];
+        aad[0] = self.version;
+        aad[1..3].copy_from_slice(&self.sequence.to_le_bytes());
+        aad[3..7].copy_from_slice(&self.timestamp.to_le_bytes());
+        aad
+    }
+}
+
+/// Concatenates packed packets into one bundle. Packets that don't fit the
+/// bundle limits (255 entries, 65535 bytes each) are left out.
+fn write_bundle<'a>(packets: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut out = vec![BUNDLE_MARKER, 0];
+    let mut count = 0u8;
+    for packet in packets {
+        if count == u8::MAX || packet.len() > u16::MAX as usize {
+            continue;
+        }
+        out.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+        out.extend_from_slice(packet);
+        count += 1;
+    }
+    out[1] = count;
+    out
+}
+
+/// Splits a bundle into its packed packets. Returns `None` if the data is
+/// not a bundle or is truncated.
+fn parse_bundle(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    if bytes.len() < BUNDLE_HEADER_SIZE || bytes[0] != BUNDLE_MARKER {
+        return None;
+    }
+
+    let count = bytes[1] as usize;
+    let mut packets = Vec::with_capacity(count);
+    let mut offset = BUNDLE_HEADER_SIZE;
+    for _ in 0..count {
+        let prefix = bytes.get(offset..offset + BUNDLE_ENTRY_PREFIX_SIZE)?;
+        let len = u16::from_le_bytes([prefix[0], prefix[1]]) as usize;
+        offset += BUNDLE_ENTRY_PREFIX_SIZE;
+        packets.push(bytes.get(offset..offset + len)?);
+        offset += len;
+    }
+    Some(packets)
+}
+
+/// Signed distance from sequence `b` to sequence `a`, accounting for
+/// wraparound. Positive when `a` is newer than `b`.
+pub(crate) fn sequence_delta(a: u16, b: u16) -> i16 {
+    a.wrapping_sub(b) as i16
+}
+
+/// A voice packet with a compact wire header.
+///
+/// Use [method pack] to serialize a packet before handing it to a transport,
+/// and [method unpack] on the receiving side. Sequence numbers are 16 bits
+/// and wrap around; timestamps count 48 kHz samples and wrap at 32 bits.
+///
+/// Several packets, e.g. from different speakers relayed to the same peer,
+/// can be combined into one datagram with [method pack_bundle]. Each bundled
+/// packet keeps its own header, so receivers can tell the speakers apart.
+#[derive(GodotClass, Debug)]
+#[class(base=RefCounted)]
+pub(crate) struct VoipPacket {
+    /// Peer id of the original speaker.
+    #[var]
+    peer_id: i64,
+    /// Packet sequence number, wraps at 65536.
+    #[var]
+    sequence: i64,
+    /// Capture timestamp in 48 kHz samples, wraps at 2^32.
+    #[var]
+    timestamp: i64,
+    /// Bitmask of the FLAG_* constants.
+    #[var]
+    flags: i64,
+    /// Encoded voice data.
+    #[var]
+    payload: PackedByteArray,
+    #[allow(dead_code)]
+    base: Base<RefCounted>,
+}
+
+#[godot_api]
+impl IRefCounted for VoipPacket {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            peer_id: 0,
+            sequence: 0,
+            timestamp: 0,
+            flags: 0,
+            payload: PackedByteArray::new(),
+            base,
+        }
+    }
+}
+
+#[godot_api]
+impl VoipPacket {
+    /// Payload is raw PCM instead of Opus.
+    #[constant]
+    const FLAG_PCM: i64 = 1 << 0;
+    /// Last packet before the speaker stopped transmitting.
+    #[constant]
+    const FLAG_END_OF_SPEECH: i64 = 1 << 1;
+    /// Payload is encrypted with a [VoipCipher].
+    #[constant]
+    const FLAG_ENCRYPTED: i64 = 1 << 2;
+    /// Speaker left voice chat and sends nothing more. The payload is empty.
+    #[constant]
+    const FLAG_LEAVE: i64 = 1 << 3;
+
+    /// Returns the wire format version written by [method pack].
+    #[func]
+    fn get_version() -> i64 {
+        PACKET_VERSION as i64
+    }
+
+    /// Returns the size of the packed header in bytes.
+    #[func]
+    fn get_header_size() -> i64 {
+        HEADER_SIZE as i64
+    }
+
+    /// Creates a packet from its fields.
+    #[func]
+    fn create(
+        peer_id: i64,
+        sequence: i64,
+        timestamp: i64,
+        flags: i64,
+        payload: PackedByteArray,
+    ) -> Gd<VoipPacket> {
+        let mut packet = VoipPacket::new_gd();
+        {
+            let mut packet_mut = packet.bind_mut();
+            packet_mut.peer_id = peer_id;
+            packet_mut.sequence = sequence;
+            packet_mut.timestamp = timestamp;
+            packet_mut.flags = flags;
+            packet_mut.payload = payload;
+        }
+        packet
+    }
+
+    fn header(&self) -> VoipPacketHeader {
+        VoipPacketHeader {
+            version: PACKET_VERSION,
+            flags: self.flags as u8,
+            peer_id: self.peer_id as u32,
+            sequence: self.sequence as u16,
+            timestamp: self.timestamp as u32,
+        }
+    }
+
+    /// Serializes the header and payload into a single byte array.
+    #[func]
+    fn pack(&self) -> PackedByteArray {
+        let header = self.header();
+        let payload = self.payload.as_slice();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+        header.write_to(&mut bytes);
+        bytes.extend_from_slice(payload);
+        PackedByteArray::from(bytes)
+    }
+
+    /// Parses bytes produced by [method pack]. Returns null if the data is
+    /// truncated or uses an unknown version.
+    #[func]
+    fn unpack(bytes: PackedByteArray) -> Option<Gd<VoipPacket>> {
+        let (header, payload) = VoipPacketHeader::parse(bytes.as_slice())?;
+        Some(Self::create(
+            header.peer_id as i64,
+            header.sequence as i64,
+            header.timestamp as i64,
+            header.flags as i64,
+            PackedByteArray::from(payload),
+        ))
+    }
+
+    /// Returns how many packets sequence [param a] is ahead of [param b],
+    /// accounting for wraparound. Negative when [param a] is older.
+    #[func]
+    fn sequence_distance(a: i64, b: i64) -> i64 {
+        sequence_delta(a as u16, b as u16) as i64
+    }
+
+    #[func]
+    fn has_flag(&self, flag: i64) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Encrypts the payload with [param cipher] and sets
+    /// [constant FLAG_ENCRYPTED]. The sequence and timestamp are
+    /// authenticated too, so they can't be altered on the way. Returns false,
+    /// leaving the packet unchanged, if it's already encrypted or the cipher
+    /// has no key.
+    #[func]
+    fn encrypt(&mut self, mut cipher: Gd<VoipCipher>) -> bool {
+        if self.has_flag(Self::FLAG_ENCRYPTED) {
+            return false;
+        }
+        let aad = PackedByteArray::from(&self.header().associated_data()[..]);
+        let encrypted = cipher.bind_mut().encrypt(self.payload.clone(), aad);
+        if encrypted.is_empty() {
+            return false;
+        }
+        self.payload = encrypted;
+        self.flags |= Self::FLAG_ENCRYPTED;
+        true
+    }
+
+    /// Decrypts a payload encrypted by [method encrypt] on another peer and
+    /// clears [constant FLAG_ENCRYPTED]. Returns false, leaving the packet
+    /// unchanged, if it isn't encrypted or fails authentication; drop such
+    /// packets.
+    #[func]
+    fn decrypt(&mut self, mut cipher: Gd<VoipCipher>) -> bool {
+        if !self.has_flag(Self::FLAG_ENCRYPTED) {
+            return false;
+        }
+        let aad = PackedByteArray::from(&self.header().associated_data()[..]);
+        let decrypted = cipher.bind_mut().decrypt(self.payload.clone(), aad);
+        if decrypted.is_empty() {
+            return false;
+        }
+        self.payload = decrypted;
+        self.flags &= !Self::FLAG_ENCRYPTED;
+        true
+    }
+
+    /// Packs several packets into a single bundle. At most 255 packets are
+    /// included.
+    #[func]
+    fn pack_bundle(packets: Array<Gd<VoipPacket>>) -> PackedByteArray {
+        let packed: Vec<PackedByteArray> = packets
+            .iter_shared()
+            .map(|packet| packet.bind().pack())
+            .collect();
+        PackedByteArray::from(write_bundle(packed.iter().map(|p| p.as_slice())))
+    }
+
+    /// Splits bytes produced by [method pack_bundle] back into packets.
+    /// Returns an empty array if the data is not a valid bundle; invalid
+    /// entries inside a bundle are skipped.
+    #[func]
+    fn unpack_bundle(bytes: PackedByteArray) -> Array<Gd<VoipPacket>> {
+        let mut packets = Array::new();
+        let Some(entries) = parse_bundle(bytes.as_slice()) else {
+            return packets;
+        };
+
+        for entry in entries {
+            if let Some(packet) = Self::unpack(PackedByteArray::from(entry)) {
+                packets.push(&packet);
+            }
+        }
+        packets
+    }
+
+    /// Returns true if the bytes start like a bundle rather than a single packet.
+    #[func]
+    fn is_bundle(bytes: PackedByteArray) -> bool {
+        bytes.as_slice().first() == Some(&BUNDLE_MARKER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = VoipPacketHeader {
+            version: PACKET_VERSION,
+            flags: 0b11,
+            peer_id: 1_234_567_890,
+            sequence: 65_535,
+            timestamp: 0xDEAD_BEEF,
+        };
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes);
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(bytes.len(), HEADER_SIZE + 3);
+
+        let (parsed, payload) = VoipPacketHeader::parse(&bytes).expect("header should parse");
+        assert_eq!(parsed, header);
+        assert_eq!(payload, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_truncated_and_unknown_version() {
+        let mut bytes = Vec::new();
+        VoipPacketHeader {
+            version: PACKET_VERSION,
+            ..Default::default()
+        }
+        .write_to(&mut bytes);
+        assert!(VoipPacketHeader::parse(&bytes[..HEADER_SIZE - 1]).is_none());
+
+        bytes[0] = PACKET_VERSION + 1;
+        assert!(VoipPacketHeader::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn bundle_round_trips() {
+        let a: &[u8] = &[1, 2, 3];
+        let b: &[u8] = &[];
+        let c: &[u8] = &[9; 300];
+        let bundle = write_bundle([a, b, c]);
+
+        let entries = parse_bundle(&bundle).expect("bundle should parse");
+        assert_eq!(entries, vec![a, b, c]);
+    }
+
+    #[test]
+    fn bundle_rejects_truncated_data_and_single_packets() {
+        let bundle = write_bundle([&[1u8, 2, 3][..]]);
+        assert!(parse_bundle(&bundle[..bundle.len() - 1]).is_none());
+
+        let mut packet = Vec::new();
+        VoipPacketHeader {
+            version: PACKET_VERSION,
+            ..Default::default()
+        }
+        .write_to(&mut packet);
+        assert!(parse_bundle(&packet).is_none());
+    }
+
+    #[test]
+    fn sequence_delta_handles_wraparound() {
+        assert_eq!(sequence_delta(5, 3), 2);
+        assert_eq!(sequence_delta(3, 5), -2);
+        assert_eq!(sequence_delta(1, 65_535), 2);
+        assert_eq!(sequence_delta(65_535, 1), -2);
+    }
+}